@@ -15,11 +15,9 @@
 use std::fs::File;
 use std::io::{Result, Write};
 use std::path::PathBuf;
-use std::process::Command;
 
 fn main() -> Result<()> {
     gen_vector_asm()?;
-    build_trust_lib();
     Ok(())
 }
 
@@ -47,9 +45,3 @@ fn gen_vector_asm() -> Result<()> {
     }
     Ok(())
 }
-
-fn build_trust_lib() {
-    Command::new("make").args(&["-C", "lib"]).status().unwrap();
-    println!("cargo:rustc-link-search=native=lib");
-    println!("cargo:rustc-link-lib=static=tpm");
-}
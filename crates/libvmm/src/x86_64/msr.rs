@@ -21,6 +21,7 @@ use x86::msr::{rdmsr, wrmsr};
 #[derive(Debug, Copy, Clone)]
 #[allow(non_camel_case_types)]
 pub enum Msr {
+    IA32_APIC_BASE = 0x1b,
     IA32_FEATURE_CONTROL = 0x3a,
 
     IA32_SYSENTER_CS = 0x174,
@@ -31,6 +32,9 @@ pub enum Msr {
     IA32_MTRR_DEF_TYPE = 0x2ff,
     IA32_PERF_GLOBAL_CTRL = 0x38f,
 
+    IA32_U_CET = 0x6a0,
+    IA32_PL3_SSP = 0x6a7,
+
     IA32_VMX_BASIC = 0x480,
     IA32_VMX_PINBASED_CTLS = 0x481,
     IA32_VMX_PROCBASED_CTLS = 0x482,
@@ -48,6 +52,8 @@ pub enum Msr {
     IA32_VMX_TRUE_EXIT_CTLS = 0x48f,
     IA32_VMX_TRUE_ENTRY_CTLS = 0x490,
 
+    IA32_XSS = 0xda0,
+
     IA32_EFER = 0xc000_0080,
     IA32_STAR = 0xc000_0081,
     IA32_LSTAR = 0xc000_0082,
@@ -0,0 +1,103 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! SM2 and SM3 on byte strings, in the big-endian encodings of quotes and
+//! certificates, and the ECDSA P-256 with SHA-256 of DCAP quotes.
+
+use std::convert::TryInto;
+
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature as EcdsaSignature, VerifyingKey};
+use p256::EncodedPoint;
+use sha2::{Digest, Sha256};
+use yogcrypt::sm2::{self, Coordinate, PubKey, Signature, U64x4};
+use yogcrypt::sm3::sm3_enc;
+
+/// The distinguishing identifier of certificate signatures, the default of
+/// GM/T 0009.
+const DEFAULT_ID: &[u8] = b"1234567812345678";
+
+pub fn sm3(data: &[u8]) -> [u8; 32] {
+    let mut digest = [0; 32];
+    for (bytes, word) in digest.chunks_exact_mut(4).zip(sm3_enc(data).iter()) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn u64x4(bytes: &[u8]) -> U64x4 {
+    let word = |i: usize| u64::from_be_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+    U64x4::new(word(3), word(2), word(1), word(0))
+}
+
+/// Decodes a public key `x || y`, if it is a point of the curve.
+pub fn pub_key(key: &[u8; 64]) -> Option<PubKey> {
+    let coordinate = |bytes| Coordinate { num: u64x4(bytes) };
+    let key = PubKey::new(coordinate(&key[..32]), coordinate(&key[32..]));
+    Some(key).filter(|key| sm2::is_valid_pub_key(*key))
+}
+
+/// Decodes a signature `r || s`.
+pub fn signature(sig: &[u8; 64]) -> Signature {
+    Signature {
+        r: u64x4(&sig[..32]),
+        s: u64x4(&sig[32..]),
+    }
+}
+
+/// Verifies a signature of the hypervisor, made with the identifier of
+/// `sm2_gen_sign`.
+pub fn verify(msg: &[u8], key: &[u8; 64], sig: &[u8; 64]) -> bool {
+    match pub_key(key) {
+        Some(key) => sm2::sm2_ver_sign(msg, key, &signature(sig)),
+        None => false,
+    }
+}
+
+/// Verifies a signature on a digest, as made by a TPM.
+pub fn verify_digest(digest: &[u8; 32], key: &[u8; 64], sig: &[u8; 64]) -> bool {
+    match pub_key(key) {
+        Some(key) => sm2::sm2_ver_sign_digest(digest, key, &signature(sig)),
+        None => false,
+    }
+}
+
+/// Verifies a certificate signature on `msg`, made with the default
+/// identifier.
+pub fn verify_with_default_id(msg: &[u8], key: &[u8; 64], sig: &[u8; 64]) -> bool {
+    match pub_key(key) {
+        Some(pk) => {
+            let digest = sm3(&[&sm2::sm2_z(DEFAULT_ID, pk)[..], msg].concat());
+            sm2::sm2_ver_sign_digest(&digest, pk, &signature(sig))
+        }
+        None => false,
+    }
+}
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// Verifies an ECDSA P-256 signature `r || s` with SHA-256 on `msg`, by the
+/// public key `x || y`.
+pub fn verify_p256(msg: &[u8], key: &[u8; 64], sig: &[u8; 64]) -> bool {
+    let point = EncodedPoint::from_untagged_bytes(key.into());
+    match (
+        VerifyingKey::from_encoded_point(&point),
+        EcdsaSignature::from_slice(sig),
+    ) {
+        (Ok(key), Ok(sig)) => key.verify(msg, &sig).is_ok(),
+        _ => false,
+    }
+}
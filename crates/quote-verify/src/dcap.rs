@@ -0,0 +1,420 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Quotes in the version 3 and 4 layouts of Intel SGX DCAP, as made by the
+//! hypervisor for `EnclaveDcapQuote`, `src/enclave/dcap.rs`.
+//!
+//! The report body is signed with ECDSA P-256 by an attestation key, which
+//! the QE report certifies: its report data is the SHA-256 digest of the key
+//! and of the QE authentication data. Instead of a PCK certificate chain, the
+//! QE report is signed with SM2 by the hypervisor attestation key, and the
+//! certification data holds the rest of the signature data of a [`Quote`]
+//! for it: `hv_att_pub`, the TPM quote and the AK certificate. The QE report
+//! is thus verified as the report body of a [`Quote`] would be.
+
+use std::convert::TryInto;
+
+use tpm2::marshal::Reader;
+
+use crate::crypto::{self, sha256};
+use crate::quote::{
+    array, le_u16, le_u32, KEY_SIZE, REPORT_BODY_OFFSET, REPORT_BODY_SIZE, SIGNATURE_SIZE,
+};
+use crate::verify::{check, parse_event_log};
+use crate::{Check, Error, Quote, ReportBody, Result, Verdict, Verifier};
+
+pub const DCAP_QUOTE_VERSION_3: u16 = 3;
+pub const DCAP_QUOTE_VERSION_4: u16 = 4;
+/// `SGX_QL_ALG_ECDSA_P256`.
+pub const ATT_KEY_TYPE_ECDSA_P256: u16 = 2;
+/// The TEE type of version 4 quotes.
+pub const TEE_TYPE_SGX: u32 = 0;
+/// The QE vendor ID of the hypervisor, in place of that of the Intel QE.
+pub const HE_QE_VENDOR_ID: [u8; 16] = [
+    0xa7, 0x58, 0x29, 0xd9, 0x99, 0x4c, 0x40, 0x2c, 0x85, 0x62, 0xcc, 0x89, 0x49, 0xf2, 0x41, 0xc2,
+];
+/// Certification data wrapping the QE report of version 4 quotes.
+pub const CERT_DATA_TYPE_QE_REPORT: u16 = 6;
+/// Certification data holding the TPM evidence for the hypervisor
+/// attestation key.
+pub const CERT_DATA_TYPE_HE_TPM_EVIDENCE: u16 = 0x4845;
+
+/// `sgx_quote_header_t`.
+const HEADER_SIZE: usize = REPORT_BODY_OFFSET;
+/// The header and the report body, followed by the length of the signature
+/// data.
+const SIGNED_SIZE: usize = HEADER_SIZE + REPORT_BODY_SIZE;
+
+/// A parsed DCAP quote of the hypervisor.
+#[derive(Debug, Clone)]
+pub struct DcapQuote {
+    pub version: u16,
+    /// Zero in version 4 quotes.
+    pub qe_svn: u16,
+    /// Zero in version 4 quotes.
+    pub pce_svn: u16,
+    pub user_data: [u8; 20],
+    pub report_body: ReportBody,
+    /// The header and the report body, as signed by the attestation key.
+    pub signed_bytes: Vec<u8>,
+    /// The ECDSA signature of `signed_bytes` by the attestation key,
+    /// `r || s`.
+    pub isv_signature: [u8; SIGNATURE_SIZE],
+    /// The ECDSA P-256 attestation key, `x || y`.
+    pub attestation_key: [u8; KEY_SIZE],
+    pub qe_report: ReportBody,
+    /// The QE report as signed by the hypervisor.
+    pub qe_report_bytes: [u8; REPORT_BODY_SIZE],
+    /// The SM2 signature of the QE report by the hypervisor attestation key,
+    /// `r || s`.
+    pub qe_report_signature: [u8; SIGNATURE_SIZE],
+    pub qe_auth_data: Vec<u8>,
+    /// `hv_att_pub`, the TPM quote and its signature, then the AK
+    /// certificate, parsed by the verifier.
+    pub tpm_evidence: Vec<u8>,
+}
+
+fn truncated<T>(_: T) -> Error {
+    Error::Quote("truncated signature data")
+}
+
+fn take<'a>(r: &mut Reader<'a>, len: usize) -> Result<&'a [u8]> {
+    r.bytes(len).map_err(truncated)
+}
+
+fn take_u16(r: &mut Reader) -> Result<u16> {
+    Ok(le_u16(take(r, 2)?, 0))
+}
+
+/// Reads certification data of type `cert_type`.
+fn take_cert_data<'a>(r: &mut Reader<'a>, cert_type: u16) -> Result<&'a [u8]> {
+    if take_u16(r)? != cert_type {
+        return Err(Error::Quote("unsupported certification data"));
+    }
+    let size = le_u32(take(r, 4)?, 0) as usize;
+    take(r, size)
+}
+
+fn finish(r: &Reader) -> Result<()> {
+    r.finish()
+        .map_err(|_| Error::Quote("trailing signature data"))
+}
+
+impl DcapQuote {
+    /// Parses a quote, which may be followed by unused bytes of the quote
+    /// buffer.
+    pub fn parse(quote: &[u8]) -> Result<Self> {
+        if quote.len() < SIGNED_SIZE + 4 {
+            return Err(Error::Quote("truncated header"));
+        }
+        let version = le_u16(quote, 0);
+        if !matches!(version, DCAP_QUOTE_VERSION_3 | DCAP_QUOTE_VERSION_4)
+            || le_u16(quote, 2) != ATT_KEY_TYPE_ECDSA_P256
+        {
+            return Err(Error::Quote("unsupported version or attestation key type"));
+        }
+        if version == DCAP_QUOTE_VERSION_4 && le_u32(quote, 4) != TEE_TYPE_SGX {
+            return Err(Error::Quote("unsupported TEE type"));
+        }
+        if quote[12..28] != HE_QE_VENDOR_ID {
+            return Err(Error::Quote("not a quote of the hypervisor"));
+        }
+        let sig_len = le_u32(quote, SIGNED_SIZE) as usize;
+        let sig = quote
+            .get(SIGNED_SIZE + 4..SIGNED_SIZE + 4 + sig_len)
+            .ok_or(Error::Quote("truncated signature"))?;
+
+        let mut r = Reader::new(sig);
+        let isv_signature = take(&mut r, SIGNATURE_SIZE)?.try_into().unwrap();
+        let attestation_key = take(&mut r, KEY_SIZE)?.try_into().unwrap();
+        let mut r = if version == DCAP_QUOTE_VERSION_4 {
+            let qe_cert_data = take_cert_data(&mut r, CERT_DATA_TYPE_QE_REPORT)?;
+            finish(&r)?;
+            Reader::new(qe_cert_data)
+        } else {
+            r
+        };
+        let qe_report_bytes = take(&mut r, REPORT_BODY_SIZE)?.try_into().unwrap();
+        let qe_report_signature = take(&mut r, SIGNATURE_SIZE)?.try_into().unwrap();
+        let auth_size = take_u16(&mut r)? as usize;
+        let qe_auth_data = take(&mut r, auth_size)?.to_vec();
+        let tpm_evidence = take_cert_data(&mut r, CERT_DATA_TYPE_HE_TPM_EVIDENCE)?.to_vec();
+        finish(&r)?;
+
+        let (qe_svn, pce_svn) = match version {
+            DCAP_QUOTE_VERSION_3 => (le_u16(quote, 8), le_u16(quote, 10)),
+            _ => (0, 0),
+        };
+        Ok(Self {
+            version,
+            qe_svn,
+            pce_svn,
+            user_data: array(quote, 28),
+            report_body: ReportBody::parse(&array(quote, REPORT_BODY_OFFSET)),
+            signed_bytes: quote[..SIGNED_SIZE].to_vec(),
+            isv_signature,
+            attestation_key,
+            qe_report: ReportBody::parse(&qe_report_bytes),
+            qe_report_bytes,
+            qe_report_signature,
+            qe_auth_data,
+            tpm_evidence,
+        })
+    }
+
+    /// The report data of a QE report certifying the attestation key.
+    pub fn expected_qe_report_data(&self) -> [u8; 64] {
+        let mut report_data = [0; 64];
+        let key_and_auth = [&self.attestation_key[..], &self.qe_auth_data].concat();
+        report_data[..32].copy_from_slice(&sha256(&key_and_auth));
+        report_data
+    }
+}
+
+/// What a DCAP quote proves, check by check.
+#[derive(Debug, Clone)]
+pub struct DcapVerdict {
+    pub quote: DcapQuote,
+    /// The header and the report body are signed by the attestation key.
+    pub isv_signature: Check,
+    /// The QE report certifies the attestation key.
+    pub attestation_key: Check,
+    /// The verdict on the QE report, as the report body of a [`Quote`]
+    /// signed by the hypervisor.
+    pub qe: Verdict,
+}
+
+impl DcapVerdict {
+    pub fn checks(&self) -> Vec<(&'static str, Check)> {
+        let mut checks = vec![
+            ("ISV signature", self.isv_signature),
+            ("QE attestation key", self.attestation_key),
+        ];
+        checks.extend_from_slice(&self.qe.checks());
+        checks
+    }
+
+    /// Whether every check passed, so that the report body comes from an
+    /// enclave on a hypervisor measured by a TPM the root vouches for.
+    pub fn is_trusted(&self) -> bool {
+        self.checks()
+            .iter()
+            .all(|(_, check)| *check == Check::Passed)
+    }
+}
+
+impl Verifier {
+    /// Verifies the DCAP quote `quote` at the current time, as
+    /// [`Verifier::verify`] does.
+    pub fn verify_dcap(&self, quote: &[u8], event_log: Option<&[u8]>) -> Result<DcapVerdict> {
+        self.verify_dcap_at(quote, event_log, crate::verify::now())
+    }
+
+    /// Verifies the DCAP quote `quote` at `time`, in seconds since the Unix
+    /// epoch.
+    pub fn verify_dcap_at(
+        &self,
+        quote: &[u8],
+        event_log: Option<&[u8]>,
+        time: u64,
+    ) -> Result<DcapVerdict> {
+        let quote = DcapQuote::parse(quote)?;
+        let events = parse_event_log(event_log)?;
+        let qe = Quote::with_evidence(
+            quote.qe_report_bytes,
+            quote.qe_report_signature,
+            &quote.tpm_evidence,
+        )?;
+
+        let isv_signature = check(
+            crypto::verify_p256(
+                &quote.signed_bytes,
+                &quote.attestation_key,
+                &quote.isv_signature,
+            ),
+            "report body not signed by the attestation key",
+        );
+        let attestation_key = check(
+            quote.qe_report.report_data == quote.expected_qe_report_data(),
+            "QE report of another attestation key",
+        );
+        Ok(DcapVerdict {
+            isv_signature,
+            attestation_key,
+            qe: self.verify_quote(qe, events, time),
+            quote,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use p256::ecdsa::signature::Signer;
+    use p256::ecdsa::{Signature, SigningKey};
+
+    use super::*;
+    use crate::verify::test::*;
+
+    const ATT_KEY_SECRET: [u8; 32] = [0x42; 32];
+    const AUTH_DATA: &[u8] = b"qe auth";
+
+    fn att_key() -> SigningKey {
+        SigningKey::from_bytes(&ATT_KEY_SECRET.into()).unwrap()
+    }
+
+    fn att_key_bytes() -> Vec<u8> {
+        att_key().verifying_key().to_encoded_point(false).as_bytes()[1..].to_vec()
+    }
+
+    fn cert_data(cert_type: u16, data: &[u8]) -> Vec<u8> {
+        let mut cert_data = cert_type.to_le_bytes().to_vec();
+        cert_data.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        cert_data.extend_from_slice(data);
+        cert_data
+    }
+
+    /// Builds a quote as the hypervisor does, with a QE report of
+    /// `qe_report_data` on a TPM whose PCRs hold the extends of `log`.
+    fn build_dcap_quote(
+        version: u16,
+        qe_report_data: &[u8; 64],
+        log: &tpm2::eventlog::EventLog,
+    ) -> Vec<u8> {
+        let mut quote = Vec::new();
+        quote.extend_from_slice(&version.to_le_bytes());
+        quote.extend_from_slice(&ATT_KEY_TYPE_ECDSA_P256.to_le_bytes());
+        quote.extend_from_slice(&[0; 8]);
+        quote.extend_from_slice(&HE_QE_VENDOR_ID);
+        quote.extend_from_slice(&[0; 20]);
+        let mut body = [0; 384];
+        body[64..96].copy_from_slice(&[0x11; 32]);
+        body[320..].copy_from_slice(&[0xab; 64]);
+        quote.extend_from_slice(&body);
+        let isv_signature: Signature = att_key().sign(&quote);
+
+        let mut qe_report = [0; 384];
+        qe_report[256..258].copy_from_slice(&1u16.to_le_bytes());
+        qe_report[320..].copy_from_slice(qe_report_data);
+        let mut qe_cert_data = qe_report.to_vec();
+        qe_cert_data.extend_from_slice(&hv_sign(&qe_report));
+        qe_cert_data.extend_from_slice(&(AUTH_DATA.len() as u16).to_le_bytes());
+        qe_cert_data.extend_from_slice(AUTH_DATA);
+        let evidence = tpm_evidence(qe_report_data, log, AK);
+        qe_cert_data.extend_from_slice(&cert_data(CERT_DATA_TYPE_HE_TPM_EVIDENCE, &evidence));
+
+        let mut signature = isv_signature.to_bytes().to_vec();
+        signature.extend_from_slice(&att_key_bytes());
+        if version == DCAP_QUOTE_VERSION_4 {
+            signature.extend_from_slice(&cert_data(CERT_DATA_TYPE_QE_REPORT, &qe_cert_data));
+        } else {
+            signature.extend_from_slice(&qe_cert_data);
+        }
+        quote.extend_from_slice(&(signature.len() as u32).to_le_bytes());
+        quote.extend_from_slice(&signature);
+        quote
+    }
+
+    fn qe_report_data() -> [u8; 64] {
+        let mut report_data = [0; 64];
+        report_data[..32].copy_from_slice(&sha256(&[&att_key_bytes()[..], AUTH_DATA].concat()));
+        report_data
+    }
+
+    #[test]
+    fn test_dcap_trusted() {
+        let log = event_log(&hv_att_pub());
+        for version in [DCAP_QUOTE_VERSION_3, DCAP_QUOTE_VERSION_4] {
+            let quote = build_dcap_quote(version, &qe_report_data(), &log);
+            let verdict = verifier()
+                .verify_dcap_at(&quote, Some(log.as_bytes()), NOW)
+                .unwrap();
+            for (name, check) in verdict.checks() {
+                assert_eq!(check, Check::Passed, "{} of version {}", name, version);
+            }
+            assert!(verdict.is_trusted());
+            assert_eq!(verdict.quote.version, version);
+            assert_eq!(verdict.quote.report_body.mr_enclave, [0x11; 32]);
+            assert_eq!(verdict.quote.report_body.report_data, [0xab; 64]);
+            assert_eq!(verdict.quote.qe_auth_data, AUTH_DATA);
+            assert_eq!(verdict.qe.quote.report_body.isv_prod_id, 1);
+        }
+    }
+
+    #[test]
+    fn test_dcap_tampered() {
+        let log = event_log(&hv_att_pub());
+        let mut quote = build_dcap_quote(DCAP_QUOTE_VERSION_4, &qe_report_data(), &log);
+        // the last byte of the report data
+        quote[48 + 383] ^= 1;
+        let verdict = verifier()
+            .verify_dcap_at(&quote, Some(log.as_bytes()), NOW)
+            .unwrap();
+        assert!(matches!(verdict.isv_signature, Check::Failed(_)));
+        assert_eq!(verdict.attestation_key, Check::Passed);
+        assert!(verdict.qe.is_trusted());
+
+        // a QE report of another attestation key, signed by the hypervisor
+        let quote = build_dcap_quote(DCAP_QUOTE_VERSION_3, &[0; 64], &log);
+        let verdict = verifier()
+            .verify_dcap_at(&quote, Some(log.as_bytes()), NOW)
+            .unwrap();
+        assert_eq!(verdict.isv_signature, Check::Passed);
+        let other_key = Check::Failed("QE report of another attestation key");
+        assert_eq!(verdict.attestation_key, other_key);
+        assert!(verdict.qe.is_trusted());
+        assert!(!verdict.is_trusted());
+
+        // the QE report, whose signature covers the report data
+        let mut quote = build_dcap_quote(DCAP_QUOTE_VERSION_3, &qe_report_data(), &log);
+        quote[436 + 128 + 256] ^= 1;
+        let verdict = verifier()
+            .verify_dcap_at(&quote, Some(log.as_bytes()), NOW)
+            .unwrap();
+        assert!(matches!(verdict.qe.enclave_signature, Check::Failed(_)));
+        assert!(!verdict.is_trusted());
+    }
+
+    #[test]
+    fn test_dcap_malformed() {
+        let log = event_log(&hv_att_pub());
+        let verifier = verifier();
+        let quote = build_dcap_quote(DCAP_QUOTE_VERSION_4, &qe_report_data(), &log);
+        let err = verifier.verify_dcap_at(&quote[..quote.len() - 1], None, NOW);
+        assert_eq!(err.unwrap_err(), Error::Quote("truncated signature"));
+
+        let mut other_vendor = quote.clone();
+        other_vendor[12] ^= 1;
+        let err = verifier.verify_dcap_at(&other_vendor, None, NOW);
+        assert_eq!(
+            err.unwrap_err(),
+            Error::Quote("not a quote of the hypervisor")
+        );
+
+        // a version 4 quote is not laid out like a version 3 quote
+        let mut version_3 = quote.clone();
+        version_3[0] = 3;
+        assert!(verifier.verify_dcap_at(&version_3, None, NOW).is_err());
+
+        // the TPM evidence is cut short, within a well-formed quote
+        let mut quote = build_dcap_quote(DCAP_QUOTE_VERSION_3, &qe_report_data(), &log);
+        let offset = 436 + 128 + 384 + 64 + 2 + AUTH_DATA.len() + 2;
+        let cut = le_u32(&quote, offset) - 100;
+        quote.drain(offset + 4 + 100..);
+        quote[offset..offset + 4].copy_from_slice(&100u32.to_le_bytes());
+        let sig_len = le_u32(&quote, 432) - cut;
+        quote[432..436].copy_from_slice(&sig_len.to_le_bytes());
+        let err = verifier.verify_dcap_at(&quote, None, NOW);
+        assert_eq!(err.unwrap_err(), Error::Quote("bad TPM evidence length"));
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Just enough of a DER reader for X.509 certificates.
+
+use crate::{Error, Result};
+
+pub const TAG_BOOLEAN: u8 = 0x01;
+pub const TAG_INTEGER: u8 = 0x02;
+pub const TAG_BIT_STRING: u8 = 0x03;
+pub const TAG_OCTET_STRING: u8 = 0x04;
+pub const TAG_OID: u8 = 0x06;
+pub const TAG_UTC_TIME: u8 = 0x17;
+pub const TAG_GENERALIZED_TIME: u8 = 0x18;
+pub const TAG_SEQUENCE: u8 = 0x30;
+
+/// Tag of the context-specific, constructed field `[n]`.
+pub const fn context(n: u8) -> u8 {
+    0xa0 | n
+}
+
+fn malformed<T>() -> Result<T> {
+    Err(Error::Certificate("malformed DER"))
+}
+
+pub struct Der<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Der<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn peek_tag(&self) -> Option<u8> {
+        self.buf.first().copied()
+    }
+
+    /// Reads the next element, returning its tag, its contents and the
+    /// whole encoding.
+    pub fn read_raw(&mut self) -> Result<(u8, &'a [u8], &'a [u8])> {
+        let (tag, first) = match *self.buf {
+            [tag, first, ..] if tag & 0x1f != 0x1f => (tag, first),
+            _ => return malformed(),
+        };
+        let (header, len) = match first {
+            0..=0x7f => (2, first as usize),
+            0x81..=0x84 => {
+                let n = (first & 0x7f) as usize;
+                let bytes = match self.buf.get(2..2 + n) {
+                    Some(bytes) if bytes[0] != 0 => bytes,
+                    _ => return malformed(),
+                };
+                let len = bytes.iter().fold(0, |len, &b| len << 8 | b as usize);
+                // DER requires the shortest form
+                if len < 0x80 {
+                    return malformed();
+                }
+                (2 + n, len)
+            }
+            _ => return malformed(),
+        };
+        if self.buf.len() - header < len {
+            return malformed();
+        }
+        let (whole, rest) = self.buf.split_at(header + len);
+        self.buf = rest;
+        Ok((tag, &whole[header..], whole))
+    }
+
+    /// Reads the contents of the next element, which must have `tag`.
+    pub fn read(&mut self, tag: u8) -> Result<&'a [u8]> {
+        match self.read_raw()? {
+            (t, contents, _) if t == tag => Ok(contents),
+            _ => malformed(),
+        }
+    }
+
+    /// Reads the contents of the next element if it has `tag`.
+    pub fn read_optional(&mut self, tag: u8) -> Result<Option<&'a [u8]>> {
+        if self.peek_tag() == Some(tag) {
+            self.read(tag).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Reads a `BIT STRING` without unused bits.
+    pub fn read_bits(&mut self) -> Result<&'a [u8]> {
+        match self.read(TAG_BIT_STRING)? {
+            [0, bits @ ..] => Ok(bits),
+            _ => malformed(),
+        }
+    }
+
+    /// Reads a non-negative `INTEGER` of at most `N` bytes, big-endian and
+    /// left-padded with zeros.
+    pub fn read_uint<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let int = match self.read(TAG_INTEGER)? {
+            [] => return malformed(),
+            [0, rest @ ..] if rest.first().is_some_and(|b| b & 0x80 != 0) => rest,
+            [0, _, ..] => return malformed(),
+            [b, ..] if b & 0x80 != 0 => return malformed(),
+            int => int,
+        };
+        let pad = match N.checked_sub(int.len()) {
+            Some(pad) => pad,
+            None => return malformed(),
+        };
+        let mut value = [0; N];
+        value[pad..].copy_from_slice(int);
+        Ok(value)
+    }
+
+    pub fn finish(&self) -> Result<()> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            malformed()
+        }
+    }
+}
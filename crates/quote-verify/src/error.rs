@@ -0,0 +1,42 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Inputs that cannot be verified at all. A quote that parses but fails a
+/// check is reported in the `Verdict` instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The quote does not follow the `SgxQuote` layout.
+    Quote(&'static str),
+    /// A certificate given to the verifier does not parse, or uses an
+    /// algorithm other than SM2 with SM3.
+    Certificate(&'static str),
+    /// The event log does not parse.
+    EventLog(tpm2::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Quote(reason) => write!(f, "malformed quote: {}", reason),
+            Error::Certificate(reason) => write!(f, "bad certificate: {}", reason),
+            Error::EventLog(e) => write!(f, "malformed event log: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
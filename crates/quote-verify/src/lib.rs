@@ -0,0 +1,62 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Verification of HyperEnclave quotes, for relying parties.
+//!
+//! A quote links a report body to a root of trust through a chain of
+//! signatures and measurements, each of which is a [`Check`] of the
+//! [`Verdict`]:
+//!
+//! - the report body is signed with SM2 by the hypervisor attestation key,
+//!   `hv_att_pub`;
+//! - the hypervisor measured `hv_att_pub` into a PCR at boot, and the TPM
+//!   quotes the PCRs together with the SM3 digest of the report data;
+//! - the TPM quote is signed by the TPM attestation key (AK), whose
+//!   certificate chains up to a root the verifier trusts.
+//!
+//! The PCRs are replayed from the event log of the hypervisor, which also
+//! tells what hypervisor and enclaves were measured.
+//!
+//! Quotes in the DCAP layout are verified by [`Verifier::verify_dcap`]: their
+//! report body is signed with ECDSA by an attestation key certified by a QE
+//! report, which is then verified as the report body of a quote.
+//!
+//! RA-TLS certificates are verified by [`Verifier::verify_ratls`]: they carry
+//! a quote bound to the key they certify, and are signed by its `hv_att_pub`.
+
+mod crypto;
+mod dcap;
+mod der;
+mod error;
+mod quote;
+mod ratls;
+mod verify;
+mod x509;
+
+/// The certificate encoder of the hypervisor, whose output the tests parse.
+#[cfg(test)]
+#[path = "../../../src/enclave/ratls.rs"]
+mod hv_ratls;
+#[cfg(test)]
+extern crate alloc;
+
+pub use dcap::{
+    DcapQuote, DcapVerdict, ATT_KEY_TYPE_ECDSA_P256, CERT_DATA_TYPE_HE_TPM_EVIDENCE,
+    CERT_DATA_TYPE_QE_REPORT, DCAP_QUOTE_VERSION_3, DCAP_QUOTE_VERSION_4, HE_QE_VENDOR_ID,
+    TEE_TYPE_SGX,
+};
+pub use error::{Error, Result};
+pub use quote::{Quote, ReportBody, QUOTE_VERSION, SIGN_TYPE_SM2};
+pub use ratls::{RaTlsCertificate, RaTlsVerdict};
+pub use verify::{Check, Verdict, Verifier, HV_AK_PCR};
@@ -0,0 +1,200 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The `SgxQuote` layout of the hypervisor, `src/enclave/report.rs`.
+
+use std::convert::TryInto;
+
+use tpm2::marshal::Reader;
+use tpm2::Attest;
+
+use crate::{Error, Result};
+
+/// `HE_QUOTE_VER`.
+pub const QUOTE_VERSION: u16 = 1;
+/// `HE_SIGN_TYPE`: the report body signed with SM2 by the hypervisor, whose
+/// key is vouched for by a TPM quote.
+pub const SIGN_TYPE_SM2: u16 = 4;
+
+pub(crate) const REPORT_BODY_OFFSET: usize = 48;
+pub(crate) const REPORT_BODY_SIZE: usize = 384;
+/// `SGX_QUOTE_SIZE`, the part of the quote before the signature data.
+const QUOTE_HEADER_SIZE: usize = 436;
+pub(crate) const SIGNATURE_SIZE: usize = 64;
+pub(crate) const KEY_SIZE: usize = 64;
+/// `HE_TPM_ATT_DATA_LEN`, the `TPM2B_ATTEST` of an SM2 quote of a digest.
+const TPM_ATTEST_SIZE: usize = 147;
+/// `HE_CERT_BUF_LEN`.
+const CERT_BUF_SIZE: usize = 1280;
+/// The signature data, without the certificate.
+const MIN_SIG_LEN: usize = 2 * SIGNATURE_SIZE + KEY_SIZE + TPM_ATTEST_SIZE;
+
+/// The `SgxReportBody` of an enclave.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReportBody {
+    pub cpu_svn: [u8; 16],
+    pub misc_select: u32,
+    pub isv_ext_prod_id: [u8; 16],
+    pub flags: u64,
+    pub xfrm: u64,
+    pub mr_enclave: [u8; 32],
+    pub mr_signer: [u8; 32],
+    pub config_id: [u8; 64],
+    pub isv_prod_id: u16,
+    pub isv_svn: u16,
+    pub config_svn: u16,
+    pub isv_family_id: [u8; 16],
+    pub report_data: [u8; 64],
+}
+
+pub(crate) fn array<const N: usize>(bytes: &[u8], offset: usize) -> [u8; N] {
+    bytes[offset..offset + N].try_into().unwrap()
+}
+
+pub(crate) fn le_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(array(bytes, offset))
+}
+
+pub(crate) fn le_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(array(bytes, offset))
+}
+
+impl ReportBody {
+    pub fn parse(body: &[u8; REPORT_BODY_SIZE]) -> Self {
+        Self {
+            cpu_svn: array(body, 0),
+            misc_select: le_u32(body, 16),
+            isv_ext_prod_id: array(body, 32),
+            flags: u64::from_le_bytes(array(body, 48)),
+            xfrm: u64::from_le_bytes(array(body, 56)),
+            mr_enclave: array(body, 64),
+            mr_signer: array(body, 128),
+            config_id: array(body, 192),
+            isv_prod_id: le_u16(body, 256),
+            isv_svn: le_u16(body, 258),
+            config_svn: le_u16(body, 260),
+            isv_family_id: array(body, 304),
+            report_data: array(body, 320),
+        }
+    }
+}
+
+/// A parsed `SgxQuote`.
+#[derive(Debug, Clone)]
+pub struct Quote {
+    pub version: u16,
+    pub sign_type: u16,
+    pub epid_group_id: [u8; 4],
+    pub qe_svn: u16,
+    pub pce_svn: u16,
+    pub xeid: u32,
+    pub basename: [u8; 32],
+    pub report_body: ReportBody,
+    /// The report body as signed by the hypervisor.
+    pub report_body_bytes: [u8; REPORT_BODY_SIZE],
+    /// The SM2 signature of the report body by the hypervisor attestation
+    /// key, `r || s`.
+    pub encl_quote: [u8; SIGNATURE_SIZE],
+    /// The hypervisor attestation public key, `x || y`.
+    pub hv_att_pub: [u8; KEY_SIZE],
+    /// The `TPMS_ATTEST` of the TPM quote, exactly as signed.
+    pub tpm_attest_bytes: Vec<u8>,
+    pub tpm_attest: Attest,
+    /// The SM2 signature of the TPM quote by the TPM attestation key,
+    /// `r || s`.
+    pub tpm_signature: [u8; SIGNATURE_SIZE],
+    /// The DER certificate of the TPM attestation key, empty if the platform
+    /// has none.
+    pub certificate: Vec<u8>,
+}
+
+impl Quote {
+    /// Parses a quote, which may be followed by unused bytes of the quote
+    /// buffer.
+    pub fn parse(quote: &[u8]) -> Result<Self> {
+        if quote.len() < QUOTE_HEADER_SIZE {
+            return Err(Error::Quote("truncated header"));
+        }
+        let version = le_u16(quote, 0);
+        let sign_type = le_u16(quote, 2);
+        if version != QUOTE_VERSION || sign_type != SIGN_TYPE_SM2 {
+            return Err(Error::Quote("unsupported version or signature type"));
+        }
+        let sig_len = le_u32(quote, QUOTE_HEADER_SIZE - 4) as usize;
+        if !(MIN_SIG_LEN..=MIN_SIG_LEN + CERT_BUF_SIZE).contains(&sig_len) {
+            return Err(Error::Quote("bad signature length"));
+        }
+        let sig = quote
+            .get(QUOTE_HEADER_SIZE..QUOTE_HEADER_SIZE + sig_len)
+            .ok_or(Error::Quote("truncated signature"))?;
+        let (encl_quote, evidence) = sig.split_at(SIGNATURE_SIZE);
+
+        let mut parsed = Self::with_evidence(
+            array(quote, REPORT_BODY_OFFSET),
+            encl_quote.try_into().unwrap(),
+            evidence,
+        )?;
+        parsed.epid_group_id = array(quote, 4);
+        parsed.qe_svn = le_u16(quote, 8);
+        parsed.pce_svn = le_u16(quote, 10);
+        parsed.xeid = le_u32(quote, 12);
+        parsed.basename = array(quote, 16);
+        Ok(parsed)
+    }
+
+    /// Builds the quote of a report body signed with `encl_quote`, whose
+    /// `evidence` is the rest of the signature data: `hv_att_pub`, the TPM
+    /// quote with its signature, then the AK certificate.
+    pub(crate) fn with_evidence(
+        report_body_bytes: [u8; REPORT_BODY_SIZE],
+        encl_quote: [u8; SIGNATURE_SIZE],
+        evidence: &[u8],
+    ) -> Result<Self> {
+        let min_len = MIN_SIG_LEN - SIGNATURE_SIZE;
+        if !(min_len..=min_len + CERT_BUF_SIZE).contains(&evidence.len()) {
+            return Err(Error::Quote("bad TPM evidence length"));
+        }
+        let (hv_att_pub, evidence) = evidence.split_at(KEY_SIZE);
+        let (tpm_attest, evidence) = evidence.split_at(TPM_ATTEST_SIZE);
+        let (tpm_signature, certificate) = evidence.split_at(SIGNATURE_SIZE);
+
+        let mut r = Reader::new(tpm_attest);
+        let tpm_attest_bytes = r
+            .tpm2b()
+            .map_err(|_| Error::Quote("malformed TPM quote"))?
+            .to_vec();
+        let tpm_attest = r
+            .finish()
+            .and_then(|_| Attest::parse(&tpm_attest_bytes))
+            .map_err(|_| Error::Quote("malformed TPM quote"))?;
+
+        Ok(Self {
+            version: QUOTE_VERSION,
+            sign_type: SIGN_TYPE_SM2,
+            epid_group_id: [0; 4],
+            qe_svn: 0,
+            pce_svn: 0,
+            xeid: 0,
+            basename: [0; 32],
+            report_body: ReportBody::parse(&report_body_bytes),
+            report_body_bytes,
+            encl_quote,
+            hv_att_pub: hv_att_pub.try_into().unwrap(),
+            tpm_attest_bytes,
+            tpm_attest,
+            tpm_signature: tpm_signature.try_into().unwrap(),
+            certificate: certificate.to_vec(),
+        })
+    }
+}
@@ -0,0 +1,249 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! RA-TLS certificates, as made by the hypervisor for `EnclaveRaTlsCert`,
+//! `src/enclave/ratls.rs`.
+//!
+//! The certificate of a key of an enclave carries a [`Quote`] whose report
+//! data starts with the SHA-256 digest of the `SubjectPublicKeyInfo`, and is
+//! signed with SM2 by the `hv_att_pub` of that quote.
+
+use crate::crypto::{self, sha256};
+use crate::verify::{check, now, parse_event_log};
+use crate::x509::Fields;
+use crate::{Check, Error, Quote, Result, Verdict, Verifier};
+
+/// `2.25.53311593051785037378517846825463353372`, the extension holding the
+/// `SgxQuote`.
+const OID_HE_QUOTE: &[u8] = &[
+    0x69, 0xd0, 0x9b, 0xb8, 0x9e, 0x9b, 0xb9, 0x8a, 0xa0, 0x81, 0xad, 0x9c, 0x8e, 0xb6, 0x92, 0xfc,
+    0xda, 0xa0, 0x1c,
+];
+
+/// A parsed RA-TLS certificate.
+#[derive(Debug, Clone)]
+pub struct RaTlsCertificate {
+    /// The DER `SubjectPublicKeyInfo` of the key of the enclave.
+    pub public_key_info: Vec<u8>,
+    /// The `SgxQuote` of the quote extension.
+    pub quote: Vec<u8>,
+    tbs: Vec<u8>,
+    /// The signature of the hypervisor attestation key, `r || s`.
+    signature: [u8; 64],
+}
+
+impl RaTlsCertificate {
+    /// Parses a DER certificate, which must be exactly `der` and carry a
+    /// quote.
+    pub fn parse(der: &[u8]) -> Result<Self> {
+        let fields = Fields::parse(der)?;
+        let mut quote = None;
+        for ext in fields.extensions.iter() {
+            match ext.oid {
+                OID_HE_QUOTE if quote.is_some() => {
+                    return Err(Error::Certificate("duplicate quote extension"))
+                }
+                OID_HE_QUOTE => quote = Some(ext.value.to_vec()),
+                _ if ext.critical => return Err(Error::Certificate("unknown critical extension")),
+                _ => {}
+            }
+        }
+        Ok(Self {
+            public_key_info: fields.public_key_info.to_vec(),
+            quote: quote.ok_or(Error::Certificate("no quote extension"))?,
+            tbs: fields.tbs.to_vec(),
+            signature: fields.signature,
+        })
+    }
+
+    /// Whether the certificate bears the signature of `hv_att_pub`, `x || y`.
+    pub fn is_signed_by(&self, hv_att_pub: &[u8; 64]) -> bool {
+        crypto::verify_with_default_id(&self.tbs, hv_att_pub, &self.signature)
+    }
+
+    /// The report data of a report bound to the key of the certificate.
+    pub fn expected_report_data(&self) -> [u8; 64] {
+        let mut report_data = [0; 64];
+        report_data[..32].copy_from_slice(&sha256(&self.public_key_info));
+        report_data
+    }
+}
+
+/// What an RA-TLS certificate proves, check by check.
+#[derive(Debug, Clone)]
+pub struct RaTlsVerdict {
+    pub certificate: RaTlsCertificate,
+    /// The certificate is signed by the `hv_att_pub` of its quote.
+    pub certificate_signature: Check,
+    /// The report data of the quote is bound to the key of the certificate.
+    pub public_key: Check,
+    /// The verdict on the quote of the certificate.
+    pub quote: Verdict,
+}
+
+impl RaTlsVerdict {
+    pub fn checks(&self) -> Vec<(&'static str, Check)> {
+        let mut checks = vec![
+            ("RA-TLS certificate signature", self.certificate_signature),
+            ("RA-TLS public key", self.public_key),
+        ];
+        checks.extend_from_slice(&self.quote.checks());
+        checks
+    }
+
+    /// Whether every check passed, so that the key of the certificate
+    /// belongs to the enclave of the quote.
+    pub fn is_trusted(&self) -> bool {
+        self.checks()
+            .iter()
+            .all(|(_, check)| *check == Check::Passed)
+    }
+}
+
+impl Verifier {
+    /// Verifies the DER RA-TLS certificate `cert` at the current time, as
+    /// [`Verifier::verify`] does.
+    pub fn verify_ratls(&self, cert: &[u8], event_log: Option<&[u8]>) -> Result<RaTlsVerdict> {
+        self.verify_ratls_at(cert, event_log, now())
+    }
+
+    /// Verifies the DER RA-TLS certificate `cert` at `time`, in seconds since
+    /// the Unix epoch.
+    pub fn verify_ratls_at(
+        &self,
+        cert: &[u8],
+        event_log: Option<&[u8]>,
+        time: u64,
+    ) -> Result<RaTlsVerdict> {
+        let certificate = RaTlsCertificate::parse(cert)?;
+        let quote = Quote::parse(&certificate.quote)?;
+        let events = parse_event_log(event_log)?;
+
+        let certificate_signature = check(
+            certificate.is_signed_by(&quote.hv_att_pub),
+            "certificate not signed by hv_att_pub",
+        );
+        let public_key = check(
+            quote.report_body.report_data == certificate.expected_report_data(),
+            "report of another public key",
+        );
+        Ok(RaTlsVerdict {
+            certificate_signature,
+            public_key,
+            quote: self.verify_quote(quote, events, time),
+            certificate,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryInto;
+
+    use yogcrypt::sm2::{self, get_pub_key};
+
+    use super::*;
+    use crate::hv_ratls::{public_key_report_data, RaTlsCert};
+    use crate::verify::test::{
+        be_bytes, build_quote, event_log, hv_att_pub, sec_key, verifier, AK, HV_SECRET, NOW,
+    };
+
+    /// The `SubjectPublicKeyInfo` of a P-256 key.
+    fn p256_key_info() -> Vec<u8> {
+        let mut key = vec![
+            0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06,
+            0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00, 0x04,
+        ];
+        key.resize(key.len() + 64, 0x5a);
+        key
+    }
+
+    /// Issues the certificate of `key` as `create_ratls_cert` does, with the
+    /// quote of a report whose report data is `report_data`.
+    fn issue(key: &[u8], report_data: &[u8; 64]) -> Vec<u8> {
+        let log = event_log(&hv_att_pub());
+        let quote = build_quote(report_data, &log, AK);
+        let cert = RaTlsCert::new(key, &quote, &[0x80, 1]).unwrap();
+
+        let hv_secret = sec_key(HV_SECRET);
+        let z = sm2::sm2_z(b"1234567812345678", get_pub_key(hv_secret));
+        let digest = crypto::sm3(&[&z[..], cert.tbs_certificate()].concat());
+        let sig = sm2::sm2_gen_sign_digest(&digest, hv_secret);
+        cert.finish(&be_bytes(&[sig.r, sig.s]).try_into().unwrap())
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let key = p256_key_info();
+        let der = issue(&key, &public_key_report_data(&key));
+        let cert = RaTlsCertificate::parse(&der).unwrap();
+        assert_eq!(cert.public_key_info, key);
+        let quote = Quote::parse(&cert.quote).unwrap();
+        assert_eq!(quote.report_body.report_data, cert.expected_report_data());
+        assert!(cert.is_signed_by(&quote.hv_att_pub));
+        let mut other_key = quote.hv_att_pub;
+        other_key[..32].copy_from_slice(&crypto::sm3(b"other"));
+        assert!(!cert.is_signed_by(&other_key));
+
+        let log = event_log(&hv_att_pub());
+        let verdict = verifier()
+            .verify_ratls_at(&der, Some(log.as_bytes()), NOW)
+            .unwrap();
+        for (name, check) in verdict.checks().iter() {
+            assert_eq!(*check, Check::Passed, "{}", name);
+        }
+        assert!(verdict.is_trusted());
+    }
+
+    #[test]
+    fn test_long_key() {
+        // an RSA-3072 `SubjectPublicKeyInfo` takes two length bytes
+        let mut key = vec![0x30, 0x82, 0x01, 0xa2];
+        key.resize(4 + 0x1a2, 0x11);
+        let der = issue(&key, &public_key_report_data(&key));
+        let verdict = verifier().verify_ratls_at(&der, None, NOW).unwrap();
+        assert_eq!(verdict.certificate.public_key_info, key);
+        assert_eq!(verdict.certificate_signature, Check::Passed);
+        assert_eq!(verdict.public_key, Check::Passed);
+    }
+
+    #[test]
+    fn test_tampered() {
+        let key = p256_key_info();
+        let unbound = issue(&key, &[0xab; 64]);
+        let verdict = verifier().verify_ratls_at(&unbound, None, NOW).unwrap();
+        assert_eq!(verdict.certificate_signature, Check::Passed);
+        assert_eq!(
+            verdict.public_key,
+            Check::Failed("report of another public key")
+        );
+
+        let mut forged = issue(&key, &public_key_report_data(&key));
+        // a byte of the enclave key
+        let offset = forged.windows(4).position(|w| w == [0x5a; 4]).unwrap();
+        forged[offset] ^= 1;
+        let verdict = verifier().verify_ratls_at(&forged, None, NOW).unwrap();
+        assert_eq!(
+            verdict.certificate_signature,
+            Check::Failed("certificate not signed by hv_att_pub")
+        );
+        assert_eq!(
+            verdict.public_key,
+            Check::Failed("report of another public key")
+        );
+
+        let der = issue(&key, &public_key_report_data(&key));
+        assert!(RaTlsCertificate::parse(&der[..der.len() - 1]).is_err());
+    }
+}
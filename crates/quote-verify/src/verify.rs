@@ -0,0 +1,543 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tpm2::consts::{TPM_ALG_SM3_256, TPM_GENERATED_VALUE};
+use tpm2::eventlog::{self, Bank, Event, EV_NO_ACTION};
+use tpm2::Attest;
+
+use crate::crypto::{self, sm3};
+use crate::x509::Certificate;
+use crate::{Error, Quote, Result};
+
+/// The PCR the hypervisor extends with the SM3 digest of its attestation key,
+/// `HV_AK_PCR`.
+pub const HV_AK_PCR: u32 = 13;
+
+const SM3_BANK: Bank = Bank::new(TPM_ALG_SM3_256, 32);
+/// Intermediate certificates allowed between the root and the AK certificate.
+const MAX_CHAIN_LEN: usize = 8;
+
+/// The outcome of one check of a quote.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Check {
+    Passed,
+    Failed(&'static str),
+    /// The check needs an input that was not given or did not verify.
+    Skipped(&'static str),
+}
+
+pub(crate) fn check(passed: bool, reason: &'static str) -> Check {
+    if passed {
+        Check::Passed
+    } else {
+        Check::Failed(reason)
+    }
+}
+
+/// What a quote proves, check by check.
+#[derive(Debug, Clone)]
+pub struct Verdict {
+    pub quote: Quote,
+    /// The events of the event log, empty if none was given.
+    pub events: Vec<Event>,
+    /// The PCRs selected by the TPM quote, with their values replayed from
+    /// the event log.
+    pub pcrs: Vec<(u32, Vec<u8>)>,
+    /// The report body is signed by `hv_att_pub`.
+    pub enclave_signature: Check,
+    /// The AK certificate chains up to the root.
+    pub certificate_chain: Check,
+    /// The TPM quote is signed by the key of the AK certificate.
+    pub tpm_signature: Check,
+    /// The TPM quote carries the SM3 digest of the report data.
+    pub report_data: Check,
+    /// The PCRs replayed from the event log are those of the TPM quote.
+    pub pcr_digest: Check,
+    /// `hv_att_pub` is the key measured into `HV_AK_PCR`.
+    pub attestation_key: Check,
+}
+
+impl Verdict {
+    pub fn checks(&self) -> [(&'static str, Check); 6] {
+        [
+            ("enclave signature", self.enclave_signature),
+            ("certificate chain", self.certificate_chain),
+            ("TPM signature", self.tpm_signature),
+            ("report data", self.report_data),
+            ("PCR digest", self.pcr_digest),
+            ("attestation key", self.attestation_key),
+        ]
+    }
+
+    /// Whether every check passed, so that the report body comes from an
+    /// enclave on a hypervisor measured by a TPM the root vouches for.
+    ///
+    /// The measurements themselves, in `events`, are left to the caller.
+    pub fn is_trusted(&self) -> bool {
+        self.checks()
+            .iter()
+            .all(|(_, check)| *check == Check::Passed)
+    }
+}
+
+/// Verifies quotes against a root certificate, the issuer of the AK
+/// certificates or of their intermediate CAs.
+#[derive(Debug, Clone)]
+pub struct Verifier {
+    root: Certificate,
+    intermediates: Vec<Certificate>,
+}
+
+impl Verifier {
+    /// Creates a verifier trusting the DER certificate `root`.
+    pub fn new(root: &[u8]) -> Result<Self> {
+        Ok(Self {
+            root: Certificate::parse(root)?,
+            intermediates: Vec::new(),
+        })
+    }
+
+    /// Adds a DER certificate that may chain an AK certificate up to the root.
+    pub fn add_intermediate(&mut self, cert: &[u8]) -> Result<()> {
+        self.intermediates.push(Certificate::parse(cert)?);
+        Ok(())
+    }
+
+    /// Verifies `quote` at the current time. Without the `event_log` of the
+    /// hypervisor, the PCRs and thus `hv_att_pub` cannot be verified.
+    pub fn verify(&self, quote: &[u8], event_log: Option<&[u8]>) -> Result<Verdict> {
+        self.verify_at(quote, event_log, now())
+    }
+
+    /// Verifies `quote` at `time`, in seconds since the Unix epoch.
+    pub fn verify_at(&self, quote: &[u8], event_log: Option<&[u8]>, time: u64) -> Result<Verdict> {
+        let quote = Quote::parse(quote)?;
+        let events = parse_event_log(event_log)?;
+        Ok(self.verify_quote(quote, events, time))
+    }
+
+    pub(crate) fn verify_quote(
+        &self,
+        quote: Quote,
+        events: Option<Vec<Event>>,
+        time: u64,
+    ) -> Verdict {
+        let attest = &quote.tpm_attest;
+
+        let enclave_signature = check(
+            crypto::verify(
+                &quote.report_body_bytes,
+                &quote.hv_att_pub,
+                &quote.encl_quote,
+            ),
+            "report body not signed by the attestation key",
+        );
+        let report_data = check(
+            attest.extra_data == sm3(&quote.report_body.report_data),
+            "TPM quote of other report data",
+        );
+
+        let ak = if quote.certificate.is_empty() {
+            Err("no AK certificate")
+        } else {
+            Certificate::parse(&quote.certificate).map_err(|_| "unsupported AK certificate")
+        };
+        let (certificate_chain, tpm_signature) = match &ak {
+            Ok(ak) => {
+                let signed = crypto::verify_digest(
+                    &sm3(&quote.tpm_attest_bytes),
+                    ak.public_key(),
+                    &quote.tpm_signature,
+                );
+                (
+                    self.check_chain(ak, time),
+                    check(
+                        signed && attest.magic == TPM_GENERATED_VALUE,
+                        "TPM quote not signed by the AK",
+                    ),
+                )
+            }
+            Err(reason) => (Check::Failed(reason), Check::Skipped(reason)),
+        };
+
+        let (pcrs, pcr_digest) = match &events {
+            Some(events) => replay_pcrs(attest, events),
+            None => (Vec::new(), Check::Skipped("no event log")),
+        };
+        let attestation_key = match pcrs.iter().find(|(pcr, _)| *pcr == HV_AK_PCR) {
+            _ if pcr_digest != Check::Passed => Check::Skipped("PCRs not verified"),
+            Some((_, value)) => check(
+                *value == attestation_key_pcr(&quote.hv_att_pub),
+                "another attestation key measured",
+            ),
+            None => Check::Failed("attestation key PCR not quoted"),
+        };
+
+        Verdict {
+            quote,
+            events: events.unwrap_or_default(),
+            pcrs,
+            enclave_signature,
+            certificate_chain,
+            tpm_signature,
+            report_data,
+            pcr_digest,
+            attestation_key,
+        }
+    }
+
+    fn check_chain(&self, ak: &Certificate, time: u64) -> Check {
+        let mut cert = ak;
+        for _ in 0..=MAX_CHAIN_LEN {
+            if !cert.is_valid_at(time) {
+                return Check::Failed("certificate expired or not yet valid");
+            }
+            if cert.is_issued_by(&self.root) {
+                return check(self.root.is_valid_at(time), "root expired or not yet valid");
+            }
+            cert = match self
+                .intermediates
+                .iter()
+                .find(|ca| ca.is_ca() && cert.is_issued_by(ca))
+            {
+                Some(ca) => ca,
+                None => return Check::Failed("certificate not issued by the root"),
+            };
+        }
+        Check::Failed("certificate chain too long")
+    }
+}
+
+/// The current time, in seconds since the Unix epoch.
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+pub(crate) fn parse_event_log(event_log: Option<&[u8]>) -> Result<Option<Vec<Event>>> {
+    match event_log {
+        Some(log) => Ok(Some(eventlog::parse(log).map_err(Error::EventLog)?.1)),
+        None => Ok(None),
+    }
+}
+
+/// The value of `HV_AK_PCR` after the hypervisor measured `hv_att_pub`.
+fn attestation_key_pcr(hv_att_pub: &[u8]) -> Vec<u8> {
+    sm3(&[&[0; 32][..], &sm3(hv_att_pub)].concat()).to_vec()
+}
+
+/// Replays the PCRs selected by `attest` from `events`, checking that each
+/// event is what was extended.
+fn replay_pcrs(attest: &Attest, events: &[Event]) -> (Vec<(u32, Vec<u8>)>, Check) {
+    let extended_data = events
+        .iter()
+        .filter(|event| event.event_type != EV_NO_ACTION)
+        .all(|event| event.digest(TPM_ALG_SM3_256) == Some(&sm3(&event.data)[..]));
+    if !extended_data {
+        return (
+            Vec::new(),
+            Check::Failed("event data does not match its digest"),
+        );
+    }
+    let selections = &attest.quote.pcr_select;
+    if selections.iter().any(|sel| sel.hash != TPM_ALG_SM3_256) {
+        return (Vec::new(), Check::Failed("PCR bank other than SM3 quoted"));
+    }
+    let pcrs: Vec<_> = selections
+        .iter()
+        .flat_map(|sel| sel.iter())
+        .map(|pcr| {
+            let value = eventlog::replay(events, pcr, SM3_BANK, |data| sm3(data).to_vec());
+            // every event has an SM3 digest, as checked above
+            (pcr, value.unwrap())
+        })
+        .collect();
+    let values: Vec<u8> = pcrs.iter().flat_map(|(_, value)| value.clone()).collect();
+    let digest = check(
+        sm3(&values)[..] == attest.quote.pcr_digest[..],
+        "event log does not match the PCRs",
+    );
+    (pcrs, digest)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use tpm2::eventlog::{tagged_event, EventLog, EV_EVENT_TAG, EV_IPL};
+    use tpm2::marshal::Writer;
+    use tpm2::{PcrSelection, QuoteInfo};
+    use yogcrypt::sm2::{self, get_pub_key, PubKey, SecKey, Signature, U64x4};
+
+    use super::*;
+    use crate::{QUOTE_VERSION, SIGN_TYPE_SM2};
+
+    const ROOT: &[u8] = include_bytes!("testdata/root.der");
+    const CA: &[u8] = include_bytes!("testdata/ca.der");
+    /// Certifies `AK_SECRET`, valid from 2023 to 2033.
+    pub(crate) const AK: &[u8] = include_bytes!("testdata/ak.der");
+    const AK_SECRET: &str = "ee8414c637da45729b20ba1a78ada8b1c4eced24fdf8fee8992a51190c4dd930";
+    pub(crate) const HV_SECRET: &str =
+        "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    /// 2023-11-14.
+    pub(crate) const NOW: u64 = 1_700_000_000;
+
+    pub(crate) fn sec_key(hex: &str) -> SecKey {
+        let word = |i: usize| u64::from_str_radix(&hex[i * 16..i * 16 + 16], 16).unwrap();
+        U64x4::new(word(3), word(2), word(1), word(0))
+    }
+
+    pub(crate) fn be_bytes(values: &[U64x4]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|v| v.value.iter().rev().flat_map(|w| w.to_be_bytes()))
+            .collect()
+    }
+
+    fn pub_key_bytes(key: PubKey) -> Vec<u8> {
+        be_bytes(&[key.x.num, key.y.num])
+    }
+
+    fn sig_bytes(sig: Signature) -> Vec<u8> {
+        be_bytes(&[sig.r, sig.s])
+    }
+
+    pub(crate) fn event_log(hv_att_pub: &[u8]) -> EventLog {
+        let mut log = EventLog::new(&[SM3_BANK]);
+        let hypervisor = tagged_event(0x4845_0002, b"hypervisor");
+        let enclave = tagged_event(0x4845_0001, b"enclave");
+        for (pcr, event_type, event) in [
+            (12, EV_EVENT_TAG, &hypervisor[..]),
+            (HV_AK_PCR, EV_IPL, hv_att_pub),
+            (14, EV_EVENT_TAG, &enclave),
+        ] {
+            log.push(pcr, event_type, &[&sm3(event)], event).unwrap();
+        }
+        log
+    }
+
+    /// Signs `data` with the hypervisor attestation key.
+    pub(crate) fn hv_sign(data: &[u8]) -> Vec<u8> {
+        let hv_secret = sec_key(HV_SECRET);
+        sig_bytes(sm2::sm2_gen_sign(
+            data,
+            hv_secret,
+            get_pub_key(hv_secret),
+            true,
+        ))
+    }
+
+    /// The signature data following `encl_quote`: `hv_att_pub`, then a TPM
+    /// quote of `report_data` on a TPM whose PCRs hold the extends of `log`,
+    /// then `cert`.
+    pub(crate) fn tpm_evidence(report_data: &[u8; 64], log: &EventLog, cert: &[u8]) -> Vec<u8> {
+        let (_, events) = eventlog::parse(log.as_bytes()).unwrap();
+        let selection = PcrSelection::new(TPM_ALG_SM3_256, &[12, HV_AK_PCR, 14]);
+        let pcrs: Vec<u8> = selection
+            .iter()
+            .flat_map(|pcr| eventlog::replay(&events, pcr, SM3_BANK, |d| sm3(d).to_vec()).unwrap())
+            .collect();
+        let attest = Attest {
+            magic: TPM_GENERATED_VALUE,
+            qualified_signer: vec![0x55; 34],
+            extra_data: sm3(report_data).to_vec(),
+            clock: 1,
+            reset_count: 0,
+            restart_count: 0,
+            safe: true,
+            firmware_version: 0,
+            quote: QuoteInfo {
+                pcr_select: vec![selection],
+                pcr_digest: sm3(&pcrs).to_vec(),
+            },
+        };
+        let mut attest_bytes = Writer::new();
+        attest_bytes.put(&attest);
+        let tpm_signature =
+            sm2::sm2_gen_sign_digest(&sm3(attest_bytes.as_slice()), sec_key(AK_SECRET));
+
+        let mut evidence = hv_att_pub();
+        evidence.extend_from_slice(Writer::new().tpm2b(attest_bytes.as_slice()).as_slice());
+        evidence.extend_from_slice(&sig_bytes(tpm_signature));
+        evidence.extend_from_slice(cert);
+        evidence
+    }
+
+    /// Builds a quote as the hypervisor does, on a TPM whose PCRs hold the
+    /// extends of `log`.
+    pub(crate) fn build_quote(report_data: &[u8; 64], log: &EventLog, cert: &[u8]) -> Vec<u8> {
+        let mut body = [0; 384];
+        body[64..96].copy_from_slice(&[0x11; 32]);
+        body[128..160].copy_from_slice(&[0x22; 32]);
+        body[256..258].copy_from_slice(&7u16.to_le_bytes());
+        body[320..].copy_from_slice(report_data);
+
+        let mut signature = hv_sign(&body);
+        signature.extend_from_slice(&tpm_evidence(report_data, log, cert));
+
+        let mut quote = Vec::new();
+        quote.extend_from_slice(&QUOTE_VERSION.to_le_bytes());
+        quote.extend_from_slice(&SIGN_TYPE_SM2.to_le_bytes());
+        quote.extend_from_slice(&[0; 44]);
+        quote.extend_from_slice(&body);
+        quote.extend_from_slice(&(signature.len() as u32).to_le_bytes());
+        quote.extend_from_slice(&signature);
+        // the rest of the quote buffer
+        quote.resize(quote.len() + 100, 0);
+        quote
+    }
+
+    pub(crate) fn hv_att_pub() -> Vec<u8> {
+        pub_key_bytes(get_pub_key(sec_key(HV_SECRET)))
+    }
+
+    pub(crate) fn verifier() -> Verifier {
+        let mut verifier = Verifier::new(ROOT).unwrap();
+        verifier.add_intermediate(CA).unwrap();
+        verifier
+    }
+
+    #[test]
+    fn test_trusted() {
+        let log = event_log(&hv_att_pub());
+        let quote = build_quote(&[0xab; 64], &log, AK);
+        let verdict = verifier()
+            .verify_at(&quote, Some(log.as_bytes()), NOW)
+            .unwrap();
+        for (name, check) in verdict.checks().iter() {
+            assert_eq!(*check, Check::Passed, "{}", name);
+        }
+        assert!(verdict.is_trusted());
+        assert_eq!(verdict.quote.report_body.mr_enclave, [0x11; 32]);
+        assert_eq!(verdict.quote.report_body.isv_prod_id, 7);
+        assert_eq!(verdict.events.len(), 3);
+        let pcrs: Vec<_> = verdict.pcrs.iter().map(|(pcr, _)| *pcr).collect();
+        assert_eq!(pcrs, [12, 13, 14]);
+    }
+
+    #[test]
+    fn test_certificate_chain() {
+        let log = event_log(&hv_att_pub());
+        let quote = build_quote(&[0; 64], &log, AK);
+        let log = Some(log.as_bytes());
+
+        let verdict = Verifier::new(ROOT)
+            .unwrap()
+            .verify_at(&quote, log, NOW)
+            .unwrap();
+        let not_issued = Check::Failed("certificate not issued by the root");
+        assert_eq!(verdict.certificate_chain, not_issued);
+        assert_eq!(verdict.tpm_signature, Check::Passed);
+        assert!(!verdict.is_trusted());
+
+        let verdict = verifier().verify_at(&quote, log, 2_000_000_000).unwrap();
+        let expired = Check::Failed("certificate expired or not yet valid");
+        assert_eq!(verdict.certificate_chain, expired);
+
+        // any CA of the chain may be trusted as the root
+        let mut verifier = Verifier::new(CA).unwrap();
+        verifier.add_intermediate(ROOT).unwrap();
+        let verdict = verifier.verify_at(&quote, log, NOW).unwrap();
+        assert_eq!(verdict.certificate_chain, Check::Passed);
+        let verdict = Verifier::new(AK).unwrap().verify_at(&quote, log, NOW);
+        assert_eq!(verdict.unwrap().certificate_chain, not_issued);
+
+        let quote = build_quote(&[0; 64], &event_log(&hv_att_pub()), &[]);
+        let verdict = verifier.verify_at(&quote, log, NOW).unwrap();
+        assert_eq!(
+            verdict.certificate_chain,
+            Check::Failed("no AK certificate")
+        );
+        assert_eq!(verdict.tpm_signature, Check::Skipped("no AK certificate"));
+    }
+
+    #[test]
+    fn test_tampered() {
+        let log = event_log(&hv_att_pub());
+        let mut quote = build_quote(&[0; 64], &log, AK);
+        // the last byte of the report data
+        quote[48 + 383] ^= 1;
+        let verdict = verifier()
+            .verify_at(&quote, Some(log.as_bytes()), NOW)
+            .unwrap();
+        assert!(matches!(verdict.enclave_signature, Check::Failed(_)));
+        assert!(matches!(verdict.report_data, Check::Failed(_)));
+        assert_eq!(verdict.tpm_signature, Check::Passed);
+
+        let mut quote = build_quote(&[0; 64], &log, AK);
+        // the clock of the TPM quote
+        let clock = 436 + 128 + 2 + 4 + 2 + 36 + 34;
+        quote[clock + 7] ^= 1;
+        let verdict = verifier()
+            .verify_at(&quote, Some(log.as_bytes()), NOW)
+            .unwrap();
+        assert_eq!(verdict.enclave_signature, Check::Passed);
+        assert!(matches!(verdict.tpm_signature, Check::Failed(_)));
+    }
+
+    #[test]
+    fn test_pcrs() {
+        let log = event_log(&hv_att_pub());
+        let quote = build_quote(&[0; 64], &log, AK);
+        let verdict = verifier().verify_at(&quote, None, NOW).unwrap();
+        assert_eq!(verdict.pcr_digest, Check::Skipped("no event log"));
+        assert_eq!(verdict.attestation_key, Check::Skipped("PCRs not verified"));
+        assert!(!verdict.is_trusted());
+
+        // the TPM quote is of the PCRs of another event log
+        let other = event_log(&[0; 64]);
+        let verdict = verifier()
+            .verify_at(&quote, Some(other.as_bytes()), NOW)
+            .unwrap();
+        let mismatch = Check::Failed("event log does not match the PCRs");
+        assert_eq!(verdict.pcr_digest, mismatch);
+
+        // the PCRs are those of a hypervisor with another key
+        let quote = build_quote(&[0; 64], &other, AK);
+        let verdict = verifier()
+            .verify_at(&quote, Some(other.as_bytes()), NOW)
+            .unwrap();
+        assert_eq!(verdict.pcr_digest, Check::Passed);
+        let other_key = Check::Failed("another attestation key measured");
+        assert_eq!(verdict.attestation_key, other_key);
+
+        // an event whose data is not what was extended
+        let mut forged = EventLog::new(&[SM3_BANK]);
+        forged
+            .push(HV_AK_PCR, EV_IPL, &[&sm3(&hv_att_pub())], b"forged")
+            .unwrap();
+        let verdict = verifier()
+            .verify_at(&quote, Some(forged.as_bytes()), NOW)
+            .unwrap();
+        let forged = Check::Failed("event data does not match its digest");
+        assert_eq!(verdict.pcr_digest, forged);
+    }
+
+    #[test]
+    fn test_malformed() {
+        let log = event_log(&hv_att_pub());
+        let quote = build_quote(&[0; 64], &log, AK);
+        let verifier = verifier();
+        let truncated = verifier.verify_at(&quote[..435], None, NOW);
+        assert_eq!(truncated.unwrap_err(), Error::Quote("truncated header"));
+        let end = 436 + 339 + AK.len();
+        let truncated = verifier.verify_at(&quote[..end - 1], None, NOW);
+        assert_eq!(truncated.unwrap_err(), Error::Quote("truncated signature"));
+        let bytes = log.as_bytes();
+        let err = verifier.verify_at(&quote, Some(&bytes[..bytes.len() - 1]), NOW);
+        assert_eq!(err.unwrap_err(), Error::EventLog(tpm2::Error::Malformed));
+        assert!(Verifier::new(&ROOT[1..]).is_err());
+    }
+}
@@ -0,0 +1,356 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! X.509 certificates with SM2 keys, signed with SM2 and SM3.
+
+use std::convert::TryInto;
+
+use crate::crypto;
+use crate::der::*;
+use crate::{Error, Result};
+
+/// `SM2-with-SM3`, 1.2.156.10197.1.501.
+const OID_SM2_WITH_SM3: &[u8] = &[0x2a, 0x81, 0x1c, 0xcf, 0x55, 0x01, 0x83, 0x75];
+/// `id-ecPublicKey`, 1.2.840.10045.2.1.
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+/// The SM2 curve, 1.2.156.10197.1.301.
+const OID_SM2_CURVE: &[u8] = &[0x2a, 0x81, 0x1c, 0xcf, 0x55, 0x01, 0x82, 0x2d];
+const OID_BASIC_CONSTRAINTS: &[u8] = &[0x55, 0x1d, 0x13];
+const OID_KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x0f];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// The `keyCertSign` bit of the first byte of a key usage.
+const KEY_USAGE_CERT_SIGN: u8 = 0x04;
+
+fn bad<T>(reason: &'static str) -> Result<T> {
+    Err(Error::Certificate(reason))
+}
+
+#[derive(Debug, Clone)]
+pub struct Certificate {
+    tbs: Vec<u8>,
+    issuer: Vec<u8>,
+    subject: Vec<u8>,
+    /// The validity period, in seconds since the Unix epoch.
+    not_before: u64,
+    not_after: u64,
+    /// The SM2 public key, `x || y`.
+    public_key: [u8; 64],
+    is_ca: bool,
+    /// Whether the key usage, if any, allows signing certificates.
+    cert_sign: bool,
+    /// The signature of the issuer, `r || s`.
+    signature: [u8; 64],
+}
+
+/// An extension of a certificate.
+pub(crate) struct Extension<'a> {
+    pub oid: &'a [u8],
+    pub critical: bool,
+    pub value: &'a [u8],
+}
+
+/// The fields of a certificate signed with SM2 and SM3, whatever its key.
+pub(crate) struct Fields<'a> {
+    pub tbs: &'a [u8],
+    pub issuer: &'a [u8],
+    pub subject: &'a [u8],
+    pub not_before: u64,
+    pub not_after: u64,
+    /// The DER `SubjectPublicKeyInfo`.
+    pub public_key_info: &'a [u8],
+    pub extensions: Vec<Extension<'a>>,
+    /// The signature of the issuer, `r || s`.
+    pub signature: [u8; 64],
+}
+
+impl<'a> Fields<'a> {
+    /// Parses a DER certificate, which must be exactly `der`.
+    pub fn parse(der: &'a [u8]) -> Result<Self> {
+        let mut outer = Der::new(der);
+        let mut cert = Der::new(outer.read(TAG_SEQUENCE)?);
+        outer.finish()?;
+        let (_, tbs, tbs_raw) = cert.read_raw()?;
+        let alg = read_signature_algorithm(&mut cert)?;
+        let mut sig = Der::new(cert.read_bits()?);
+        cert.finish()?;
+
+        let mut seq = Der::new(sig.read(TAG_SEQUENCE)?);
+        sig.finish()?;
+        let mut signature = [0; 64];
+        signature[..32].copy_from_slice(&seq.read_uint::<32>()?);
+        signature[32..].copy_from_slice(&seq.read_uint::<32>()?);
+        seq.finish()?;
+
+        let mut tbs = Der::new(tbs);
+        if let Some(version) = tbs.read_optional(context(0))? {
+            // only v3 certificates have extensions
+            if version != [TAG_INTEGER, 1, 2] {
+                return bad("unsupported version");
+            }
+        }
+        tbs.read(TAG_INTEGER)?;
+        if read_signature_algorithm(&mut tbs)? != alg {
+            return bad("mismatched signature algorithms");
+        }
+        let (_, _, issuer) = tbs.read_raw()?;
+        let mut validity = Der::new(tbs.read(TAG_SEQUENCE)?);
+        let not_before = read_time(&mut validity)?;
+        let not_after = read_time(&mut validity)?;
+        validity.finish()?;
+        let (_, _, subject) = tbs.read_raw()?;
+        let (_, _, public_key_info) = tbs.read_raw()?;
+
+        let mut parsed_extensions = Vec::new();
+        // unique identifiers
+        tbs.read_optional(0x81)?;
+        tbs.read_optional(0x82)?;
+        if let Some(extensions) = tbs.read_optional(context(3))? {
+            let mut outer = Der::new(extensions);
+            let mut extensions = Der::new(outer.read(TAG_SEQUENCE)?);
+            outer.finish()?;
+            while !extensions.is_empty() {
+                let mut ext = Der::new(extensions.read(TAG_SEQUENCE)?);
+                let oid = ext.read(TAG_OID)?;
+                let critical = ext.read_optional(TAG_BOOLEAN)? == Some(&[0xff][..]);
+                let value = ext.read(TAG_OCTET_STRING)?;
+                ext.finish()?;
+                parsed_extensions.push(Extension {
+                    oid,
+                    critical,
+                    value,
+                });
+            }
+        }
+        tbs.finish()?;
+
+        Ok(Self {
+            tbs: tbs_raw,
+            issuer,
+            subject,
+            not_before,
+            not_after,
+            public_key_info,
+            extensions: parsed_extensions,
+            signature,
+        })
+    }
+}
+
+impl Certificate {
+    /// Parses a DER certificate, which must be exactly `der`.
+    pub fn parse(der: &[u8]) -> Result<Self> {
+        let fields = Fields::parse(der)?;
+        let public_key = read_public_key(fields.public_key_info)?;
+
+        let mut is_ca = false;
+        let mut cert_sign = true;
+        for ext in fields.extensions.iter() {
+            match ext.oid {
+                OID_BASIC_CONSTRAINTS => is_ca = read_basic_constraints(ext.value)?,
+                OID_KEY_USAGE => cert_sign = read_key_usage(ext.value)? & KEY_USAGE_CERT_SIGN != 0,
+                OID_SUBJECT_ALT_NAME => {}
+                _ if ext.critical => return bad("unknown critical extension"),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            tbs: fields.tbs.to_vec(),
+            issuer: fields.issuer.to_vec(),
+            subject: fields.subject.to_vec(),
+            not_before: fields.not_before,
+            not_after: fields.not_after,
+            public_key,
+            is_ca,
+            cert_sign,
+            signature: fields.signature,
+        })
+    }
+
+    pub fn public_key(&self) -> &[u8; 64] {
+        &self.public_key
+    }
+
+    pub fn is_valid_at(&self, time: u64) -> bool {
+        self.not_before <= time && time <= self.not_after
+    }
+
+    /// Whether the key may sign other certificates.
+    pub fn is_ca(&self) -> bool {
+        self.is_ca && self.cert_sign
+    }
+
+    /// Whether this certificate names `issuer` as its issuer and bears its
+    /// signature.
+    pub fn is_issued_by(&self, issuer: &Certificate) -> bool {
+        self.issuer == issuer.subject
+            && crypto::verify_with_default_id(&self.tbs, &issuer.public_key, &self.signature)
+    }
+}
+
+fn read_signature_algorithm<'a>(der: &mut Der<'a>) -> Result<&'a [u8]> {
+    let mut alg = Der::new(der.read(TAG_SEQUENCE)?);
+    let oid = alg.read(TAG_OID)?;
+    // the parameters are absent
+    alg.finish()?;
+    if oid != OID_SM2_WITH_SM3 {
+        return bad("unsupported signature algorithm");
+    }
+    Ok(oid)
+}
+
+fn read_public_key(public_key_info: &[u8]) -> Result<[u8; 64]> {
+    let mut der = Der::new(public_key_info);
+    let mut spki = Der::new(der.read(TAG_SEQUENCE)?);
+    der.finish()?;
+    let mut alg = Der::new(spki.read(TAG_SEQUENCE)?);
+    if alg.read(TAG_OID)? != OID_EC_PUBLIC_KEY || alg.read(TAG_OID)? != OID_SM2_CURVE {
+        return bad("unsupported public key algorithm");
+    }
+    alg.finish()?;
+    let key = match spki.read_bits()? {
+        // uncompressed points only
+        [0x04, key @ ..] if key.len() == 64 => key.try_into().unwrap(),
+        _ => return bad("unsupported public key encoding"),
+    };
+    spki.finish()?;
+    Ok(key)
+}
+
+fn read_basic_constraints(value: &[u8]) -> Result<bool> {
+    let mut outer = Der::new(value);
+    let mut constraints = Der::new(outer.read(TAG_SEQUENCE)?);
+    outer.finish()?;
+    let is_ca = constraints.read_optional(TAG_BOOLEAN)? == Some(&[0xff][..]);
+    // the path length constraint is not enforced
+    constraints.read_optional(TAG_INTEGER)?;
+    constraints.finish()?;
+    Ok(is_ca)
+}
+
+fn read_key_usage(value: &[u8]) -> Result<u8> {
+    let mut der = Der::new(value);
+    let usage = match der.read(TAG_BIT_STRING)? {
+        [unused, first, ..] if *unused < 8 => *first,
+        _ => return bad("malformed key usage"),
+    };
+    der.finish()?;
+    Ok(usage)
+}
+
+/// Reads a `UTCTime` or a `GeneralizedTime` in UTC, as seconds since the
+/// Unix epoch.
+fn read_time(der: &mut Der) -> Result<u64> {
+    let (tag, time, _) = der.read_raw()?;
+    let (year, rest) = match (tag, time.len()) {
+        (TAG_UTC_TIME, 13) => {
+            let year = digits(&time[..2])?;
+            // RFC 5280: two-digit years from 50 are in the 20th century
+            (
+                if year >= 50 { 1900 + year } else { 2000 + year },
+                &time[2..],
+            )
+        }
+        (TAG_GENERALIZED_TIME, 15) => (digits(&time[..4])?, &time[4..]),
+        _ => return bad("unsupported time"),
+    };
+    if rest[10] != b'Z' {
+        return bad("unsupported time");
+    }
+    let (month, day) = (digits(&rest[..2])?, digits(&rest[2..4])?);
+    let (hour, minute, second) = (
+        digits(&rest[4..6])?,
+        digits(&rest[6..8])?,
+        digits(&rest[8..10])?,
+    );
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+        || year < 1970
+    {
+        return bad("unsupported time");
+    }
+    let days = days_since_epoch(year, month, day);
+    Ok(days * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+fn digits(s: &[u8]) -> Result<u64> {
+    s.iter().try_fold(0, |n, &c| match c {
+        b'0'..=b'9' => Ok(n * 10 + (c - b'0') as u64),
+        _ => bad("unsupported time"),
+    })
+}
+
+/// Days from 1970-01-01 to a date of the proleptic Gregorian calendar.
+fn days_since_epoch(year: u64, month: u64, day: u64) -> u64 {
+    // count years from March, so that leap days end them
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ROOT: &[u8] = include_bytes!("testdata/root.der");
+    const CA: &[u8] = include_bytes!("testdata/ca.der");
+    const AK: &[u8] = include_bytes!("testdata/ak.der");
+
+    #[test]
+    fn test_parse() {
+        let root = Certificate::parse(ROOT).unwrap();
+        let ca = Certificate::parse(CA).unwrap();
+        let ak = Certificate::parse(AK).unwrap();
+        assert!(root.is_ca() && ca.is_ca() && !ak.is_ca());
+        // 2023-01-01T00:00:00Z and 2033-01-01T00:00:00Z
+        assert_eq!(
+            (ak.not_before, ak.not_after),
+            (1_672_531_200, 1_988_150_400)
+        );
+        assert!(ak.is_valid_at(1_700_000_000) && !ak.is_valid_at(2_000_000_000));
+        assert_eq!(&ak.public_key[..4], &[0xea, 0xa5, 0xc0, 0x0d]);
+
+        assert_eq!(
+            Certificate::parse(&AK[..AK.len() - 1]).unwrap_err(),
+            Error::Certificate("malformed DER")
+        );
+        let mut trailing = AK.to_vec();
+        trailing.push(0);
+        assert!(Certificate::parse(&trailing).is_err());
+    }
+
+    #[test]
+    fn test_chain() {
+        let root = Certificate::parse(ROOT).unwrap();
+        let ca = Certificate::parse(CA).unwrap();
+        let ak = Certificate::parse(AK).unwrap();
+        assert!(root.is_issued_by(&root));
+        assert!(ca.is_issued_by(&root));
+        assert!(ak.is_issued_by(&ca));
+        assert!(!ak.is_issued_by(&root));
+
+        let mut forged = AK.to_vec();
+        // a byte of the subject name
+        let offset = AK.windows(11).position(|w| w == b"Test TPM AK").unwrap();
+        forged[offset] ^= 1;
+        assert!(!Certificate::parse(&forged).unwrap().is_issued_by(&ca));
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Constants from the TPM 2.0 specification, Part 2: Structures.
+
+/// Structure tags (`TPM_ST`).
+pub const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+pub const TPM_ST_SESSIONS: u16 = 0x8002;
+pub const TPM_ST_ATTEST_QUOTE: u16 = 0x8018;
+pub const TPM_ST_CREATION: u16 = 0x8021;
+pub const TPM_ST_HASHCHECK: u16 = 0x8024;
+
+/// The `magic` value of every `TPMS_ATTEST` generated by a TPM.
+pub const TPM_GENERATED_VALUE: u32 = 0xff54_4347;
+
+/// Command codes (`TPM_CC`).
+pub const TPM_CC_EVICT_CONTROL: u32 = 0x0000_0120;
+pub const TPM_CC_NV_UNDEFINE_SPACE: u32 = 0x0000_0122;
+pub const TPM_CC_NV_DEFINE_SPACE: u32 = 0x0000_012a;
+pub const TPM_CC_CREATE_PRIMARY: u32 = 0x0000_0131;
+pub const TPM_CC_NV_INCREMENT: u32 = 0x0000_0134;
+pub const TPM_CC_NV_WRITE: u32 = 0x0000_0137;
+pub const TPM_CC_NV_WRITE_LOCK: u32 = 0x0000_0138;
+pub const TPM_CC_STARTUP: u32 = 0x0000_0144;
+pub const TPM_CC_SHUTDOWN: u32 = 0x0000_0145;
+pub const TPM_CC_ACTIVATE_CREDENTIAL: u32 = 0x0000_0147;
+pub const TPM_CC_NV_READ: u32 = 0x0000_014e;
+pub const TPM_CC_NV_READ_LOCK: u32 = 0x0000_014f;
+pub const TPM_CC_POLICY_SECRET: u32 = 0x0000_0151;
+pub const TPM_CC_QUOTE: u32 = 0x0000_0158;
+pub const TPM_CC_SIGN: u32 = 0x0000_015d;
+pub const TPM_CC_FLUSH_CONTEXT: u32 = 0x0000_0165;
+pub const TPM_CC_NV_READ_PUBLIC: u32 = 0x0000_0169;
+pub const TPM_CC_POLICY_LOCALITY: u32 = 0x0000_016f;
+pub const TPM_CC_READ_PUBLIC: u32 = 0x0000_0173;
+pub const TPM_CC_START_AUTH_SESSION: u32 = 0x0000_0176;
+pub const TPM_CC_GET_RANDOM: u32 = 0x0000_017b;
+pub const TPM_CC_HASH: u32 = 0x0000_017d;
+pub const TPM_CC_PCR_READ: u32 = 0x0000_017e;
+pub const TPM_CC_POLICY_PCR: u32 = 0x0000_017f;
+pub const TPM_CC_PCR_EXTEND: u32 = 0x0000_0182;
+pub const TPM_CC_POLICY_GET_DIGEST: u32 = 0x0000_0189;
+
+/// Response codes (`TPM_RC`).
+pub const TPM_RC_SUCCESS: u32 = 0x000;
+pub const TPM_RC_FMT1: u32 = 0x080;
+pub const TPM_RC_HANDLE: u32 = TPM_RC_FMT1 + 0x00b;
+pub const TPM_RC_AUTH_FAIL: u32 = TPM_RC_FMT1 + 0x00e;
+pub const TPM_RC_POLICY_FAIL: u32 = TPM_RC_FMT1 + 0x01d;
+pub const TPM_RC_INITIALIZE: u32 = 0x100;
+pub const TPM_RC_FAILURE: u32 = 0x101;
+pub const TPM_RC_LOCALITY: u32 = 0x107;
+pub const TPM_RC_COMMAND_SIZE: u32 = 0x142;
+pub const TPM_RC_COMMAND_CODE: u32 = 0x143;
+pub const TPM_RC_NV_RANGE: u32 = 0x146;
+pub const TPM_RC_NV_SIZE: u32 = 0x147;
+pub const TPM_RC_NV_LOCKED: u32 = 0x148;
+pub const TPM_RC_NV_AUTHORIZATION: u32 = 0x149;
+pub const TPM_RC_NV_UNINITIALIZED: u32 = 0x14a;
+pub const TPM_RC_NV_SPACE: u32 = 0x14b;
+pub const TPM_RC_NV_DEFINED: u32 = 0x14c;
+pub const TPM_RC_TESTING: u32 = 0x90a;
+pub const TPM_RC_YIELDED: u32 = 0x908;
+pub const TPM_RC_RETRY: u32 = 0x922;
+
+/// Permanent and reserved handles (`TPM_RH`, `TPM_RS`).
+pub const TPM_RH_OWNER: u32 = 0x4000_0001;
+pub const TPM_RH_NULL: u32 = 0x4000_0007;
+pub const TPM_RS_PW: u32 = 0x4000_0009;
+pub const TPM_RH_ENDORSEMENT: u32 = 0x4000_000b;
+pub const TPM_RH_PLATFORM: u32 = 0x4000_000c;
+
+/// Handle ranges (`TPM_HT` in the top byte).
+pub const TPM_HT_NV_INDEX: u32 = 0x0100_0000;
+pub const TPM_HT_POLICY_SESSION: u32 = 0x0300_0000;
+pub const TPM_HT_TRANSIENT: u32 = 0x8000_0000;
+pub const TPM_HT_PERSISTENT: u32 = 0x8100_0000;
+
+/// Startup types (`TPM_SU`).
+pub const TPM_SU_CLEAR: u16 = 0x0000;
+pub const TPM_SU_STATE: u16 = 0x0001;
+
+/// Session types (`TPM_SE`).
+pub const TPM_SE_HMAC: u8 = 0x00;
+pub const TPM_SE_POLICY: u8 = 0x01;
+pub const TPM_SE_TRIAL: u8 = 0x03;
+
+/// Locality bitmap of `TPM2_PolicyLocality` (`TPMA_LOCALITY`), for
+/// localities 0 to 4.
+pub const fn tpma_locality(locality: u8) -> u8 {
+    1 << locality
+}
+
+/// Algorithm identifiers (`TPM_ALG`).
+pub const TPM_ALG_RSA: u16 = 0x0001;
+pub const TPM_ALG_SHA1: u16 = 0x0004;
+pub const TPM_ALG_AES: u16 = 0x0006;
+pub const TPM_ALG_SHA256: u16 = 0x000b;
+pub const TPM_ALG_SHA384: u16 = 0x000c;
+pub const TPM_ALG_SHA512: u16 = 0x000d;
+pub const TPM_ALG_NULL: u16 = 0x0010;
+pub const TPM_ALG_SM3_256: u16 = 0x0012;
+pub const TPM_ALG_SM4: u16 = 0x0013;
+pub const TPM_ALG_ECDSA: u16 = 0x0018;
+pub const TPM_ALG_ECDAA: u16 = 0x001a;
+pub const TPM_ALG_SM2: u16 = 0x001b;
+pub const TPM_ALG_ECSCHNORR: u16 = 0x001c;
+pub const TPM_ALG_ECC: u16 = 0x0023;
+pub const TPM_ALG_CFB: u16 = 0x0043;
+
+/// ECC curves (`TPM_ECC_CURVE`).
+pub const TPM_ECC_NIST_P256: u16 = 0x0003;
+pub const TPM_ECC_SM2_P256: u16 = 0x0020;
+
+/// Object attributes (`TPMA_OBJECT`).
+pub const TPMA_OBJECT_FIXED_TPM: u32 = 1 << 1;
+pub const TPMA_OBJECT_ST_CLEAR: u32 = 1 << 2;
+pub const TPMA_OBJECT_FIXED_PARENT: u32 = 1 << 4;
+pub const TPMA_OBJECT_SENSITIVE_DATA_ORIGIN: u32 = 1 << 5;
+pub const TPMA_OBJECT_USER_WITH_AUTH: u32 = 1 << 6;
+pub const TPMA_OBJECT_ADMIN_WITH_POLICY: u32 = 1 << 7;
+pub const TPMA_OBJECT_NO_DA: u32 = 1 << 10;
+pub const TPMA_OBJECT_RESTRICTED: u32 = 1 << 16;
+pub const TPMA_OBJECT_DECRYPT: u32 = 1 << 17;
+pub const TPMA_OBJECT_SIGN: u32 = 1 << 18;
+
+/// NV index attributes (`TPMA_NV`).
+pub const TPMA_NV_PPWRITE: u32 = 1 << 0;
+pub const TPMA_NV_OWNERWRITE: u32 = 1 << 1;
+pub const TPMA_NV_AUTHWRITE: u32 = 1 << 2;
+pub const TPMA_NV_POLICYWRITE: u32 = 1 << 3;
+/// The `TPM_NT` field: the type of the index.
+pub const TPMA_NV_TPM_NT_MASK: u32 = 0xf << 4;
+/// `TPM_NT_COUNTER`: an 8-byte monotonic counter, changed only by
+/// `TPM2_NV_Increment`.
+pub const TPMA_NV_COUNTER: u32 = 0x1 << 4;
+pub const TPMA_NV_WRITELOCKED: u32 = 1 << 11;
+pub const TPMA_NV_WRITEDEFINE: u32 = 1 << 13;
+pub const TPMA_NV_WRITE_STCLEAR: u32 = 1 << 14;
+pub const TPMA_NV_PPREAD: u32 = 1 << 16;
+pub const TPMA_NV_OWNERREAD: u32 = 1 << 17;
+pub const TPMA_NV_AUTHREAD: u32 = 1 << 18;
+pub const TPMA_NV_POLICYREAD: u32 = 1 << 19;
+pub const TPMA_NV_NO_DA: u32 = 1 << 25;
+pub const TPMA_NV_ORDERLY: u32 = 1 << 26;
+pub const TPMA_NV_CLEAR_STCLEAR: u32 = 1 << 27;
+pub const TPMA_NV_READLOCKED: u32 = 1 << 28;
+pub const TPMA_NV_WRITTEN: u32 = 1 << 29;
+pub const TPMA_NV_PLATFORMCREATE: u32 = 1 << 30;
+pub const TPMA_NV_READ_STCLEAR: u32 = 1 << 31;
+
+/// `PolicySecret(TPM_RH_ENDORSEMENT)` with SM3-256, the authorization
+/// policy of the default endorsement key templates (TCG EK Credential
+/// Profile, "PolicyA").
+pub const EK_POLICY_A_SM3_256: [u8; 32] = [
+    0xc6, 0x7f, 0x7d, 0x35, 0xf6, 0x6f, 0x3b, 0xec, 0x13, 0xc8, 0x9f, 0xe8, 0x98, 0x92, 0x1c, 0x65,
+    0x1b, 0x0c, 0xb5, 0xa3, 0x8a, 0x92, 0x69, 0x0a, 0x62, 0xa4, 0x3c, 0x00, 0x12, 0xe4, 0xfb, 0x8b,
+];
+
+/// Largest command or response the crate will build or accept.
+pub const TPM_MAX_COMMAND_SIZE: usize = 4096;
+/// Largest chunk moved by a single `TPM2_NV_Read` or `TPM2_NV_Write`.
+///
+/// Every TPM supports at least this `MAX_NV_BUFFER_SIZE`.
+pub const TPM_MAX_NV_CHUNK: usize = 512;
+/// Largest `TPM2B_MAX_BUFFER` accepted by `TPM2_Hash`.
+pub const TPM_MAX_DIGEST_BUFFER: usize = 1024;
+
+/// Returns the digest size of a hash algorithm.
+pub fn digest_size(alg: u16) -> Option<usize> {
+    match alg {
+        TPM_ALG_SHA1 => Some(20),
+        TPM_ALG_SHA256 | TPM_ALG_SM3_256 => Some(32),
+        TPM_ALG_SHA384 => Some(48),
+        TPM_ALG_SHA512 => Some(64),
+        _ => None,
+    }
+}
@@ -0,0 +1,61 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::fmt;
+
+use crate::consts::TPM_RC_FMT1;
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The transport failed to deliver the command or to fetch the response.
+    Transport(&'static str),
+    /// The TPM answered with a non-success response code.
+    Tpm(u32),
+    /// The response does not parse as the expected structure.
+    Malformed,
+    /// A parameter does not fit into the TPM wire format.
+    InvalidParam,
+    /// The structure uses an algorithm or object type this crate does not handle.
+    Unsupported(u16),
+}
+
+impl Error {
+    /// Returns the response code with the handle, session and parameter
+    /// number stripped, so it can be compared with the `TPM_RC_*` constants.
+    pub fn rc(&self) -> Option<u32> {
+        match *self {
+            Error::Tpm(rc) if rc & TPM_RC_FMT1 != 0 => Some(rc & (TPM_RC_FMT1 | 0x3f)),
+            Error::Tpm(rc) => Some(rc),
+            _ => None,
+        }
+    }
+
+    pub fn is_rc(&self, rc: u32) -> bool {
+        self.rc() == Some(rc)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Transport(reason) => write!(f, "TPM transport error: {}", reason),
+            Error::Tpm(rc) => write!(f, "TPM error {:#x}", rc),
+            Error::Malformed => write!(f, "malformed TPM response"),
+            Error::InvalidParam => write!(f, "invalid TPM command parameter"),
+            Error::Unsupported(alg) => write!(f, "unsupported TPM algorithm {:#x}", alg),
+        }
+    }
+}
@@ -0,0 +1,337 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Event logs in the crypto agile format of the TCG PC Client Platform
+//! Firmware Profile, recording what was extended into the PCRs so that a
+//! verifier can replay them against a quote.
+//!
+//! The log starts with a `TCG_PCR_EVENT` holding the Spec ID event, which
+//! lists the PCR banks, followed by one `TCG_PCR_EVENT2` per extend. Unlike
+//! the TPM wire format, event logs are little-endian.
+
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use crate::marshal::Reader;
+use crate::{Error, Result};
+
+/// Events recorded for information only, never extended.
+pub const EV_NO_ACTION: u32 = 0x3;
+/// Events holding a `TCG_PCClientTaggedEvent`.
+pub const EV_EVENT_TAG: u32 = 0x6;
+/// Events holding code or data loaded to boot, as it was measured.
+pub const EV_IPL: u32 = 0xd;
+
+const SPEC_ID_SIGNATURE: &[u8; 16] = b"Spec ID Event03\0";
+const PLATFORM_CLASS_CLIENT: u32 = 0;
+const SPEC_VERSION_MAJOR: u8 = 2;
+const SPEC_VERSION_MINOR: u8 = 0;
+const SPEC_ERRATA: u8 = 0;
+/// `UINTN` is 64-bit.
+const UINTN_SIZE: u8 = 2;
+/// The header event has a SHA-1 sized digest, whatever the banks are.
+const HEADER_DIGEST_SIZE: usize = 20;
+
+/// A PCR bank recorded in the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bank {
+    pub alg: u16,
+    pub digest_size: u16,
+}
+
+impl Bank {
+    pub const fn new(alg: u16, digest_size: u16) -> Self {
+        Self { alg, digest_size }
+    }
+}
+
+/// An event following the header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub pcr: u32,
+    pub event_type: u32,
+    /// The digest extended into each bank, in the order of the header.
+    pub digests: Vec<(u16, Vec<u8>)>,
+    pub data: Vec<u8>,
+}
+
+impl Event {
+    pub fn digest(&self, alg: u16) -> Option<&[u8]> {
+        self.digests
+            .iter()
+            .find(|(a, _)| *a == alg)
+            .map(|(_, digest)| digest.as_slice())
+    }
+}
+
+/// A log being recorded.
+#[derive(Debug, Clone)]
+pub struct EventLog {
+    buf: Vec<u8>,
+    banks: Vec<Bank>,
+}
+
+impl EventLog {
+    /// Starts a log of extends into `banks`, which are listed in the header.
+    pub fn new(banks: &[Bank]) -> Self {
+        let mut spec_id = Vec::new();
+        spec_id.extend_from_slice(SPEC_ID_SIGNATURE);
+        spec_id.extend_from_slice(&PLATFORM_CLASS_CLIENT.to_le_bytes());
+        spec_id.extend_from_slice(&[
+            SPEC_VERSION_MINOR,
+            SPEC_VERSION_MAJOR,
+            SPEC_ERRATA,
+            UINTN_SIZE,
+        ]);
+        spec_id.extend_from_slice(&(banks.len() as u32).to_le_bytes());
+        for bank in banks {
+            spec_id.extend_from_slice(&bank.alg.to_le_bytes());
+            spec_id.extend_from_slice(&bank.digest_size.to_le_bytes());
+        }
+        // no vendor information
+        spec_id.push(0);
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf.extend_from_slice(&EV_NO_ACTION.to_le_bytes());
+        buf.extend_from_slice(&[0; HEADER_DIGEST_SIZE]);
+        buf.extend_from_slice(&(spec_id.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&spec_id);
+        Self {
+            buf,
+            banks: banks.to_vec(),
+        }
+    }
+
+    pub fn banks(&self) -> &[Bank] {
+        &self.banks
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Size of the record `push` appends for `data`.
+    pub fn event_size(&self, data: &[u8]) -> usize {
+        let digests: usize = self
+            .banks
+            .iter()
+            .map(|bank| 2 + bank.digest_size as usize)
+            .sum();
+        16 + digests + data.len()
+    }
+
+    /// Records that `digests`, one per bank in the order of `banks`, were
+    /// extended into `pcr`.
+    pub fn push(
+        &mut self,
+        pcr: u32,
+        event_type: u32,
+        digests: &[&[u8]],
+        data: &[u8],
+    ) -> Result<()> {
+        if digests.len() != self.banks.len()
+            || digests
+                .iter()
+                .zip(&self.banks)
+                .any(|(digest, bank)| digest.len() != bank.digest_size as usize)
+            || data.len() > u32::MAX as usize
+        {
+            return Err(Error::InvalidParam);
+        }
+        self.buf.reserve(self.event_size(data));
+        self.buf.extend_from_slice(&pcr.to_le_bytes());
+        self.buf.extend_from_slice(&event_type.to_le_bytes());
+        self.buf
+            .extend_from_slice(&(digests.len() as u32).to_le_bytes());
+        for (digest, bank) in digests.iter().zip(&self.banks) {
+            self.buf.extend_from_slice(&bank.alg.to_le_bytes());
+            self.buf.extend_from_slice(digest);
+        }
+        self.buf
+            .extend_from_slice(&(data.len() as u32).to_le_bytes());
+        self.buf.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Builds a `TCG_PCClientTaggedEvent`, the data of `EV_EVENT_TAG` events.
+pub fn tagged_event(id: u32, data: &[u8]) -> Vec<u8> {
+    let mut event = Vec::with_capacity(8 + data.len());
+    event.extend_from_slice(&id.to_le_bytes());
+    event.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    event.extend_from_slice(data);
+    event
+}
+
+/// Splits the data of an `EV_EVENT_TAG` event into its ID and data.
+pub fn parse_tagged_event(event: &[u8]) -> Result<(u32, &[u8])> {
+    let mut r = Reader::new(event);
+    let id = le_u32(&mut r)?;
+    let size = le_u32(&mut r)? as usize;
+    let data = r.bytes(size)?;
+    r.finish()?;
+    Ok((id, data))
+}
+
+fn le_u16(r: &mut Reader) -> Result<u16> {
+    Ok(u16::from_le_bytes(r.bytes(2)?.try_into().unwrap()))
+}
+
+fn le_u32(r: &mut Reader) -> Result<u32> {
+    Ok(u32::from_le_bytes(r.bytes(4)?.try_into().unwrap()))
+}
+
+fn parse_spec_id(data: &[u8]) -> Result<Vec<Bank>> {
+    let mut r = Reader::new(data);
+    if r.bytes(SPEC_ID_SIGNATURE.len())? != SPEC_ID_SIGNATURE {
+        return Err(Error::Malformed);
+    }
+    // platform class, version, errata and UINTN size
+    r.bytes(8)?;
+    let count = le_u32(&mut r)?;
+    let mut banks = Vec::new();
+    for _ in 0..count {
+        banks.push(Bank::new(le_u16(&mut r)?, le_u16(&mut r)?));
+    }
+    let vendor_info_size = r.u8()? as usize;
+    r.bytes(vendor_info_size)?;
+    r.finish()?;
+    Ok(banks)
+}
+
+/// Parses a whole log into the banks of its header and its events.
+pub fn parse(log: &[u8]) -> Result<(Vec<Bank>, Vec<Event>)> {
+    let mut r = Reader::new(log);
+    let (pcr, event_type) = (le_u32(&mut r)?, le_u32(&mut r)?);
+    if pcr != 0 || event_type != EV_NO_ACTION {
+        return Err(Error::Malformed);
+    }
+    r.bytes(HEADER_DIGEST_SIZE)?;
+    let size = le_u32(&mut r)? as usize;
+    let banks = parse_spec_id(r.bytes(size)?)?;
+
+    let mut events = Vec::new();
+    while !r.is_empty() {
+        let pcr = le_u32(&mut r)?;
+        let event_type = le_u32(&mut r)?;
+        let count = le_u32(&mut r)?;
+        let mut digests = Vec::new();
+        for _ in 0..count {
+            let alg = le_u16(&mut r)?;
+            let bank = banks
+                .iter()
+                .find(|bank| bank.alg == alg)
+                .ok_or(Error::Unsupported(alg))?;
+            digests.push((alg, r.bytes(bank.digest_size as usize)?.to_vec()));
+        }
+        let size = le_u32(&mut r)? as usize;
+        let data = r.bytes(size)?.to_vec();
+        events.push(Event {
+            pcr,
+            event_type,
+            digests,
+            data,
+        });
+    }
+    Ok((banks, events))
+}
+
+/// Computes the value of `pcr` in `bank` after the events, from a PCR reset
+/// to zeros. `hash` is the hash algorithm of the bank.
+pub fn replay(
+    events: &[Event],
+    pcr: u32,
+    bank: Bank,
+    hash: impl Fn(&[u8]) -> Vec<u8>,
+) -> Result<Vec<u8>> {
+    let mut value = alloc::vec![0; bank.digest_size as usize];
+    for event in events {
+        if event.pcr != pcr || event.event_type == EV_NO_ACTION {
+            continue;
+        }
+        let digest = event.digest(bank.alg).ok_or(Error::Malformed)?;
+        value.extend_from_slice(digest);
+        value = hash(&value);
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::consts::*;
+    use crate::sim::{sm3, SimTpm};
+    use crate::Tpm;
+
+    const SM3_BANK: Bank = Bank::new(TPM_ALG_SM3_256, 32);
+
+    #[test]
+    fn test_round_trip() {
+        let mut log = EventLog::new(&[SM3_BANK]);
+        let header_size = log.len();
+        let event = tagged_event(0x4845_0001, b"enclave");
+        let digest = sm3(&event);
+        log.push(14, EV_EVENT_TAG, &[&digest], &event).unwrap();
+        assert_eq!(log.len(), header_size + log.event_size(&event));
+        assert_eq!(
+            log.push(14, EV_EVENT_TAG, &[&digest[..20]], &event),
+            Err(Error::InvalidParam)
+        );
+
+        let (banks, events) = parse(log.as_bytes()).unwrap();
+        assert_eq!(banks, [SM3_BANK]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].pcr, 14);
+        assert_eq!(events[0].digest(TPM_ALG_SM3_256), Some(&digest[..]));
+        assert_eq!(
+            parse_tagged_event(&events[0].data),
+            Ok((0x4845_0001, &b"enclave"[..]))
+        );
+
+        let bytes = log.as_bytes();
+        assert_eq!(parse(&bytes[..bytes.len() - 1]), Err(Error::Malformed));
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut sim = SimTpm::new();
+        let mut tpm = Tpm::new(&mut sim);
+        tpm.startup(TPM_SU_CLEAR).unwrap();
+
+        let mut log = EventLog::new(&[SM3_BANK]);
+        for (pcr, data) in [(14, &b"first"[..]), (12, b"other"), (14, b"second")] {
+            let digest = sm3(data);
+            tpm.pcr_extend(pcr, &[(TPM_ALG_SM3_256, &digest)]).unwrap();
+            log.push(pcr, EV_EVENT_TAG, &[&digest], data).unwrap();
+        }
+        log.push(14, EV_NO_ACTION, &[&[0; 32]], b"not extended")
+            .unwrap();
+
+        let (_, events) = parse(log.as_bytes()).unwrap();
+        let hash = |data: &[u8]| sm3(data).to_vec();
+        for pcr in [12, 14, 15] {
+            let value = replay(&events, pcr, SM3_BANK, hash).unwrap();
+            assert_eq!(value, sim.pcr(pcr as usize));
+        }
+    }
+}
@@ -0,0 +1,45 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A minimal TPM 2.0 command layer.
+//!
+//! Commands are marshalled into the TPM wire format, handed to a
+//! [`Transport`] and the responses are unmarshalled into the types in this
+//! crate. Only password authorizations and unsalted policy sessions are
+//! supported, which is all the hypervisor root of trust needs. The [`mmio`]
+//! module drives TPMs behind the TIS and CRB interfaces and the firmware TPM
+//! of Hygon CPUs, and [`eventlog`] records what is extended into the PCRs.
+//! With the `sim` feature, `sim::SimTpm` stands in for a missing TPM.
+
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+extern crate alloc;
+
+pub mod consts;
+mod error;
+pub mod eventlog;
+pub mod marshal;
+pub mod mmio;
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
+mod tpm;
+mod transport;
+mod types;
+
+pub use error::{Error, Result};
+pub use tpm::{Auth, Tpm};
+#[cfg(feature = "std")]
+pub use transport::SocketTransport;
+pub use transport::Transport;
+pub use types::*;
@@ -0,0 +1,223 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Big-endian marshalling of TPM structures.
+
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use crate::{Error, Result};
+
+/// Types that can be written in the TPM wire format.
+pub trait Marshal {
+    fn marshal(&self, w: &mut Writer);
+}
+
+/// Types that can be read from the TPM wire format.
+pub trait Unmarshal: Sized {
+    fn unmarshal(r: &mut Reader) -> Result<Self>;
+}
+
+#[derive(Debug, Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn u8(&mut self, v: u8) -> &mut Self {
+        self.buf.push(v);
+        self
+    }
+
+    pub fn u16(&mut self, v: u16) -> &mut Self {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub fn u32(&mut self, v: u32) -> &mut Self {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, v: u64) -> &mut Self {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub fn bytes(&mut self, v: &[u8]) -> &mut Self {
+        self.buf.extend_from_slice(v);
+        self
+    }
+
+    /// Writes a `TPM2B_*` buffer: a 16-bit size followed by the bytes.
+    ///
+    /// Buffers longer than `u16::MAX` cannot be expressed on the wire, callers
+    /// must bound them beforehand.
+    pub fn tpm2b(&mut self, v: &[u8]) -> &mut Self {
+        debug_assert!(v.len() <= u16::MAX as usize);
+        self.u16(v.len() as u16).bytes(v)
+    }
+
+    /// Writes `v` as a sized structure (`TPM2B_PUBLIC`, `TPM2B_NV_PUBLIC`, ...).
+    pub fn sized<M: Marshal + ?Sized>(&mut self, v: &M) -> &mut Self {
+        let start = self.buf.len();
+        self.u16(0);
+        v.marshal(self);
+        let size = (self.buf.len() - start - 2) as u16;
+        self.buf[start..start + 2].copy_from_slice(&size.to_be_bytes());
+        self
+    }
+
+    pub fn put<M: Marshal + ?Sized>(&mut self, v: &M) -> &mut Self {
+        v.marshal(self);
+        self
+    }
+
+    /// Overwrites the 32-bit value at `offset`, used to patch size fields.
+    pub fn patch_u32(&mut self, offset: usize, v: u32) {
+        self.buf[offset..offset + 4].copy_from_slice(&v.to_be_bytes());
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.remaining() {
+            return Err(Error::Malformed);
+        }
+        let v = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(v)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// Reads a `TPM2B_*` buffer.
+    pub fn tpm2b(&mut self) -> Result<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+
+    /// Reads a sized structure, which must consume its size field exactly.
+    pub fn sized<U: Unmarshal>(&mut self) -> Result<U> {
+        let mut inner = Reader::new(self.tpm2b()?);
+        let v = U::unmarshal(&mut inner)?;
+        inner.finish()?;
+        Ok(v)
+    }
+
+    pub fn get<U: Unmarshal>(&mut self) -> Result<U> {
+        U::unmarshal(self)
+    }
+
+    /// Fails unless every byte has been consumed.
+    pub fn finish(&self) -> Result<()> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Malformed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut w = Writer::new();
+        w.u8(0x12)
+            .u16(0x3456)
+            .u32(0x789a_bcde)
+            .u64(0x0102_0304_0506_0708)
+            .tpm2b(b"abc");
+        assert_eq!(
+            w.as_slice(),
+            &[
+                0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 1, 2, 3, 4, 5, 6, 7, 8, 0, 3, b'a', b'b',
+                b'c'
+            ]
+        );
+
+        let buf = w.into_inner();
+        let mut r = Reader::new(&buf);
+        assert_eq!(r.u8(), Ok(0x12));
+        assert_eq!(r.u16(), Ok(0x3456));
+        assert_eq!(r.u32(), Ok(0x789a_bcde));
+        assert_eq!(r.u64(), Ok(0x0102_0304_0506_0708));
+        assert_eq!(r.tpm2b(), Ok(&b"abc"[..]));
+        assert!(r.finish().is_ok());
+        assert_eq!(r.u8(), Err(Error::Malformed));
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut r = Reader::new(&[0x00, 0x04, 0xaa, 0xbb]);
+        assert_eq!(r.tpm2b(), Err(Error::Malformed));
+        let mut r = Reader::new(&[0x00]);
+        assert_eq!(r.u16(), Err(Error::Malformed));
+    }
+}
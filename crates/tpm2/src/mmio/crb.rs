@@ -0,0 +1,260 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::*;
+use crate::{Error, Result, Transport};
+
+pub(super) const LOC_STATE: usize = 0x00;
+pub(super) const LOC_CTRL: usize = 0x08;
+pub(super) const LOC_STS: usize = 0x0c;
+pub(super) const CTRL_REQ: usize = 0x40;
+pub(super) const CTRL_STS: usize = 0x44;
+pub(super) const CTRL_CANCEL: usize = 0x48;
+pub(super) const CTRL_START: usize = 0x4c;
+pub(super) const CTRL_CMD_SIZE: usize = 0x58;
+pub(super) const CTRL_CMD_LADDR: usize = 0x5c;
+pub(super) const CTRL_CMD_HADDR: usize = 0x60;
+pub(super) const CTRL_RSP_SIZE: usize = 0x64;
+pub(super) const CTRL_RSP_ADDR: usize = 0x68;
+
+pub(super) const LOC_STATE_ASSIGNED: u32 = 1 << 1;
+pub(super) const LOC_STATE_ACTIVE_SHIFT: u32 = 2;
+pub(super) const LOC_STATE_ACTIVE_MASK: u32 = 0x7;
+pub(super) const LOC_STATE_VALID: u32 = 1 << 7;
+pub(super) const LOC_CTRL_REQUEST_ACCESS: u32 = 1 << 0;
+pub(super) const LOC_CTRL_RELINQUISH: u32 = 1 << 1;
+pub(super) const LOC_STS_GRANTED: u32 = 1 << 0;
+pub(super) const CTRL_REQ_CMD_READY: u32 = 1 << 0;
+pub(super) const CTRL_REQ_GO_IDLE: u32 = 1 << 1;
+pub(super) const CTRL_STS_ERROR: u32 = 1 << 0;
+pub(super) const CTRL_STS_IDLE: u32 = 1 << 1;
+pub(super) const CTRL_CANCEL_INVOKE: u32 = 1 << 0;
+pub(super) const CTRL_START_INVOKE: u32 = 1 << 0;
+
+/// A TPM behind the Command Response Buffer interface.
+///
+/// The command and response buffers are located by their physical address,
+/// so they must lie within the register window.
+pub struct Crb<R, C> {
+    regs: R,
+    clock: C,
+    locality: u8,
+    phys_base: u64,
+}
+
+impl<R: Registers, C: Clock> Crb<R, C> {
+    /// Creates a driver sending commands at `locality`, whose registers must
+    /// lie within `regs`, mapped from physical address `phys_base`.
+    pub fn new(regs: R, clock: C, locality: u8, phys_base: u64) -> Result<Self> {
+        if locality > MAX_LOCALITY || (locality as usize + 1) * LOCALITY_SIZE > regs.size() {
+            return Err(Error::InvalidParam);
+        }
+        Ok(Self {
+            regs,
+            clock,
+            locality,
+            phys_base,
+        })
+    }
+
+    pub fn locality(&self) -> u8 {
+        self.locality
+    }
+
+    pub fn into_inner(self) -> (R, C) {
+        (self.regs, self.clock)
+    }
+
+    fn reg(&self, offset: usize) -> usize {
+        self.locality as usize * LOCALITY_SIZE + offset
+    }
+
+    fn read(&mut self, offset: usize) -> u32 {
+        let reg = self.reg(offset);
+        self.regs.read32(reg)
+    }
+
+    fn write(&mut self, offset: usize, value: u32) {
+        let reg = self.reg(offset);
+        self.regs.write32(reg, value)
+    }
+
+    /// Waits until the bits of `mask` in the register at `offset` equal
+    /// `value`.
+    fn wait_reg(&mut self, offset: usize, mask: u32, value: u32, timeout_ms: u64) -> bool {
+        let reg = self.reg(offset);
+        let (regs, clock) = (&mut self.regs, &self.clock);
+        wait_for(clock, timeout_ms, || regs.read32(reg) & mask == value)
+    }
+
+    /// Requests the use of our locality, unless it is already active.
+    pub fn request_locality(&mut self) -> Result<()> {
+        let state = self.read(LOC_STATE);
+        let active = (state >> LOC_STATE_ACTIVE_SHIFT) & LOC_STATE_ACTIVE_MASK;
+        let assigned = LOC_STATE_VALID | LOC_STATE_ASSIGNED;
+        if state & assigned == assigned && active == self.locality as u32 {
+            return Ok(());
+        }
+        self.write(LOC_CTRL, LOC_CTRL_REQUEST_ACCESS);
+        if !self.wait_reg(LOC_STS, LOC_STS_GRANTED, LOC_STS_GRANTED, TIMEOUT_A) {
+            return Err(Error::Transport("TPM locality request timed out"));
+        }
+        Ok(())
+    }
+
+    pub fn relinquish_locality(&mut self) {
+        self.write(LOC_CTRL, LOC_CTRL_RELINQUISH);
+    }
+
+    /// Aborts the command being executed, which then completes early with
+    /// `TPM_RC_CANCELED` or with its result.
+    pub fn cancel(&mut self) -> Result<()> {
+        self.write(CTRL_CANCEL, CTRL_CANCEL_INVOKE);
+        let done = self.wait_reg(CTRL_START, CTRL_START_INVOKE, 0, TIMEOUT_B);
+        self.write(CTRL_CANCEL, 0);
+        if !done {
+            return Err(Error::Transport("TPM command cancellation timed out"));
+        }
+        Ok(())
+    }
+
+    /// Returns the register window offset of the buffer at physical address
+    /// `addr`, with room for at most `size` bytes.
+    fn buffer(&self, addr: u64, size: u32) -> Result<(usize, usize)> {
+        let offset = addr
+            .checked_sub(self.phys_base)
+            .filter(|offset| *offset + size as u64 <= self.regs.size() as u64)
+            .ok_or(Error::Transport("TPM buffer is outside of the registers"))?;
+        Ok((offset as usize, size as usize))
+    }
+
+    fn execute(&mut self, command: &[u8], response: &mut [u8]) -> Result<usize> {
+        self.write(CTRL_REQ, CTRL_REQ_CMD_READY);
+        if !self.wait_reg(CTRL_REQ, CTRL_REQ_CMD_READY, 0, TIMEOUT_C)
+            || !self.wait_reg(CTRL_STS, CTRL_STS_IDLE, 0, TIMEOUT_C)
+        {
+            return Err(Error::Transport("TPM is not ready for a command"));
+        }
+
+        let cmd_addr = (self.read(CTRL_CMD_HADDR) as u64) << 32 | self.read(CTRL_CMD_LADDR) as u64;
+        let cmd_size = self.read(CTRL_CMD_SIZE);
+        let (cmd, cmd_size) = self.buffer(cmd_addr, cmd_size)?;
+        if command.len() > cmd_size {
+            return Err(Error::InvalidParam);
+        }
+        for (i, &byte) in command.iter().enumerate() {
+            self.regs.write8(cmd + i, byte);
+        }
+
+        self.write(CTRL_START, CTRL_START_INVOKE);
+        if !self.wait_reg(CTRL_START, CTRL_START_INVOKE, 0, TIMEOUT_COMMAND) {
+            self.cancel()?;
+            return Err(Error::Transport("TPM command timed out"));
+        }
+        if self.read(CTRL_STS) & CTRL_STS_ERROR != 0 {
+            return Err(Error::Transport("TPM is in a fatal error state"));
+        }
+
+        let rsp_addr =
+            (self.read(CTRL_RSP_ADDR + 4) as u64) << 32 | self.read(CTRL_RSP_ADDR) as u64;
+        let rsp_size = self.read(CTRL_RSP_SIZE);
+        let (rsp, rsp_size) = self.buffer(rsp_addr, rsp_size)?;
+        if rsp_size < HEADER_SIZE {
+            return Err(Error::Transport("TPM response buffer is too small"));
+        }
+        for (i, byte) in response[..HEADER_SIZE].iter_mut().enumerate() {
+            *byte = self.regs.read8(rsp + i);
+        }
+        let size = parsed_size(response, response.len().min(rsp_size))?;
+        for (i, byte) in response[..size].iter_mut().enumerate().skip(HEADER_SIZE) {
+            *byte = self.regs.read8(rsp + i);
+        }
+        Ok(size)
+    }
+}
+
+impl<R: Registers, C: Clock> Transport for Crb<R, C> {
+    fn transmit(&mut self, command: &[u8], response: &mut [u8]) -> Result<usize> {
+        if command.len() < HEADER_SIZE || response.len() < HEADER_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        self.request_locality()?;
+        let result = self.execute(command, response);
+        self.write(CTRL_REQ, CTRL_REQ_GO_IDLE);
+        self.relinquish_locality();
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::sim::{SimClock, SimCrb};
+    use super::*;
+    use crate::consts::*;
+    use crate::Tpm;
+
+    const PHYS_BASE: u64 = 0xfed4_0000;
+
+    #[test]
+    fn test_crb_commands() {
+        let mut sim = SimCrb::new(PHYS_BASE);
+        let crb = Crb::new(&mut sim, SimClock::new(), 0, PHYS_BASE).unwrap();
+        let mut tpm = Tpm::new(crb);
+        tpm.startup(TPM_SU_CLEAR).unwrap();
+        assert_eq!(tpm.get_random(32).unwrap().len(), 32);
+        let (sim, _) = tpm.into_inner().into_inner();
+        assert_eq!(sim.tpm.commands, 2);
+        assert_eq!(sim.active_locality(), None);
+        assert!(sim.idle());
+    }
+
+    #[test]
+    fn test_crb_locality() {
+        let mut sim = SimCrb::new(PHYS_BASE);
+        let crb = Crb::new(&mut sim, SimClock::new(), 2, PHYS_BASE).unwrap();
+        Tpm::new(crb).startup(TPM_SU_CLEAR).unwrap();
+        assert_eq!(sim.locality_requests, [2]);
+
+        sim.set_active_locality(Some(0));
+        let crb = Crb::new(&mut sim, SimClock::new(), 2, PHYS_BASE).unwrap();
+        let err = Tpm::new(crb).get_random(8).unwrap_err();
+        assert_eq!(err, Error::Transport("TPM locality request timed out"));
+    }
+
+    #[test]
+    fn test_crb_timeout() {
+        let mut sim = SimCrb::new(PHYS_BASE);
+        sim.hang = true;
+        let crb = Crb::new(&mut sim, SimClock::new(), 0, PHYS_BASE).unwrap();
+        let err = Tpm::new(crb).startup(TPM_SU_CLEAR).unwrap_err();
+        assert_eq!(err, Error::Transport("TPM command timed out"));
+        assert_eq!(sim.cancels, 1);
+
+        sim.hang = false;
+        let crb = Crb::new(&mut sim, SimClock::new(), 0, PHYS_BASE).unwrap();
+        Tpm::new(crb).startup(TPM_SU_CLEAR).unwrap();
+    }
+
+    #[test]
+    fn test_crb_buffer_outside_window() {
+        let mut sim = SimCrb::new(PHYS_BASE);
+        // the driver thinks the registers are mapped elsewhere
+        let crb = Crb::new(&mut sim, SimClock::new(), 0, PHYS_BASE + 0x1000).unwrap();
+        let err = Tpm::new(crb).startup(TPM_SU_CLEAR).unwrap_err();
+        assert_eq!(
+            err,
+            Error::Transport("TPM buffer is outside of the registers")
+        );
+    }
+}
@@ -0,0 +1,172 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Drivers for the memory-mapped interfaces of the TCG PC Client Platform
+//! TPM Profile: the FIFO interface (TIS) and the Command Response Buffer
+//! interface (CRB), and for the mailbox of the firmware TPM in the Platform
+//! Security Processor (PSP) of Hygon CPUs.
+//!
+//! All drivers implement [`Transport`](crate::Transport) on top of a
+//! [`Registers`] window covering the localities they use, and poll with the
+//! timeouts of the profile against a [`Clock`].
+
+mod crb;
+mod psp;
+#[cfg(test)]
+mod sim;
+mod tis;
+
+pub use crb::Crb;
+pub use psp::{Psp, PSP_BUFFER_SIZE};
+pub use tis::Tis;
+
+/// Size of the register space of one locality.
+pub const LOCALITY_SIZE: usize = 0x1000;
+/// The highest locality of the PC Client profile.
+pub const MAX_LOCALITY: u8 = 4;
+
+/// The interface identifier register, at the same offset in both interfaces.
+const INTERFACE_ID: usize = 0x30;
+const INTERFACE_TYPE_MASK: u32 = 0xf;
+const INTERFACE_TYPE_FIFO: u32 = 0x0;
+const INTERFACE_TYPE_CRB: u32 = 0x1;
+/// TPMs implementing TIS 1.3 or earlier do not have the register.
+const INTERFACE_TYPE_TIS13: u32 = 0xf;
+/// The vendor and device identifier register of the FIFO interface.
+const TIS_DID_VID: usize = 0xf00;
+
+/// Timeouts of the PC Client profile, in milliseconds.
+const TIMEOUT_A: u64 = 750;
+const TIMEOUT_B: u64 = 2000;
+const TIMEOUT_C: u64 = 200;
+const TIMEOUT_D: u64 = 30;
+/// Upper bound of command durations, long enough for key generation.
+const TIMEOUT_COMMAND: u64 = 120_000;
+
+/// Size of a command or response header: tag, size and code.
+const HEADER_SIZE: usize = 10;
+
+/// Register access to the memory-mapped TPM interface, by offset from the
+/// start of the locality 0 registers.
+///
+/// Reads take `&mut self` because reading the FIFO consumes data.
+pub trait Registers {
+    /// Size of the register window in bytes.
+    fn size(&self) -> usize;
+    fn read8(&mut self, offset: usize) -> u8;
+    fn read32(&mut self, offset: usize) -> u32;
+    fn write8(&mut self, offset: usize, value: u8);
+    fn write32(&mut self, offset: usize, value: u32);
+}
+
+impl<R: Registers + ?Sized> Registers for &mut R {
+    fn size(&self) -> usize {
+        (**self).size()
+    }
+
+    fn read8(&mut self, offset: usize) -> u8 {
+        (**self).read8(offset)
+    }
+
+    fn read32(&mut self, offset: usize) -> u32 {
+        (**self).read32(offset)
+    }
+
+    fn write8(&mut self, offset: usize, value: u8) {
+        (**self).write8(offset, value)
+    }
+
+    fn write32(&mut self, offset: usize, value: u32) {
+        (**self).write32(offset, value)
+    }
+}
+
+/// The time source of the interface timeouts.
+pub trait Clock {
+    /// A monotonic time in microseconds.
+    fn now_us(&self) -> u64;
+
+    /// Called between two polls of a register.
+    fn relax(&self) {
+        core::hint::spin_loop();
+    }
+}
+
+/// The interface of a TPM, as found by [`probe`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interface {
+    Fifo,
+    Crb,
+}
+
+/// Identifies the interface behind `regs`, or returns `None` if there does
+/// not seem to be a TPM.
+pub fn probe<R: Registers>(regs: &mut R) -> Option<Interface> {
+    if regs.size() < LOCALITY_SIZE {
+        return None;
+    }
+    match regs.read32(INTERFACE_ID) & INTERFACE_TYPE_MASK {
+        INTERFACE_TYPE_CRB => Some(Interface::Crb),
+        INTERFACE_TYPE_FIFO | INTERFACE_TYPE_TIS13 => {
+            // unbacked MMIO reads as all ones
+            match regs.read32(TIS_DID_VID) {
+                0 | u32::MAX => None,
+                _ => Some(Interface::Fifo),
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Polls `cond` until it holds or `timeout_ms` elapse, returning whether it
+/// held.
+fn wait_for(clock: &impl Clock, timeout_ms: u64, mut cond: impl FnMut() -> bool) -> bool {
+    let start = clock.now_us();
+    loop {
+        if cond() {
+            return true;
+        }
+        if clock.now_us().wrapping_sub(start) >= timeout_ms * 1000 {
+            // the TPM may have been slow to answer rather than us slow to ask
+            return cond();
+        }
+        clock.relax();
+    }
+}
+
+/// Returns the command or response size in `header`, checking it against
+/// the room available.
+fn parsed_size(header: &[u8], max: usize) -> crate::Result<usize> {
+    let size = u32::from_be_bytes([header[2], header[3], header[4], header[5]]) as usize;
+    if size < HEADER_SIZE || size > max {
+        return Err(crate::Error::Transport("invalid response size"));
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod test {
+    use super::sim::{SimCrb, SimTis};
+    use super::*;
+
+    #[test]
+    fn test_probe() {
+        assert_eq!(probe(&mut SimTis::new()), Some(Interface::Fifo));
+        assert_eq!(probe(&mut SimCrb::new(0xfed4_0000)), Some(Interface::Crb));
+
+        let mut absent = SimTis::new();
+        absent.absent = true;
+        assert_eq!(probe(&mut absent), None);
+    }
+}
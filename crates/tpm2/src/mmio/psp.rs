@@ -0,0 +1,191 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::sync::atomic::{fence, Ordering};
+
+use super::*;
+use crate::{Error, Result, Transport};
+
+pub(super) const PSP_CMDRESP: usize = 0x10580;
+pub(super) const PSP_CMDBUFF_ADDR_LO: usize = 0x105e0;
+pub(super) const PSP_CMDBUFF_ADDR_HI: usize = 0x105e4;
+/// The mailbox registers end with the high half of the buffer address.
+pub(super) const PSP_REGS_SIZE: usize = PSP_CMDBUFF_ADDR_HI + 4;
+
+pub(super) const CMDRESP_CMD_SHIFT: u32 = 16;
+pub(super) const CMDRESP_CMD_MASK: u32 = 0x3ff;
+pub(super) const CMDRESP_STATUS_MASK: u32 = 0xffff;
+pub(super) const CMDRESP_READY: u32 = 1 << 31;
+/// The mailbox command submitting a TPM command.
+pub(super) const PSP_CMD_TPM: u32 = 0x100;
+
+/// Size of the command buffer, which starts with its own size and the size
+/// of the TPM command or response that follows, both big-endian.
+pub const PSP_BUFFER_SIZE: usize = 0x1000;
+pub(super) const PSP_BUFFER_HEADER_SIZE: usize = 8;
+
+/// The firmware TPM of Hygon CPUs, which runs in the Platform Security
+/// Processor and takes commands through its mailbox.
+///
+/// Commands and responses go through a buffer in memory, which the PSP reads
+/// and writes by its physical address. It is accessed through `Registers` as
+/// well, so that it can be mapped as the platform requires.
+pub struct Psp<R, C, B> {
+    regs: R,
+    clock: C,
+    buffer: B,
+    buffer_addr: u64,
+}
+
+impl<R: Registers, C: Clock, B: Registers> Psp<R, C, B> {
+    /// Creates a driver for the mailbox registers `regs`, passing commands in
+    /// `buffer` of `PSP_BUFFER_SIZE` bytes, found by the PSP at physical
+    /// address `buffer_addr`.
+    pub fn new(regs: R, clock: C, buffer: B, buffer_addr: u64) -> Result<Self> {
+        if regs.size() < PSP_REGS_SIZE || buffer.size() < PSP_BUFFER_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        Ok(Self {
+            regs,
+            clock,
+            buffer,
+            buffer_addr,
+        })
+    }
+
+    pub fn into_inner(self) -> (R, C, B) {
+        (self.regs, self.clock, self.buffer)
+    }
+
+    fn write_be32(&mut self, offset: usize, value: u32) {
+        for (i, &byte) in value.to_be_bytes().iter().enumerate() {
+            self.buffer.write8(offset + i, byte);
+        }
+    }
+
+    fn read_be32(&mut self, offset: usize) -> u32 {
+        let mut bytes = [0; 4];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.buffer.read8(offset + i);
+        }
+        u32::from_be_bytes(bytes)
+    }
+}
+
+impl<R: Registers, C: Clock, B: Registers> Transport for Psp<R, C, B> {
+    fn transmit(&mut self, command: &[u8], response: &mut [u8]) -> Result<usize> {
+        let max = PSP_BUFFER_SIZE - PSP_BUFFER_HEADER_SIZE;
+        if command.len() < HEADER_SIZE || command.len() > max || response.len() < HEADER_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        self.write_be32(0, PSP_BUFFER_SIZE as u32);
+        self.write_be32(4, command.len() as u32);
+        for (i, &byte) in command.iter().enumerate() {
+            self.buffer.write8(PSP_BUFFER_HEADER_SIZE + i, byte);
+        }
+        for i in PSP_BUFFER_HEADER_SIZE + command.len()..PSP_BUFFER_SIZE {
+            self.buffer.write8(i, 0);
+        }
+        // the PSP must see the whole command once it is rung
+        fence(Ordering::SeqCst);
+
+        let addr = self.buffer_addr;
+        self.regs.write32(PSP_CMDBUFF_ADDR_LO, addr as u32);
+        self.regs.write32(PSP_CMDBUFF_ADDR_HI, (addr >> 32) as u32);
+        self.regs
+            .write32(PSP_CMDRESP, PSP_CMD_TPM << CMDRESP_CMD_SHIFT);
+        let (regs, clock) = (&mut self.regs, &self.clock);
+        if !wait_for(clock, TIMEOUT_COMMAND, || {
+            regs.read32(PSP_CMDRESP) & CMDRESP_READY != 0
+        }) {
+            return Err(Error::Transport("TPM command timed out"));
+        }
+        let status = self.regs.read32(PSP_CMDRESP);
+        if (status >> CMDRESP_CMD_SHIFT) & CMDRESP_CMD_MASK != PSP_CMD_TPM
+            || status & CMDRESP_STATUS_MASK != 0
+        {
+            return Err(Error::Transport("PSP failed to run the TPM command"));
+        }
+        fence(Ordering::SeqCst);
+
+        let rsp_size = (self.read_be32(4) as usize).min(max);
+        if rsp_size < HEADER_SIZE {
+            return Err(Error::Transport("invalid response size"));
+        }
+        for (i, byte) in response[..HEADER_SIZE].iter_mut().enumerate() {
+            *byte = self.buffer.read8(PSP_BUFFER_HEADER_SIZE + i);
+        }
+        let size = parsed_size(response, response.len().min(rsp_size))?;
+        for (i, byte) in response[..size].iter_mut().enumerate().skip(HEADER_SIZE) {
+            *byte = self.buffer.read8(PSP_BUFFER_HEADER_SIZE + i);
+        }
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::sim::{SimClock, SimPsp};
+    use super::*;
+    use crate::consts::*;
+    use crate::Tpm;
+
+    const BUFFER_ADDR: u64 = 0x8000_1234_5000;
+
+    #[test]
+    fn test_psp_commands() {
+        let mut sim = SimPsp::new();
+        let buffer = sim.buffer();
+        let psp = Psp::new(&mut sim, SimClock::new(), buffer, BUFFER_ADDR).unwrap();
+        let mut tpm = Tpm::new(psp);
+        tpm.startup(TPM_SU_CLEAR).unwrap();
+        assert_eq!(tpm.get_random(32).unwrap().len(), 32);
+        assert_eq!(sim.tpm.commands, 2);
+        assert_eq!(sim.buffer_addrs, [BUFFER_ADDR; 2]);
+    }
+
+    #[test]
+    fn test_psp_timeout() {
+        let mut sim = SimPsp::new();
+        sim.hang = true;
+        let buffer = sim.buffer();
+        let psp = Psp::new(&mut sim, SimClock::new(), buffer, BUFFER_ADDR).unwrap();
+        let err = Tpm::new(psp).startup(TPM_SU_CLEAR).unwrap_err();
+        assert_eq!(err, Error::Transport("TPM command timed out"));
+    }
+
+    #[test]
+    fn test_psp_failure() {
+        let mut sim = SimPsp::new();
+        sim.status = 0x9;
+        let buffer = sim.buffer();
+        let psp = Psp::new(&mut sim, SimClock::new(), buffer, BUFFER_ADDR).unwrap();
+        let err = Tpm::new(psp).startup(TPM_SU_CLEAR).unwrap_err();
+        assert_eq!(err, Error::Transport("PSP failed to run the TPM command"));
+    }
+
+    #[test]
+    fn test_psp_command_too_large() {
+        let mut sim = SimPsp::new();
+        let buffer = sim.buffer();
+        let mut psp = Psp::new(&mut sim, SimClock::new(), buffer, BUFFER_ADDR).unwrap();
+        let command = [0; PSP_BUFFER_SIZE];
+        let mut response = [0; TPM_MAX_COMMAND_SIZE];
+        assert_eq!(
+            psp.transmit(&command, &mut response),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(sim.tpm.commands, 0);
+    }
+}
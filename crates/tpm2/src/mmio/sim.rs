@@ -0,0 +1,487 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Register-level simulations of the FIFO, CRB and PSP mailbox interfaces
+//! for the unit tests, executing commands on a [`SimTpm`].
+//!
+//! Localities are granted at once if no other locality is active, and
+//! commands complete as soon as they are started unless `hang` is set.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::vec::Vec;
+
+use super::crb::*;
+use super::psp::*;
+use super::tis::*;
+use super::{Clock, Registers, INTERFACE_ID, LOCALITY_SIZE, TIS_DID_VID};
+use crate::consts::TPM_MAX_COMMAND_SIZE;
+use crate::sim::SimTpm;
+use crate::Transport;
+
+/// A clock that advances by a millisecond every time it is read, so that
+/// timeouts expire after a bounded number of polls.
+pub struct SimClock(Cell<u64>);
+
+impl SimClock {
+    pub fn new() -> Self {
+        Self(Cell::new(0))
+    }
+}
+
+impl Clock for SimClock {
+    fn now_us(&self) -> u64 {
+        let now = self.0.get();
+        self.0.set(now + 1000);
+        now
+    }
+}
+
+const REGS_SIZE: usize = 5 * LOCALITY_SIZE;
+
+fn split(offset: usize) -> (u8, usize) {
+    assert!(
+        offset < REGS_SIZE,
+        "register offset {:#x} out of range",
+        offset
+    );
+    ((offset / LOCALITY_SIZE) as u8, offset % LOCALITY_SIZE)
+}
+
+/// Runs `command` on `tpm`, returning the response.
+fn execute(tpm: &mut SimTpm, command: &[u8]) -> Vec<u8> {
+    let mut response = vec![0; TPM_MAX_COMMAND_SIZE];
+    let len = tpm.transmit(command, &mut response).unwrap();
+    response.truncate(len);
+    response
+}
+
+/// The expected size of a partially received command.
+fn expected_size(command: &[u8]) -> Option<usize> {
+    command
+        .get(2..6)
+        .map(|size| u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TisState {
+    Idle,
+    Ready,
+    Reception,
+    Execution,
+    Completion,
+}
+
+pub struct SimTis {
+    pub tpm: SimTpm,
+    active: Option<u8>,
+    state: TisState,
+    fifo_in: Vec<u8>,
+    fifo_out: VecDeque<u8>,
+    /// The burst count reported while data can move.
+    pub burst: u16,
+    /// Whether commands never complete.
+    pub hang: bool,
+    /// Whether the registers read as all ones, as if there were no TPM.
+    pub absent: bool,
+    /// The localities requested, in order.
+    pub locality_requests: Vec<u8>,
+    /// The number of commands canceled.
+    pub cancels: usize,
+}
+
+impl SimTis {
+    pub fn new() -> Self {
+        Self {
+            tpm: SimTpm::new(),
+            active: None,
+            state: TisState::Idle,
+            fifo_in: Vec::new(),
+            fifo_out: VecDeque::new(),
+            burst: 64,
+            hang: false,
+            absent: false,
+            locality_requests: Vec::new(),
+            cancels: 0,
+        }
+    }
+
+    pub fn active_locality(&self) -> Option<u8> {
+        self.active
+    }
+
+    pub fn set_active_locality(&mut self, locality: Option<u8>) {
+        self.active = locality;
+    }
+
+    fn status(&self) -> u32 {
+        let mut sts = STS_VALID;
+        let moving = match self.state {
+            TisState::Ready => {
+                sts |= STS_COMMAND_READY;
+                true
+            }
+            TisState::Reception => {
+                if expected_size(&self.fifo_in).is_none_or(|size| self.fifo_in.len() < size) {
+                    sts |= STS_EXPECT;
+                }
+                true
+            }
+            TisState::Completion if !self.fifo_out.is_empty() => {
+                sts |= STS_DATA_AVAIL;
+                true
+            }
+            _ => false,
+        };
+        if moving {
+            sts |= (self.burst as u32) << STS_BURST_COUNT_SHIFT;
+        }
+        sts
+    }
+
+    fn set_status(&mut self, bits: u32) {
+        if bits & STS_COMMAND_CANCEL != 0 && self.state == TisState::Execution {
+            self.cancels += 1;
+            self.state = TisState::Completion;
+        }
+        if bits & STS_COMMAND_READY != 0 {
+            self.state = TisState::Ready;
+            self.fifo_in.clear();
+            self.fifo_out.clear();
+        }
+        if bits & STS_GO != 0 && self.status() & STS_EXPECT == 0 {
+            if self.state != TisState::Reception {
+                return;
+            }
+            if self.hang {
+                self.state = TisState::Execution;
+            } else {
+                self.fifo_out = execute(&mut self.tpm, &self.fifo_in).into();
+                self.state = TisState::Completion;
+            }
+        }
+    }
+}
+
+impl Registers for SimTis {
+    fn size(&self) -> usize {
+        REGS_SIZE
+    }
+
+    fn read8(&mut self, offset: usize) -> u8 {
+        let (locality, reg) = split(offset);
+        if self.absent {
+            return u8::MAX;
+        }
+        match reg {
+            TPM_ACCESS if self.active == Some(locality) => ACCESS_VALID | ACCESS_ACTIVE_LOCALITY,
+            TPM_ACCESS => ACCESS_VALID,
+            TPM_DATA_FIFO if self.active == Some(locality) => {
+                self.fifo_out.pop_front().unwrap_or(0)
+            }
+            _ => panic!("unexpected read8 of {:#x}", offset),
+        }
+    }
+
+    fn read32(&mut self, offset: usize) -> u32 {
+        let (locality, reg) = split(offset);
+        if self.absent {
+            return u32::MAX;
+        }
+        match reg {
+            TPM_STS if self.active == Some(locality) => self.status(),
+            TPM_STS => u32::MAX,
+            INTERFACE_ID => 0,
+            TIS_DID_VID => 0x0001_1050,
+            _ => panic!("unexpected read32 of {:#x}", offset),
+        }
+    }
+
+    fn write8(&mut self, offset: usize, value: u8) {
+        let (locality, reg) = split(offset);
+        match reg {
+            TPM_ACCESS => {
+                if value & ACCESS_REQUEST_USE != 0 {
+                    self.locality_requests.push(locality);
+                    self.active.get_or_insert(locality);
+                }
+                if value & ACCESS_ACTIVE_LOCALITY != 0 && self.active == Some(locality) {
+                    self.active = None;
+                }
+            }
+            TPM_DATA_FIFO if self.active == Some(locality) => {
+                if self.status() & STS_EXPECT == 0 && self.state != TisState::Ready {
+                    panic!("unexpected command byte");
+                }
+                self.state = TisState::Reception;
+                self.fifo_in.push(value);
+            }
+            _ => panic!("unexpected write8 of {:#x}", offset),
+        }
+    }
+
+    fn write32(&mut self, offset: usize, value: u32) {
+        let (locality, reg) = split(offset);
+        match reg {
+            TPM_STS if self.active == Some(locality) => self.set_status(value),
+            TPM_STS => {}
+            _ => panic!("unexpected write32 of {:#x}", offset),
+        }
+    }
+}
+
+pub struct SimCrb {
+    pub tpm: SimTpm,
+    phys_base: u64,
+    active: Option<u8>,
+    idle: bool,
+    started: bool,
+    buffer: Vec<u8>,
+    /// Whether commands never complete.
+    pub hang: bool,
+    /// The localities requested, in order.
+    pub locality_requests: Vec<u8>,
+    /// The number of commands canceled.
+    pub cancels: usize,
+}
+
+/// The command and response buffer follows the CRB registers of each
+/// locality, as on most implementations.
+const DATA_BUFFER: usize = 0x80;
+const CRB_BUFFER_SIZE: usize = LOCALITY_SIZE - DATA_BUFFER;
+
+impl SimCrb {
+    /// Creates a TPM whose registers are at physical address `phys_base`.
+    pub fn new(phys_base: u64) -> Self {
+        Self {
+            tpm: SimTpm::new(),
+            phys_base,
+            active: None,
+            idle: true,
+            started: false,
+            buffer: vec![0; CRB_BUFFER_SIZE],
+            hang: false,
+            locality_requests: Vec::new(),
+            cancels: 0,
+        }
+    }
+
+    pub fn active_locality(&self) -> Option<u8> {
+        self.active
+    }
+
+    pub fn set_active_locality(&mut self, locality: Option<u8>) {
+        self.active = locality;
+    }
+
+    pub fn idle(&self) -> bool {
+        self.idle
+    }
+
+    fn buffer_addr(&self, locality: u8) -> u64 {
+        self.phys_base + (locality as usize * LOCALITY_SIZE + DATA_BUFFER) as u64
+    }
+
+    fn start(&mut self) {
+        if self.hang {
+            self.started = true;
+            return;
+        }
+        let size = expected_size(&self.buffer).unwrap().min(CRB_BUFFER_SIZE);
+        let response = execute(&mut self.tpm, &self.buffer[..size]);
+        self.buffer[..response.len()].copy_from_slice(&response);
+    }
+}
+
+impl Registers for SimCrb {
+    fn size(&self) -> usize {
+        REGS_SIZE
+    }
+
+    fn read8(&mut self, offset: usize) -> u8 {
+        let (locality, reg) = split(offset);
+        match reg {
+            DATA_BUFFER..=0xfff if self.active == Some(locality) => self.buffer[reg - DATA_BUFFER],
+            _ => panic!("unexpected read8 of {:#x}", offset),
+        }
+    }
+
+    fn read32(&mut self, offset: usize) -> u32 {
+        let (locality, reg) = split(offset);
+        match reg {
+            LOC_STATE => match self.active {
+                Some(active) => {
+                    LOC_STATE_VALID | LOC_STATE_ASSIGNED | (active as u32) << LOC_STATE_ACTIVE_SHIFT
+                }
+                None => LOC_STATE_VALID,
+            },
+            LOC_STS if self.active == Some(locality) => LOC_STS_GRANTED,
+            LOC_STS => 0,
+            INTERFACE_ID => 1,
+            CTRL_REQ => 0,
+            CTRL_STS if self.idle => CTRL_STS_IDLE,
+            CTRL_STS => 0,
+            CTRL_START => self.started as u32,
+            CTRL_CMD_SIZE | CTRL_RSP_SIZE => CRB_BUFFER_SIZE as u32,
+            CTRL_CMD_LADDR | CTRL_RSP_ADDR => self.buffer_addr(locality) as u32,
+            CTRL_CMD_HADDR => (self.buffer_addr(locality) >> 32) as u32,
+            reg if reg == CTRL_RSP_ADDR + 4 => (self.buffer_addr(locality) >> 32) as u32,
+            _ => panic!("unexpected read32 of {:#x}", offset),
+        }
+    }
+
+    fn write8(&mut self, offset: usize, value: u8) {
+        let (locality, reg) = split(offset);
+        match reg {
+            DATA_BUFFER..=0xfff if self.active == Some(locality) => {
+                self.buffer[reg - DATA_BUFFER] = value
+            }
+            _ => panic!("unexpected write8 of {:#x}", offset),
+        }
+    }
+
+    fn write32(&mut self, offset: usize, value: u32) {
+        let (locality, reg) = split(offset);
+        if reg == LOC_CTRL {
+            if value & LOC_CTRL_REQUEST_ACCESS != 0 {
+                self.locality_requests.push(locality);
+                self.active.get_or_insert(locality);
+            }
+            if value & LOC_CTRL_RELINQUISH != 0 && self.active == Some(locality) {
+                self.active = None;
+            }
+            return;
+        }
+        if self.active != Some(locality) {
+            return;
+        }
+        match reg {
+            CTRL_REQ if value & CTRL_REQ_CMD_READY != 0 => self.idle = false,
+            CTRL_REQ if value & CTRL_REQ_GO_IDLE != 0 => self.idle = true,
+            CTRL_CANCEL if value & CTRL_CANCEL_INVOKE != 0 && self.started => {
+                self.cancels += 1;
+                self.started = false;
+            }
+            CTRL_CANCEL => {}
+            CTRL_START if value & CTRL_START_INVOKE != 0 => self.start(),
+            _ => panic!("unexpected write32 {:#x} of {:#x}", value, offset),
+        }
+    }
+}
+
+/// The memory holding the command buffer of a [`SimPsp`], shared between the
+/// driver and the simulated PSP.
+pub struct SimPspBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Registers for SimPspBuffer {
+    fn size(&self) -> usize {
+        self.0.borrow().len()
+    }
+
+    fn read8(&mut self, offset: usize) -> u8 {
+        self.0.borrow()[offset]
+    }
+
+    fn read32(&mut self, offset: usize) -> u32 {
+        panic!("unexpected read32 of {:#x}", offset)
+    }
+
+    fn write8(&mut self, offset: usize, value: u8) {
+        self.0.borrow_mut()[offset] = value;
+    }
+
+    fn write32(&mut self, offset: usize, _value: u32) {
+        panic!("unexpected write32 of {:#x}", offset)
+    }
+}
+
+pub struct SimPsp {
+    pub tpm: SimTpm,
+    buffer: Rc<RefCell<Vec<u8>>>,
+    addr: u64,
+    cmdresp: u32,
+    /// Whether commands never complete.
+    pub hang: bool,
+    /// The status the mailbox reports for every command.
+    pub status: u32,
+    /// The buffer addresses commands were submitted with, in order.
+    pub buffer_addrs: Vec<u64>,
+}
+
+impl SimPsp {
+    pub fn new() -> Self {
+        Self {
+            tpm: SimTpm::new(),
+            buffer: Rc::new(RefCell::new(vec![0; PSP_BUFFER_SIZE])),
+            addr: 0,
+            cmdresp: 0,
+            hang: false,
+            status: 0,
+            buffer_addrs: Vec::new(),
+        }
+    }
+
+    /// The command buffer to hand to the driver.
+    pub fn buffer(&self) -> SimPspBuffer {
+        SimPspBuffer(self.buffer.clone())
+    }
+
+    fn run(&mut self, cmd: u32) {
+        self.buffer_addrs.push(self.addr);
+        self.cmdresp = cmd << CMDRESP_CMD_SHIFT;
+        if self.hang {
+            return;
+        }
+        let mut buffer = self.buffer.borrow_mut();
+        assert_eq!(buffer[..4], (PSP_BUFFER_SIZE as u32).to_be_bytes());
+        let size = expected_size(&buffer[2..]).unwrap();
+        let command = &buffer[PSP_BUFFER_HEADER_SIZE..PSP_BUFFER_HEADER_SIZE + size];
+        let response = execute(&mut self.tpm, command);
+        buffer[4..PSP_BUFFER_HEADER_SIZE].copy_from_slice(&(response.len() as u32).to_be_bytes());
+        buffer[PSP_BUFFER_HEADER_SIZE..PSP_BUFFER_HEADER_SIZE + response.len()]
+            .copy_from_slice(&response);
+        self.cmdresp |= CMDRESP_READY | self.status;
+    }
+}
+
+impl Registers for SimPsp {
+    fn size(&self) -> usize {
+        PSP_REGS_SIZE
+    }
+
+    fn read8(&mut self, offset: usize) -> u8 {
+        panic!("unexpected read8 of {:#x}", offset)
+    }
+
+    fn read32(&mut self, offset: usize) -> u32 {
+        match offset {
+            PSP_CMDRESP => self.cmdresp,
+            _ => panic!("unexpected read32 of {:#x}", offset),
+        }
+    }
+
+    fn write8(&mut self, offset: usize, _value: u8) {
+        panic!("unexpected write8 of {:#x}", offset)
+    }
+
+    fn write32(&mut self, offset: usize, value: u32) {
+        match offset {
+            PSP_CMDBUFF_ADDR_LO => self.addr = self.addr & !0xffff_ffff | value as u64,
+            PSP_CMDBUFF_ADDR_HI => self.addr = self.addr & 0xffff_ffff | (value as u64) << 32,
+            PSP_CMDRESP => self.run((value >> CMDRESP_CMD_SHIFT) & CMDRESP_CMD_MASK),
+            _ => panic!("unexpected write32 of {:#x}", offset),
+        }
+    }
+}
@@ -0,0 +1,282 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::*;
+use crate::{Error, Result, Transport};
+
+pub(super) const TPM_ACCESS: usize = 0x00;
+pub(super) const TPM_STS: usize = 0x18;
+pub(super) const TPM_DATA_FIFO: usize = 0x24;
+
+pub(super) const ACCESS_REQUEST_USE: u8 = 1 << 1;
+pub(super) const ACCESS_ACTIVE_LOCALITY: u8 = 1 << 5;
+pub(super) const ACCESS_VALID: u8 = 1 << 7;
+
+pub(super) const STS_EXPECT: u32 = 1 << 3;
+pub(super) const STS_DATA_AVAIL: u32 = 1 << 4;
+pub(super) const STS_GO: u32 = 1 << 5;
+pub(super) const STS_COMMAND_READY: u32 = 1 << 6;
+pub(super) const STS_VALID: u32 = 1 << 7;
+pub(super) const STS_BURST_COUNT_SHIFT: u32 = 8;
+pub(super) const STS_BURST_COUNT_MASK: u32 = 0xffff;
+pub(super) const STS_COMMAND_CANCEL: u32 = 1 << 24;
+
+/// A TPM behind the FIFO interface.
+pub struct Tis<R, C> {
+    regs: R,
+    clock: C,
+    locality: u8,
+}
+
+impl<R: Registers, C: Clock> Tis<R, C> {
+    /// Creates a driver sending commands at `locality`, whose registers must
+    /// lie within `regs`.
+    pub fn new(regs: R, clock: C, locality: u8) -> Result<Self> {
+        if locality > MAX_LOCALITY || (locality as usize + 1) * LOCALITY_SIZE > regs.size() {
+            return Err(Error::InvalidParam);
+        }
+        Ok(Self {
+            regs,
+            clock,
+            locality,
+        })
+    }
+
+    pub fn locality(&self) -> u8 {
+        self.locality
+    }
+
+    pub fn into_inner(self) -> (R, C) {
+        (self.regs, self.clock)
+    }
+
+    fn reg(&self, offset: usize) -> usize {
+        self.locality as usize * LOCALITY_SIZE + offset
+    }
+
+    fn access(&mut self) -> u8 {
+        self.regs.read8(self.reg(TPM_ACCESS))
+    }
+
+    fn status(&mut self) -> u32 {
+        self.regs.read32(self.reg(TPM_STS))
+    }
+
+    fn set_status(&mut self, bits: u32) {
+        let reg = self.reg(TPM_STS);
+        self.regs.write32(reg, bits);
+    }
+
+    /// Waits until the valid status has all of `bits` set.
+    fn wait_status(&mut self, bits: u32, timeout_ms: u64) -> bool {
+        let reg = self.reg(TPM_STS);
+        let (regs, clock) = (&mut self.regs, &self.clock);
+        wait_for(clock, timeout_ms, || {
+            let sts = regs.read32(reg);
+            sts & (STS_VALID | bits) == STS_VALID | bits
+        })
+    }
+
+    /// Requests the use of our locality, unless it is already active.
+    pub fn request_locality(&mut self) -> Result<()> {
+        let active = ACCESS_VALID | ACCESS_ACTIVE_LOCALITY;
+        if self.access() & active == active {
+            return Ok(());
+        }
+        let reg = self.reg(TPM_ACCESS);
+        self.regs.write8(reg, ACCESS_REQUEST_USE);
+        let (regs, clock) = (&mut self.regs, &self.clock);
+        if !wait_for(clock, TIMEOUT_A, || regs.read8(reg) & active == active) {
+            return Err(Error::Transport("TPM locality request timed out"));
+        }
+        Ok(())
+    }
+
+    pub fn relinquish_locality(&mut self) {
+        let reg = self.reg(TPM_ACCESS);
+        self.regs.write8(reg, ACCESS_ACTIVE_LOCALITY);
+    }
+
+    /// Aborts the command being executed, which then completes early with
+    /// `TPM_RC_CANCELED` or with its result.
+    pub fn cancel(&mut self) {
+        self.set_status(STS_COMMAND_CANCEL);
+    }
+
+    /// Waits until the TPM accepts more data or has more data to give.
+    fn burst_count(&mut self) -> Result<usize> {
+        let reg = self.reg(TPM_STS);
+        let (regs, clock) = (&mut self.regs, &self.clock);
+        let mut burst = 0;
+        wait_for(clock, TIMEOUT_D, || {
+            let sts = regs.read32(reg);
+            burst = (sts >> STS_BURST_COUNT_SHIFT) & STS_BURST_COUNT_MASK;
+            burst != 0
+        });
+        match burst {
+            0 => Err(Error::Transport("TPM burst count timed out")),
+            burst => Ok(burst as usize),
+        }
+    }
+
+    fn send(&mut self, command: &[u8]) -> Result<()> {
+        self.set_status(STS_COMMAND_READY);
+        if !self.wait_status(STS_COMMAND_READY, TIMEOUT_B) {
+            return Err(Error::Transport("TPM is not ready for a command"));
+        }
+        let fifo = self.reg(TPM_DATA_FIFO);
+        let (body, last) = command.split_at(command.len() - 1);
+        let mut sent = 0;
+        while sent < body.len() {
+            let burst = self.burst_count()?.min(body.len() - sent);
+            for &byte in &body[sent..sent + burst] {
+                self.regs.write8(fifo, byte);
+            }
+            sent += burst;
+        }
+        if !self.wait_status(STS_EXPECT, TIMEOUT_C) {
+            return Err(Error::Transport("TPM expects a shorter command"));
+        }
+        self.burst_count()?;
+        self.regs.write8(fifo, last[0]);
+        if !self.wait_status(0, TIMEOUT_C) || self.status() & STS_EXPECT != 0 {
+            return Err(Error::Transport("TPM expects a longer command"));
+        }
+        self.set_status(STS_GO);
+        Ok(())
+    }
+
+    fn recv_into(&mut self, buf: &mut [u8]) -> Result<()> {
+        let fifo = self.reg(TPM_DATA_FIFO);
+        let mut received = 0;
+        while received < buf.len() {
+            if !self.wait_status(STS_DATA_AVAIL, TIMEOUT_C) {
+                return Err(Error::Transport("TPM response is truncated"));
+            }
+            let burst = self.burst_count()?.min(buf.len() - received);
+            for byte in &mut buf[received..received + burst] {
+                *byte = self.regs.read8(fifo);
+            }
+            received += burst;
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self, response: &mut [u8]) -> Result<usize> {
+        if !self.wait_status(STS_DATA_AVAIL, TIMEOUT_COMMAND) {
+            self.cancel();
+            return Err(Error::Transport("TPM command timed out"));
+        }
+        self.recv_into(&mut response[..HEADER_SIZE])?;
+        let size = parsed_size(response, response.len())?;
+        self.recv_into(&mut response[HEADER_SIZE..size])?;
+        if !self.wait_status(0, TIMEOUT_C) || self.status() & STS_DATA_AVAIL != 0 {
+            return Err(Error::Transport("TPM response is longer than its size"));
+        }
+        Ok(size)
+    }
+}
+
+impl<R: Registers, C: Clock> Transport for Tis<R, C> {
+    fn transmit(&mut self, command: &[u8], response: &mut [u8]) -> Result<usize> {
+        if command.len() < HEADER_SIZE || response.len() < HEADER_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        self.request_locality()?;
+        let result = self.send(command).and_then(|_| self.recv(response));
+        // back to idle, which also completes a canceled command
+        self.set_status(STS_COMMAND_READY);
+        self.relinquish_locality();
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::sim::{SimClock, SimTis};
+    use super::*;
+    use crate::consts::*;
+    use crate::{NvPublic, Tpm};
+
+    #[test]
+    fn test_tis_commands() {
+        let mut sim = SimTis::new();
+        let mut tpm = Tpm::new(Tis::new(&mut sim, SimClock::new(), 0).unwrap());
+        tpm.startup(TPM_SU_CLEAR).unwrap();
+        assert_eq!(tpm.get_random(32).unwrap().len(), 32);
+        let (sim, _) = tpm.into_inner().into_inner();
+        assert_eq!(sim.tpm.commands, 2);
+        // the locality is only held during a command
+        assert_eq!(sim.active_locality(), None);
+        assert_eq!(sim.locality_requests, [0, 0]);
+    }
+
+    #[test]
+    fn test_tis_bursts() {
+        let mut sim = SimTis::new();
+        sim.burst = 3;
+        let mut tpm = Tpm::new(Tis::new(&mut sim, SimClock::new(), 2).unwrap());
+        tpm.startup(TPM_SU_CLEAR).unwrap();
+        let public = NvPublic {
+            index: 0x0150_0020,
+            name_alg: TPM_ALG_SM3_256,
+            attributes: TPMA_NV_OWNERWRITE | TPMA_NV_OWNERREAD | TPMA_NV_NO_DA,
+            auth_policy: Vec::new(),
+            data_size: 1000,
+        };
+        tpm.nv_define_space(TPM_RH_OWNER, &[], &[], &public)
+            .unwrap();
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        tpm.nv_write(TPM_RH_OWNER, &[], public.index, &data, 0)
+            .unwrap();
+        assert_eq!(
+            tpm.nv_read(TPM_RH_OWNER, &[], public.index, 1000, 0)
+                .unwrap(),
+            data
+        );
+        let (sim, _) = tpm.into_inner().into_inner();
+        assert!(sim.locality_requests.iter().all(|&l| l == 2));
+    }
+
+    #[test]
+    fn test_tis_timeout() {
+        let mut sim = SimTis::new();
+        sim.hang = true;
+        let mut tis = Tis::new(&mut sim, SimClock::new(), 0).unwrap();
+        let err = Tpm::new(&mut tis).startup(TPM_SU_CLEAR).unwrap_err();
+        assert_eq!(err, Error::Transport("TPM command timed out"));
+        let (sim, _) = tis.into_inner();
+        assert_eq!(sim.cancels, 1);
+        assert_eq!(sim.active_locality(), None);
+
+        // the interface recovers once the command is canceled
+        sim.hang = false;
+        let mut tpm = Tpm::new(Tis::new(sim, SimClock::new(), 0).unwrap());
+        tpm.startup(TPM_SU_CLEAR).unwrap();
+    }
+
+    #[test]
+    fn test_tis_locality() {
+        let mut sim = SimTis::new();
+        assert!(Tis::new(&mut sim, SimClock::new(), 5).is_err());
+
+        // another locality holds the interface and never lets go
+        sim.set_active_locality(Some(3));
+        let mut tpm = Tpm::new(Tis::new(&mut sim, SimClock::new(), 0).unwrap());
+        let err = tpm.startup(TPM_SU_CLEAR).unwrap_err();
+        assert_eq!(err, Error::Transport("TPM locality request timed out"));
+        let (sim, _) = tpm.into_inner().into_inner();
+        assert_eq!(sim.tpm.commands, 0);
+    }
+}
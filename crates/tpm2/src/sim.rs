@@ -0,0 +1,817 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An in-memory TPM, for the unit tests and, with the `sim` feature, for
+//! platforms without a TPM.
+//!
+//! It implements the startup, random, PCR (SM3 bank only), NV and policy
+//! session commands closely enough to check the command layer, including
+//! authorization and locking rules of NV indices, and SM2 signing keys derived
+//! from its seed. Of the passwords, only the authValues of NV indices are
+//! checked, and of the policy assertions only `TPM2_PolicyPCR` and `TPM2_PolicyLocality` are known.
+//!
+//! Nothing outlives the `SimTpm`, and whoever knows its seed knows its keys and
+//! random numbers: it protects nothing.
+
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use yogcrypt::sm2::{get_pub_key, sm2_gen_sign_digest, SecKey, U64x4};
+use yogcrypt::sm3::sm3_enc;
+
+use crate::consts::*;
+use crate::marshal::{Reader, Writer};
+use crate::types::*;
+use crate::{Result, Transport};
+
+const TPM_RC_ATTRIBUTES: u32 = TPM_RC_FMT1 + 0x002;
+const TPM_RC_HASH: u32 = TPM_RC_FMT1 + 0x003;
+const TPM_RC_VALUE: u32 = TPM_RC_FMT1 + 0x004;
+const TPM_RC_KEY: u32 = TPM_RC_FMT1 + 0x01c;
+const TPM_RC_TICKET: u32 = TPM_RC_FMT1 + 0x00c;
+const TPM_RC_AUTH_UNAVAILABLE: u32 = 0x12f;
+
+/// Marks a format-one response code as caused by handle number `n`.
+const fn rc_handle(rc: u32, n: u32) -> u32 {
+    rc | (n << 8)
+}
+
+pub fn sm3(data: &[u8]) -> [u8; 32] {
+    let mut digest = [0; 32];
+    for (i, word) in sm3_enc(data).iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+/// The integer of 32 big-endian bytes.
+pub(crate) fn u64x4_from_be(bytes: &[u8; 32]) -> U64x4 {
+    let word = |i: usize| {
+        let mut word = [0; 8];
+        word.copy_from_slice(&bytes[32 - 8 * (i + 1)..32 - 8 * i]);
+        u64::from_be_bytes(word)
+    };
+    U64x4::new(word(0), word(1), word(2), word(3))
+}
+
+fn u64x4_to_be(value: &U64x4) -> Vec<u8> {
+    value
+        .value
+        .iter()
+        .rev()
+        .flat_map(|word| word.to_be_bytes())
+        .collect()
+}
+
+/// How a command is authorized: by the digest of its policy session, or by
+/// the password of its password session.
+#[derive(Clone, Copy)]
+enum CmdAuth<'a> {
+    Policy([u8; 32]),
+    Password(&'a [u8]),
+}
+
+struct NvIndex {
+    public: NvPublic,
+    auth: Vec<u8>,
+    data: Vec<u8>,
+}
+
+/// A loaded SM2 key, transient or persistent.
+struct Object {
+    public: Public,
+    secret: SecKey,
+}
+
+impl Object {
+    /// `nameAlg || H(public)`, as the name of the key and the qualified
+    /// name of the hierarchy it is the primary key of.
+    fn name(&self) -> Vec<u8> {
+        let mut name = TPM_ALG_SM3_256.to_be_bytes().to_vec();
+        name.extend_from_slice(&sm3(&self.public.to_bytes()));
+        name
+    }
+
+    fn sign(&self, digest: &[u8]) -> core::result::Result<Signature, u32> {
+        let mut e = [0; 32];
+        if digest.len() != e.len() {
+            return Err(TPM_RC_VALUE);
+        }
+        e.copy_from_slice(digest);
+        let sig = sm2_gen_sign_digest(&e, self.secret);
+        Ok(Signature {
+            alg: TPM_ALG_SM2,
+            hash: TPM_ALG_SM3_256,
+            r: u64x4_to_be(&sig.r),
+            s: u64x4_to_be(&sig.s),
+        })
+    }
+}
+
+struct Session {
+    trial: bool,
+    digest: [u8; 32],
+    /// The `TPMA_LOCALITY` bitmap of `TPM2_PolicyLocality`, if asserted.
+    localities: Option<u8>,
+}
+
+pub struct SimTpm {
+    started: bool,
+    pcrs: [[u8; 32]; 24],
+    nv: BTreeMap<u32, NvIndex>,
+    sessions: BTreeMap<u32, Session>,
+    next_session: u32,
+    objects: BTreeMap<u32, Object>,
+    next_object: u32,
+    /// The highest value of any counter index, deleted or not.
+    highest_counter: u64,
+    /// Random numbers are `SM3(seed || n)` for n = 0, 1, ... with a seed, or
+    /// else consecutive bytes, easier to tell apart in the tests.
+    seed: Option<[u8; 32]>,
+    random: u64,
+    /// The number of upcoming commands to answer with `TPM_RC_RETRY`.
+    pub retries: usize,
+    /// The number of commands received.
+    pub commands: usize,
+    /// The locality commands are received at.
+    pub locality: u8,
+}
+
+type CmdResult = core::result::Result<Vec<u8>, u32>;
+
+impl Default for SimTpm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SimTpm {
+    pub fn new() -> Self {
+        Self {
+            started: false,
+            pcrs: [[0; 32]; 24],
+            nv: BTreeMap::new(),
+            sessions: BTreeMap::new(),
+            next_session: TPM_HT_POLICY_SESSION,
+            objects: BTreeMap::new(),
+            next_object: TPM_HT_TRANSIENT,
+            highest_counter: 0,
+            seed: None,
+            random: 0,
+            retries: 0,
+            commands: 0,
+            locality: 0,
+        }
+    }
+
+    /// A TPM whose primary keys and random numbers derive from `seed`, so
+    /// that they are the same for every TPM with that seed.
+    pub fn with_seed(seed: [u8; 32]) -> Self {
+        Self {
+            seed: Some(seed),
+            ..Self::new()
+        }
+    }
+
+    /// Simulates a platform reset: PCRs are cleared and the TPM must be
+    /// started again.
+    pub fn reset(&mut self) {
+        self.started = false;
+        self.pcrs = [[0; 32]; 24];
+        self.sessions.clear();
+        self.objects
+            .retain(|&handle, _| handle & 0xff00_0000 == TPM_HT_PERSISTENT);
+    }
+
+    pub fn pcr(&self, pcr: usize) -> [u8; 32] {
+        self.pcrs[pcr]
+    }
+
+    fn handle_count(cc: u32) -> usize {
+        match cc {
+            TPM_CC_PCR_EXTEND
+            | TPM_CC_CREATE_PRIMARY
+            | TPM_CC_READ_PUBLIC
+            | TPM_CC_QUOTE
+            | TPM_CC_SIGN
+            | TPM_CC_NV_DEFINE_SPACE
+            | TPM_CC_NV_READ_PUBLIC
+            | TPM_CC_POLICY_PCR
+            | TPM_CC_POLICY_LOCALITY
+            | TPM_CC_POLICY_GET_DIGEST => 1,
+            TPM_CC_START_AUTH_SESSION | TPM_CC_EVICT_CONTROL => 2,
+            TPM_CC_NV_UNDEFINE_SPACE
+            | TPM_CC_NV_READ
+            | TPM_CC_NV_WRITE
+            | TPM_CC_NV_INCREMENT
+            | TPM_CC_NV_READ_LOCK
+            | TPM_CC_NV_WRITE_LOCK => 2,
+            _ => 0,
+        }
+    }
+
+    fn random_bytes(&mut self, len: usize) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(len);
+        while bytes.len() < len {
+            let n = self.random;
+            self.random += 1;
+            match self.seed {
+                Some(seed) => {
+                    let mut data = seed.to_vec();
+                    data.extend_from_slice(&n.to_be_bytes());
+                    bytes.extend_from_slice(&sm3(&data));
+                }
+                None => bytes.push((n + 1) as u8),
+            }
+        }
+        bytes.truncate(len);
+        bytes
+    }
+
+    fn object(&self, handle: u32, n: u32) -> core::result::Result<&Object, u32> {
+        self.objects
+            .get(&handle)
+            .ok_or_else(|| rc_handle(TPM_RC_HANDLE, n))
+    }
+
+    /// The primary key of `template` in `hierarchy`, the same for every TPM
+    /// with the same seed.
+    fn primary_key(&self, hierarchy: u32, template: &Public) -> core::result::Result<Object, u32> {
+        let scheme_ok = template.scheme.alg == TPM_ALG_SM2 || template.scheme == Scheme::NULL;
+        if template.curve != TPM_ECC_SM2_P256 || !scheme_ok {
+            return Err(TPM_RC_KEY);
+        }
+        let mut data = self.seed.unwrap_or_default().to_vec();
+        data.extend_from_slice(&hierarchy.to_be_bytes());
+        data.extend_from_slice(&template.to_bytes());
+        // a digest at or above the group order, with a negligible
+        // probability, still works modulo it
+        let secret = u64x4_from_be(&sm3(&data));
+        let point = get_pub_key(secret);
+        let mut public = template.clone();
+        public.unique = EccPoint {
+            x: u64x4_to_be(&point.x.num),
+            y: u64x4_to_be(&point.y.num),
+        };
+        Ok(Object { public, secret })
+    }
+
+    /// The ticket of `TPM2_Hash` for `digest` in `hierarchy`.
+    fn hashcheck_ticket(&self, hierarchy: u32, digest: &[u8]) -> HashcheckTicket {
+        let mut data = self.seed.unwrap_or_default().to_vec();
+        data.extend_from_slice(&hierarchy.to_be_bytes());
+        data.extend_from_slice(digest);
+        HashcheckTicket {
+            hierarchy,
+            digest: sm3(&data).to_vec(),
+        }
+    }
+
+    fn nv_index(&mut self, index: u32, n: u32) -> core::result::Result<&mut NvIndex, u32> {
+        self.nv
+            .get_mut(&index)
+            .ok_or_else(|| rc_handle(TPM_RC_HANDLE, n))
+    }
+
+    /// Checks that `auth_handle` may read or write the index `handle`, whose
+    /// authValue is `index_auth`, with `auth` the authorization of the command.
+    fn nv_authorize(
+        public: &NvPublic,
+        index_auth: &[u8],
+        auth_handle: u32,
+        handle: u32,
+        auth: CmdAuth,
+        is_read: bool,
+    ) -> core::result::Result<(), u32> {
+        let attrs = public.attributes;
+        let (owner_bit, auth_bit, policy_bit, locked_bit) = if is_read {
+            (
+                TPMA_NV_OWNERREAD,
+                TPMA_NV_AUTHREAD,
+                TPMA_NV_POLICYREAD,
+                TPMA_NV_READLOCKED,
+            )
+        } else {
+            (
+                TPMA_NV_OWNERWRITE,
+                TPMA_NV_AUTHWRITE,
+                TPMA_NV_POLICYWRITE,
+                TPMA_NV_WRITELOCKED,
+            )
+        };
+        let allowed = match auth {
+            CmdAuth::Policy(digest) if auth_handle == handle && attrs & policy_bit != 0 => {
+                if public.auth_policy != digest {
+                    return Err(TPM_RC_POLICY_FAIL);
+                }
+                true
+            }
+            CmdAuth::Policy(_) => false,
+            CmdAuth::Password(password) if auth_handle == handle && attrs & auth_bit != 0 => {
+                if password != index_auth {
+                    return Err(TPM_RC_AUTH_FAIL);
+                }
+                true
+            }
+            CmdAuth::Password(_) => auth_handle == TPM_RH_OWNER && attrs & owner_bit != 0,
+        };
+        if !allowed {
+            return Err(TPM_RC_NV_AUTHORIZATION);
+        }
+        if attrs & locked_bit != 0 {
+            return Err(TPM_RC_NV_LOCKED);
+        }
+        Ok(())
+    }
+
+    /// The digest of the policy session authorizing a command, checking the
+    /// deferred assertions of the session.
+    fn policy_auth(&self, sessions: &[u32]) -> core::result::Result<Option<[u8; 32]>, u32> {
+        let handle = match sessions.iter().find(|&&s| s != TPM_RS_PW) {
+            Some(&handle) => handle,
+            None => return Ok(None),
+        };
+        let session = self.sessions.get(&handle).ok_or(TPM_RC_VALUE)?;
+        if session.trial {
+            return Err(TPM_RC_ATTRIBUTES);
+        }
+        if let Some(localities) = session.localities {
+            if localities & tpma_locality(self.locality) == 0 {
+                return Err(TPM_RC_LOCALITY);
+            }
+        }
+        Ok(Some(session.digest))
+    }
+
+    /// Extends the policy digest of `session` with `data`.
+    fn policy_update(&mut self, session: u32, data: &[u8]) -> core::result::Result<(), u32> {
+        let session = self
+            .sessions
+            .get_mut(&session)
+            .ok_or_else(|| rc_handle(TPM_RC_HANDLE, 1))?;
+        let mut buf = session.digest.to_vec();
+        buf.extend_from_slice(data);
+        session.digest = sm3(&buf);
+        Ok(())
+    }
+
+    fn dispatch(
+        &mut self,
+        cc: u32,
+        handles: &[u32],
+        sessions: &[u32],
+        password: &[u8],
+        r: &mut Reader,
+    ) -> CmdResult {
+        let malformed = |_| TPM_RC_COMMAND_SIZE;
+        if cc == TPM_CC_STARTUP {
+            let su = r.u16().map_err(malformed)?;
+            if self.started {
+                return Err(TPM_RC_INITIALIZE);
+            }
+            self.started = true;
+            if su == TPM_SU_CLEAR {
+                for index in self.nv.values_mut() {
+                    let attrs = &mut index.public.attributes;
+                    if *attrs & TPMA_NV_READ_STCLEAR != 0 {
+                        *attrs &= !TPMA_NV_READLOCKED;
+                    }
+                    if *attrs & TPMA_NV_WRITE_STCLEAR != 0 {
+                        *attrs &= !TPMA_NV_WRITELOCKED;
+                    }
+                }
+            }
+            return Ok(Vec::new());
+        }
+        if !self.started {
+            return Err(TPM_RC_INITIALIZE);
+        }
+
+        let auth = match self.policy_auth(sessions)? {
+            Some(digest) => CmdAuth::Policy(digest),
+            None => CmdAuth::Password(password),
+        };
+        let mut out = Writer::new();
+        match cc {
+            TPM_CC_GET_RANDOM => {
+                let len = r.u16().map_err(malformed)?.min(32);
+                out.tpm2b(&self.random_bytes(len as usize));
+            }
+            TPM_CC_PCR_READ => {
+                let selections: Vec<PcrSelection> = r.get().map_err(malformed)?;
+                let mut read = Vec::new();
+                let mut digests = Vec::new();
+                for sel in selections.iter().filter(|s| s.hash == TPM_ALG_SM3_256) {
+                    let mut pcrs = Vec::new();
+                    for pcr in sel.iter().filter(|&pcr| pcr < 24).take(8 - digests.len()) {
+                        pcrs.push(pcr);
+                        digests.push(self.pcrs[pcr as usize]);
+                    }
+                    read.push(PcrSelection::new(sel.hash, &pcrs));
+                }
+                out.u32(0).put(&read[..]).u32(digests.len() as u32);
+                for digest in &digests {
+                    out.tpm2b(digest);
+                }
+            }
+            TPM_CC_PCR_EXTEND => {
+                let pcr = handles[0] as usize;
+                if pcr >= 24 {
+                    return Err(rc_handle(TPM_RC_VALUE, 1));
+                }
+                let count = r.u32().map_err(malformed)?;
+                for _ in 0..count {
+                    let alg = r.u16().map_err(malformed)?;
+                    let size = digest_size(alg).ok_or(TPM_RC_HASH)?;
+                    let digest = r.bytes(size).map_err(malformed)?;
+                    if alg == TPM_ALG_SM3_256 {
+                        let mut data = self.pcrs[pcr].to_vec();
+                        data.extend_from_slice(digest);
+                        self.pcrs[pcr] = sm3(&data);
+                    }
+                }
+            }
+            TPM_CC_CREATE_PRIMARY => {
+                let hierarchy = handles[0];
+                if ![TPM_RH_OWNER, TPM_RH_ENDORSEMENT, TPM_RH_PLATFORM].contains(&hierarchy) {
+                    return Err(rc_handle(TPM_RC_HANDLE, 1));
+                }
+                let _sensitive = r.tpm2b().map_err(malformed)?;
+                let template: Public = r.sized().map_err(malformed)?;
+                let _outside_info = r.tpm2b().map_err(malformed)?;
+                let _creation_pcrs: Vec<PcrSelection> = r.get().map_err(malformed)?;
+                let object = self.primary_key(hierarchy, &template)?;
+                let handle = self.next_object;
+                self.next_object += 1;
+                out.u32(handle)
+                    .sized(&object.public)
+                    .tpm2b(&[])
+                    .tpm2b(&[])
+                    .u16(TPM_ST_CREATION)
+                    .u32(hierarchy)
+                    .tpm2b(&[])
+                    .tpm2b(&object.name());
+                self.objects.insert(handle, object);
+            }
+            TPM_CC_READ_PUBLIC => {
+                let object = self.object(handles[0], 1)?;
+                let name = object.name();
+                out.sized(&object.public).tpm2b(&name).tpm2b(&name);
+            }
+            TPM_CC_EVICT_CONTROL => {
+                let (object, persistent) = (handles[1], r.u32().map_err(malformed)?);
+                if handles[0] != TPM_RH_OWNER && handles[0] != TPM_RH_PLATFORM {
+                    return Err(rc_handle(TPM_RC_HANDLE, 1));
+                }
+                self.object(object, 2)?;
+                if persistent & 0xff00_0000 != TPM_HT_PERSISTENT {
+                    return Err(TPM_RC_VALUE);
+                }
+                if object == persistent {
+                    self.objects.remove(&object);
+                } else if object & 0xff00_0000 != TPM_HT_TRANSIENT {
+                    return Err(rc_handle(TPM_RC_HANDLE, 2));
+                } else if self.objects.contains_key(&persistent) {
+                    return Err(TPM_RC_NV_DEFINED);
+                } else {
+                    let public = self.objects[&object].public.clone();
+                    let secret = self.objects[&object].secret;
+                    self.objects.insert(persistent, Object { public, secret });
+                }
+            }
+            TPM_CC_QUOTE => {
+                let qualifying_data = r.tpm2b().map_err(malformed)?.to_vec();
+                let _scheme: Scheme = r.get().map_err(malformed)?;
+                let selections: Vec<PcrSelection> = r.get().map_err(malformed)?;
+                let mut values = Vec::new();
+                for sel in &selections {
+                    if sel.hash != TPM_ALG_SM3_256 {
+                        return Err(TPM_RC_HASH);
+                    }
+                    for pcr in sel.iter().filter(|&pcr| pcr < 24) {
+                        values.extend_from_slice(&self.pcrs[pcr as usize]);
+                    }
+                }
+                let key = self.object(handles[0], 1)?;
+                let attest = Attest {
+                    magic: TPM_GENERATED_VALUE,
+                    qualified_signer: key.name(),
+                    extra_data: qualifying_data,
+                    clock: self.commands as u64,
+                    reset_count: 0,
+                    restart_count: 0,
+                    safe: true,
+                    firmware_version: 0,
+                    quote: QuoteInfo {
+                        pcr_select: selections,
+                        pcr_digest: sm3(&values).to_vec(),
+                    },
+                };
+                let mut attest_bytes = Writer::new();
+                attest_bytes.put(&attest);
+                let signature = key.sign(&sm3(attest_bytes.as_slice()))?;
+                out.tpm2b(attest_bytes.as_slice()).put(&signature);
+            }
+            TPM_CC_HASH => {
+                let data = r.tpm2b().map_err(malformed)?;
+                if r.u16().map_err(malformed)? != TPM_ALG_SM3_256 {
+                    return Err(TPM_RC_HASH);
+                }
+                let hierarchy = r.u32().map_err(malformed)?;
+                let digest = sm3(data);
+                // the TPM only vouches for data it could not have generated
+                let ticket = if data.starts_with(&TPM_GENERATED_VALUE.to_be_bytes()) {
+                    HashcheckTicket::null()
+                } else {
+                    self.hashcheck_ticket(hierarchy, &digest)
+                };
+                out.tpm2b(&digest).put(&ticket);
+            }
+            TPM_CC_SIGN => {
+                let digest = r.tpm2b().map_err(malformed)?.to_vec();
+                let _scheme: Scheme = r.get().map_err(malformed)?;
+                let ticket: HashcheckTicket = r.get().map_err(malformed)?;
+                let key = self.object(handles[0], 1)?;
+                if key.public.attributes & TPMA_OBJECT_RESTRICTED != 0
+                    && (ticket.hierarchy == TPM_RH_NULL
+                        || ticket != self.hashcheck_ticket(ticket.hierarchy, &digest))
+                {
+                    return Err(TPM_RC_TICKET);
+                }
+                out.put(&key.sign(&digest)?);
+            }
+            TPM_CC_NV_DEFINE_SPACE => {
+                if handles[0] != TPM_RH_OWNER && handles[0] != TPM_RH_PLATFORM {
+                    return Err(rc_handle(TPM_RC_HANDLE, 1));
+                }
+                let auth = r.tpm2b().map_err(malformed)?.to_vec();
+                let public: NvPublic = r.sized().map_err(malformed)?;
+                if public.data_size as usize > 2048 {
+                    return Err(TPM_RC_NV_SIZE);
+                }
+                match public.attributes & TPMA_NV_TPM_NT_MASK {
+                    0 => {}
+                    TPMA_NV_COUNTER if public.data_size == 8 => {}
+                    _ => return Err(TPM_RC_ATTRIBUTES),
+                }
+                if self.nv.contains_key(&public.index) {
+                    return Err(TPM_RC_NV_DEFINED);
+                }
+                let data = vec![0xff; public.data_size as usize];
+                self.nv.insert(public.index, NvIndex { public, auth, data });
+            }
+            TPM_CC_NV_UNDEFINE_SPACE => {
+                self.nv_index(handles[1], 2)?;
+                self.nv.remove(&handles[1]);
+            }
+            TPM_CC_NV_READ_PUBLIC => {
+                let index = self.nv_index(handles[0], 1)?;
+                out.sized(&index.public).tpm2b(&handles[0].to_be_bytes());
+            }
+            TPM_CC_NV_READ | TPM_CC_NV_WRITE => {
+                let (auth_handle, handle) = (handles[0], handles[1]);
+                let is_read = cc == TPM_CC_NV_READ;
+                let index = self.nv_index(handle, 2)?;
+                let attrs = index.public.attributes;
+                Self::nv_authorize(
+                    &index.public,
+                    &index.auth,
+                    auth_handle,
+                    handle,
+                    auth,
+                    is_read,
+                )?;
+                if !is_read && attrs & TPMA_NV_TPM_NT_MASK != 0 {
+                    return Err(TPM_RC_ATTRIBUTES);
+                }
+                let (data, size) = if is_read {
+                    (None, r.u16().map_err(malformed)? as usize)
+                } else {
+                    let data = r.tpm2b().map_err(malformed)?;
+                    (Some(data), data.len())
+                };
+                let offset = r.u16().map_err(malformed)? as usize;
+                if size > TPM_MAX_NV_CHUNK {
+                    return Err(TPM_RC_VALUE);
+                }
+                if offset + size > index.data.len() {
+                    return Err(TPM_RC_NV_RANGE);
+                }
+                match data {
+                    None => {
+                        if attrs & TPMA_NV_WRITTEN == 0 {
+                            return Err(TPM_RC_NV_UNINITIALIZED);
+                        }
+                        out.tpm2b(&index.data[offset..offset + size]);
+                    }
+                    Some(data) => {
+                        index.data[offset..offset + size].copy_from_slice(data);
+                        index.public.attributes |= TPMA_NV_WRITTEN;
+                    }
+                }
+            }
+            TPM_CC_NV_INCREMENT => {
+                let (auth_handle, handle) = (handles[0], handles[1]);
+                let highest = self.highest_counter;
+                let index = self.nv_index(handle, 2)?;
+                let attrs = index.public.attributes;
+                Self::nv_authorize(&index.public, &index.auth, auth_handle, handle, auth, false)?;
+                if attrs & TPMA_NV_TPM_NT_MASK != TPMA_NV_COUNTER {
+                    return Err(TPM_RC_ATTRIBUTES);
+                }
+                let mut value = [0; 8];
+                value.copy_from_slice(&index.data);
+                let value = if attrs & TPMA_NV_WRITTEN == 0 {
+                    highest + 1
+                } else {
+                    u64::from_be_bytes(value) + 1
+                };
+                index.data.copy_from_slice(&value.to_be_bytes());
+                index.public.attributes |= TPMA_NV_WRITTEN;
+                self.highest_counter = self.highest_counter.max(value);
+            }
+            TPM_CC_NV_READ_LOCK | TPM_CC_NV_WRITE_LOCK => {
+                let is_read = cc == TPM_CC_NV_READ_LOCK;
+                let (lockable, locked) = if is_read {
+                    (TPMA_NV_READ_STCLEAR, TPMA_NV_READLOCKED)
+                } else {
+                    (
+                        TPMA_NV_WRITEDEFINE | TPMA_NV_WRITE_STCLEAR,
+                        TPMA_NV_WRITELOCKED,
+                    )
+                };
+                let index = self.nv_index(handles[1], 2)?;
+                if index.public.attributes & lockable == 0 {
+                    return Err(TPM_RC_ATTRIBUTES);
+                }
+                // Locking an index again is fine.
+                let unlocked = NvPublic {
+                    attributes: index.public.attributes & !locked,
+                    ..index.public.clone()
+                };
+                let (auth_handle, handle) = (handles[0], handles[1]);
+                Self::nv_authorize(&unlocked, &index.auth, auth_handle, handle, auth, is_read)?;
+                index.public.attributes |= locked;
+            }
+            TPM_CC_START_AUTH_SESSION => {
+                let _nonce = r.tpm2b().map_err(malformed)?;
+                let _salt = r.tpm2b().map_err(malformed)?;
+                let session_type = r.u8().map_err(malformed)?;
+                let _symmetric: SymDef = r.get().map_err(malformed)?;
+                if r.u16().map_err(malformed)? != TPM_ALG_SM3_256 {
+                    return Err(TPM_RC_HASH);
+                }
+                if session_type != TPM_SE_POLICY && session_type != TPM_SE_TRIAL {
+                    return Err(TPM_RC_VALUE);
+                }
+                let handle = self.next_session;
+                self.next_session += 1;
+                self.sessions.insert(
+                    handle,
+                    Session {
+                        trial: session_type == TPM_SE_TRIAL,
+                        digest: [0; 32],
+                        localities: None,
+                    },
+                );
+                out.u32(handle).tpm2b(&[0x5a; 16]);
+            }
+            TPM_CC_FLUSH_CONTEXT => {
+                let handle = r.u32().map_err(malformed)?;
+                if handle & 0xff00_0000 == TPM_HT_TRANSIENT {
+                    self.objects.remove(&handle).ok_or(TPM_RC_HANDLE)?;
+                } else {
+                    self.sessions.remove(&handle).ok_or(TPM_RC_HANDLE)?;
+                }
+            }
+            TPM_CC_POLICY_PCR => {
+                let pcr_digest = r.tpm2b().map_err(malformed)?.to_vec();
+                let selections: Vec<PcrSelection> = r.get().map_err(malformed)?;
+                let mut values = Vec::new();
+                for sel in &selections {
+                    if sel.hash != TPM_ALG_SM3_256 {
+                        return Err(TPM_RC_HASH);
+                    }
+                    for pcr in sel.iter().filter(|&pcr| pcr < 24) {
+                        values.extend_from_slice(&self.pcrs[pcr as usize]);
+                    }
+                }
+                let trial = self.sessions.get(&handles[0]).is_some_and(|s| s.trial);
+                let current = sm3(&values).to_vec();
+                let digest = if pcr_digest.is_empty() {
+                    current
+                } else if trial || pcr_digest == current {
+                    pcr_digest
+                } else {
+                    return Err(TPM_RC_VALUE);
+                };
+                let mut data = Writer::new();
+                data.u32(cc).put(&selections[..]).bytes(&digest);
+                self.policy_update(handles[0], data.as_slice())?;
+            }
+            TPM_CC_POLICY_LOCALITY => {
+                let localities = r.u8().map_err(malformed)?;
+                let mut data = Writer::new();
+                data.u32(cc).u8(localities);
+                self.policy_update(handles[0], data.as_slice())?;
+                if let Some(session) = self.sessions.get_mut(&handles[0]) {
+                    session.localities = Some(localities);
+                }
+            }
+            TPM_CC_POLICY_GET_DIGEST => {
+                let session = self
+                    .sessions
+                    .get(&handles[0])
+                    .ok_or_else(|| rc_handle(TPM_RC_HANDLE, 1))?;
+                out.tpm2b(&session.digest);
+            }
+            _ => return Err(TPM_RC_COMMAND_CODE),
+        }
+        // Policy sessions are used without continueSession.
+        for session in sessions {
+            self.sessions.remove(session);
+        }
+        Ok(out.into_inner())
+    }
+}
+
+impl Transport for SimTpm {
+    fn transmit(&mut self, command: &[u8], response: &mut [u8]) -> Result<usize> {
+        self.commands += 1;
+        let mut r = Reader::new(command);
+        let tag = r.u16()?;
+        let _size = r.u32()?;
+        let cc = r.u32()?;
+        let handles = (0..Self::handle_count(cc))
+            .map(|_| r.u32())
+            .collect::<Result<Vec<_>>>()?;
+        let mut auths = Vec::new();
+        let mut password = Vec::new();
+        if tag == TPM_ST_SESSIONS {
+            let size = r.u32()? as usize;
+            let mut area = Reader::new(r.bytes(size)?);
+            while !area.is_empty() {
+                let session = area.u32()?;
+                if session != TPM_RS_PW && session & 0xff00_0000 != TPM_HT_POLICY_SESSION {
+                    return Err(crate::Error::Malformed);
+                }
+                let _nonce = area.tpm2b()?;
+                let _attrs = area.u8()?;
+                let session_password = area.tpm2b()?;
+                if session == TPM_RS_PW {
+                    password = session_password.to_vec();
+                }
+                auths.push(session);
+            }
+        }
+        let sessions = auths.len();
+
+        let result = if self.retries > 0 {
+            self.retries -= 1;
+            Err(TPM_RC_RETRY)
+        } else if cc == TPM_CC_PCR_EXTEND && sessions == 0 {
+            Err(TPM_RC_AUTH_UNAVAILABLE)
+        } else {
+            self.dispatch(cc, &handles, &auths, &password, &mut r)
+        };
+
+        // the response handle of CreatePrimary comes before the parameters
+        let handle_len = if cc == TPM_CC_CREATE_PRIMARY { 4 } else { 0 };
+        let mut w = Writer::new();
+        match result {
+            Ok(params) if sessions > 0 => {
+                let (handle, params) = params.split_at(handle_len);
+                w.u16(TPM_ST_SESSIONS).u32(0).u32(TPM_RC_SUCCESS);
+                w.bytes(handle).u32(params.len() as u32).bytes(params);
+                for _ in 0..sessions {
+                    w.tpm2b(&[]).u8(0).tpm2b(&[]);
+                }
+            }
+            Ok(params) => {
+                w.u16(TPM_ST_NO_SESSIONS)
+                    .u32(0)
+                    .u32(TPM_RC_SUCCESS)
+                    .bytes(&params);
+            }
+            Err(rc) => {
+                w.u16(TPM_ST_NO_SESSIONS).u32(0).u32(rc);
+            }
+        }
+        let len = w.len();
+        w.patch_u32(2, len as u32);
+        response[..len].copy_from_slice(w.as_slice());
+        Ok(len)
+    }
+}
@@ -0,0 +1,1631 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::consts::*;
+use crate::marshal::{Reader, Writer};
+use crate::types::*;
+use crate::{Error, Result, Transport};
+
+/// How many times a command is resubmitted while the TPM answers
+/// `TPM_RC_RETRY`, `TPM_RC_YIELDED` or `TPM_RC_TESTING`.
+const MAX_RETRIES: usize = 5;
+
+/// The maximum size of a password (an `authValue` of the largest digest).
+const MAX_AUTH_SIZE: usize = 64;
+
+/// The `nonceCaller` sent with policy sessions. The sessions are neither
+/// salted nor used for HMACs or encryption, so it carries no secret.
+const POLICY_NONCE: [u8; 16] = [0; 16];
+
+/// The authorization of one handle of a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Auth<'a> {
+    /// A password session (`TPM_RS_PW`) with the given password.
+    Password(&'a [u8]),
+    /// A policy session whose policy has been satisfied. The session is
+    /// flushed by the command that uses it.
+    Policy(u32),
+}
+
+struct Response {
+    handle: u32,
+    params: Vec<u8>,
+}
+
+impl Response {
+    fn reader(&self) -> Reader<'_> {
+        Reader::new(&self.params)
+    }
+}
+
+/// A TPM 2.0 device behind a [`Transport`].
+///
+/// Authorizations are password sessions (`TPM_RS_PW`), empty slices standing
+/// for an empty password, except where a method takes a policy session.
+pub struct Tpm<T> {
+    transport: T,
+}
+
+impl<T: Transport> Tpm<T> {
+    pub fn new(transport: T) -> Self {
+        Self { transport }
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    fn build_command(cc: u32, handles: &[u32], auths: &[Auth], params: &[u8]) -> Result<Vec<u8>> {
+        let mut w = Writer::new();
+        let tag = if auths.is_empty() {
+            TPM_ST_NO_SESSIONS
+        } else {
+            TPM_ST_SESSIONS
+        };
+        w.u16(tag).u32(0).u32(cc);
+        for &handle in handles {
+            w.u32(handle);
+        }
+        if !auths.is_empty() {
+            let mut area = Writer::new();
+            for auth in auths {
+                match *auth {
+                    Auth::Password(password) if password.len() > MAX_AUTH_SIZE => {
+                        return Err(Error::InvalidParam);
+                    }
+                    Auth::Password(password) => {
+                        area.u32(TPM_RS_PW).tpm2b(&[]).u8(0).tpm2b(password)
+                    }
+                    Auth::Policy(session) => {
+                        area.u32(session).tpm2b(&POLICY_NONCE).u8(0).tpm2b(&[])
+                    }
+                };
+            }
+            w.u32(area.len() as u32).bytes(area.as_slice());
+        }
+        w.bytes(params);
+        if w.len() > TPM_MAX_COMMAND_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        let size = w.len() as u32;
+        w.patch_u32(2, size);
+        Ok(w.into_inner())
+    }
+
+    fn parse_response(rsp: &[u8], has_handle: bool) -> Result<Response> {
+        let mut r = Reader::new(rsp);
+        let tag = r.u16()?;
+        if r.u32()? as usize != rsp.len() {
+            return Err(Error::Malformed);
+        }
+        let rc = r.u32()?;
+        if rc != TPM_RC_SUCCESS {
+            return Err(Error::Tpm(rc));
+        }
+        let handle = if has_handle { r.u32()? } else { 0 };
+        let params = match tag {
+            TPM_ST_NO_SESSIONS => r.bytes(r.remaining())?,
+            TPM_ST_SESSIONS => {
+                let size = r.u32()? as usize;
+                // The password session responses that follow carry nothing of interest.
+                r.bytes(size)?
+            }
+            _ => return Err(Error::Malformed),
+        };
+        Ok(Response {
+            handle,
+            params: params.into(),
+        })
+    }
+
+    fn execute(
+        &mut self,
+        cc: u32,
+        handles: &[u32],
+        auths: &[Auth],
+        params: &[u8],
+        has_handle: bool,
+    ) -> Result<Response> {
+        let cmd = Self::build_command(cc, handles, auths, params)?;
+        let mut rsp = vec![0; TPM_MAX_COMMAND_SIZE];
+        let mut retries = 0;
+        loop {
+            let len = self.transport.transmit(&cmd, &mut rsp)?;
+            if len > rsp.len() {
+                return Err(Error::Transport("response overflows the buffer"));
+            }
+            match Self::parse_response(&rsp[..len], has_handle) {
+                Err(Error::Tpm(rc))
+                    if (rc == TPM_RC_RETRY || rc == TPM_RC_YIELDED || rc == TPM_RC_TESTING)
+                        && retries < MAX_RETRIES =>
+                {
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// `TPM2_Startup`. A TPM that has already been started (by firmware or
+    /// by the host OS) answers `TPM_RC_INITIALIZE`, which is not an error.
+    pub fn startup(&mut self, startup_type: u16) -> Result<()> {
+        let mut p = Writer::new();
+        p.u16(startup_type);
+        match self.execute(TPM_CC_STARTUP, &[], &[], p.as_slice(), false) {
+            Err(e) if e.is_rc(TPM_RC_INITIALIZE) => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+
+    /// `TPM2_Shutdown`.
+    pub fn shutdown(&mut self, shutdown_type: u16) -> Result<()> {
+        let mut p = Writer::new();
+        p.u16(shutdown_type);
+        self.execute(TPM_CC_SHUTDOWN, &[], &[], p.as_slice(), false)?;
+        Ok(())
+    }
+
+    /// `TPM2_GetRandom`, repeated until `len` bytes have been collected.
+    pub fn get_random(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(len);
+        while bytes.len() < len {
+            let mut p = Writer::new();
+            p.u16((len - bytes.len()).min(u16::MAX as usize) as u16);
+            let rsp = self.execute(TPM_CC_GET_RANDOM, &[], &[], p.as_slice(), false)?;
+            let mut r = rsp.reader();
+            let chunk = r.tpm2b()?;
+            r.finish()?;
+            if chunk.is_empty() || chunk.len() > len - bytes.len() {
+                return Err(Error::Malformed);
+            }
+            bytes.extend_from_slice(chunk);
+        }
+        Ok(bytes)
+    }
+
+    /// `TPM2_PCR_Read`. The TPM may return fewer PCRs than requested (at most
+    /// eight digests per call); [`PcrValues::selections`] tells which ones.
+    pub fn pcr_read(&mut self, selections: &[PcrSelection]) -> Result<PcrValues> {
+        let mut p = Writer::new();
+        p.put(selections);
+        let rsp = self.execute(TPM_CC_PCR_READ, &[], &[], p.as_slice(), false)?;
+        let mut r = rsp.reader();
+        let update_counter = r.u32()?;
+        let selections: Vec<PcrSelection> = r.get()?;
+        let count = r.u32()?;
+        let expected: usize = selections.iter().map(|sel| sel.iter().count()).sum();
+        if count as usize != expected {
+            return Err(Error::Malformed);
+        }
+        let digests = (0..count)
+            .map(|_| r.tpm2b().map(Vec::from))
+            .collect::<Result<_>>()?;
+        r.finish()?;
+        Ok(PcrValues {
+            update_counter,
+            selections,
+            digests,
+        })
+    }
+
+    /// `TPM2_PCR_Extend` of `pcr` with one digest per bank, as `(hash, digest)`.
+    pub fn pcr_extend(&mut self, pcr: u32, digests: &[(u16, &[u8])]) -> Result<()> {
+        let mut p = Writer::new();
+        p.u32(digests.len() as u32);
+        for &(alg, digest) in digests {
+            if digest_size(alg) != Some(digest.len()) {
+                return Err(Error::InvalidParam);
+            }
+            p.u16(alg).bytes(digest);
+        }
+        self.execute(
+            TPM_CC_PCR_EXTEND,
+            &[pcr],
+            &[Auth::Password(&[])],
+            p.as_slice(),
+            false,
+        )?;
+        Ok(())
+    }
+
+    /// `TPM2_CreatePrimary` of `template` under `hierarchy`, with `key_auth`
+    /// as the password of the new key.
+    pub fn create_primary(
+        &mut self,
+        hierarchy: u32,
+        hierarchy_auth: &[u8],
+        template: &Public,
+        key_auth: &[u8],
+    ) -> Result<CreatedPrimary> {
+        if key_auth.len() > MAX_AUTH_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        let mut sensitive = Writer::new();
+        sensitive.tpm2b(key_auth).tpm2b(&[]);
+        let mut p = Writer::new();
+        p.tpm2b(sensitive.as_slice())
+            .sized(template)
+            .tpm2b(&[])
+            .put(&[][..] as &[PcrSelection]);
+        let rsp = self.execute(
+            TPM_CC_CREATE_PRIMARY,
+            &[hierarchy],
+            &[Auth::Password(hierarchy_auth)],
+            p.as_slice(),
+            true,
+        )?;
+        let mut r = rsp.reader();
+        let public = r.sized()?;
+        let _creation_data = r.tpm2b()?;
+        let _creation_hash = r.tpm2b()?;
+        let _creation_ticket = (r.u16()?, r.u32()?, r.tpm2b()?);
+        let name = r.tpm2b()?.into();
+        r.finish()?;
+        Ok(CreatedPrimary {
+            handle: rsp.handle,
+            public,
+            name,
+        })
+    }
+
+    /// `TPM2_ReadPublic`, returning the public area and the name of `handle`.
+    pub fn read_public(&mut self, handle: u32) -> Result<(Public, Vec<u8>)> {
+        let rsp = self.execute(TPM_CC_READ_PUBLIC, &[handle], &[], &[], false)?;
+        let mut r = rsp.reader();
+        let public = r.sized()?;
+        let name = r.tpm2b()?.into();
+        let _qualified_name = r.tpm2b()?;
+        r.finish()?;
+        Ok((public, name))
+    }
+
+    /// `TPM2_EvictControl`: makes the transient `object` persistent at
+    /// `persistent`, or evicts it if `object` is already that persistent handle.
+    pub fn evict_control(
+        &mut self,
+        auth_handle: u32,
+        auth: &[u8],
+        object: u32,
+        persistent: u32,
+    ) -> Result<()> {
+        let mut p = Writer::new();
+        p.u32(persistent);
+        self.execute(
+            TPM_CC_EVICT_CONTROL,
+            &[auth_handle, object],
+            &[Auth::Password(auth)],
+            p.as_slice(),
+            false,
+        )?;
+        Ok(())
+    }
+
+    /// `TPM2_FlushContext`.
+    pub fn flush_context(&mut self, handle: u32) -> Result<()> {
+        let mut p = Writer::new();
+        p.u32(handle);
+        self.execute(TPM_CC_FLUSH_CONTEXT, &[], &[], p.as_slice(), false)?;
+        Ok(())
+    }
+
+    /// `TPM2_Quote` of `selections` signed by `key`, with `qualifying_data`
+    /// (a nonce or a digest of user data) embedded in the attestation.
+    pub fn quote(
+        &mut self,
+        key: u32,
+        key_auth: &[u8],
+        qualifying_data: &[u8],
+        scheme: Scheme,
+        selections: &[PcrSelection],
+    ) -> Result<Quote> {
+        if qualifying_data.len() > MAX_AUTH_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        let mut p = Writer::new();
+        p.tpm2b(qualifying_data).put(&scheme).put(selections);
+        let rsp = self.execute(
+            TPM_CC_QUOTE,
+            &[key],
+            &[Auth::Password(key_auth)],
+            p.as_slice(),
+            false,
+        )?;
+        let mut r = rsp.reader();
+        let attest = r.tpm2b()?.into();
+        let signature = r.get()?;
+        r.finish()?;
+        Ok(Quote { attest, signature })
+    }
+
+    /// `TPM2_Hash` of `data`. The ticket lets a restricted key in `hierarchy`
+    /// sign the digest, since the TPM has seen that the data does not start
+    /// with `TPM_GENERATED_VALUE`.
+    pub fn hash(
+        &mut self,
+        data: &[u8],
+        alg: u16,
+        hierarchy: u32,
+    ) -> Result<(Vec<u8>, HashcheckTicket)> {
+        if data.len() > TPM_MAX_DIGEST_BUFFER {
+            return Err(Error::InvalidParam);
+        }
+        let mut p = Writer::new();
+        p.tpm2b(data).u16(alg).u32(hierarchy);
+        let rsp = self.execute(TPM_CC_HASH, &[], &[], p.as_slice(), false)?;
+        let mut r = rsp.reader();
+        let digest = r.tpm2b()?.into();
+        let ticket = r.get()?;
+        r.finish()?;
+        Ok((digest, ticket))
+    }
+
+    /// `TPM2_Sign` of `digest` by `key`.
+    pub fn sign(
+        &mut self,
+        key: u32,
+        key_auth: &[u8],
+        digest: &[u8],
+        scheme: Scheme,
+        ticket: &HashcheckTicket,
+    ) -> Result<Signature> {
+        if digest.len() > MAX_AUTH_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        let mut p = Writer::new();
+        p.tpm2b(digest).put(&scheme).put(ticket);
+        let rsp = self.execute(
+            TPM_CC_SIGN,
+            &[key],
+            &[Auth::Password(key_auth)],
+            p.as_slice(),
+            false,
+        )?;
+        let mut r = rsp.reader();
+        let signature = r.get()?;
+        r.finish()?;
+        Ok(signature)
+    }
+
+    /// `TPM2_NV_DefineSpace`.
+    pub fn nv_define_space(
+        &mut self,
+        auth_handle: u32,
+        auth: &[u8],
+        index_auth: &[u8],
+        public: &NvPublic,
+    ) -> Result<()> {
+        if index_auth.len() > MAX_AUTH_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        let mut p = Writer::new();
+        p.tpm2b(index_auth).sized(public);
+        self.execute(
+            TPM_CC_NV_DEFINE_SPACE,
+            &[auth_handle],
+            &[Auth::Password(auth)],
+            p.as_slice(),
+            false,
+        )?;
+        Ok(())
+    }
+
+    /// `TPM2_NV_UndefineSpace`.
+    pub fn nv_undefine_space(&mut self, auth_handle: u32, auth: &[u8], index: u32) -> Result<()> {
+        self.execute(
+            TPM_CC_NV_UNDEFINE_SPACE,
+            &[auth_handle, index],
+            &[Auth::Password(auth)],
+            &[],
+            false,
+        )?;
+        Ok(())
+    }
+
+    /// `TPM2_NV_ReadPublic`, returning the public area and the name of `index`.
+    pub fn nv_read_public(&mut self, index: u32) -> Result<(NvPublic, Vec<u8>)> {
+        let rsp = self.execute(TPM_CC_NV_READ_PUBLIC, &[index], &[], &[], false)?;
+        let mut r = rsp.reader();
+        let public = r.sized()?;
+        let name = r.tpm2b()?.into();
+        r.finish()?;
+        Ok((public, name))
+    }
+
+    fn nv_read_chunk(
+        &mut self,
+        auth_handle: u32,
+        auth: Auth,
+        index: u32,
+        size: u16,
+        offset: u16,
+    ) -> Result<Vec<u8>> {
+        let mut p = Writer::new();
+        p.u16(size).u16(offset);
+        let rsp = self.execute(
+            TPM_CC_NV_READ,
+            &[auth_handle, index],
+            &[auth],
+            p.as_slice(),
+            false,
+        )?;
+        let mut r = rsp.reader();
+        let bytes = r.tpm2b()?;
+        r.finish()?;
+        if bytes.len() != size as usize {
+            return Err(Error::Malformed);
+        }
+        Ok(bytes.into())
+    }
+
+    /// `TPM2_NV_Read` of `size` bytes at `offset`, in chunks the TPM accepts.
+    pub fn nv_read(
+        &mut self,
+        auth_handle: u32,
+        auth: &[u8],
+        index: u32,
+        size: u16,
+        offset: u16,
+    ) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(size as usize);
+        while data.len() < size as usize {
+            let chunk = (size as usize - data.len()).min(TPM_MAX_NV_CHUNK) as u16;
+            data.extend_from_slice(&self.nv_read_chunk(
+                auth_handle,
+                Auth::Password(auth),
+                index,
+                chunk,
+                offset + data.len() as u16,
+            )?);
+        }
+        Ok(data)
+    }
+
+    /// `TPM2_NV_Read` of a `TPMA_NV_POLICYREAD` index authorized by the
+    /// policy `session`. The command consumes the session, so at most one
+    /// chunk can be read.
+    pub fn nv_read_policy(
+        &mut self,
+        index: u32,
+        session: u32,
+        size: u16,
+        offset: u16,
+    ) -> Result<Vec<u8>> {
+        if size as usize > TPM_MAX_NV_CHUNK {
+            return Err(Error::InvalidParam);
+        }
+        self.nv_read_chunk(index, Auth::Policy(session), index, size, offset)
+    }
+
+    fn nv_write_chunk(
+        &mut self,
+        auth_handle: u32,
+        auth: Auth,
+        index: u32,
+        data: &[u8],
+        offset: u16,
+    ) -> Result<()> {
+        let mut p = Writer::new();
+        p.tpm2b(data).u16(offset);
+        self.execute(
+            TPM_CC_NV_WRITE,
+            &[auth_handle, index],
+            &[auth],
+            p.as_slice(),
+            false,
+        )?;
+        Ok(())
+    }
+
+    /// `TPM2_NV_Write` of `data` at `offset`, in chunks the TPM accepts.
+    pub fn nv_write(
+        &mut self,
+        auth_handle: u32,
+        auth: &[u8],
+        index: u32,
+        data: &[u8],
+        offset: u16,
+    ) -> Result<()> {
+        if offset as usize + data.len() > u16::MAX as usize {
+            return Err(Error::InvalidParam);
+        }
+        for (i, chunk) in data.chunks(TPM_MAX_NV_CHUNK).enumerate() {
+            let chunk_offset = offset + (i * TPM_MAX_NV_CHUNK) as u16;
+            let auth = Auth::Password(auth);
+            self.nv_write_chunk(auth_handle, auth, index, chunk, chunk_offset)?;
+        }
+        Ok(())
+    }
+
+    /// `TPM2_NV_Write` of a `TPMA_NV_POLICYWRITE` index authorized by the
+    /// policy `session`. The command consumes the session, so at most one
+    /// chunk can be written.
+    pub fn nv_write_policy(
+        &mut self,
+        index: u32,
+        session: u32,
+        data: &[u8],
+        offset: u16,
+    ) -> Result<()> {
+        if data.len() > TPM_MAX_NV_CHUNK {
+            return Err(Error::InvalidParam);
+        }
+        self.nv_write_chunk(index, Auth::Policy(session), index, data, offset)
+    }
+
+    /// `TPM2_NV_Increment` of a `TPMA_NV_COUNTER` index. The first increment
+    /// starts the counter above any counter value the TPM has ever had.
+    pub fn nv_increment(&mut self, auth_handle: u32, auth: &[u8], index: u32) -> Result<()> {
+        self.execute(
+            TPM_CC_NV_INCREMENT,
+            &[auth_handle, index],
+            &[Auth::Password(auth)],
+            &[],
+            false,
+        )?;
+        Ok(())
+    }
+
+    /// Reads the value of a `TPMA_NV_COUNTER` index.
+    pub fn nv_read_counter(&mut self, auth_handle: u32, auth: &[u8], index: u32) -> Result<u64> {
+        let data = self.nv_read(auth_handle, auth, index, 8, 0)?;
+        let mut value = [0; 8];
+        value.copy_from_slice(&data);
+        Ok(u64::from_be_bytes(value))
+    }
+
+    /// `TPM2_NV_ReadLock`: blocks reads of a `TPMA_NV_READ_STCLEAR` index
+    /// until the next `TPM2_Startup(TPM_SU_CLEAR)`.
+    pub fn nv_read_lock(&mut self, auth_handle: u32, auth: &[u8], index: u32) -> Result<()> {
+        self.execute(
+            TPM_CC_NV_READ_LOCK,
+            &[auth_handle, index],
+            &[Auth::Password(auth)],
+            &[],
+            false,
+        )?;
+        Ok(())
+    }
+
+    /// `TPM2_NV_ReadLock` of a `TPMA_NV_POLICYREAD` index authorized by the
+    /// policy `session`, which the command consumes.
+    pub fn nv_read_lock_policy(&mut self, index: u32, session: u32) -> Result<()> {
+        self.execute(
+            TPM_CC_NV_READ_LOCK,
+            &[index, index],
+            &[Auth::Policy(session)],
+            &[],
+            false,
+        )?;
+        Ok(())
+    }
+
+    /// `TPM2_NV_WriteLock`: blocks writes of a `TPMA_NV_WRITEDEFINE` index for
+    /// good, or of a `TPMA_NV_WRITE_STCLEAR` index until the next
+    /// `TPM2_Startup(TPM_SU_CLEAR)`.
+    pub fn nv_write_lock(&mut self, auth_handle: u32, auth: &[u8], index: u32) -> Result<()> {
+        self.execute(
+            TPM_CC_NV_WRITE_LOCK,
+            &[auth_handle, index],
+            &[Auth::Password(auth)],
+            &[],
+            false,
+        )?;
+        Ok(())
+    }
+
+    /// `TPM2_NV_WriteLock` of a `TPMA_NV_POLICYWRITE` index authorized by the
+    /// policy `session`, which the command consumes.
+    pub fn nv_write_lock_policy(&mut self, index: u32, session: u32) -> Result<()> {
+        self.execute(
+            TPM_CC_NV_WRITE_LOCK,
+            &[index, index],
+            &[Auth::Policy(session)],
+            &[],
+            false,
+        )?;
+        Ok(())
+    }
+
+    fn start_auth_session(&mut self, session_type: u8, hash: u16) -> Result<u32> {
+        let mut p = Writer::new();
+        p.tpm2b(&POLICY_NONCE)
+            .tpm2b(&[])
+            .u8(session_type)
+            .put(&SymDef::NULL)
+            .u16(hash);
+        let rsp = self.execute(
+            TPM_CC_START_AUTH_SESSION,
+            &[TPM_RH_NULL, TPM_RH_NULL],
+            &[],
+            p.as_slice(),
+            true,
+        )?;
+        let mut r = rsp.reader();
+        let _nonce_tpm = r.tpm2b()?;
+        r.finish()?;
+        Ok(rsp.handle)
+    }
+
+    /// `TPM2_StartAuthSession` of an unsalted, unbound policy session using
+    /// `hash` for its policy digest.
+    pub fn start_policy_session(&mut self, hash: u16) -> Result<u32> {
+        self.start_auth_session(TPM_SE_POLICY, hash)
+    }
+
+    /// `TPM2_StartAuthSession` of a trial session, which only computes the
+    /// digest of the assertions made in it, for [`Tpm::policy_get_digest`].
+    /// The session must be flushed once done with.
+    pub fn start_trial_session(&mut self, hash: u16) -> Result<u32> {
+        self.start_auth_session(TPM_SE_TRIAL, hash)
+    }
+
+    /// `TPM2_PolicySecret`: satisfies a `PolicySecret(auth_handle)` assertion
+    /// of `session` by proving knowledge of the password of `auth_handle`.
+    pub fn policy_secret(&mut self, auth_handle: u32, auth: &[u8], session: u32) -> Result<()> {
+        let mut p = Writer::new();
+        p.tpm2b(&[]).tpm2b(&[]).tpm2b(&[]).u32(0);
+        let rsp = self.execute(
+            TPM_CC_POLICY_SECRET,
+            &[auth_handle, session],
+            &[Auth::Password(auth)],
+            p.as_slice(),
+            false,
+        )?;
+        let mut r = rsp.reader();
+        let _timeout = r.tpm2b()?;
+        let _ticket = (r.u16()?, r.u32()?, r.tpm2b()?);
+        r.finish()?;
+        Ok(())
+    }
+
+    /// `TPM2_PolicyPCR`: asserts that the PCRs of `selections` have the
+    /// values whose digest is `pcr_digest`, or keep the values they have now
+    /// if it is empty. A trial session takes `pcr_digest` without checking
+    /// it, which computes the policy for values the PCRs are yet to have.
+    pub fn policy_pcr(
+        &mut self,
+        session: u32,
+        pcr_digest: &[u8],
+        selections: &[PcrSelection],
+    ) -> Result<()> {
+        if pcr_digest.len() > MAX_AUTH_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        let mut p = Writer::new();
+        p.tpm2b(pcr_digest).put(selections);
+        self.execute(TPM_CC_POLICY_PCR, &[session], &[], p.as_slice(), false)?;
+        Ok(())
+    }
+
+    /// `TPM2_PolicyLocality`: asserts that the command authorized by
+    /// `session` comes from one of `localities`, a `TPMA_LOCALITY` bitmap.
+    pub fn policy_locality(&mut self, session: u32, localities: u8) -> Result<()> {
+        let mut p = Writer::new();
+        p.u8(localities);
+        self.execute(TPM_CC_POLICY_LOCALITY, &[session], &[], p.as_slice(), false)?;
+        Ok(())
+    }
+
+    /// `TPM2_PolicyGetDigest`, returning the policy digest of `session`.
+    pub fn policy_get_digest(&mut self, session: u32) -> Result<Vec<u8>> {
+        let rsp = self.execute(TPM_CC_POLICY_GET_DIGEST, &[session], &[], &[], false)?;
+        let mut r = rsp.reader();
+        let digest = r.tpm2b()?.into();
+        r.finish()?;
+        Ok(digest)
+    }
+
+    /// `TPM2_ActivateCredential`: recovers the secret that a certificate
+    /// authority bound to the name of `activate` and encrypted to `key`.
+    ///
+    /// `secret` is the content of the `TPM2B_ENCRYPTED_SECRET`, for an ECC
+    /// `key` a marshalled `TPMS_ECC_POINT`. An endorsement key created from
+    /// the default templates needs an [`Auth::Policy`] session that went
+    /// through [`Tpm::policy_secret`] with the endorsement hierarchy.
+    pub fn activate_credential(
+        &mut self,
+        activate: u32,
+        activate_auth: &[u8],
+        key: u32,
+        key_auth: Auth,
+        credential_blob: &[u8],
+        secret: &[u8],
+    ) -> Result<Vec<u8>> {
+        if credential_blob.len() > u16::MAX as usize || secret.len() > u16::MAX as usize {
+            return Err(Error::InvalidParam);
+        }
+        let mut p = Writer::new();
+        p.tpm2b(credential_blob).tpm2b(secret);
+        let rsp = self.execute(
+            TPM_CC_ACTIVATE_CREDENTIAL,
+            &[activate, key],
+            &[Auth::Password(activate_auth), key_auth],
+            p.as_slice(),
+            false,
+        )?;
+        let mut r = rsp.reader();
+        let cert_info = r.tpm2b()?.into();
+        r.finish()?;
+        Ok(cert_info)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sim::{sm3, SimTpm};
+
+    /// Answers every command with the response built by a closure, which
+    /// also gets to check the command.
+    struct Scripted<F>(F);
+
+    impl<F: FnMut(&[u8]) -> Vec<u8>> Transport for Scripted<F> {
+        fn transmit(&mut self, command: &[u8], response: &mut [u8]) -> Result<usize> {
+            let rsp = (self.0)(command);
+            response[..rsp.len()].copy_from_slice(&rsp);
+            Ok(rsp.len())
+        }
+    }
+
+    struct Command {
+        cc: u32,
+        handles: Vec<u32>,
+        passwords: Vec<Vec<u8>>,
+        policy_sessions: Vec<u32>,
+        params: Vec<u8>,
+    }
+
+    fn parse_command(cmd: &[u8], handle_count: usize) -> Command {
+        let mut r = Reader::new(cmd);
+        let tag = r.u16().unwrap();
+        assert_eq!(r.u32().unwrap() as usize, cmd.len());
+        let cc = r.u32().unwrap();
+        let handles = (0..handle_count).map(|_| r.u32().unwrap()).collect();
+        let mut passwords = Vec::new();
+        let mut policy_sessions = Vec::new();
+        if tag == TPM_ST_SESSIONS {
+            let size = r.u32().unwrap() as usize;
+            let mut area = Reader::new(r.bytes(size).unwrap());
+            while !area.is_empty() {
+                let session = area.u32().unwrap();
+                let nonce = area.tpm2b().unwrap();
+                assert_eq!(area.u8(), Ok(0));
+                let hmac = area.tpm2b().unwrap();
+                if session == TPM_RS_PW {
+                    assert!(nonce.is_empty());
+                    passwords.push(hmac.to_vec());
+                } else {
+                    assert_eq!(session & 0xff00_0000, TPM_HT_POLICY_SESSION);
+                    assert_eq!(nonce, POLICY_NONCE);
+                    assert!(hmac.is_empty());
+                    policy_sessions.push(session);
+                }
+            }
+        } else {
+            assert_eq!(tag, TPM_ST_NO_SESSIONS);
+        }
+        Command {
+            cc,
+            handles,
+            passwords,
+            policy_sessions,
+            params: r.bytes(r.remaining()).unwrap().to_vec(),
+        }
+    }
+
+    fn response(handle: Option<u32>, sessions: usize, params: &[u8]) -> Vec<u8> {
+        let mut w = Writer::new();
+        let tag = if sessions > 0 {
+            TPM_ST_SESSIONS
+        } else {
+            TPM_ST_NO_SESSIONS
+        };
+        w.u16(tag).u32(0).u32(TPM_RC_SUCCESS);
+        if let Some(handle) = handle {
+            w.u32(handle);
+        }
+        if sessions > 0 {
+            w.u32(params.len() as u32);
+        }
+        w.bytes(params);
+        for _ in 0..sessions {
+            w.tpm2b(&[]).u8(1).tpm2b(&[]);
+        }
+        let len = w.len() as u32;
+        w.patch_u32(2, len);
+        w.into_inner()
+    }
+
+    fn sm2_key() -> Public {
+        let mut public = Public::ecc_signing_key(
+            TPM_ALG_SM3_256,
+            TPM_ECC_SM2_P256,
+            Scheme::new(TPM_ALG_SM2, TPM_ALG_SM3_256),
+        );
+        public.unique = EccPoint {
+            x: [0x11; 32].to_vec(),
+            y: [0x22; 32].to_vec(),
+        };
+        public
+    }
+
+    fn sm2_signature() -> Signature {
+        Signature {
+            alg: TPM_ALG_SM2,
+            hash: TPM_ALG_SM3_256,
+            r: [0x33; 32].to_vec(),
+            s: [0x44; 32].to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_startup() {
+        let mut tpm = Tpm::new(Scripted(|cmd: &[u8]| {
+            assert_eq!(
+                cmd,
+                &[0x80, 0x01, 0, 0, 0, 0x0c, 0, 0, 0x01, 0x44, 0, 0][..]
+            );
+            response(None, 0, &[])
+        }));
+        assert_eq!(tpm.startup(TPM_SU_CLEAR), Ok(()));
+
+        let mut tpm = Tpm::new(SimTpm::new());
+        assert_eq!(tpm.get_random(1), Err(Error::Tpm(TPM_RC_INITIALIZE)));
+        assert_eq!(tpm.startup(TPM_SU_CLEAR), Ok(()));
+        // Already started, e.g. by firmware.
+        assert_eq!(tpm.startup(TPM_SU_CLEAR), Ok(()));
+    }
+
+    #[test]
+    fn test_retry() {
+        let mut sim = SimTpm::new();
+        sim.retries = 2;
+        let mut tpm = Tpm::new(&mut sim);
+        assert_eq!(tpm.startup(TPM_SU_CLEAR), Ok(()));
+        assert_eq!(sim.commands, 3);
+
+        sim.retries = MAX_RETRIES + 1;
+        let mut tpm = Tpm::new(&mut sim);
+        assert_eq!(tpm.get_random(8), Err(Error::Tpm(TPM_RC_RETRY)));
+    }
+
+    #[test]
+    fn test_malformed_response() {
+        let mut tpm = Tpm::new(Scripted(|_: &[u8]| {
+            let mut rsp = response(None, 0, &[0, 1, 0xaa]);
+            rsp[5] += 1;
+            rsp
+        }));
+        assert_eq!(tpm.get_random(1), Err(Error::Malformed));
+
+        let mut tpm = Tpm::new(Scripted(|_: &[u8]| {
+            let mut rsp = response(None, 0, &[0, 1, 0xaa]);
+            rsp[1] = 0x99;
+            rsp
+        }));
+        assert_eq!(tpm.get_random(1), Err(Error::Malformed));
+
+        // More random bytes than asked for.
+        let mut tpm = Tpm::new(Scripted(|_: &[u8]| response(None, 0, &[0, 2, 0xaa, 0xbb])));
+        assert_eq!(tpm.get_random(1), Err(Error::Malformed));
+    }
+
+    #[test]
+    fn test_get_random() {
+        let mut tpm = Tpm::new(SimTpm::new());
+        tpm.startup(TPM_SU_CLEAR).unwrap();
+        let bytes = tpm.get_random(100).unwrap();
+        assert_eq!(bytes.len(), 100);
+        assert_eq!(bytes[99], 100);
+    }
+
+    #[test]
+    fn test_pcr_extend_read() {
+        let mut sim = SimTpm::new();
+        let mut tpm = Tpm::new(&mut sim);
+        tpm.startup(TPM_SU_CLEAR).unwrap();
+
+        let digest = sm3(b"hypervisor");
+        tpm.pcr_extend(13, &[(TPM_ALG_SM3_256, &digest)]).unwrap();
+        assert_eq!(
+            tpm.pcr_extend(13, &[(TPM_ALG_SM3_256, &digest[..20])]),
+            Err(Error::InvalidParam)
+        );
+
+        let sel = PcrSelection::new(TPM_ALG_SM3_256, &[0, 13]);
+        let values = tpm.pcr_read(&[sel]).unwrap();
+        assert_eq!(values.selections, [sel]);
+        let mut expected = [0; 64];
+        expected[32..].copy_from_slice(&digest);
+        assert_eq!(values.digests, [[0; 32].to_vec(), sm3(&expected).to_vec()]);
+        assert_eq!(sim.pcr(13), sm3(&expected));
+
+        // The SHA-256 bank is not allocated in the simulator.
+        let mut tpm = Tpm::new(&mut sim);
+        let values = tpm
+            .pcr_read(&[PcrSelection::new(TPM_ALG_SHA256, &[0])])
+            .unwrap();
+        assert!(values.selections.is_empty());
+        assert!(values.digests.is_empty());
+    }
+
+    #[test]
+    fn test_nv() {
+        const INDEX: u32 = TPM_HT_NV_INDEX | 0x50_0016;
+        let mut sim = SimTpm::new();
+        let mut tpm = Tpm::new(&mut sim);
+        tpm.startup(TPM_SU_CLEAR).unwrap();
+
+        let public = NvPublic {
+            index: INDEX,
+            name_alg: TPM_ALG_SM3_256,
+            attributes: TPMA_NV_OWNERWRITE
+                | TPMA_NV_OWNERREAD
+                | TPMA_NV_READ_STCLEAR
+                | TPMA_NV_NO_DA,
+            auth_policy: Vec::new(),
+            data_size: 1000,
+        };
+        assert!(tpm.nv_read_public(INDEX).unwrap_err().is_rc(TPM_RC_HANDLE));
+        tpm.nv_define_space(TPM_RH_OWNER, &[], &[], &public)
+            .unwrap();
+        assert!(tpm
+            .nv_define_space(TPM_RH_OWNER, &[], &[], &public)
+            .unwrap_err()
+            .is_rc(TPM_RC_NV_DEFINED));
+        assert_eq!(tpm.nv_read_public(INDEX).unwrap().0, public);
+        assert_eq!(
+            tpm.nv_read(TPM_RH_OWNER, &[], INDEX, 4, 0),
+            Err(Error::Tpm(TPM_RC_NV_UNINITIALIZED))
+        );
+
+        // Larger than one chunk in both directions.
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        tpm.nv_write(TPM_RH_OWNER, &[], INDEX, &data, 0).unwrap();
+        assert_eq!(
+            tpm.nv_read(TPM_RH_OWNER, &[], INDEX, 1000, 0),
+            Ok(data.clone())
+        );
+        assert_eq!(
+            tpm.nv_read(TPM_RH_OWNER, &[], INDEX, 8, 996),
+            Err(Error::Tpm(TPM_RC_NV_RANGE))
+        );
+        assert_eq!(
+            tpm.nv_read(INDEX, &[], INDEX, 4, 0),
+            Err(Error::Tpm(TPM_RC_NV_AUTHORIZATION))
+        );
+        let attributes = tpm.nv_read_public(INDEX).unwrap().0.attributes;
+        assert_ne!(attributes & TPMA_NV_WRITTEN, 0);
+
+        // Locked until the next TPM2_Startup(CLEAR).
+        tpm.nv_read_lock(TPM_RH_OWNER, &[], INDEX).unwrap();
+        assert_eq!(
+            tpm.nv_read(TPM_RH_OWNER, &[], INDEX, 4, 0),
+            Err(Error::Tpm(TPM_RC_NV_LOCKED))
+        );
+        sim.reset();
+        let mut tpm = Tpm::new(&mut sim);
+        tpm.startup(TPM_SU_CLEAR).unwrap();
+        assert_eq!(
+            tpm.nv_read(TPM_RH_OWNER, &[], INDEX, 4, 8),
+            Ok(data[8..12].to_vec())
+        );
+
+        tpm.nv_undefine_space(TPM_RH_OWNER, &[], INDEX).unwrap();
+        assert!(tpm
+            .nv_read(TPM_RH_OWNER, &[], INDEX, 4, 0)
+            .unwrap_err()
+            .is_rc(TPM_RC_HANDLE));
+    }
+
+    #[test]
+    fn test_nv_write_lock() {
+        const INDEX: u32 = TPM_HT_NV_INDEX | 0x50_0017;
+        let mut sim = SimTpm::new();
+        let mut tpm = Tpm::new(&mut sim);
+        tpm.startup(TPM_SU_CLEAR).unwrap();
+
+        let mut public = NvPublic {
+            index: INDEX,
+            name_alg: TPM_ALG_SM3_256,
+            attributes: TPMA_NV_OWNERWRITE | TPMA_NV_OWNERREAD | TPMA_NV_WRITEDEFINE,
+            auth_policy: Vec::new(),
+            data_size: 32,
+        };
+        tpm.nv_define_space(TPM_RH_OWNER, &[], &[], &public)
+            .unwrap();
+        tpm.nv_write(TPM_RH_OWNER, &[], INDEX, &[1; 32], 0).unwrap();
+        tpm.nv_write_lock(TPM_RH_OWNER, &[], INDEX).unwrap();
+        assert_eq!(
+            tpm.nv_write(TPM_RH_OWNER, &[], INDEX, &[2; 32], 0),
+            Err(Error::Tpm(TPM_RC_NV_LOCKED))
+        );
+        // TPMA_NV_WRITEDEFINE locks survive a reset.
+        sim.reset();
+        let mut tpm = Tpm::new(&mut sim);
+        tpm.startup(TPM_SU_CLEAR).unwrap();
+        assert_eq!(
+            tpm.nv_write(TPM_RH_OWNER, &[], INDEX, &[2; 32], 0),
+            Err(Error::Tpm(TPM_RC_NV_LOCKED))
+        );
+        assert_eq!(
+            tpm.nv_read(TPM_RH_OWNER, &[], INDEX, 32, 0),
+            Ok([1; 32].to_vec())
+        );
+
+        public.index += 1;
+        public.attributes &= !TPMA_NV_WRITEDEFINE;
+        tpm.nv_define_space(TPM_RH_OWNER, &[], &[], &public)
+            .unwrap();
+        assert!(tpm.nv_write_lock(TPM_RH_OWNER, &[], public.index).is_err());
+    }
+
+    #[test]
+    fn test_nv_counter() {
+        const INDEX: u32 = TPM_HT_NV_INDEX | 0x50_0018;
+        let mut sim = SimTpm::new();
+        let mut tpm = Tpm::new(&mut sim);
+        tpm.startup(TPM_SU_CLEAR).unwrap();
+
+        let mut public = NvPublic {
+            index: INDEX,
+            name_alg: TPM_ALG_SM3_256,
+            attributes: TPMA_NV_OWNERWRITE | TPMA_NV_OWNERREAD | TPMA_NV_COUNTER,
+            auth_policy: Vec::new(),
+            data_size: 4,
+        };
+        assert!(tpm
+            .nv_define_space(TPM_RH_OWNER, &[], &[], &public)
+            .is_err());
+        public.data_size = 8;
+        tpm.nv_define_space(TPM_RH_OWNER, &[], &[], &public)
+            .unwrap();
+        assert_eq!(
+            tpm.nv_read_counter(TPM_RH_OWNER, &[], INDEX),
+            Err(Error::Tpm(TPM_RC_NV_UNINITIALIZED))
+        );
+        tpm.nv_increment(TPM_RH_OWNER, &[], INDEX).unwrap();
+        tpm.nv_increment(TPM_RH_OWNER, &[], INDEX).unwrap();
+        assert_eq!(tpm.nv_read_counter(TPM_RH_OWNER, &[], INDEX), Ok(2));
+        // Counters only change by increments.
+        assert!(tpm.nv_write(TPM_RH_OWNER, &[], INDEX, &[0; 8], 0).is_err());
+        assert_eq!(
+            tpm.nv_increment(INDEX, &[], INDEX),
+            Err(Error::Tpm(TPM_RC_NV_AUTHORIZATION))
+        );
+
+        // A counter defined again does not go back.
+        tpm.nv_undefine_space(TPM_RH_OWNER, &[], INDEX).unwrap();
+        tpm.nv_define_space(TPM_RH_OWNER, &[], &[], &public)
+            .unwrap();
+        tpm.nv_increment(TPM_RH_OWNER, &[], INDEX).unwrap();
+        assert_eq!(tpm.nv_read_counter(TPM_RH_OWNER, &[], INDEX), Ok(3));
+
+        // Ordinary indices cannot be incremented.
+        public.index += 1;
+        public.attributes &= !TPMA_NV_COUNTER;
+        tpm.nv_define_space(TPM_RH_OWNER, &[], &[], &public)
+            .unwrap();
+        assert!(tpm.nv_increment(TPM_RH_OWNER, &[], public.index).is_err());
+
+        // A counter reached with its own authValue.
+        public.index += 1;
+        public.attributes = TPMA_NV_AUTHWRITE | TPMA_NV_AUTHREAD | TPMA_NV_COUNTER;
+        let index = public.index;
+        tpm.nv_define_space(TPM_RH_OWNER, &[], b"secret", &public)
+            .unwrap();
+        assert!(tpm
+            .nv_increment(index, b"wrong", index)
+            .unwrap_err()
+            .is_rc(TPM_RC_AUTH_FAIL));
+        assert_eq!(
+            tpm.nv_increment(TPM_RH_OWNER, &[], index),
+            Err(Error::Tpm(TPM_RC_NV_AUTHORIZATION))
+        );
+        tpm.nv_increment(index, b"secret", index).unwrap();
+        assert_eq!(tpm.nv_read_counter(index, b"secret", index), Ok(4));
+    }
+
+    #[test]
+    fn test_create_primary() {
+        let template = Public::ecc_signing_key(
+            TPM_ALG_SM3_256,
+            TPM_ECC_SM2_P256,
+            Scheme::new(TPM_ALG_SM2, TPM_ALG_SM3_256),
+        );
+        let expected = template.clone();
+        let mut tpm = Tpm::new(Scripted(move |cmd: &[u8]| {
+            let cmd = parse_command(cmd, 1);
+            assert_eq!(cmd.cc, TPM_CC_CREATE_PRIMARY);
+            assert_eq!(cmd.handles, [TPM_RH_ENDORSEMENT]);
+            assert_eq!(cmd.passwords, [b"endorsement".to_vec()]);
+            let mut r = Reader::new(&cmd.params);
+            let mut sensitive = Reader::new(r.tpm2b().unwrap());
+            assert_eq!(sensitive.tpm2b(), Ok(&b"key"[..]));
+            assert_eq!(sensitive.tpm2b(), Ok(&[][..]));
+            assert_eq!(r.sized::<Public>(), Ok(expected.clone()));
+            assert_eq!(r.tpm2b(), Ok(&[][..]));
+            assert_eq!(r.u32(), Ok(0));
+            assert!(r.is_empty());
+
+            let mut p = Writer::new();
+            p.sized(&sm2_key())
+                .tpm2b(&[0x55; 40])
+                .tpm2b(&[0x66; 32])
+                .u16(0x8021)
+                .u32(TPM_RH_ENDORSEMENT)
+                .tpm2b(&[0x77; 32])
+                .tpm2b(&[0x88; 34]);
+            response(Some(0x8000_0000), 1, p.as_slice())
+        }));
+        let created = tpm
+            .create_primary(TPM_RH_ENDORSEMENT, b"endorsement", &template, b"key")
+            .unwrap();
+        assert_eq!(created.handle, 0x8000_0000);
+        assert_eq!(created.public, sm2_key());
+        assert!(template.matches_template(&created.public));
+        assert_eq!(created.name, [0x88; 34].to_vec());
+    }
+
+    #[test]
+    fn test_quote() {
+        let sel = PcrSelection::new(TPM_ALG_SM3_256, &[12, 13, 14]);
+        let attest = Attest {
+            magic: TPM_GENERATED_VALUE,
+            qualified_signer: [0xaa; 34].to_vec(),
+            extra_data: [0xbb; 32].to_vec(),
+            clock: 0x1234,
+            reset_count: 1,
+            restart_count: 0,
+            safe: true,
+            firmware_version: 0x2023,
+            quote: QuoteInfo {
+                pcr_select: [sel].to_vec(),
+                pcr_digest: [0xcc; 32].to_vec(),
+            },
+        };
+        let mut attest_bytes = Writer::new();
+        attest_bytes.put(&attest);
+        let attest_bytes = attest_bytes.into_inner();
+        let rsp_attest = attest_bytes.clone();
+
+        let mut tpm = Tpm::new(Scripted(move |cmd: &[u8]| {
+            let cmd = parse_command(cmd, 1);
+            assert_eq!(cmd.cc, TPM_CC_QUOTE);
+            assert_eq!(cmd.handles, [0x8100_0001]);
+            let mut r = Reader::new(&cmd.params);
+            assert_eq!(r.tpm2b(), Ok(&[0xbb; 32][..]));
+            assert_eq!(
+                r.get::<Scheme>(),
+                Ok(Scheme::new(TPM_ALG_SM2, TPM_ALG_SM3_256))
+            );
+            assert_eq!(r.get::<Vec<PcrSelection>>(), Ok([sel].to_vec()));
+            assert!(r.is_empty());
+
+            let mut p = Writer::new();
+            p.tpm2b(&rsp_attest).put(&sm2_signature());
+            response(None, 1, p.as_slice())
+        }));
+        let quote = tpm
+            .quote(
+                0x8100_0001,
+                &[],
+                &[0xbb; 32],
+                Scheme::new(TPM_ALG_SM2, TPM_ALG_SM3_256),
+                &[sel],
+            )
+            .unwrap();
+        assert_eq!(quote.attest, attest_bytes);
+        assert_eq!(Attest::parse(&quote.attest), Ok(attest));
+        assert_eq!(quote.signature, sm2_signature());
+        assert_eq!(
+            tpm.quote(0x8100_0001, &[], &[0; 65], Scheme::NULL, &[sel]),
+            Err(Error::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn test_hash_sign() {
+        let digest = sm3(b"csr");
+        let ticket = HashcheckTicket {
+            hierarchy: TPM_RH_ENDORSEMENT,
+            digest: [0x99; 32].to_vec(),
+        };
+        let (rsp_digest, rsp_ticket) = (digest, ticket.clone());
+        let mut tpm = Tpm::new(Scripted(move |cmd: &[u8]| {
+            let mut r = Reader::new(cmd);
+            r.bytes(6).unwrap();
+            match r.u32().unwrap() {
+                TPM_CC_HASH => {
+                    let cmd = parse_command(cmd, 0);
+                    let mut r = Reader::new(&cmd.params);
+                    assert_eq!(r.tpm2b(), Ok(&b"csr"[..]));
+                    assert_eq!(r.u16(), Ok(TPM_ALG_SM3_256));
+                    assert_eq!(r.u32(), Ok(TPM_RH_ENDORSEMENT));
+                    let mut p = Writer::new();
+                    p.tpm2b(&rsp_digest).put(&rsp_ticket);
+                    response(None, 0, p.as_slice())
+                }
+                TPM_CC_SIGN => {
+                    let cmd = parse_command(cmd, 1);
+                    assert_eq!(cmd.handles, [0x8100_0001]);
+                    let mut r = Reader::new(&cmd.params);
+                    assert_eq!(r.tpm2b(), Ok(&rsp_digest[..]));
+                    assert_eq!(
+                        r.get::<Scheme>(),
+                        Ok(Scheme::new(TPM_ALG_SM2, TPM_ALG_SM3_256))
+                    );
+                    assert_eq!(r.get::<HashcheckTicket>(), Ok(rsp_ticket.clone()));
+                    let mut p = Writer::new();
+                    p.put(&sm2_signature());
+                    response(None, 1, p.as_slice())
+                }
+                cc => panic!("unexpected command {:#x}", cc),
+            }
+        }));
+        let (hashed, hashed_ticket) = tpm
+            .hash(b"csr", TPM_ALG_SM3_256, TPM_RH_ENDORSEMENT)
+            .unwrap();
+        assert_eq!(hashed, digest.to_vec());
+        assert_eq!(hashed_ticket, ticket);
+        let signature = tpm
+            .sign(
+                0x8100_0001,
+                &[],
+                &hashed,
+                Scheme::new(TPM_ALG_SM2, TPM_ALG_SM3_256),
+                &hashed_ticket,
+            )
+            .unwrap();
+        assert_eq!(signature, sm2_signature());
+        assert_eq!(
+            tpm.hash(
+                &[0; TPM_MAX_DIGEST_BUFFER + 1],
+                TPM_ALG_SM3_256,
+                TPM_RH_NULL
+            ),
+            Err(Error::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn test_policy_session() {
+        let mut tpm = Tpm::new(Scripted(|cmd: &[u8]| {
+            let mut r = Reader::new(cmd);
+            r.bytes(6).unwrap();
+            match r.u32().unwrap() {
+                TPM_CC_START_AUTH_SESSION => {
+                    let cmd = parse_command(cmd, 2);
+                    assert_eq!(cmd.handles, [TPM_RH_NULL, TPM_RH_NULL]);
+                    let mut r = Reader::new(&cmd.params);
+                    assert_eq!(r.tpm2b(), Ok(&POLICY_NONCE[..]));
+                    assert_eq!(r.tpm2b(), Ok(&[][..]));
+                    assert_eq!(r.u8(), Ok(TPM_SE_POLICY));
+                    assert_eq!(r.get::<SymDef>(), Ok(SymDef::NULL));
+                    assert_eq!(r.u16(), Ok(TPM_ALG_SM3_256));
+                    assert!(r.is_empty());
+                    let mut p = Writer::new();
+                    p.tpm2b(&[0x12; 32]);
+                    response(Some(0x0300_0000), 0, p.as_slice())
+                }
+                TPM_CC_POLICY_SECRET => {
+                    let cmd = parse_command(cmd, 2);
+                    assert_eq!(cmd.handles, [TPM_RH_ENDORSEMENT, 0x0300_0000]);
+                    assert_eq!(cmd.passwords, [Vec::new()]);
+                    assert_eq!(cmd.params, [0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+                    let mut p = Writer::new();
+                    p.tpm2b(&[]).u16(0x8021).u32(TPM_RH_NULL).tpm2b(&[]);
+                    response(None, 1, p.as_slice())
+                }
+                cc => panic!("unexpected command {:#x}", cc),
+            }
+        }));
+        let session = tpm.start_policy_session(TPM_ALG_SM3_256).unwrap();
+        assert_eq!(session, 0x0300_0000);
+        tpm.policy_secret(TPM_RH_ENDORSEMENT, &[], session).unwrap();
+
+        let mut tpm = Tpm::new(Scripted(|cmd: &[u8]| {
+            let cmd = parse_command(cmd, 1);
+            assert_eq!(cmd.handles, [0x0300_0001]);
+            assert!(cmd.passwords.is_empty() && cmd.policy_sessions.is_empty());
+            match cmd.cc {
+                TPM_CC_POLICY_PCR => {
+                    assert_eq!(
+                        cmd.params,
+                        [0, 0, 0, 0, 0, 1, 0, 0x12, 3, 0xff, 0, 0x10][..]
+                    );
+                    response(None, 0, &[])
+                }
+                TPM_CC_POLICY_LOCALITY => {
+                    assert_eq!(cmd.params, [0x04]);
+                    response(None, 0, &[])
+                }
+                TPM_CC_POLICY_GET_DIGEST => {
+                    assert!(cmd.params.is_empty());
+                    let mut p = Writer::new();
+                    p.tpm2b(&[0x56; 32]);
+                    response(None, 0, p.as_slice())
+                }
+                cc => panic!("unexpected command {:#x}", cc),
+            }
+        }));
+        let pcrs = PcrSelection::new(TPM_ALG_SM3_256, &[0, 1, 2, 3, 4, 5, 6, 7, 20]);
+        tpm.policy_pcr(0x0300_0001, &[], &[pcrs]).unwrap();
+        tpm.policy_locality(0x0300_0001, tpma_locality(2)).unwrap();
+        assert_eq!(tpm.policy_get_digest(0x0300_0001), Ok([0x56; 32].to_vec()));
+    }
+
+    #[test]
+    fn test_nv_policy() {
+        const INDEX: u32 = TPM_HT_NV_INDEX | 0x50_0101;
+        let pcrs = [PcrSelection::new(
+            TPM_ALG_SM3_256,
+            &[0, 1, 2, 3, 4, 5, 6, 7],
+        )];
+        let policy = |tpm: &mut Tpm<&mut SimTpm>, session: u32| {
+            tpm.policy_pcr(session, &[], &pcrs)?;
+            tpm.policy_locality(session, tpma_locality(2))
+        };
+        let mut sim = SimTpm::new();
+        let mut tpm = Tpm::new(&mut sim);
+        tpm.startup(TPM_SU_CLEAR).unwrap();
+        tpm.pcr_extend(0, &[(TPM_ALG_SM3_256, &[1; 32])]).unwrap();
+
+        let trial = tpm.start_trial_session(TPM_ALG_SM3_256).unwrap();
+        policy(&mut tpm, trial).unwrap();
+        let digest = tpm.policy_get_digest(trial).unwrap();
+        tpm.flush_context(trial).unwrap();
+        let mut expected = [0; 32].to_vec();
+        expected.extend_from_slice(&TPM_CC_POLICY_PCR.to_be_bytes());
+        expected.extend_from_slice(&[0, 0, 0, 1, 0, 0x12, 3, 0xff, 0, 0]);
+        let pcr_values = tpm.pcr_read(&pcrs).unwrap().digests.concat();
+        expected.extend_from_slice(&sm3(&pcr_values));
+        let mut expected = sm3(&expected).to_vec();
+        expected.extend_from_slice(&TPM_CC_POLICY_LOCALITY.to_be_bytes());
+        expected.push(tpma_locality(2));
+        assert_eq!(digest, sm3(&expected));
+
+        let public = NvPublic {
+            index: INDEX,
+            name_alg: TPM_ALG_SM3_256,
+            attributes: TPMA_NV_POLICYREAD
+                | TPMA_NV_POLICYWRITE
+                | TPMA_NV_WRITEDEFINE
+                | TPMA_NV_READ_STCLEAR
+                | TPMA_NV_NO_DA,
+            auth_policy: digest,
+            data_size: 32,
+        };
+        tpm.nv_define_space(TPM_RH_OWNER, &[], &[], &public)
+            .unwrap();
+        assert_eq!(
+            tpm.nv_write(TPM_RH_OWNER, &[], INDEX, &[7; 32], 0),
+            Err(Error::Tpm(TPM_RC_NV_AUTHORIZATION))
+        );
+
+        // The assertions are only checked at the locality of the write.
+        let session = tpm.start_policy_session(TPM_ALG_SM3_256).unwrap();
+        policy(&mut tpm, session).unwrap();
+        assert_eq!(
+            tpm.nv_write_policy(INDEX, session, &[7; 32], 0),
+            Err(Error::Tpm(TPM_RC_LOCALITY))
+        );
+        tpm.flush_context(session).unwrap();
+        sim.locality = 2;
+        let mut tpm = Tpm::new(&mut sim);
+        let session = tpm.start_policy_session(TPM_ALG_SM3_256).unwrap();
+        policy(&mut tpm, session).unwrap();
+        tpm.nv_write_policy(INDEX, session, &[7; 32], 0).unwrap();
+        let session = tpm.start_policy_session(TPM_ALG_SM3_256).unwrap();
+        policy(&mut tpm, session).unwrap();
+        tpm.nv_write_lock_policy(INDEX, session).unwrap();
+        let session = tpm.start_policy_session(TPM_ALG_SM3_256).unwrap();
+        policy(&mut tpm, session).unwrap();
+        assert_eq!(
+            tpm.nv_write_policy(INDEX, session, &[8; 32], 0),
+            Err(Error::Tpm(TPM_RC_NV_LOCKED))
+        );
+        tpm.flush_context(session).unwrap();
+        assert_eq!(
+            tpm.nv_write_policy(INDEX, session, &[0; TPM_MAX_NV_CHUNK + 1], 0),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(
+            tpm.nv_read(TPM_RH_OWNER, &[], INDEX, 32, 0),
+            Err(Error::Tpm(TPM_RC_NV_AUTHORIZATION))
+        );
+
+        let session = tpm.start_policy_session(TPM_ALG_SM3_256).unwrap();
+        policy(&mut tpm, session).unwrap();
+        assert_eq!(
+            tpm.nv_read_policy(INDEX, session, 32, 0),
+            Ok([7; 32].to_vec())
+        );
+        // The read consumed the session.
+        assert!(tpm.flush_context(session).is_err());
+        assert_eq!(
+            tpm.nv_read_policy(INDEX, session, TPM_MAX_NV_CHUNK as u16 + 1, 0),
+            Err(Error::InvalidParam)
+        );
+
+        let trial = tpm.start_trial_session(TPM_ALG_SM3_256).unwrap();
+        policy(&mut tpm, trial).unwrap();
+        assert!(tpm.nv_read_policy(INDEX, trial, 32, 0).is_err());
+        tpm.flush_context(trial).unwrap();
+
+        let session = tpm.start_policy_session(TPM_ALG_SM3_256).unwrap();
+        policy(&mut tpm, session).unwrap();
+        tpm.nv_read_lock_policy(INDEX, session).unwrap();
+        let session = tpm.start_policy_session(TPM_ALG_SM3_256).unwrap();
+        policy(&mut tpm, session).unwrap();
+        assert_eq!(
+            tpm.nv_read_policy(INDEX, session, 32, 0),
+            Err(Error::Tpm(TPM_RC_NV_LOCKED))
+        );
+        tpm.flush_context(session).unwrap();
+
+        // Other PCR values fail the policy after the next reset.
+        sim.reset();
+        let mut tpm = Tpm::new(&mut sim);
+        tpm.startup(TPM_SU_CLEAR).unwrap();
+        tpm.pcr_extend(0, &[(TPM_ALG_SM3_256, &[2; 32])]).unwrap();
+        let session = tpm.start_policy_session(TPM_ALG_SM3_256).unwrap();
+        policy(&mut tpm, session).unwrap();
+        assert_eq!(
+            tpm.nv_read_policy(INDEX, session, 32, 0),
+            Err(Error::Tpm(TPM_RC_POLICY_FAIL))
+        );
+
+        // The policy of the PCR values of the first boot is still known to a
+        // trial session, but a policy session only accepts the current ones.
+        let trial = tpm.start_trial_session(TPM_ALG_SM3_256).unwrap();
+        tpm.policy_pcr(trial, &sm3(&pcr_values), &pcrs).unwrap();
+        tpm.policy_locality(trial, tpma_locality(2)).unwrap();
+        assert_eq!(tpm.policy_get_digest(trial), Ok(public.auth_policy));
+        tpm.flush_context(trial).unwrap();
+        let session = tpm.start_policy_session(TPM_ALG_SM3_256).unwrap();
+        assert!(tpm.policy_pcr(session, &sm3(&pcr_values), &pcrs).is_err());
+        assert_eq!(
+            tpm.policy_pcr(session, &[0; 65], &pcrs),
+            Err(Error::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn test_activate_credential() {
+        let mut tpm = Tpm::new(Scripted(|cmd: &[u8]| {
+            let cmd = parse_command(cmd, 2);
+            assert_eq!(cmd.cc, TPM_CC_ACTIVATE_CREDENTIAL);
+            assert_eq!(cmd.handles, [0x8100_0001, 0x8000_0001]);
+            assert_eq!(cmd.passwords, [Vec::new()]);
+            assert_eq!(cmd.policy_sessions, [0x0300_0000]);
+            let mut r = Reader::new(&cmd.params);
+            assert_eq!(r.tpm2b(), Ok(&[0x01; 50][..]));
+            assert_eq!(r.tpm2b(), Ok(&[0x02; 68][..]));
+            let mut p = Writer::new();
+            p.tpm2b(&[0x5a; 16]);
+            response(None, 2, p.as_slice())
+        }));
+        assert_eq!(
+            tpm.activate_credential(
+                0x8100_0001,
+                &[],
+                0x8000_0001,
+                Auth::Policy(0x0300_0000),
+                &[0x01; 50],
+                &[0x02; 68]
+            ),
+            Ok([0x5a; 16].to_vec())
+        );
+    }
+
+    #[test]
+    fn test_sim_keys() {
+        use crate::sim::u64x4_from_be;
+        use core::convert::TryInto;
+        use yogcrypt::sm2::{sm2_ver_sign_digest, Coordinate, PubKey, Signature as Sm2Signature};
+
+        let verify = |public: &Public, digest: &[u8], sig: &Signature| {
+            let be = |bytes: &[u8]| u64x4_from_be(bytes.try_into().unwrap());
+            let q = PubKey::new(
+                Coordinate::new(be(&public.unique.x)),
+                Coordinate::new(be(&public.unique.y)),
+            );
+            let sig = Sm2Signature {
+                r: be(&sig.r),
+                s: be(&sig.s),
+            };
+            sm2_ver_sign_digest(digest.try_into().unwrap(), q, &sig)
+        };
+        let template = Public::ecc_signing_key(
+            TPM_ALG_SM3_256,
+            TPM_ECC_SM2_P256,
+            Scheme::new(TPM_ALG_SM2, TPM_ALG_SM3_256),
+        );
+        let mut sim = SimTpm::with_seed([0x42; 32]);
+        let mut tpm = Tpm::new(&mut sim);
+        tpm.startup(TPM_SU_CLEAR).unwrap();
+        let key = tpm
+            .create_primary(TPM_RH_ENDORSEMENT, &[], &template, &[])
+            .unwrap();
+        assert!(template.matches_template(&key.public));
+        assert_eq!(
+            tpm.read_public(key.handle),
+            Ok((key.public.clone(), key.name))
+        );
+        tpm.evict_control(TPM_RH_OWNER, &[], key.handle, 0x8100_0101)
+            .unwrap();
+        tpm.flush_context(key.handle).unwrap();
+        assert!(tpm.read_public(key.handle).is_err());
+        let (public, _) = tpm.read_public(0x8100_0101).unwrap();
+        assert_eq!(public, key.public);
+
+        let scheme = Scheme::new(TPM_ALG_SM2, TPM_ALG_SM3_256);
+        let sel = PcrSelection::new(TPM_ALG_SM3_256, &[12]);
+        let quote = tpm
+            .quote(0x8100_0101, &[], &[0xbb; 32], scheme, &[sel])
+            .unwrap();
+        let attest = Attest::parse(&quote.attest).unwrap();
+        assert_eq!(attest.extra_data, [0xbb; 32].to_vec());
+        assert_eq!(attest.quote.pcr_digest, sm3(&[0; 32]).to_vec());
+        assert!(verify(&public, &sm3(&quote.attest), &quote.signature));
+
+        let (digest, ticket) = tpm
+            .hash(b"csr", TPM_ALG_SM3_256, TPM_RH_ENDORSEMENT)
+            .unwrap();
+        let signature = tpm
+            .sign(0x8100_0101, &[], &digest, scheme, &ticket)
+            .unwrap();
+        assert!(verify(&public, &digest, &signature));
+        // a restricted key only signs what the TPM hashed
+        assert!(tpm
+            .sign(0x8100_0101, &[], &digest, scheme, &HashcheckTicket::null())
+            .is_err());
+        let (_, ticket) = tpm
+            .hash(
+                &TPM_GENERATED_VALUE.to_be_bytes(),
+                TPM_ALG_SM3_256,
+                TPM_RH_ENDORSEMENT,
+            )
+            .unwrap();
+        assert_eq!(ticket, HashcheckTicket::null());
+
+        tpm.evict_control(TPM_RH_OWNER, &[], 0x8100_0101, 0x8100_0101)
+            .unwrap();
+        assert!(tpm.read_public(0x8100_0101).is_err());
+        let random = tpm.get_random(32).unwrap();
+
+        // The keys and random numbers only depend on the seed.
+        let mut tpm = Tpm::new(SimTpm::with_seed([0x42; 32]));
+        tpm.startup(TPM_SU_CLEAR).unwrap();
+        let again = tpm
+            .create_primary(TPM_RH_ENDORSEMENT, &[], &template, &[])
+            .unwrap();
+        assert_eq!(again.public, key.public);
+        assert_eq!(tpm.get_random(32), Ok(random));
+        let mut tpm = Tpm::new(SimTpm::with_seed([0x43; 32]));
+        tpm.startup(TPM_SU_CLEAR).unwrap();
+        let other = tpm
+            .create_primary(TPM_RH_ENDORSEMENT, &[], &template, &[])
+            .unwrap();
+        assert_ne!(other.public, key.public);
+    }
+}
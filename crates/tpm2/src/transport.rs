@@ -0,0 +1,148 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::Result;
+
+/// Moves marshalled commands to a TPM and responses back.
+pub trait Transport {
+    /// Sends `command` and writes the response into `response`, returning its
+    /// length. `response` is at least `TPM_MAX_COMMAND_SIZE` bytes long.
+    fn transmit(&mut self, command: &[u8], response: &mut [u8]) -> Result<usize>;
+}
+
+impl<T: Transport + ?Sized> Transport for &mut T {
+    fn transmit(&mut self, command: &[u8], response: &mut [u8]) -> Result<usize> {
+        (**self).transmit(command, response)
+    }
+}
+
+#[cfg(feature = "std")]
+pub use self::socket::SocketTransport;
+
+#[cfg(feature = "std")]
+mod socket {
+    use std::io::{Read, Write};
+    use std::net::{TcpStream, ToSocketAddrs};
+
+    use super::Transport;
+    use crate::{Error, Result};
+
+    const TPM_SIGNAL_POWER_ON: u32 = 1;
+    const TPM_SEND_COMMAND: u32 = 8;
+    const TPM_SIGNAL_NV_ON: u32 = 11;
+
+    /// Default command and platform ports of the simulator.
+    pub const DEFAULT_TPM_PORT: u16 = 2321;
+    pub const DEFAULT_PLATFORM_PORT: u16 = 2322;
+
+    /// The TCP protocol of the Microsoft/IBM TPM 2.0 reference simulator.
+    pub struct SocketTransport {
+        tpm: TcpStream,
+        platform: TcpStream,
+        locality: u8,
+    }
+
+    fn io_err(_: std::io::Error) -> Error {
+        Error::Transport("simulator socket error")
+    }
+
+    fn read_u32(stream: &mut TcpStream) -> Result<u32> {
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).map_err(io_err)?;
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    impl SocketTransport {
+        /// Connects to a simulator on `host` at the default ports.
+        pub fn connect(host: &str) -> Result<Self> {
+            Self::connect_ports((host, DEFAULT_TPM_PORT), (host, DEFAULT_PLATFORM_PORT))
+        }
+
+        pub fn connect_ports(
+            tpm: impl ToSocketAddrs,
+            platform: impl ToSocketAddrs,
+        ) -> Result<Self> {
+            Ok(Self {
+                tpm: TcpStream::connect(tpm).map_err(io_err)?,
+                platform: TcpStream::connect(platform).map_err(io_err)?,
+                locality: 0,
+            })
+        }
+
+        pub fn set_locality(&mut self, locality: u8) {
+            self.locality = locality;
+        }
+
+        /// Powers the simulated TPM on and makes its NV available, which a
+        /// freshly started simulator needs before it accepts `TPM2_Startup`.
+        pub fn power_on(&mut self) -> Result<()> {
+            for signal in [TPM_SIGNAL_POWER_ON, TPM_SIGNAL_NV_ON].iter() {
+                self.platform
+                    .write_all(&signal.to_be_bytes())
+                    .map_err(io_err)?;
+                if read_u32(&mut self.platform)? != 0 {
+                    return Err(Error::Transport("simulator rejected a platform signal"));
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl Transport for SocketTransport {
+        fn transmit(&mut self, command: &[u8], response: &mut [u8]) -> Result<usize> {
+            let mut msg = Vec::with_capacity(command.len() + 9);
+            msg.extend_from_slice(&TPM_SEND_COMMAND.to_be_bytes());
+            msg.push(self.locality);
+            msg.extend_from_slice(&(command.len() as u32).to_be_bytes());
+            msg.extend_from_slice(command);
+            self.tpm.write_all(&msg).map_err(io_err)?;
+
+            let len = read_u32(&mut self.tpm)? as usize;
+            if len > response.len() {
+                return Err(Error::Transport("response too large"));
+            }
+            self.tpm.read_exact(&mut response[..len]).map_err(io_err)?;
+            if read_u32(&mut self.tpm)? != 0 {
+                return Err(Error::Transport("simulator reported a command failure"));
+            }
+            Ok(len)
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::SocketTransport;
+        use crate::consts::*;
+        use crate::{PcrSelection, Tpm};
+
+        /// Needs a TPM 2.0 simulator listening on the default ports.
+        #[test]
+        #[ignore]
+        fn test_simulator() {
+            let mut transport = SocketTransport::connect("127.0.0.1").unwrap();
+            transport.power_on().unwrap();
+            let mut tpm = Tpm::new(transport);
+            tpm.startup(TPM_SU_CLEAR).unwrap();
+            assert_eq!(tpm.get_random(16).unwrap().len(), 16);
+
+            let sel = PcrSelection::new(TPM_ALG_SHA256, &[16]);
+            let before = tpm.pcr_read(&[sel]).unwrap();
+            tpm.pcr_extend(16, &[(TPM_ALG_SHA256, &[0x5a; 32])])
+                .unwrap();
+            let after = tpm.pcr_read(&[sel]).unwrap();
+            assert_eq!(after.selections, [sel]);
+            assert_ne!(before.digests, after.digests);
+        }
+    }
+}
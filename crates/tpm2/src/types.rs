@@ -0,0 +1,645 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! TPM structures used by the supported commands.
+//!
+//! Only the ECC flavours of keys and signatures are modelled; everything the
+//! hypervisor creates is an SM2 (or NIST P-256) key.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::consts::*;
+use crate::marshal::{Marshal, Reader, Unmarshal, Writer};
+use crate::{Error, Result};
+
+/// Upper bound on the entries of a `TPML_*` list accepted from the TPM.
+const MAX_LIST_COUNT: u32 = 16;
+
+/// A `TPMS_PCR_SELECTION`: a hash bank and a bitmap of PCRs 0 to 23.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PcrSelection {
+    pub hash: u16,
+    pub pcrs: u32,
+}
+
+impl PcrSelection {
+    /// Number of bytes in the PCR bitmap of a PC Client TPM.
+    pub const SIZE_OF_SELECT: u8 = 3;
+
+    pub fn new(hash: u16, pcrs: &[u32]) -> Self {
+        let pcrs = pcrs.iter().fold(0, |bits, &pcr| {
+            debug_assert!(pcr < 24);
+            bits | (1 << pcr)
+        });
+        Self { hash, pcrs }
+    }
+
+    pub fn contains(&self, pcr: u32) -> bool {
+        pcr < 32 && self.pcrs & (1 << pcr) != 0
+    }
+
+    /// Iterates over the selected PCR indices in ascending order, which is
+    /// the order the TPM returns and digests them in.
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..32).filter(move |&pcr| self.contains(pcr))
+    }
+}
+
+impl Marshal for PcrSelection {
+    fn marshal(&self, w: &mut Writer) {
+        w.u16(self.hash).u8(Self::SIZE_OF_SELECT);
+        for i in 0..Self::SIZE_OF_SELECT {
+            w.u8((self.pcrs >> (i * 8)) as u8);
+        }
+    }
+}
+
+impl Unmarshal for PcrSelection {
+    fn unmarshal(r: &mut Reader) -> Result<Self> {
+        let hash = r.u16()?;
+        let size = r.u8()?;
+        if size > 4 {
+            return Err(Error::Malformed);
+        }
+        let pcrs = r
+            .bytes(size as usize)?
+            .iter()
+            .enumerate()
+            .fold(0, |bits, (i, &byte)| bits | (byte as u32) << (i * 8));
+        Ok(Self { hash, pcrs })
+    }
+}
+
+/// A `TPML_PCR_SELECTION`.
+impl Marshal for [PcrSelection] {
+    fn marshal(&self, w: &mut Writer) {
+        w.u32(self.len() as u32);
+        for sel in self {
+            w.put(sel);
+        }
+    }
+}
+
+impl Unmarshal for Vec<PcrSelection> {
+    fn unmarshal(r: &mut Reader) -> Result<Self> {
+        let count = r.u32()?;
+        if count > MAX_LIST_COUNT {
+            return Err(Error::Malformed);
+        }
+        (0..count).map(|_| r.get()).collect()
+    }
+}
+
+/// PCR contents returned by `TPM2_PCR_Read`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PcrValues {
+    pub update_counter: u32,
+    /// The PCRs actually read, which may be fewer than requested.
+    pub selections: Vec<PcrSelection>,
+    /// One digest per selected PCR, bank by bank in ascending PCR order.
+    pub digests: Vec<Vec<u8>>,
+}
+
+/// A `TPMT_SYM_DEF_OBJECT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SymDef {
+    pub alg: u16,
+    pub key_bits: u16,
+    pub mode: u16,
+}
+
+impl SymDef {
+    pub const NULL: Self = Self {
+        alg: TPM_ALG_NULL,
+        key_bits: 0,
+        mode: 0,
+    };
+}
+
+impl Marshal for SymDef {
+    fn marshal(&self, w: &mut Writer) {
+        w.u16(self.alg);
+        if self.alg != TPM_ALG_NULL {
+            w.u16(self.key_bits).u16(self.mode);
+        }
+    }
+}
+
+impl Unmarshal for SymDef {
+    fn unmarshal(r: &mut Reader) -> Result<Self> {
+        let alg = r.u16()?;
+        if alg == TPM_ALG_NULL {
+            return Ok(Self::NULL);
+        }
+        Ok(Self {
+            alg,
+            key_bits: r.u16()?,
+            mode: r.u16()?,
+        })
+    }
+}
+
+/// A signing or KDF scheme (`TPMT_SIG_SCHEME`, `TPMT_ECC_SCHEME`,
+/// `TPMT_KDF_SCHEME`): an algorithm and, unless it is `TPM_ALG_NULL`, the
+/// hash it uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scheme {
+    pub alg: u16,
+    pub hash: u16,
+}
+
+impl Scheme {
+    pub const NULL: Self = Self {
+        alg: TPM_ALG_NULL,
+        hash: TPM_ALG_NULL,
+    };
+
+    pub const fn new(alg: u16, hash: u16) -> Self {
+        Self { alg, hash }
+    }
+}
+
+impl Marshal for Scheme {
+    fn marshal(&self, w: &mut Writer) {
+        w.u16(self.alg);
+        if self.alg != TPM_ALG_NULL {
+            w.u16(self.hash);
+        }
+    }
+}
+
+impl Unmarshal for Scheme {
+    fn unmarshal(r: &mut Reader) -> Result<Self> {
+        match r.u16()? {
+            TPM_ALG_NULL => Ok(Self::NULL),
+            TPM_ALG_ECDAA => Err(Error::Unsupported(TPM_ALG_ECDAA)),
+            alg => Ok(Self::new(alg, r.u16()?)),
+        }
+    }
+}
+
+/// A `TPMS_ECC_POINT`, with big-endian coordinates.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EccPoint {
+    pub x: Vec<u8>,
+    pub y: Vec<u8>,
+}
+
+impl Marshal for EccPoint {
+    fn marshal(&self, w: &mut Writer) {
+        w.tpm2b(&self.x).tpm2b(&self.y);
+    }
+}
+
+impl Unmarshal for EccPoint {
+    fn unmarshal(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            x: r.tpm2b()?.into(),
+            y: r.tpm2b()?.into(),
+        })
+    }
+}
+
+/// A `TPMT_PUBLIC` of type `TPM_ALG_ECC`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Public {
+    pub name_alg: u16,
+    pub attributes: u32,
+    pub auth_policy: Vec<u8>,
+    pub symmetric: SymDef,
+    pub scheme: Scheme,
+    pub curve: u16,
+    pub kdf: Scheme,
+    pub unique: EccPoint,
+}
+
+impl Public {
+    /// Template of a restricted signing key, such as an attestation key.
+    pub fn ecc_signing_key(name_alg: u16, curve: u16, scheme: Scheme) -> Self {
+        Self {
+            name_alg,
+            attributes: TPMA_OBJECT_FIXED_TPM
+                | TPMA_OBJECT_FIXED_PARENT
+                | TPMA_OBJECT_SENSITIVE_DATA_ORIGIN
+                | TPMA_OBJECT_USER_WITH_AUTH
+                | TPMA_OBJECT_RESTRICTED
+                | TPMA_OBJECT_SIGN,
+            auth_policy: Vec::new(),
+            symmetric: SymDef::NULL,
+            scheme,
+            curve,
+            kdf: Scheme::NULL,
+            unique: EccPoint::default(),
+        }
+    }
+
+    /// Template of a restricted decryption key, such as an endorsement key.
+    pub fn ecc_storage_key(name_alg: u16, curve: u16, symmetric: SymDef) -> Self {
+        Self {
+            name_alg,
+            attributes: TPMA_OBJECT_FIXED_TPM
+                | TPMA_OBJECT_FIXED_PARENT
+                | TPMA_OBJECT_SENSITIVE_DATA_ORIGIN
+                | TPMA_OBJECT_USER_WITH_AUTH
+                | TPMA_OBJECT_RESTRICTED
+                | TPMA_OBJECT_DECRYPT,
+            auth_policy: Vec::new(),
+            symmetric,
+            scheme: Scheme::NULL,
+            curve,
+            kdf: Scheme::NULL,
+            unique: EccPoint::default(),
+        }
+    }
+
+    /// Template of an endorsement key in the style of the TCG EK Credential
+    /// Profile: a restricted decryption key usable only through `auth_policy`.
+    pub fn ecc_endorsement_key(
+        name_alg: u16,
+        curve: u16,
+        symmetric: SymDef,
+        auth_policy: &[u8],
+    ) -> Self {
+        let size = digest_size(name_alg).unwrap_or(0);
+        Self {
+            name_alg,
+            attributes: TPMA_OBJECT_FIXED_TPM
+                | TPMA_OBJECT_FIXED_PARENT
+                | TPMA_OBJECT_SENSITIVE_DATA_ORIGIN
+                | TPMA_OBJECT_ADMIN_WITH_POLICY
+                | TPMA_OBJECT_RESTRICTED
+                | TPMA_OBJECT_DECRYPT,
+            auth_policy: auth_policy.into(),
+            symmetric,
+            scheme: Scheme::NULL,
+            curve,
+            kdf: Scheme::NULL,
+            unique: EccPoint {
+                x: vec![0; size],
+                y: vec![0; size],
+            },
+        }
+    }
+
+    /// Whether `other` was created from this template: everything but the
+    /// `unique` field must match.
+    pub fn matches_template(&self, other: &Public) -> bool {
+        self.name_alg == other.name_alg
+            && self.attributes == other.attributes
+            && self.auth_policy == other.auth_policy
+            && self.symmetric == other.symmetric
+            && self.scheme == other.scheme
+            && self.curve == other.curve
+            && self.kdf == other.kdf
+    }
+
+    /// Marshals this structure as a bare `TPMT_PUBLIC`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.put(self);
+        w.into_inner()
+    }
+}
+
+impl Marshal for Public {
+    fn marshal(&self, w: &mut Writer) {
+        w.u16(TPM_ALG_ECC)
+            .u16(self.name_alg)
+            .u32(self.attributes)
+            .tpm2b(&self.auth_policy)
+            .put(&self.symmetric)
+            .put(&self.scheme)
+            .u16(self.curve)
+            .put(&self.kdf)
+            .put(&self.unique);
+    }
+}
+
+impl Unmarshal for Public {
+    fn unmarshal(r: &mut Reader) -> Result<Self> {
+        let ty = r.u16()?;
+        if ty != TPM_ALG_ECC {
+            return Err(Error::Unsupported(ty));
+        }
+        Ok(Self {
+            name_alg: r.u16()?,
+            attributes: r.u32()?,
+            auth_policy: r.tpm2b()?.into(),
+            symmetric: r.get()?,
+            scheme: r.get()?,
+            curve: r.u16()?,
+            kdf: r.get()?,
+            unique: r.get()?,
+        })
+    }
+}
+
+/// A `TPMT_SIGNATURE` produced by an ECC key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub alg: u16,
+    pub hash: u16,
+    pub r: Vec<u8>,
+    pub s: Vec<u8>,
+}
+
+impl Marshal for Signature {
+    fn marshal(&self, w: &mut Writer) {
+        w.u16(self.alg);
+        if self.alg != TPM_ALG_NULL {
+            w.u16(self.hash).tpm2b(&self.r).tpm2b(&self.s);
+        }
+    }
+}
+
+impl Unmarshal for Signature {
+    fn unmarshal(r: &mut Reader) -> Result<Self> {
+        let alg = r.u16()?;
+        match alg {
+            TPM_ALG_NULL => Ok(Self {
+                alg,
+                hash: TPM_ALG_NULL,
+                r: Vec::new(),
+                s: Vec::new(),
+            }),
+            TPM_ALG_ECDSA | TPM_ALG_SM2 | TPM_ALG_ECDAA | TPM_ALG_ECSCHNORR => Ok(Self {
+                alg,
+                hash: r.u16()?,
+                r: r.tpm2b()?.into(),
+                s: r.tpm2b()?.into(),
+            }),
+            _ => Err(Error::Unsupported(alg)),
+        }
+    }
+}
+
+/// A `TPMT_TK_HASHCHECK`, proving to `TPM2_Sign` that the TPM hashed the
+/// signed data itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashcheckTicket {
+    pub hierarchy: u32,
+    pub digest: Vec<u8>,
+}
+
+impl HashcheckTicket {
+    /// The empty ticket, accepted for unrestricted keys only.
+    pub fn null() -> Self {
+        Self {
+            hierarchy: TPM_RH_NULL,
+            digest: Vec::new(),
+        }
+    }
+}
+
+impl Marshal for HashcheckTicket {
+    fn marshal(&self, w: &mut Writer) {
+        w.u16(TPM_ST_HASHCHECK)
+            .u32(self.hierarchy)
+            .tpm2b(&self.digest);
+    }
+}
+
+impl Unmarshal for HashcheckTicket {
+    fn unmarshal(r: &mut Reader) -> Result<Self> {
+        if r.u16()? != TPM_ST_HASHCHECK {
+            return Err(Error::Malformed);
+        }
+        Ok(Self {
+            hierarchy: r.u32()?,
+            digest: r.tpm2b()?.into(),
+        })
+    }
+}
+
+/// A `TPMS_NV_PUBLIC`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NvPublic {
+    pub index: u32,
+    pub name_alg: u16,
+    pub attributes: u32,
+    pub auth_policy: Vec<u8>,
+    pub data_size: u16,
+}
+
+impl Marshal for NvPublic {
+    fn marshal(&self, w: &mut Writer) {
+        w.u32(self.index)
+            .u16(self.name_alg)
+            .u32(self.attributes)
+            .tpm2b(&self.auth_policy)
+            .u16(self.data_size);
+    }
+}
+
+impl Unmarshal for NvPublic {
+    fn unmarshal(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            index: r.u32()?,
+            name_alg: r.u16()?,
+            attributes: r.u32()?,
+            auth_policy: r.tpm2b()?.into(),
+            data_size: r.u16()?,
+        })
+    }
+}
+
+/// The `TPMS_QUOTE_INFO` of a quote.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuoteInfo {
+    pub pcr_select: Vec<PcrSelection>,
+    pub pcr_digest: Vec<u8>,
+}
+
+/// A `TPMS_ATTEST` of type `TPM_ST_ATTEST_QUOTE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attest {
+    pub magic: u32,
+    pub qualified_signer: Vec<u8>,
+    pub extra_data: Vec<u8>,
+    pub clock: u64,
+    pub reset_count: u32,
+    pub restart_count: u32,
+    pub safe: bool,
+    pub firmware_version: u64,
+    pub quote: QuoteInfo,
+}
+
+impl Attest {
+    /// Parses the contents of a `TPM2B_ATTEST` returned by `TPM2_Quote`.
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let mut r = Reader::new(bytes);
+        let attest = r.get()?;
+        r.finish()?;
+        Ok(attest)
+    }
+}
+
+impl Marshal for Attest {
+    fn marshal(&self, w: &mut Writer) {
+        w.u32(self.magic)
+            .u16(TPM_ST_ATTEST_QUOTE)
+            .tpm2b(&self.qualified_signer)
+            .tpm2b(&self.extra_data)
+            .u64(self.clock)
+            .u32(self.reset_count)
+            .u32(self.restart_count)
+            .u8(self.safe as u8)
+            .u64(self.firmware_version)
+            .put(&self.quote.pcr_select[..])
+            .tpm2b(&self.quote.pcr_digest);
+    }
+}
+
+impl Unmarshal for Attest {
+    fn unmarshal(r: &mut Reader) -> Result<Self> {
+        let magic = r.u32()?;
+        let ty = r.u16()?;
+        if ty != TPM_ST_ATTEST_QUOTE {
+            return Err(Error::Unsupported(ty));
+        }
+        Ok(Self {
+            magic,
+            qualified_signer: r.tpm2b()?.into(),
+            extra_data: r.tpm2b()?.into(),
+            clock: r.u64()?,
+            reset_count: r.u32()?,
+            restart_count: r.u32()?,
+            safe: r.u8()? != 0,
+            firmware_version: r.u64()?,
+            quote: QuoteInfo {
+                pcr_select: r.get()?,
+                pcr_digest: r.tpm2b()?.into(),
+            },
+        })
+    }
+}
+
+/// The output of `TPM2_Quote`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quote {
+    /// The raw `TPMS_ATTEST`, exactly as signed.
+    pub attest: Vec<u8>,
+    pub signature: Signature,
+}
+
+/// The output of `TPM2_CreatePrimary`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CreatedPrimary {
+    pub handle: u32,
+    pub public: Public,
+    pub name: Vec<u8>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pcr_selection() {
+        let sel = PcrSelection::new(TPM_ALG_SM3_256, &[0, 12, 13]);
+        let mut w = Writer::new();
+        w.put(&[sel][..]);
+        assert_eq!(w.as_slice(), &[0, 0, 0, 1, 0x00, 0x12, 3, 0x01, 0x30, 0x00]);
+        let buf = w.into_inner();
+        let back: Vec<PcrSelection> = Reader::new(&buf).get().unwrap();
+        assert_eq!(back, [sel]);
+        assert_eq!(sel.iter().collect::<Vec<_>>(), [0, 12, 13]);
+    }
+
+    #[test]
+    fn test_public_round_trip() {
+        let mut public = Public::ecc_signing_key(
+            TPM_ALG_SM3_256,
+            TPM_ECC_SM2_P256,
+            Scheme::new(TPM_ALG_SM2, TPM_ALG_SM3_256),
+        );
+        public.unique = EccPoint {
+            x: [0x11; 32].to_vec(),
+            y: [0x22; 32].to_vec(),
+        };
+        let bytes = public.to_bytes();
+        // type, nameAlg, attributes, authPolicy, symmetric, scheme, curve, kdf, unique
+        assert_eq!(bytes.len(), 2 + 2 + 4 + 2 + 2 + 4 + 2 + 2 + 68);
+        let back: Public = Reader::new(&bytes).get().unwrap();
+        assert_eq!(back, public);
+        assert!(back.matches_template(&Public::ecc_signing_key(
+            TPM_ALG_SM3_256,
+            TPM_ECC_SM2_P256,
+            Scheme::new(TPM_ALG_SM2, TPM_ALG_SM3_256),
+        )));
+
+        let mut w = Writer::new();
+        w.u16(TPM_ALG_RSA);
+        assert_eq!(
+            Reader::new(w.as_slice()).get::<Public>(),
+            Err(Error::Unsupported(TPM_ALG_RSA))
+        );
+    }
+
+    #[test]
+    fn test_endorsement_key() {
+        // PolicySecret(TPM_RH_ENDORSEMENT): extend TPM_CC_PolicySecret and the
+        // name of the endorsement hierarchy, then the empty policyRef.
+        let mut data = [0; 40].to_vec();
+        data[32..36].copy_from_slice(&TPM_CC_POLICY_SECRET.to_be_bytes());
+        data[36..].copy_from_slice(&TPM_RH_ENDORSEMENT.to_be_bytes());
+        assert_eq!(
+            crate::sim::sm3(&crate::sim::sm3(&data)),
+            EK_POLICY_A_SM3_256
+        );
+
+        let sm4 = SymDef {
+            alg: TPM_ALG_SM4,
+            key_bits: 128,
+            mode: TPM_ALG_CFB,
+        };
+        let ek = Public::ecc_endorsement_key(
+            TPM_ALG_SM3_256,
+            TPM_ECC_SM2_P256,
+            sm4,
+            &EK_POLICY_A_SM3_256,
+        );
+        assert_eq!(ek.attributes, 0x0003_00b2);
+        assert_eq!(ek.unique.x, [0; 32]);
+        assert_eq!(ek.to_bytes().len(), 2 + 2 + 4 + 34 + 6 + 2 + 2 + 2 + 68);
+    }
+
+    #[test]
+    fn test_attest_round_trip() {
+        let attest = Attest {
+            magic: TPM_GENERATED_VALUE,
+            qualified_signer: [0xaa; 34].to_vec(),
+            extra_data: [0xbb; 32].to_vec(),
+            clock: 1,
+            reset_count: 2,
+            restart_count: 3,
+            safe: true,
+            firmware_version: 4,
+            quote: QuoteInfo {
+                pcr_select: [PcrSelection::new(TPM_ALG_SM3_256, &[12, 13, 14])].to_vec(),
+                pcr_digest: [0xcc; 32].to_vec(),
+            },
+        };
+        let mut w = Writer::new();
+        w.put(&attest);
+        // The size of an SM3 quote over 32 bytes of qualifying data.
+        assert_eq!(w.len(), 145);
+        assert_eq!(Attest::parse(w.as_slice()), Ok(attest));
+
+        let mut bytes = w.into_inner();
+        bytes.push(0);
+        assert_eq!(Attest::parse(&bytes), Err(Error::Malformed));
+    }
+}
@@ -38,7 +38,9 @@
 //! Most variable's name in the source code are in accordance with the document.
 //!
 //! [OSCCA: SM2 document](http://www.oscca.gov.cn/sca/xxgk/2010-12/17/1002386/files/b791a9f908bb4803875ab6aeeb7b4e03.pdf)
+use alloc::vec::Vec;
 use basic::field::field_n::*;
+use basic::field::field_p::MODULO_P;
 use basic::group::ecc_group::*;
 use basic::util::bytes_to_u32_blocks;
 use sm3::*;
@@ -186,14 +188,12 @@ pub(crate) fn sm2_gen_sign_inner(msg: &[u32], d: SecKey, q: PubKey, len: usize,
     } else {
         sm3_enc_inner(msg, len)
     };
-    let mut e = U64x4::new(
-        u64::from(e[7]) | (u64::from(e[6]) << 32),
-        u64::from(e[5]) | (u64::from(e[4]) << 32),
-        u64::from(e[3]) | (u64::from(e[2]) << 32),
-        u64::from(e[1]) | (u64::from(e[0]) << 32),
-    );
+    sign_e(hash_to_u64x4(e), d)
+}
 
-    e = to_mod_n(e);
+/// Signs `e`, the hash of the message already reduced to a number.
+fn sign_e(e: U64x4, d: SecKey) -> Signature {
+    let e = to_mod_n(e);
 
     let mut s = U64x4::zero();
     let mut r = U64x4::zero();
@@ -221,7 +221,8 @@ pub(crate) fn sm2_gen_sign_inner(msg: &[u32], d: SecKey, q: PubKey, len: usize,
 
 /// Verify a signature on a given message using public key
 ///
-/// **Note**: The underlying hash function is `sm3`.
+/// **Note**: The underlying hash function is `sm3`. An invalid public key is
+/// rejected like a wrong signature.
 pub fn sm2_ver_sign(msg: &[u8], q: PubKey, sig: &Signature) -> bool {
     let (msg, bit_len) = bytes_to_u32_blocks(msg);
     sm2_ver_sign_inner(&msg[..], q, bit_len, sig)
@@ -231,7 +232,19 @@ pub fn sm2_ver_sign(msg: &[u8], q: PubKey, sig: &Signature) -> bool {
 pub(crate) fn sm2_ver_sign_inner(msg: &[u32], q: PubKey, len: usize, sig: &Signature) -> bool {
     // verify that Q is indeed on the curve
     // to prevent false curve attack
-    assert!(is_on_curve(q), "public key not on curve!");
+    if !is_valid_pub_key(q) {
+        return false;
+    }
+    let z_a = get_z(q);
+    // same order as the signer: first z then msg
+    let m = [&z_a, msg].concat();
+    let e = sm3_enc_inner(&m, 32 * 8 + len);
+    verify_e(hash_to_u64x4(e), q, sig)
+}
+
+/// Verifies a signature on `e`, the hash of the message already reduced to
+/// a number.
+fn verify_e(e: U64x4, q: PubKey, sig: &Signature) -> bool {
     let r = sig.r;
     let s = sig.s;
 
@@ -241,16 +254,6 @@ pub(crate) fn sm2_ver_sign_inner(msg: &[u32], q: PubKey, len: usize, sig: &Signa
     if s >= MODULO_N || s.equal_to_zero() {
         return false;
     }
-    let z_a = get_z(q);
-    let m = [msg, &z_a].concat();
-
-    let e = sm3_enc_inner(&m, (len + 8) * 32);
-    let e = U64x4::new(
-        u64::from(e[7]) | (u64::from(e[6]) << 32),
-        u64::from(e[5]) | (u64::from(e[4]) << 32),
-        u64::from(e[3]) | (u64::from(e[2]) << 32),
-        u64::from(e[1]) | (u64::from(e[0]) << 32),
-    );
 
     let t = add_mod_n(r, s);
     if t.equal_to_zero() {
@@ -268,6 +271,64 @@ pub(crate) fn sm2_ver_sign_inner(msg: &[u32], q: PubKey, len: usize, sig: &Signa
     r2 == r
 }
 
+/// Converts a hash value into a number, the first word being the most
+/// significant.
+fn hash_to_u64x4(e: HashValue) -> U64x4 {
+    U64x4::new(
+        u64::from(e[7]) | (u64::from(e[6]) << 32),
+        u64::from(e[5]) | (u64::from(e[4]) << 32),
+        u64::from(e[3]) | (u64::from(e[2]) << 32),
+        u64::from(e[1]) | (u64::from(e[0]) << 32),
+    )
+}
+
+fn digest_to_u64x4(digest: &[u8; 32]) -> U64x4 {
+    let mut e = [0; 8];
+    for (word, bytes) in e.iter_mut().zip(digest.chunks_exact(4)) {
+        *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    hash_to_u64x4(e)
+}
+
+/// Whether `q` is a point of the curve with canonical coordinates, as
+/// public keys from untrusted sources must be checked to be.
+pub fn is_valid_pub_key(q: PubKey) -> bool {
+    q.x.num < MODULO_P && q.y.num < MODULO_P && !point_equal_to_zero(q) && is_on_curve(q)
+}
+
+/// Compute `Z` for the distinguishing identifier `id`, as big-endian bytes.
+///
+/// Signers outside this crate, such as X.509 certificate issuers, use the
+/// default identifier `1234567812345678` rather than the one of `sm2_gen_sign`.
+pub fn sm2_z(id: &[u8], q: PubKey) -> [u8; 32] {
+    let mut s = Vec::with_capacity(2 + id.len() + 6 * 32);
+    s.extend_from_slice(&((id.len() * 8) as u16).to_be_bytes());
+    s.extend_from_slice(id);
+    for c in [ECC_A, ECC_B, ECC_G.x, ECC_G.y, q.x, q.y].iter() {
+        for i in (0..4).rev() {
+            s.extend_from_slice(&c.value(i).to_be_bytes());
+        }
+    }
+    let z = sm3_enc(&s);
+    let mut bytes = [0; 32];
+    for (bytes, word) in bytes.chunks_exact_mut(4).zip(z.iter()) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    bytes
+}
+
+/// Generate a signature on `digest`, the big-endian SM3 hash of the message
+/// computed by the caller, as a TPM does.
+pub fn sm2_gen_sign_digest(digest: &[u8; 32], d: SecKey) -> Signature {
+    sign_e(digest_to_u64x4(digest), d)
+}
+
+/// Verify a signature on `digest`, the big-endian SM3 hash of the message
+/// computed by the caller.
+pub fn sm2_ver_sign_digest(digest: &[u8; 32], q: PubKey, sig: &Signature) -> bool {
+    is_valid_pub_key(q) && verify_e(digest_to_u64x4(digest), q, sig)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -290,4 +351,73 @@ mod tests {
             assert!(t);
         }
     }
+
+    fn u64x4_from_hex(hex: &str) -> U64x4 {
+        let word = |i: usize| u64::from_str_radix(&hex[i * 16..i * 16 + 16], 16).unwrap();
+        U64x4::new(word(3), word(2), word(1), word(0))
+    }
+
+    #[test]
+    fn test_digest() {
+        // the key pair of GM/T 0003.5-2012, Appendix A, and a signature made
+        // with the ephemeral key of the example by another implementation
+        let d = u64x4_from_hex("3945208F7B2144B13F36E38AC6D39F95889393692860B51A42FB81EF4DF7C5B8");
+        let q = get_pub_key(d);
+        assert_eq!(
+            q.x.num,
+            u64x4_from_hex("09F9DF311E5421A150DD7D161E4BC5C672179FAD1833FC076BB08FF356F35020")
+        );
+        assert!(is_valid_pub_key(q));
+        let z = sm2_z(b"ALICE123@YAHOO.COM", q);
+        assert_eq!(
+            z,
+            [
+                0x26, 0xDB, 0x4B, 0xC1, 0x83, 0x9B, 0xD2, 0x2E, 0x97, 0xE1, 0xDA, 0xB6, 0x67, 0xEC,
+                0x5E, 0x0A, 0x73, 0x0D, 0x5E, 0x16, 0x52, 0x13, 0x98, 0xB4, 0x43, 0x5C, 0x57, 0x6A,
+                0x93, 0xAF, 0xD7, 0xED
+            ]
+        );
+        let digest = {
+            let e = sm3_enc(&[&z[..], b"message digest"].concat());
+            let mut bytes = [0; 32];
+            for (bytes, word) in bytes.chunks_exact_mut(4).zip(e.iter()) {
+                bytes.copy_from_slice(&word.to_be_bytes());
+            }
+            bytes
+        };
+        let sig = Signature {
+            r: u64x4_from_hex("B0E3E7D4AC2178F833AD73FA9D1191E41C76C8BFEDB5AD89040BA2E5184BDE58"),
+            s: u64x4_from_hex("CC8D096578F7DD2669AC1AC42F7E722BCFA42B9E0BE0B1B5DF7CA0B53FDD5750"),
+        };
+        assert!(sm2_ver_sign_digest(&digest, q, &sig));
+
+        let sig = sm2_gen_sign_digest(&digest, d);
+        assert!(sm2_ver_sign_digest(&digest, q, &sig));
+        let mut other = digest;
+        other[31] ^= 1;
+        assert!(!sm2_ver_sign_digest(&other, q, &sig));
+    }
+    #[test]
+    fn test_ver_sign() {
+        // the key pair and the ephemeral key of GM/T 0003.5-2012, Appendix A,
+        // signing SM3(Z || M) with the identifier of `get_z`, by another
+        // implementation
+        let d = u64x4_from_hex("3945208F7B2144B13F36E38AC6D39F95889393692860B51A42FB81EF4DF7C5B8");
+        let q = get_pub_key(d);
+        let id: Vec<u8> = (1..=14).collect();
+        let mut z = [0; 8];
+        for (word, bytes) in z.iter_mut().zip(sm2_z(&id, q).chunks_exact(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        assert_eq!(z, get_z(q));
+        let sig = Signature {
+            r: u64x4_from_hex("292145A57730B4BF64687A20F9A7C2453556A58E0B7CF684A2FAF2B0E7DFCD83"),
+            s: u64x4_from_hex("98FB62CC56F698C3E3EDA449D4BB1B13A7A9C6FBE2926BAC086524C7103851AF"),
+        };
+        assert!(sm2_ver_sign(b"message digest", q, &sig));
+        assert!(!sm2_ver_sign(b"message digesT", q, &sig));
+        let mut off_curve = q;
+        off_curve.y = off_curve.x;
+        assert!(!sm2_ver_sign(b"message digest", off_curve, &sig));
+    }
 }
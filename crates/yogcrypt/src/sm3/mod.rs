@@ -38,10 +38,12 @@
 //!
 //! [OSCCA: SM3 document](http://www.oscca.gov.cn/sca/xxgk/2010-12/17/1002389/files/302a3ada057c4a73830536d03e683110.pdf)
 
+use alloc::vec::Vec;
 use basic::util::bytes_to_u32_blocks;
 use core::num::Wrapping;
 
 pub type HashValue = [u32; 8];
+const BLOCK_SIZE: usize = 64;
 static IV: [u32; 8] = [
     0x7380166f, 0x4914b2b9, 0x172442d7, 0xda8a0600, 0xa96f30bc, 0x163138aa, 0xe38dee4d, 0xb0fb0e4e,
 ];
@@ -160,6 +162,28 @@ pub fn sm3_enc(msg: &[u8]) -> HashValue {
     sm3_enc_inner(&msg[..], bit_len)
 }
 
+/// Compute the HMAC (RFC 2104) of the given message with SM3
+pub fn sm3_hmac(key: &[u8], msg: &[u8]) -> HashValue {
+    let mut block_key = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        for (i, word) in sm3_enc(key).iter().enumerate() {
+            block_key[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+        }
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner: Vec<u8> = block_key.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(msg);
+    let inner_hash = sm3_enc(&inner);
+
+    let mut outer: Vec<u8> = block_key.iter().map(|b| b ^ 0x5c).collect();
+    for word in inner_hash.iter() {
+        outer.extend_from_slice(&word.to_be_bytes());
+    }
+    sm3_enc(&outer)
+}
+
 /// Core function for sm3 with specified input length
 pub(crate) fn sm3_enc_inner(msg: &[u32], prim_len: usize) -> HashValue {
     let mut msg_len = prim_len;
@@ -242,4 +266,29 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_hmac() {
+        // the test cases of RFC 4231, with SM3 in place of SHA-2
+        let hmac = sm3_hmac(&[0x0b; 20], b"Hi There");
+        assert_eq!(
+            hmac,
+            [
+                0x51b00d1f, 0xb49832bf, 0xb01c3ce2, 0x7848e59f, 0x871d9ba9, 0x38dc563b, 0x338ca964,
+                0x755cce70
+            ]
+        );
+
+        let hmac = sm3_hmac(
+            &[0xaa; 131],
+            b"Test Using Larger Than Block-Size Key - Hash Key First",
+        );
+        assert_eq!(
+            hmac,
+            [
+                0xb4fd844e, 0x13342002, 0xf0b2e069, 0x0ea7741f, 0x1497d993, 0xa70494ce, 0xa601e657,
+                0xbedf67a0
+            ]
+        );
+    }
 }
@@ -13,6 +13,13 @@ pub fn time_now() -> u64 {
     cntvct
 }
 
+/// The frequency of `time_now`, if the firmware programmed it.
+pub fn time_freq_mhz() -> Option<u64> {
+    let cntfrq: u64;
+    unsafe { core::arch::asm!("mrs {cntfrq}, cntfrq_el0", cntfrq = out(reg) cntfrq) };
+    Some(cntfrq / 1_000_000).filter(|&mhz| mhz != 0)
+}
+
 pub fn check_cpu_features() -> HvResult {
     // Example for checking NEON support
     let has_neon: bool;
@@ -15,12 +15,15 @@
 use libvmm::msr::Msr;
 use libvmm::svm::flags::{VmcbCleanBits, VmcbTlbControl};
 use libvmm::svm::SvmIntercept;
+use x86_64::registers::rflags::RFlags;
 
 use crate::arch::vmm::{Vcpu, VcpuAccessGuestState};
 use crate::enclave::{EnclaveThreadState, VcpuAccessEnclaveState};
 use crate::error::HvResult;
 use crate::memory::addr::align_down;
 
+use super::vcpu::{ENCLAVE_MSRPM, NORMAL_WORLD_MSRPM};
+
 impl VcpuAccessEnclaveState for Vcpu {
     fn load_enclave_thread_state(&self) -> HvResult<EnclaveThreadState> {
         Ok(EnclaveThreadState {
@@ -33,6 +36,8 @@ impl VcpuAccessEnclaveState for Vcpu {
             efer: self.efer(),
             idtr_base: self.vmcb.save.idtr.base,
             idtr_limit: self.vmcb.save.idtr.limit,
+            intr_exiting: false,
+            time_slice: 0,
         })
     }
 
@@ -51,23 +56,32 @@ impl VcpuAccessEnclaveState for Vcpu {
 
         self.vmcb.control.nest_cr3 = state.hv_page_table_root as _;
         self.vmcb.control.tlb_control = VmcbTlbControl::FlushAsid as _;
-        self.vmcb.control.clean_bits -=
-            VmcbCleanBits::I | VmcbCleanBits::DT | VmcbCleanBits::NP | VmcbCleanBits::CR_X;
+        self.vmcb.control.clean_bits -= VmcbCleanBits::I
+            | VmcbCleanBits::IOPM
+            | VmcbCleanBits::DT
+            | VmcbCleanBits::NP
+            | VmcbCleanBits::CR_X;
 
-        // Intercept enclave exceptions.
+        // Intercept enclave exceptions, and switch the MSR access policy.
         if is_enter {
             self.vmcb.control.intercept_exceptions = 0xffff_ffff;
+            self.vmcb.control.msrpm_base_pa = ENCLAVE_MSRPM.paddr() as _;
         } else {
             self.vmcb.control.intercept_exceptions = 0;
+            self.vmcb.control.msrpm_base_pa = NORMAL_WORLD_MSRPM.paddr() as _;
         }
 
-        if cfg!(feature = "enclave_interrupt") {
+        if is_enter && state.intr_exiting {
             // Enable interrupts during enclave running.
-            if is_enter {
-                self.vmcb.set_intercept(SvmIntercept::INTR, true);
-            } else {
-                self.vmcb.set_intercept(SvmIntercept::INTR, false);
-            }
+            self.vmcb.set_intercept(SvmIntercept::INTR, true);
+        } else if is_enter && state.time_slice != 0 {
+            // SVM has no preemption timer, intercept interrupts during enclave
+            // running so that host interrupts, the timer tick included, end the
+            // enclave execution and bound it.
+            self.vmcb.save.rflags |= RFlags::INTERRUPT_FLAG.bits();
+            self.vmcb.set_intercept(SvmIntercept::INTR, true);
+        } else {
+            self.vmcb.set_intercept(SvmIntercept::INTR, false);
         }
 
         self.set_xcr0(state.xcr0);
@@ -77,4 +91,9 @@ impl VcpuAccessEnclaveState for Vcpu {
         }
         Ok(())
     }
+
+    fn flush_tlb(&mut self) -> HvResult {
+        self.vmcb.control.tlb_control = VmcbTlbControl::FlushAsid as _;
+        Ok(())
+    }
 }
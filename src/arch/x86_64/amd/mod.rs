@@ -36,3 +36,9 @@ pub fn check_hypervisor_feature() -> HvResult {
     }
     Ok(())
 }
+
+/// SVM has no preemption timer, enclave execution is bounded by intercepting
+/// the host timer interrupt instead, which is always available.
+pub fn enclave_time_slice_supported() -> bool {
+    true
+}
@@ -26,15 +26,67 @@ use x86_64::registers::model_specific::{Efer, EferFlags};
 use x86_64::registers::rflags::RFlags;
 use x86_64::structures::DescriptorTablePointer;
 
+use crate::arch::msr_policy::{pat_valid, MsrPolicy, ENCLAVE_MSR_POLICY, NORMAL_WORLD_MSR_POLICY};
 use crate::arch::segmentation::Segment;
 use crate::arch::vmm::VcpuAccessGuestState;
-use crate::arch::{GuestPageTableImmut, GuestRegisters, LinuxContext};
+use crate::arch::{rdmsr_safe, wrmsr_safe, GuestPageTableImmut, GuestRegisters, LinuxContext};
 use crate::cell::Cell;
 use crate::error::HvResult;
 use crate::memory::addr::{phys_encrypted, virt_to_phys};
-use crate::memory::{Frame, GenericPageTableImmut};
+use crate::memory::{AlignedPage, Frame, GenericPageTableImmut, PAGE_SIZE};
 use crate::percpu::PerCpu;
 
+/// MSR permission map, two bits per MSR for reading and writing.
+#[repr(C)]
+pub(super) struct MsrPermissionMap([AlignedPage; 2]);
+
+impl MsrPermissionMap {
+    /// Builds the map that intercepts the MSR accesses `policy` does not pass
+    /// through. MSRs out of the ranges covered by the map are always
+    /// intercepted by hardware.
+    pub fn new(policy: &MsrPolicy) -> Self {
+        let mut map = Self([AlignedPage::new(), AlignedPage::new()]);
+        for base in [0, 0xc000_0000, 0xc001_0000] {
+            for msr in base..=base + 0x1fff {
+                for is_write in [false, true] {
+                    if policy.action(msr, is_write).is_intercepted() {
+                        map.mask(msr, is_write);
+                    }
+                }
+            }
+        }
+        map
+    }
+
+    fn mask(&mut self, msr: u32, is_write: bool) {
+        // (AMD APM Volume 2, Section 15.11, MSR Intercepts)
+        // The MSRPM consists of three 2-KByte vectors:
+        // 1. 0x0000..0x07FF: MSRs 0x0000_0000..0x0000_1FFF
+        // 2. 0x0800..0x0FFF: MSRs 0xC000_0000..0xC000_1FFF
+        // 3. 0x1000..0x17FF: MSRs 0xC001_0000..0xC001_1FFF
+        // The even bit intercepts reading, and the odd bit intercepts writing.
+        let vector_offset = match msr >> 16 {
+            0 => 0,
+            0xc000 => 0x800,
+            _ => 0x1000,
+        };
+        let bit = (msr & 0x1fff) as usize * 2 + is_write as usize;
+        let byte = vector_offset + bit / 8;
+        self.0[byte / PAGE_SIZE][byte % PAGE_SIZE] |= 1 << (bit % 8);
+    }
+
+    pub fn paddr(&self) -> usize {
+        phys_encrypted(virt_to_phys(self.0.as_ptr() as usize))
+    }
+}
+
+lazy_static! {
+    pub(super) static ref NORMAL_WORLD_MSRPM: MsrPermissionMap =
+        MsrPermissionMap::new(&NORMAL_WORLD_MSR_POLICY);
+    pub(super) static ref ENCLAVE_MSRPM: MsrPermissionMap =
+        MsrPermissionMap::new(&ENCLAVE_MSR_POLICY);
+}
+
 #[repr(C)]
 pub struct Vcpu {
     /// Save guest general registers when handle VM exits.
@@ -148,6 +200,24 @@ impl Vcpu {
         Ok(())
     }
 
+    /// Whether an NMI can be injected on the next VMRUN: no other event is
+    /// being injected, and the guest is not in an interrupt shadow.
+    pub fn nmi_injectable(&self) -> HvResult<bool> {
+        let event = VmcbIntInfo::from_bits_truncate(self.vmcb.control.event_inj);
+        Ok(!event.contains(VmcbIntInfo::VALID) && self.vmcb.control.int_state & 1 == 0)
+    }
+
+    pub fn inject_nmi(&mut self) -> HvResult {
+        self.vmcb.inject_event(
+            VmcbIntInfo::from(
+                InterruptType::NMI,
+                crate::arch::ExceptionType::NonMaskableInterrupt,
+            ),
+            0,
+        );
+        Ok(())
+    }
+
     pub fn advance_rip(&mut self, instr_len: u8) -> HvResult {
         self.vmcb.save.rip += instr_len as u64;
         Ok(())
@@ -231,6 +301,7 @@ impl Vcpu {
         vmcb.clean_bits = VmcbCleanBits::empty(); // Explicitly mark all of the state as new
         vmcb.nest_cr3 = cell.gpm.page_table().root_paddr() as _;
         vmcb.tlb_control = VmcbTlbControl::FlushAsid as _;
+        vmcb.msrpm_base_pa = NORMAL_WORLD_MSRPM.paddr() as _;
 
         self.vmcb.set_intercept(SvmIntercept::NMI, true);
         self.vmcb.set_intercept(SvmIntercept::CPUID, true);
@@ -242,6 +313,20 @@ impl Vcpu {
         self.vmcb.set_intercept(SvmIntercept::STGI, true);
         self.vmcb.set_intercept(SvmIntercept::CLGI, true);
         self.vmcb.set_intercept(SvmIntercept::SKINIT, true);
+        self.vmcb.set_intercept(SvmIntercept::MSR_PROT, true);
+    }
+
+    /// Fails unless the physical MSRs of the VMLOAD state still hold the guest
+    /// values. The hypervisor executes VMLOAD only in `activate_vmm` and never
+    /// executes VMSAVE, so this holds as long as the guest cannot execute them.
+    fn check_guest_msrs_loaded(&self) -> HvResult {
+        let intercepts = [SvmIntercept::VMLOAD, SvmIntercept::VMSAVE]
+            .iter()
+            .fold(0, |bits, &which| bits | 1 << (which as u8 - 0x80));
+        if self.vmcb.control.intercept_vector4 & intercepts != intercepts {
+            return hv_result_err!(EIO, "Guest MSRs are not loaded");
+        }
+        Ok(())
     }
 
     fn load_vmcb_guest(&self, linux: &mut LinuxContext) {
@@ -325,6 +410,41 @@ impl VcpuAccessGuestState for Vcpu {
             _ => unreachable!(),
         }
     }
+
+    fn read_msr(&self, msr: u32) -> HvResult<Option<u64>> {
+        // VMRUN and #VMEXIT do not switch the other MSRs: the ones in the VMLOAD
+        // state (KERNEL_GS_BASE, STAR, LSTAR, CSTAR, SFMASK and SYSENTER_*) are
+        // loaded only once by VMLOAD on activation, and the rest are never
+        // switched, so the physical MSRs hold the guest values.
+        Ok(match msr {
+            0x277 => Some(self.vmcb.save.g_pat),
+            0xc000_0080 => Some(self.vmcb.save.efer),
+            _ => {
+                self.check_guest_msrs_loaded()?;
+                rdmsr_safe(msr)
+            }
+        })
+    }
+
+    fn write_msr(&mut self, msr: u32, val: u64) -> HvResult<bool> {
+        match msr {
+            // An invalid PAT would fail VMRUN instead of raising #GP.
+            0x277 if !pat_valid(val) => return Ok(false),
+            0x277 => {
+                self.vmcb.save.g_pat = val;
+                self.vmcb.control.clean_bits -= VmcbCleanBits::NP;
+            }
+            0xc000_0080 => {
+                self.vmcb.save.efer = val | EferFlags::SECURE_VIRTUAL_MACHINE_ENABLE.bits();
+                self.vmcb.control.clean_bits -= VmcbCleanBits::CR_X;
+            }
+            _ => {
+                self.check_guest_msrs_loaded()?;
+                return Ok(wrmsr_safe(msr, val));
+            }
+        }
+        Ok(true)
+    }
 }
 
 impl Debug for Vcpu {
@@ -126,19 +126,27 @@ impl VmExit<'_> {
     }
 
     fn handle_interrupt(&mut self, exit_info: &VmExitInfo) -> HvResult {
-        let now = Instant::now();
-
         debug!(
             "#VMEXIT(INTR) @ RIP({:#x}): {:#x?}",
             exit_info.guest_rip, exit_info,
         );
 
         if self.cpu_data.state == CpuState::EnclaveRunning {
+            // Host interrupts always take the AEX at once, the time slice only
+            // tells whether this one ended the slice of the enclave.
+            let time_slice_expired = self.cpu_data.enclave_time_slice_expired();
+            let now = Instant::now();
             match self.cpu_data.enclave_aex(AexException {
                 vec: ExceptionType::IrqStart,
                 misc: None,
             }) {
-                Ok(enclave) => enclave.atomic_add_stats(EnclaveStatsId::Aex, now.elapsed()),
+                Ok(enclave) => {
+                    let elapsed = now.elapsed();
+                    enclave.atomic_add_stats(EnclaveStatsId::Aex, elapsed);
+                    if time_slice_expired {
+                        enclave.atomic_add_stats(EnclaveStatsId::ForcedAex, elapsed);
+                    }
+                }
                 Err(e) => {
                     warn!("Enclave AEX failed!: {:x?}", e);
                     self.cpu_data.fault()?;
@@ -157,9 +165,14 @@ impl VmExit<'_> {
     fn handle_nested_page_fault(&mut self, exit_info: &VmExitInfo) -> HvResult {
         let npt_vio_info = NptViolationInfo::from_exit_info(exit_info);
         if self.cpu_data.state == CpuState::EnclaveRunning {
+            let now = Instant::now();
             let enclave = self.cpu_data.get_current_enclave()?;
-            enclave
-                .handle_npt_violation(npt_vio_info.guest_paddr, npt_vio_info.final_translation)?;
+            if enclave
+                .handle_npt_violation(npt_vio_info.guest_paddr, npt_vio_info.final_translation)?
+            {
+                // A page of the clone is being copied, retry after ERESUME.
+                self.forced_aex(now, EnclaveStatsId::CowRetryAex)?;
+            }
             return Ok(());
         }
         warn!(
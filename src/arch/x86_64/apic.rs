@@ -0,0 +1,86 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Local APIC access, to send NMIs to other CPUs, through the APIC drivers of
+//! the `x86` crate. Linux owns the local APIC: the drivers are never attached,
+//! only IPIs are sent, in the mode chosen by Linux.
+
+use core::slice;
+
+use libvmm::msr::Msr;
+use x86::apic::x2apic::X2APIC;
+use x86::apic::xapic::XAPIC;
+use x86::apic::{
+    ApicControl, ApicId, DeliveryMode, DeliveryStatus, DestinationMode, DestinationShorthand, Icr,
+    Level, TriggerMode,
+};
+
+use crate::memory::{HostPhysAddr, PAGE_SIZE};
+
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+fn x2apic_enabled() -> bool {
+    Msr::IA32_APIC_BASE.read() & APIC_BASE_X2APIC_ENABLE != 0
+}
+
+/// Physical address and size of the xAPIC registers, mapped at the same
+/// address in the hypervisor.
+pub fn xapic_region() -> (HostPhysAddr, usize) {
+    let base = Msr::IA32_APIC_BASE.read() & APIC_BASE_ADDR_MASK;
+    (base as HostPhysAddr, PAGE_SIZE)
+}
+
+fn xapic() -> XAPIC {
+    let (base, size) = xapic_region();
+    XAPIC::new(unsafe { slice::from_raw_parts_mut(base as *mut u32, size / 4) })
+}
+
+/// ID of the local APIC of the current CPU, the destination of `send_nmi`.
+pub fn local_apic_id() -> u32 {
+    if x2apic_enabled() {
+        X2APIC::new().id()
+    } else {
+        xapic().id() >> 24
+    }
+}
+
+/// Sends an NMI to the CPU whose local APIC ID is `apic_id`.
+pub fn send_nmi(apic_id: u32) {
+    if x2apic_enabled() {
+        let icr = Icr::for_x2apic(
+            0,
+            ApicId::X2Apic(apic_id),
+            DestinationShorthand::NoShorthand,
+            DeliveryMode::NMI,
+            DestinationMode::Physical,
+            DeliveryStatus::Idle,
+            Level::Assert,
+            TriggerMode::Edge,
+        );
+        unsafe { X2APIC::new().send_ipi(icr) };
+    } else {
+        let icr = Icr::for_xapic(
+            0,
+            ApicId::XApic(apic_id as u8),
+            DestinationShorthand::NoShorthand,
+            DeliveryMode::NMI,
+            DestinationMode::Physical,
+            DeliveryStatus::Idle,
+            Level::Assert,
+            TriggerMode::Edge,
+        );
+        unsafe { xapic().send_ipi(icr) };
+    }
+}
@@ -0,0 +1,79 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use libvmm::msr::Msr;
+use x86_64::registers::control::Cr4Flags;
+
+/// IA32_U_CET.SUPPRESS: suppress indirect branch tracking.
+const U_CET_SUPPRESS: u64 = 1 << 10;
+/// IA32_U_CET.TRACKER: WAIT_FOR_ENDBRANCH state of indirect branch tracking.
+const U_CET_TRACKER: u64 = 1 << 11;
+/// IA32_U_CET bits 63:12, base of the legacy code page bitmap.
+const U_CET_LEG_BITMAP_MASK: u64 = !0xfff;
+
+/// User mode CET state, switched when entering or leaving an enclave with CET enabled.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CetUserState {
+    /// IA32_U_CET.
+    pub u_cet: u64,
+    /// IA32_PL3_SSP.
+    pub ssp: u64,
+}
+
+impl CetUserState {
+    /// CET takes effect only when it is enabled by the guest OS (CR4.CET = 1).
+    pub fn is_enabled(cr4: u64) -> bool {
+        Cr4Flags::from_bits_truncate(cr4).contains(Cr4Flags::CONTROL_FLOW_ENFORCEMENT)
+    }
+
+    /// Initial state for an enclave thread, built from `SECS.CET_ATTRIBUTES` and
+    /// the linear address of the legacy code page bitmap.
+    pub fn new(cet_attributes: u8, leg_bitmap_base: u64, ssp: u64) -> Self {
+        Self {
+            u_cet: cet_attributes as u64 | (leg_bitmap_base & U_CET_LEG_BITMAP_MASK),
+            ssp,
+        }
+    }
+
+    pub fn load() -> Self {
+        Self {
+            u_cet: Msr::IA32_U_CET.read(),
+            ssp: Msr::IA32_PL3_SSP.read(),
+        }
+    }
+
+    pub fn store(&self) {
+        unsafe {
+            Msr::IA32_U_CET.write(self.u_cet);
+            Msr::IA32_PL3_SSP.write(self.ssp);
+        }
+    }
+
+    /// Indirect branch tracking state in the format of a CET SSA frame.
+    pub fn ib_track_state(&self) -> u64 {
+        let suppress = (self.u_cet & U_CET_SUPPRESS != 0) as u64;
+        let tracker = (self.u_cet & U_CET_TRACKER != 0) as u64;
+        suppress | tracker << 1
+    }
+
+    pub fn set_ib_track_state(&mut self, ib_track_state: u64) {
+        self.u_cet &= !(U_CET_SUPPRESS | U_CET_TRACKER);
+        if ib_track_state & 0b01 != 0 {
+            self.u_cet |= U_CET_SUPPRESS;
+        }
+        if ib_track_state & 0b10 != 0 {
+            self.u_cet |= U_CET_TRACKER;
+        }
+    }
+}
@@ -26,6 +26,19 @@ pub fn time_now() -> u64 {
     unsafe { core::arch::x86_64::_rdtsc() }
 }
 
+/// The frequency of `time_now`, if the CPU enumerates it.
+pub fn time_freq_mhz() -> Option<u64> {
+    let cpuid = super::cpuid::CpuId::new();
+    if let Some(hz) = cpuid.get_tsc_info().and_then(|info| info.tsc_frequency()) {
+        return Some(hz / 1_000_000);
+    }
+    // the TSC runs at the base frequency on processors without leaf 0x15
+    cpuid
+        .get_processor_frequency_info()
+        .map(|info| info.processor_base_frequency() as u64)
+        .filter(|&mhz| mhz != 0)
+}
+
 pub fn check_cpuid() -> HvResult {
     let features = CpuFeatures::new();
     // CR4.PAE will be set in HOST_CR4
@@ -14,20 +14,30 @@
 
 #![cfg_attr(not(feature = "intel"), allow(dead_code))]
 
+use alloc::vec::Vec;
 use bitflags::bitflags;
+use core::{mem::size_of, slice};
 
 pub use raw_cpuid::{cpuid, CpuId};
 
+use super::xsave::xsave_size;
+use crate::config::HvSystemConfig;
+
 #[repr(u32)]
 #[derive(Debug)]
 #[allow(dead_code)]
 pub(super) enum CpuIdEax {
     VendorInfo = 0x0,
     FeatureInfo = 0x1,
+    CacheParameters = 0x4,
+    ExtendedFeatureInfo = 0x7,
     ExtendedStateInfo = 0xD,
     HypervisorInfo = 0x4000_0000,
     HypervisorFeatures = 0x4000_0001,
+    HypervisorMax = 0x4000_00ff,
+    ExtendedFunctionInfo = 0x8000_0000,
     AmdFeatureInfo = 0x8000_0001,
+    AddressSizeInfo = 0x8000_0008,
 }
 
 bitflags! {
@@ -167,6 +177,29 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// HyperEnclave capabilities, reported in CPUID.(EAX=4000_0001H):EAX.
+    pub(super) struct HypervisorFeatureFlags: u32 {
+        /// SGX-compatible enclaves are supported through hypercalls.
+        const ENCLAVE = 1 << 0;
+        /// Enclave dynamic memory management (EAUG, EMODPR, EMODT, EACCEPT).
+        const EDMM = 1 << 1;
+        /// AEX-Notify and EDECCSSA.
+        const AEX_NOTIFY = 1 << 2;
+        /// Per-enclave time slices.
+        const ENCLAVE_TIME_SLICE = 1 << 3;
+        /// CPUID emulation inside enclaves.
+        const ENCLAVE_CPUID = 1 << 4;
+        /// CET shadow stacks inside enclaves.
+        const ENCLAVE_CET_SS = 1 << 5;
+        /// Interrupts cause an AEX by default.
+        const ENCLAVE_INTR_EXITING = 1 << 6;
+        /// CPUID leaves are adjusted by the policies of `HvSystemConfig`, in and
+        /// out of enclaves.
+        const CPUID_POLICY = 1 << 7;
+    }
+}
+
 pub struct CpuFeatures {
     cpuid: CpuId,
 }
@@ -186,6 +219,23 @@ impl CpuFeatures {
         }
     }
 
+    /// Number of general-purpose performance counters per logical processor.
+    pub fn perf_monitor_counters(&self) -> u8 {
+        if let Some(info) = self.cpuid.get_performance_monitoring_info() {
+            info.number_of_counters()
+        } else {
+            0
+        }
+    }
+
+    /// AMD core performance counter extensions, enumerated by
+    /// CPUID.80000001H:ECX[bit 23].
+    pub fn has_perf_ctr_ext_core(&self) -> bool {
+        let max_ext_leaf = cpuid!(CpuIdEax::ExtendedFunctionInfo).eax;
+        max_ext_leaf >= CpuIdEax::AmdFeatureInfo as u32
+            && cpuid!(CpuIdEax::AmdFeatureInfo).ecx & (1 << 23) != 0
+    }
+
     pub fn has_vmx(&self) -> bool {
         if let Some(info) = self.cpuid.get_feature_info() {
             info.has_vmx()
@@ -194,6 +244,15 @@ impl CpuFeatures {
         }
     }
 
+    /// Safer Mode Extensions, which Intel TXT launches through.
+    pub fn has_smx(&self) -> bool {
+        if let Some(info) = self.cpuid.get_feature_info() {
+            info.has_smx()
+        } else {
+            false
+        }
+    }
+
     pub fn has_xsave(&self) -> bool {
         if let Some(info) = self.cpuid.get_feature_info() {
             info.has_xsave()
@@ -226,6 +285,69 @@ impl CpuFeatures {
         }
     }
 
+    pub fn has_cet_ss(&self) -> bool {
+        if let Some(info) = self.cpuid.get_extended_feature_info() {
+            info.has_cet_ss()
+        } else {
+            false
+        }
+    }
+
+    pub fn has_cet_ibt(&self) -> bool {
+        if self.cpuid.get_extended_feature_info().is_some() {
+            // CPUID.(EAX=07H, ECX=0):EDX[bit 20]
+            let res = cpuid!(CpuIdEax::ExtendedFeatureInfo, 0);
+            res.edx & (1 << 20) != 0
+        } else {
+            false
+        }
+    }
+
+    /// CPUID.(EAX=07H, ECX=0):EDX bits of the speculative-execution controls.
+    fn extended_feature_edx(&self, bit: u32) -> bool {
+        if self.cpuid.get_extended_feature_info().is_some() {
+            let res = cpuid!(CpuIdEax::ExtendedFeatureInfo, 0);
+            res.edx & (1 << bit) != 0
+        } else {
+            false
+        }
+    }
+
+    /// VERW clears the CPU buffers (MD_CLEAR).
+    pub fn has_md_clear(&self) -> bool {
+        self.extended_feature_edx(10)
+    }
+
+    /// IA32_FLUSH_CMD is supported (L1D_FLUSH).
+    pub fn has_l1d_flush(&self) -> bool {
+        self.extended_feature_edx(28)
+    }
+
+    /// IA32_ARCH_CAPABILITIES is supported.
+    pub fn has_arch_capabilities(&self) -> bool {
+        self.extended_feature_edx(29)
+    }
+
+    /// IA32_PRED_CMD.IBPB is supported, enumerated by CPUID.(EAX=07H, ECX=0):EDX[bit 26]
+    /// on Intel, or by CPUID.80000008H:EBX[bit 12] on AMD.
+    pub fn has_ibpb(&self) -> bool {
+        if self.extended_feature_edx(26) {
+            return true;
+        }
+        let max_ext_leaf = cpuid!(CpuIdEax::ExtendedFunctionInfo).eax;
+        max_ext_leaf >= CpuIdEax::AddressSizeInfo as u32
+            && cpuid!(CpuIdEax::AddressSizeInfo).ebx & (1 << 12) != 0
+    }
+
+    /// Restricted Transactional Memory of TSX is supported.
+    pub fn has_rtm(&self) -> bool {
+        if let Some(info) = self.cpuid.get_extended_feature_info() {
+            info.has_rtm()
+        } else {
+            false
+        }
+    }
+
     pub fn has_xsaves_xrstors(&self) -> bool {
         if let Some(info) = self.cpuid.get_extended_state_info() {
             info.has_xsaves_xrstors()
@@ -240,7 +362,7 @@ impl CpuFeatures {
         if self.cpuid.get_extended_state_info().is_some() && sub_leaf > 1 {
             let res = cpuid!(CpuIdEax::ExtendedStateInfo, sub_leaf);
             // If ECX contains an invalid sub-leaf index, EAX/EBX/ECX/EDX return 0
-            if res.eax != 0 && res.ebx != 0 && res.ecx != 0 && res.edx != 0 {
+            if res.eax != 0 {
                 // Bit 00 in ECX is clear if sub_leaf n is supported in XCR0
                 if res.ecx & 0b1 == 0b0 {
                     let size = res.eax;
@@ -253,6 +375,19 @@ impl CpuFeatures {
         (0, 0)
     }
 
+    /// Information (`size`, `aligned`) for extended state component specified by `sub_leaf`
+    /// in compacted format, `aligned` is true if the component is 64-byte aligned.
+    /// Both user and supervisor state components are reported.
+    pub fn xsave_compacted_state_info(&self, sub_leaf: u32) -> (usize, bool) {
+        if self.cpuid.get_extended_state_info().is_some() && sub_leaf > 1 {
+            let res = cpuid!(CpuIdEax::ExtendedStateInfo, sub_leaf);
+            // Bit 01 in ECX is set if the component is aligned to the next 64-byte boundary
+            return (res.eax as _, res.ecx & 0b10 != 0);
+        }
+
+        (0, false)
+    }
+
     pub fn xcr0_supported_bits(&self) -> u64 {
         if self.cpuid.get_extended_state_info().is_some() {
             let res = cpuid!(CpuIdEax::ExtendedStateInfo, 0);
@@ -262,3 +397,198 @@ impl CpuFeatures {
         }
     }
 }
+
+/// Maximum number of sub-leaves of CPUID leaf 4 served to enclaves.
+const ENCLAVE_CPUID_MAX_CACHE_SUB_LEAVES: u32 = 8;
+/// Maximum sub-leaf of CPUID leaf 7 served to enclaves.
+const ENCLAVE_CPUID_MAX_FEATURE_SUB_LEAF: u32 = 2;
+
+/// A CPUID leaf (and sub-leaf) served to the code inside an enclave.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CpuIdEntry {
+    pub leaf: u32,
+    pub sub_leaf: u32,
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/// CPUID leaves served by hypervisor to the code inside an enclave that opts in
+/// to CPUID emulation.
+///
+/// The table is taken once when the enclave is created, so the enclave sees the
+/// same values on every CPU and the host cannot change them afterwards. Only the
+/// vendor, feature, cache, extended state and address size leaves are served.
+/// Leaves that identify the platform (APIC IDs, topology, serial number, SGX,
+/// hypervisor) read as 0, as do the features enclaves cannot use (SGX, VMX...)
+/// and the extended states outside XFRM.
+pub struct EnclaveCpuIdTable {
+    entries: Vec<CpuIdEntry>,
+}
+
+impl EnclaveCpuIdTable {
+    /// Build the table for an enclave whose extended states are `xfrm`.
+    pub fn new(xfrm: u64) -> Self {
+        let mut table = Self {
+            entries: Vec::new(),
+        };
+
+        let max_leaf = cpuid!(CpuIdEax::VendorInfo).eax;
+        let max_leaf = max_leaf.min(CpuIdEax::ExtendedStateInfo as u32);
+        for leaf in 0..=max_leaf {
+            match leaf {
+                0x0 | 0x1 => table.push(leaf, 0),
+                0x4 => {
+                    for sub_leaf in 0..ENCLAVE_CPUID_MAX_CACHE_SUB_LEAVES {
+                        // EAX[4:0] is 0 for no more caches.
+                        if table.push(leaf, sub_leaf).eax & 0x1f == 0 {
+                            break;
+                        }
+                    }
+                }
+                0x7 => {
+                    let max_sub_leaf = table.push(leaf, 0).eax;
+                    for sub_leaf in 1..=max_sub_leaf.min(ENCLAVE_CPUID_MAX_FEATURE_SUB_LEAF) {
+                        table.push(leaf, sub_leaf);
+                    }
+                }
+                0xd => {
+                    table.push(leaf, 0);
+                    table.push(leaf, 1);
+                    for sub_leaf in 2..=63 {
+                        if xfrm >> sub_leaf & 0b1 == 0b1 {
+                            table.push(leaf, sub_leaf);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let max_ext_leaf = cpuid!(CpuIdEax::ExtendedFunctionInfo).eax;
+        let max_ext_leaf = max_ext_leaf.min(CpuIdEax::AddressSizeInfo as u32);
+        for leaf in CpuIdEax::ExtendedFunctionInfo as u32..=max_ext_leaf {
+            table.push(leaf, 0);
+        }
+
+        table.filter(xfrm);
+        table
+    }
+
+    fn push(&mut self, leaf: u32, sub_leaf: u32) -> CpuIdEntry {
+        let res = cpuid!(leaf, sub_leaf);
+        let entry = CpuIdEntry {
+            leaf,
+            sub_leaf,
+            eax: res.eax,
+            ebx: res.ebx,
+            ecx: res.ecx,
+            edx: res.edx,
+        };
+        self.entries.push(entry);
+        entry
+    }
+
+    /// Mask the values that identify the platform or describe the features that
+    /// are not available inside the enclave, then apply the CPUID policies of the
+    /// system as for Linux.
+    fn filter(&mut self, xfrm: u64) {
+        let xsave_size = xsave_size(xfrm) as u32;
+        for entry in self.entries.iter_mut() {
+            match (entry.leaf, entry.sub_leaf) {
+                (0x0, _) => entry.eax = entry.eax.min(CpuIdEax::ExtendedStateInfo as u32),
+                (0x1, _) => {
+                    // EBX[31:24]: initial APIC ID.
+                    entry.ebx &= 0x00ff_ffff;
+                    let mut flags = FeatureInfoFlags::from_bits_truncate(
+                        entry.ecx as u64 | (entry.edx as u64) << 32,
+                    );
+                    flags.remove(
+                        FeatureInfoFlags::MONITOR
+                            | FeatureInfoFlags::DSCPL
+                            | FeatureInfoFlags::VMX
+                            | FeatureInfoFlags::SMX
+                            | FeatureInfoFlags::EIST
+                            | FeatureInfoFlags::TM2
+                            | FeatureInfoFlags::PDCM
+                            | FeatureInfoFlags::DCA
+                            | FeatureInfoFlags::PSN
+                            | FeatureInfoFlags::DS
+                            | FeatureInfoFlags::ACPI
+                            | FeatureInfoFlags::TM
+                            | FeatureInfoFlags::PBE,
+                    );
+                    flags.insert(FeatureInfoFlags::OSXSAVE | FeatureInfoFlags::HYPERVISOR);
+                    entry.ecx = flags.bits() as u32;
+                    entry.edx = (flags.bits() >> 32) as u32;
+                }
+                // EAX[31:26]: maximum number of addressable IDs for processor cores.
+                (0x4, _) => entry.eax &= 0x03ff_ffff,
+                (0x7, 0) => {
+                    // EBX[2]: SGX, ECX[30]: SGX_LC, EDX[1]: SGX-KEYS.
+                    entry.ebx &= !(1 << 2);
+                    entry.ecx &= !(1 << 30);
+                    entry.edx &= !(1 << 1);
+                }
+                (0xd, 0) => {
+                    // XCR0 equals XFRM inside the enclave.
+                    entry.eax &= xfrm as u32;
+                    entry.edx &= (xfrm >> 32) as u32;
+                    entry.ebx = xsave_size;
+                    entry.ecx = xsave_size;
+                }
+                (0xd, 1) => {
+                    // Supervisor states are not visible to the enclave.
+                    entry.ebx = 0;
+                    entry.ecx = 0;
+                    entry.edx = 0;
+                }
+                (leaf, _) if leaf == CpuIdEax::ExtendedFunctionInfo as u32 => {
+                    entry.eax = entry.eax.min(CpuIdEax::AddressSizeInfo as u32)
+                }
+                (leaf, _) if leaf == CpuIdEax::AmdFeatureInfo as u32 => {
+                    entry.ecx &= !(FeatureInfoFlags::SVM.bits() as u32);
+                }
+                _ => {}
+            }
+            let mut regs = [entry.eax, entry.ebx, entry.ecx, entry.edx];
+            for policy in HvSystemConfig::get().cpuid_policies() {
+                if policy.matches(entry.leaf, entry.sub_leaf) {
+                    policy.apply(&mut regs);
+                }
+            }
+            entry.eax = regs[0];
+            entry.ebx = regs[1];
+            entry.ecx = regs[2];
+            entry.edx = regs[3];
+        }
+    }
+
+    fn is_indexed(leaf: u32) -> bool {
+        leaf == CpuIdEax::CacheParameters as u32
+            || leaf == CpuIdEax::ExtendedFeatureInfo as u32
+            || leaf == CpuIdEax::ExtendedStateInfo as u32
+    }
+
+    /// Values of CPUID (`leaf`, `sub_leaf`), all 0 if the leaf is not served.
+    pub fn get(&self, leaf: u32, sub_leaf: u32) -> CpuIdEntry {
+        let sub_leaf = if Self::is_indexed(leaf) { sub_leaf } else { 0 };
+        self.entries
+            .iter()
+            .find(|e| e.leaf == leaf && e.sub_leaf == sub_leaf)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Raw bytes of the table, which are measured and reported.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(
+                self.entries.as_ptr() as *const u8,
+                self.entries.len() * size_of::<CpuIdEntry>(),
+            )
+        }
+    }
+}
@@ -14,8 +14,11 @@
 
 use super::cpuid::CpuFeatures;
 use super::exception::{ExceptionInfo, ExceptionType, PageFaultErrorCode};
-use super::xsave::{XSAVE_HEADER_SIZE, XSAVE_LEGACY_REGION_SIZE, XSAVE_SYNTHETIC_STATE};
-use crate::enclave::sgx::{GprSgx, MiscSgx, SgxExitInfo, SgxSecs, StateSaveArea, SSA_FRAME_SIZE};
+use super::xsave::{xsave_size, NormalWorldXsaveRegion, XsaveRegion};
+use super::xsave::{XSAVE_REGION_SIZE, XSAVE_SYNTHETIC_STATE};
+use crate::enclave::sgx::{GprSgx, MiscSgx, SgxExitInfo, SgxSecs, SsaFrame};
+use crate::enclave::sgx::{SgxAttributeFlags, SgxCetAttributes};
+use crate::enclave::structs::HvEnclFeatures;
 use crate::enclave::{AexException, Enclave, VcpuAccessEnclaveState};
 use crate::error::HvResult;
 use crate::memory::addr::{align_down, is_aligned, GuestPhysAddr, GuestVirtAddr, HostPhysAddr};
@@ -32,6 +35,9 @@ use x86_64::registers::rflags::RFlags;
 
 /// Intel SDM, Volume 3, 38.7.3.ECREATE: The lower 2 bits of XFRM must be set
 pub const SECS_XFRM_TEMPLATE: u64 = Xcr0::XCR0_FPU_MMX_STATE.bits() | Xcr0::XCR0_SSE_STATE.bits();
+/// XCR0 bits for AMX state components, which are not defined in `Xcr0`.
+const XCR0_TILECFG_STATE: u64 = 1 << 17;
+const XCR0_TILEDATA_STATE: u64 = 1 << 18;
 
 bitflags! {
     #[repr(transparent)]
@@ -166,6 +172,12 @@ pub struct EnclaveThreadState {
     pub efer: u64,
     pub idtr_base: u64,
     pub idtr_limit: u32,
+
+    /// Whether interrupts cause an AEX. Always false for the normal world.
+    pub intr_exiting: bool,
+    /// TSC cycles the secure world may run before a forced AEX, 0 for no limit.
+    /// Always 0 for the normal world.
+    pub time_slice: u64,
 }
 
 impl EnclaveThreadState {
@@ -199,11 +211,14 @@ impl EnclaveThreadState {
         cssa: u32,
         hv_page_table_root: HostPhysAddr,
         page_table_root: HostPhysAddr,
+        intr_exiting: bool,
+        time_slice: u64,
+        normal_xsave_region: &mut NormalWorldXsaveRegion,
     ) -> HvResult {
         EnclaveThreadState::validate_xfrm(vcpu, xfrm)?;
 
         let mut rflags = vcpu.rflags();
-        if cfg!(feature = "enclave_interrupt") {
+        if intr_exiting {
             rflags |= RFlags::INTERRUPT_FLAG.bits(); // Enable IRQ
         } else {
             rflags &= !RFlags::INTERRUPT_FLAG.bits(); // Disable IRQ
@@ -220,9 +235,14 @@ impl EnclaveThreadState {
             efer,
             hv_page_table_root,
             page_table_root,
+            intr_exiting,
+            time_slice,
         };
         vcpu.regs_mut().rax = cssa as _;
         vcpu.regs_mut().rcx = vcpu.instr_pointer();
+        // Save normal world's extended state before enclave's XCR0 is loaded in
+        // `store_enclave_thread_state()`.
+        normal_xsave_region.save();
         vcpu.store_enclave_thread_state(entry_ip, &sec_world_state, true)?;
         Ok(())
     }
@@ -232,8 +252,10 @@ impl EnclaveThreadState {
         exit_ip: u64,
         aep: u64,
         normal_world_state: &Self,
+        normal_xsave_region: &mut NormalWorldXsaveRegion,
     ) -> HvResult {
         vcpu.store_enclave_thread_state(exit_ip, normal_world_state, false)?;
+        normal_xsave_region.restore();
         vcpu.regs_mut().rcx = aep;
         Ok(())
     }
@@ -244,11 +266,13 @@ impl EnclaveThreadState {
         aep: u64,
         xfrm: u64,
         tcs_vaddr: GuestVirtAddr,
-        ssa: &mut StateSaveArea,
+        ssa: &SsaFrame,
+        xsave_region: &mut XsaveRegion,
         normal_world_state: &Self,
+        normal_xsave_region: &mut NormalWorldXsaveRegion,
     ) -> HvResult {
         let regs = vcpu.regs();
-        let gpr = &mut ssa.gpr;
+        let gpr = ssa.gpr()?;
         gpr.rax = regs.rax;
         gpr.rcx = regs.rcx;
         gpr.rdx = regs.rdx;
@@ -272,7 +296,7 @@ impl EnclaveThreadState {
         gpr.gs_base = vcpu.gs_base();
 
         if let Some(misc_in) = aex_excep.misc {
-            let ssa_misc = &mut ssa.misc;
+            let ssa_misc = ssa.misc()?;
             ssa_misc.exinfo.maddr = misc_in.exinfo.maddr;
             ssa_misc.exinfo.errcd = misc_in.exinfo.errcd;
         }
@@ -281,14 +305,16 @@ impl EnclaveThreadState {
         // Which extended state will be saved is controlled by xfrm and enclave's XCR0.
         // Such operation should be placed before `store_enclave_thread_state()`,
         // since normal world's XCR0 will be restored in `store_enclave_thread_state()`.
-        let xsave_region = &mut ssa.xsave;
-        xsave_region.save(xfrm);
+        // The SSA frame may span several pages, so XSAVE is done in `xsave_region` first.
+        xsave_region.save(xfrm, ssa.xsave_size());
+        ssa.store_xsave(xsave_region)?;
         // Set extended feature to their init state.
         // Same as above, such operation is controlled by xfrm and enclave's XCR0,
         // it should be placed before `store_enclave_thread_state()`.
         XSAVE_SYNTHETIC_STATE.restore(xfrm);
 
         vcpu.store_enclave_thread_state(aep, normal_world_state, false)?;
+        normal_xsave_region.restore();
 
         let regs = vcpu.regs_mut();
         *regs = Default::default(); // scrub enclave context
@@ -305,14 +331,18 @@ impl EnclaveThreadState {
         xfrm: u64,
         hv_page_table_root: HostPhysAddr,
         page_table_root: HostPhysAddr,
-        ssa: &StateSaveArea,
+        ssa: &SsaFrame,
+        xsave_region: &mut XsaveRegion,
+        intr_exiting: bool,
+        time_slice: u64,
+        normal_xsave_region: &mut NormalWorldXsaveRegion,
     ) -> HvResult {
         EnclaveThreadState::validate_xfrm(vcpu, xfrm)?;
 
-        let xsave_region = &ssa.xsave;
+        ssa.load_xsave(xsave_region)?;
         xsave_region.validate_at_resume(xfrm)?;
 
-        let gpr = &ssa.gpr;
+        let gpr = ssa.gpr()?;
         // disable syscalls in efer
         let efer = vcpu.efer() - EferFlags::SYSTEM_CALL_EXTENSIONS.bits();
         let sec_world_state = Self {
@@ -325,7 +355,10 @@ impl EnclaveThreadState {
             efer,
             hv_page_table_root,
             page_table_root,
+            intr_exiting,
+            time_slice,
         };
+        normal_xsave_region.save();
         vcpu.store_enclave_thread_state(gpr.rip, &sec_world_state, true)?;
 
         // Restore the extended state into SSA.Xsave area.
@@ -357,6 +390,35 @@ impl EnclaveThreadState {
 }
 
 impl SgxSecs {
+    /// Intel SDM, Volume 1, 13.3: the state components in XCR0 that depend on each other.
+    fn validate_xfrm_dependencies(xfrm: u64) -> HvResult {
+        let sse = Xcr0::XCR0_SSE_STATE.bits();
+        let avx = Xcr0::XCR0_AVX_STATE.bits();
+        let mpx = Xcr0::XCR0_BNDREG_STATE.bits() | Xcr0::XCR0_BNDCSR_STATE.bits();
+        let avx512 = Xcr0::XCR0_OPMASK_STATE.bits()
+            | Xcr0::XCR0_ZMM_HI256_STATE.bits()
+            | Xcr0::XCR0_HI16_ZMM_STATE.bits();
+        let amx = XCR0_TILECFG_STATE | XCR0_TILEDATA_STATE;
+
+        let err = if xfrm & avx != 0 && xfrm & sse == 0 {
+            "AVX state requires SSE state"
+        } else if xfrm & mpx != 0 && xfrm & mpx != mpx {
+            "MPX BNDREG and BNDCSR states must be set together"
+        } else if xfrm & avx512 != 0 && xfrm & avx512 != avx512 {
+            "AVX-512 opmask, ZMM_Hi256 and Hi16_ZMM states must be set together"
+        } else if xfrm & avx512 != 0 && xfrm & avx == 0 {
+            "AVX-512 states require AVX state"
+        } else if xfrm & amx != 0 && xfrm & amx != amx {
+            "AMX TILECFG and TILEDATA states must be set together"
+        } else {
+            return Ok(());
+        };
+        hv_result_err!(
+            EINVAL,
+            format!("SgxSecs::validate(): invalid xfrm {:#x}, {}", xfrm, err)
+        )
+    }
+
     pub fn validate(&self) -> HvResult {
         if self.size < PAGE_SIZE as u64 || !self.size.is_power_of_two() {
             return hv_result_err!(
@@ -393,32 +455,29 @@ impl SgxSecs {
         // Intel SDM, Volume 3, 38.7.2.1:
         // If the processor does support XSAVE, XFRM must contain a value that would be legal if loaded into XCR0
         let xcr0_supported_bits = cpuid.xcr0_supported_bits();
-        if xfrm & xcr0_supported_bits != xfrm {
+        if xfrm & !xcr0_supported_bits != 0 {
             return hv_result_err!(
                 EINVAL,
                 format!(
-                    "SgxSecs::validate(): invalid xfrm {:#x}, xfrm must contain legal value if it set to xcr0",
-                    xfrm
+                    "SgxSecs::validate(): invalid xfrm {:#x}, features {:#x} are not supported in XCR0 {:#x}",
+                    xfrm,
+                    xfrm & !xcr0_supported_bits,
+                    xcr0_supported_bits
+                )
+            );
+        }
+        Self::validate_xfrm_dependencies(xfrm)?;
+
+        let xsave_size = xsave_size(xfrm);
+        if xsave_size > XSAVE_REGION_SIZE {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "SgxSecs::validate(): XSAVE region size {:#x} for xfrm {:#x} exceeds the max {:#x}",
+                    xsave_size, xfrm, XSAVE_REGION_SIZE
                 )
             );
         }
-        // Iterate all the bits set in xfrm to get the offset and size of CPU extended state component
-        // Follow the pseudo code provided by Intel SDM, Volume 3, 38.7.2.2
-        let xsave_size = {
-            let mut offset = XSAVE_LEGACY_REGION_SIZE + XSAVE_HEADER_SIZE;
-            let mut size = 0;
-            for sub_leaf in 2..=63 {
-                if xfrm >> sub_leaf & 0b1 == 0b1 {
-                    let (offset_res, size_res) = cpuid.xsave_state_info(sub_leaf);
-                    let tmp_offset = offset_res;
-                    if tmp_offset >= offset + size {
-                        offset = offset_res;
-                        size = size_res;
-                    }
-                }
-            }
-            offset + size
-        };
 
         let ssa_frame_size_needed =
             xsave_size + core::mem::size_of::<MiscSgx>() + core::mem::size_of::<GprSgx>();
@@ -427,18 +486,66 @@ impl SgxSecs {
             return hv_result_err!(
                 EINVAL,
                 format!(
-                    "SgxSecs::validate(): ssa_framsize {:#x} not enough",
-                    self.ssa_frame_size
+                    "SgxSecs::validate(): ssa_framsize {:#x} not enough, {:#x} bytes needed",
+                    self.ssa_frame_size, ssa_frame_size_needed
                 )
             );
         }
 
-        if ssa_frame_size_needed > SSA_FRAME_SIZE {
+        if HvEnclFeatures::from_bits(self.he_features.bits()).is_none() {
             return hv_result_err!(
                 EINVAL,
                 format!(
-                    "SgxSecs::validate(): the max SSA_FRAM_SIZE is {:#x} now",
-                    SSA_FRAME_SIZE
+                    "SgxSecs::validate(): reserved bits are set in he_features {:#x}",
+                    self.he_features.bits()
+                )
+            );
+        }
+
+        self.validate_cet(&cpuid)
+    }
+
+    fn validate_cet(&self, cpuid: &CpuFeatures) -> HvResult {
+        if !self.attributes.flags.contains(SgxAttributeFlags::CET) {
+            if self.cet_attributes != 0 || self.cet_leg_bitmap_offset != 0 {
+                return hv_result_err!(
+                    EINVAL,
+                    "SgxSecs::validate(): CET fields must be 0 if attributes.CET = 0"
+                );
+            }
+            return Ok(());
+        }
+
+        let cet_attributes = match SgxCetAttributes::from_bits(self.cet_attributes) {
+            Some(cet_attributes) => cet_attributes,
+            None => {
+                return hv_result_err!(
+                    EINVAL,
+                    format!(
+                        "SgxSecs::validate(): reserved bits are set in cet_attributes {:#x}",
+                        self.cet_attributes
+                    )
+                )
+            }
+        };
+        if cet_attributes.contains(SgxCetAttributes::SH_STK_EN) && !cpuid.has_cet_ss() {
+            return hv_result_err!(
+                EINVAL,
+                "SgxSecs::validate(): shadow stacks are not supported by the processor"
+            );
+        }
+        if cet_attributes.contains(SgxCetAttributes::ENDBR_EN) && !cpuid.has_cet_ibt() {
+            return hv_result_err!(
+                EINVAL,
+                "SgxSecs::validate(): indirect branch tracking is not supported by the processor"
+            );
+        }
+        if !is_aligned(self.cet_leg_bitmap_offset as _) || self.cet_leg_bitmap_offset >= self.size {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "SgxSecs::validate(): invalid cet_leg_bitmap_offset {:#x}",
+                    self.cet_leg_bitmap_offset
                 )
             );
         }
@@ -507,6 +614,15 @@ impl Enclave {
         } else if self.elrange().contains(&fault_gvaddr) {
             // Fix up #PF in elrange.
             self.fixup_pf_in_elrange(error_code, fault_gvaddr)
+        } else if self.shared_memory_access_denied(fault_gvaddr, error_code) {
+            // Access denied by the attributes of the shared memory range, mapping
+            // the page again would not help, deliver the #PF to the enclave.
+            let error_code = error_code | PageFaultErrorCode::PROTECTION_VIOLATION.bits();
+            Ok(Some(EnclaveExceptionInfo::page_fault_in_encl(
+                error_code,
+                error_code,
+                fault_gvaddr,
+            )))
         } else if self.shmem().read().contains(&fault_gvaddr) {
             // #PF in shared memory, error_code in aex_excep add SHARED_MEM_FETCH bit.
             // As a result, ERESUME will sync page-table mappings for gvaddr
@@ -15,6 +15,7 @@
 use bitflags::bitflags;
 
 use super::context::GuestRegisters;
+use crate::percpu::PerCpu;
 
 use core::arch::global_asm; 
 global_asm!(include_str!(concat!(env!("OUT_DIR"), "/exception.S")));
@@ -112,6 +113,10 @@ bitflags! {
         /// If this flag is set, it indicates that the access that caused the page fault was an
         /// instruction fetch.
         const INSTRUCTION_FETCH = 1 << 4;
+
+        /// If this flag is set, it indicates that the access that caused the page fault was a
+        /// shadow stack access.
+        const SHADOW_STACK = 1 << 6;
     }
 }
 
@@ -132,10 +137,11 @@ impl ExceptionInfo {
     }
 }
 
-fn exception_handler(frame: &ExceptionFrame) {
+fn exception_handler(frame: &mut ExceptionFrame) {
     trace!("Exception or interrupt #{:#x}", frame.num);
     match frame.num as u8 {
         ExceptionType::NonMaskableInterrupt => handle_nmi(),
+        ExceptionType::GeneralProtectionFault => handle_general_protection_fault(frame),
         ExceptionType::PageFault => handle_page_fault(frame),
         ExceptionType::IrqStart..=ExceptionType::IrqEnd => {
             error!("{:#x?}", frame);
@@ -149,7 +155,23 @@ fn exception_handler(frame: &ExceptionFrame) {
 }
 
 fn handle_nmi() {
-    warn!("Unhandled exception: NMI");
+    PerCpu::nmi_received();
+}
+
+fn handle_general_protection_fault(frame: &mut ExceptionFrame) {
+    let (read_insn, write_insn, fault) = unsafe {
+        (
+            &msr_read_insn as *const u8 as usize,
+            &msr_write_insn as *const u8 as usize,
+            &msr_access_fault as *const u8 as usize,
+        )
+    };
+    if frame.rip == read_insn || frame.rip == write_insn {
+        frame.rip = fault;
+        return;
+    }
+    error!("{:#x?}", frame);
+    panic!("Unhandled exception #{:#x}", frame.num);
 }
 
 fn handle_page_fault(frame: &ExceptionFrame) {
@@ -178,3 +200,56 @@ unsafe extern "sysv64" fn common_exception_entry() -> ! {
         options(noreturn),
     )
 }
+
+// RDMSR and WRMSR of an MSR chosen by the guest, returning 1 instead of
+// raising #GP in the hypervisor, see `handle_general_protection_fault`.
+global_asm!(
+    ".global msr_read_safe, msr_write_safe",
+    ".global msr_read_insn, msr_write_insn, msr_access_fault",
+    "msr_read_safe:",
+    "    mov ecx, edi",
+    "msr_read_insn:",
+    "    rdmsr",
+    "    shl rdx, 32",
+    "    or rax, rdx",
+    "    mov [rsi], rax",
+    "    xor eax, eax",
+    "    ret",
+    "msr_write_safe:",
+    "    mov ecx, edi",
+    "    mov eax, esi",
+    "    mov rdx, rsi",
+    "    shr rdx, 32",
+    "msr_write_insn:",
+    "    wrmsr",
+    "    xor eax, eax",
+    "    ret",
+    "msr_access_fault:",
+    "    mov eax, 1",
+    "    ret",
+);
+
+extern "sysv64" {
+    fn msr_read_safe(msr: u32, value: &mut u64) -> u32;
+    fn msr_write_safe(msr: u32, value: u64) -> u32;
+}
+
+extern "C" {
+    static msr_read_insn: u8;
+    static msr_write_insn: u8;
+    static msr_access_fault: u8;
+}
+
+/// Reads `msr`, returns `None` if the access raises #GP.
+pub fn rdmsr_safe(msr: u32) -> Option<u64> {
+    let mut value = 0;
+    match unsafe { msr_read_safe(msr, &mut value) } {
+        0 => Some(value),
+        _ => None,
+    }
+}
+
+/// Writes `value` to `msr`, returns false if the access raises #GP.
+pub fn wrmsr_safe(msr: u32, value: u64) -> bool {
+    unsafe { msr_write_safe(msr, value) == 0 }
+}
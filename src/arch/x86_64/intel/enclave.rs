@@ -12,6 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use libvmm::msr::Msr;
+use libvmm::vmx::flags::PinVmExecControls as PinCtrl;
+use libvmm::vmx::flags::VmExitControls as ExitCtrl;
 use libvmm::vmx::vmcs::{
     VmcsField32Control, VmcsField32Guest, VmcsField64Control, VmcsField64Guest,
 };
@@ -22,6 +25,7 @@ use crate::error::HvResult;
 use crate::memory::addr::align_down;
 
 use super::ept::EPTInstr;
+use super::vcpu::{ENCLAVE_MSR_BITMAP, NORMAL_WORLD_MSR_BITMAP};
 
 impl VcpuAccessEnclaveState for Vcpu {
     fn load_enclave_thread_state(&self) -> HvResult<EnclaveThreadState> {
@@ -35,6 +39,8 @@ impl VcpuAccessEnclaveState for Vcpu {
             efer: self.efer(),
             idtr_base: VmcsField64Guest::IDTR_BASE.read()?,
             idtr_limit: VmcsField32Guest::IDTR_LIMIT.read()?,
+            intr_exiting: false,
+            time_slice: 0,
         })
     }
 
@@ -60,32 +66,63 @@ impl VcpuAccessEnclaveState for Vcpu {
         VmcsField64Guest::IDTR_BASE.write(state.idtr_base)?;
         VmcsField32Guest::IDTR_LIMIT.write(state.idtr_limit)?;
 
-        // Intercept enclave exceptions.
+        // Intercept enclave exceptions, and switch the MSR access policy.
         if is_enter {
             VmcsField32Control::EXCEPTION_BITMAP.write(0xffff_ffff)?;
+            VmcsField64Control::MSR_BITMAP.write(ENCLAVE_MSR_BITMAP.paddr() as _)?;
         } else {
             VmcsField32Control::EXCEPTION_BITMAP.write(0)?;
+            VmcsField64Control::MSR_BITMAP.write(NORMAL_WORLD_MSR_BITMAP.paddr() as _)?;
         }
 
-        if cfg!(feature = "enclave_interrupt") {
-            // Enable interrupts during enclave running.
-            use libvmm::vmx::flags::PinVmExecControls as PinCtrl;
-            use libvmm::vmx::flags::VmExitControls as ExitCtrl;
-            let pin_based_exec_ctrl = VmcsField32Control::PIN_BASED_VM_EXEC_CONTROL.read()?;
-            let vmexit_ctrl = VmcsField32Control::VM_EXIT_CONTROLS.read()?;
-            if is_enter {
-                VmcsField32Control::PIN_BASED_VM_EXEC_CONTROL
-                    .write(pin_based_exec_ctrl | PinCtrl::INTR_EXITING.bits())?;
-                VmcsField32Control::VM_EXIT_CONTROLS
-                    .write(vmexit_ctrl | ExitCtrl::ACK_INTR_ON_EXIT.bits())?
-            } else {
-                VmcsField32Control::PIN_BASED_VM_EXEC_CONTROL
-                    .write(pin_based_exec_ctrl & !PinCtrl::INTR_EXITING.bits())?;
-                VmcsField32Control::VM_EXIT_CONTROLS
-                    .write(vmexit_ctrl & !ExitCtrl::ACK_INTR_ON_EXIT.bits())?
-            }
+        // Enable interrupts during enclave running if the enclave asks for it.
+        let pin_based_exec_ctrl = VmcsField32Control::PIN_BASED_VM_EXEC_CONTROL.read()?;
+        let vmexit_ctrl = VmcsField32Control::VM_EXIT_CONTROLS.read()?;
+        if is_enter && state.intr_exiting {
+            VmcsField32Control::PIN_BASED_VM_EXEC_CONTROL
+                .write(pin_based_exec_ctrl | PinCtrl::INTR_EXITING.bits())?;
+            VmcsField32Control::VM_EXIT_CONTROLS
+                .write(vmexit_ctrl | ExitCtrl::ACK_INTR_ON_EXIT.bits())?
+        } else if pin_based_exec_ctrl & PinCtrl::INTR_EXITING.bits() != 0 {
+            VmcsField32Control::PIN_BASED_VM_EXEC_CONTROL
+                .write(pin_based_exec_ctrl & !PinCtrl::INTR_EXITING.bits())?;
+            VmcsField32Control::VM_EXIT_CONTROLS
+                .write(vmexit_ctrl & !ExitCtrl::ACK_INTR_ON_EXIT.bits())?
         }
 
+        // Bound enclave execution with the VMX-preemption timer.
+        set_preemption_timer(if is_enter { state.time_slice } else { 0 })?;
+
         Ok(())
     }
+
+    fn flush_tlb(&mut self) -> HvResult {
+        // Reloading the EPT pointer does an INVEPT. VPID is not enabled, so
+        // linear mappings are flushed on every VM entry anyway.
+        EPTInstr::set_ept_pointer(align_down(VmcsField64Control::EPT_POINTER.read()? as _))
+    }
+}
+
+/// Arm the VMX-preemption timer with `time_slice` TSC cycles, or disarm it if
+/// `time_slice` is 0. The remaining value is saved on every VM exit, so the
+/// budget is shared by all the VM entries until the next enclave transition.
+fn set_preemption_timer(time_slice: u64) -> HvResult {
+    let pin_based_exec_ctrl = VmcsField32Control::PIN_BASED_VM_EXEC_CONTROL.read()?;
+    let vmexit_ctrl = VmcsField32Control::VM_EXIT_CONTROLS.read()?;
+    if time_slice != 0 {
+        // The timer counts down by 1 every 2^IA32_VMX_MISC[4:0] TSC cycles.
+        let rate = Msr::IA32_VMX_MISC.read() & 0x1f;
+        let value = (time_slice >> rate).clamp(1, u32::MAX as u64) as u32;
+        VmcsField32Guest::VMX_PREEMPTION_TIMER_VALUE.write(value)?;
+        VmcsField32Control::PIN_BASED_VM_EXEC_CONTROL
+            .write(pin_based_exec_ctrl | PinCtrl::PREEMPTION_TIMER.bits())?;
+        VmcsField32Control::VM_EXIT_CONTROLS
+            .write(vmexit_ctrl | ExitCtrl::SAVE_VMX_PREEMPTION_TIMER.bits())?;
+    } else if pin_based_exec_ctrl & PinCtrl::PREEMPTION_TIMER.bits() != 0 {
+        VmcsField32Control::PIN_BASED_VM_EXEC_CONTROL
+            .write(pin_based_exec_ctrl & !PinCtrl::PREEMPTION_TIMER.bits())?;
+        VmcsField32Control::VM_EXIT_CONTROLS
+            .write(vmexit_ctrl & !ExitCtrl::SAVE_VMX_PREEMPTION_TIMER.bits())?;
+    }
+    Ok(())
 }
@@ -33,6 +33,7 @@ pub use ept::ExtendedPageTable as NestedPageTable;
 pub use vcpu::Vcpu;
 pub use vtd::{IoPTEntry, IoPageTable, Iommu};
 
+use libvmm::vmx::flags::PinVmExecControls as PinCtrl;
 use libvmm::vmx::flags::VmExitControls as ExitCtrl;
 
 const VMEXIT_CTRL_MIN: u32 = ExitCtrl::HOST_ADDR_SPACE_SIZE.bits()
@@ -69,3 +70,11 @@ pub fn check_hypervisor_feature() -> HvResult {
 
     Ok(())
 }
+
+/// Whether enclave execution can be bounded by the VMX-preemption timer.
+pub fn enclave_time_slice_supported() -> bool {
+    let pin_based_ctrl = (Msr::IA32_VMX_PINBASED_CTLS.read() >> 32) as u32;
+    let vmexit_ctrl = (Msr::IA32_VMX_EXIT_CTLS.read() >> 32) as u32;
+    pin_based_ctrl & PinCtrl::PREEMPTION_TIMER.bits() != 0
+        && vmexit_ctrl & ExitCtrl::SAVE_VMX_PREEMPTION_TIMER.bits() != 0
+}
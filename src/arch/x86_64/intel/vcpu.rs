@@ -30,10 +30,11 @@ use x86_64::registers::rflags::RFlags;
 
 use super::structs::{MsrBitmap, VmxRegion};
 use crate::arch::cpuid::CpuFeatures;
+use crate::arch::msr_policy::{pat_valid, ENCLAVE_MSR_POLICY, NORMAL_WORLD_MSR_POLICY};
 use crate::arch::segmentation::{Segment, SegmentAccessRights};
 use crate::arch::tables::{GDTStruct, GDT, IDT};
 use crate::arch::vmm::VcpuAccessGuestState;
-use crate::arch::{GuestPageTableImmut, GuestRegisters, LinuxContext};
+use crate::arch::{rdmsr_safe, wrmsr_safe, GuestPageTableImmut, GuestRegisters, LinuxContext};
 use crate::cell::Cell;
 use crate::error::HvResult;
 
@@ -50,7 +51,9 @@ pub struct Vcpu {
 }
 
 lazy_static! {
-    static ref MSR_BITMAP: MsrBitmap = MsrBitmap::default();
+    pub(super) static ref NORMAL_WORLD_MSR_BITMAP: MsrBitmap =
+        MsrBitmap::new(&NORMAL_WORLD_MSR_POLICY);
+    pub(super) static ref ENCLAVE_MSR_BITMAP: MsrBitmap = MsrBitmap::new(&ENCLAVE_MSR_POLICY);
 }
 
 macro_rules! set_guest_segment {
@@ -181,6 +184,23 @@ impl Vcpu {
         Ok(())
     }
 
+    /// Whether an NMI can be injected on the next VM entry: no other event is
+    /// being injected, and the guest blocks neither NMIs nor interrupts.
+    pub fn nmi_injectable(&self) -> HvResult<bool> {
+        let event = VmcsField32Control::VM_ENTRY_INTR_INFO_FIELD.read()?;
+        // Blocking by STI, by MOV SS and by NMI.
+        let blocking = VmcsField32Guest::INTERRUPTIBILITY_INFO.read()? & 0b1011;
+        Ok(event & InterruptInfo::VALID.bits() == 0 && blocking == 0)
+    }
+
+    pub fn inject_nmi(&mut self) -> HvResult {
+        Vmcs::inject_interrupt(
+            InterruptInfo::from_vector(crate::arch::ExceptionType::NonMaskableInterrupt),
+            None,
+        )?;
+        Ok(())
+    }
+
     pub fn rollback_rip(&mut self, instr_len: u8) -> HvResult {
         VmcsField64Guest::RIP.write(VmcsField64Guest::RIP.read()? - instr_len as u64)?;
         Ok(())
@@ -390,7 +410,7 @@ impl Vcpu {
 
         unsafe { cell.gpm.activate() }; // Set EPT_POINTER
 
-        VmcsField64Control::MSR_BITMAP.write(MSR_BITMAP.paddr() as _)?;
+        VmcsField64Control::MSR_BITMAP.write(NORMAL_WORLD_MSR_BITMAP.paddr() as _)?;
         VmcsField32Control::EXCEPTION_BITMAP.write(0)?;
 
         Ok(())
@@ -486,6 +506,35 @@ impl VcpuAccessGuestState for Vcpu {
         })()
         .expect("Failed to write guest control register")
     }
+
+    fn read_msr(&self, msr: u32) -> HvResult<Option<u64>> {
+        // These MSRs are loaded from and saved to the guest-state area on
+        // VM entries and VM exits.
+        Ok(Some(match msr {
+            0x174 => VmcsField32Guest::SYSENTER_CS.read()? as _,
+            0x175 => VmcsField64Guest::SYSENTER_ESP.read()?,
+            0x176 => VmcsField64Guest::SYSENTER_EIP.read()?,
+            0x1d9 => VmcsField64Guest::IA32_DEBUGCTL.read()?,
+            0x277 => VmcsField64Guest::IA32_PAT.read()?,
+            0xc000_0080 => VmcsField64Guest::IA32_EFER.read()?,
+            _ => return Ok(rdmsr_safe(msr)),
+        }))
+    }
+
+    fn write_msr(&mut self, msr: u32, val: u64) -> HvResult<bool> {
+        match msr {
+            0x174 => VmcsField32Guest::SYSENTER_CS.write(val as _)?,
+            0x175 => VmcsField64Guest::SYSENTER_ESP.write(val)?,
+            0x176 => VmcsField64Guest::SYSENTER_EIP.write(val)?,
+            0x1d9 => VmcsField64Guest::IA32_DEBUGCTL.write(val)?,
+            // An invalid PAT would fail the VM entry instead of raising #GP.
+            0x277 if !pat_valid(val) => return Ok(false),
+            0x277 => VmcsField64Guest::IA32_PAT.write(val)?,
+            0xc000_0080 => VmcsField64Guest::IA32_EFER.write(val)?,
+            _ => return Ok(wrmsr_safe(msr, val)),
+        };
+        Ok(true)
+    }
 }
 
 impl Debug for Vcpu {
@@ -93,12 +93,37 @@ impl VmExit<'_> {
         Ok(())
     }
 
+    fn handle_preemption_timer(&mut self, exit_info: &VmExitInfo) -> HvResult {
+        let now = Instant::now();
+
+        debug!(
+            "VM exit: VMX-preemption timer expired @ RIP({:#x})",
+            exit_info.guest_rip
+        );
+        if self.cpu_data.state == CpuState::EnclaveRunning {
+            // The enclave has used up its time slice, force an AEX to give the
+            // CPU back to the normal world.
+            self.forced_aex(now, EnclaveStatsId::ForcedAex)?;
+        } else {
+            error!(
+                "handle_preemption_timer cpu state {:?} is wrong",
+                self.cpu_data.state
+            );
+            return hv_result_err!(EINVAL);
+        }
+        Ok(())
+    }
+
     fn handle_ept_violation(&mut self, exit_info: &VmExitInfo) -> HvResult {
         let ept_vio_info = EptViolationInfo::new()?;
         let guest_paddr = ept_vio_info.guest_paddr;
         if self.cpu_data.state == CpuState::EnclaveRunning {
+            let now = Instant::now();
             let enclave = self.cpu_data.get_current_enclave()?;
-            enclave.handle_npt_violation(guest_paddr, ept_vio_info.final_translation)?;
+            if enclave.handle_npt_violation(guest_paddr, ept_vio_info.final_translation)? {
+                // A page of the clone is being copied, retry after ERESUME.
+                self.forced_aex(now, EnclaveStatsId::CowRetryAex)?;
+            }
             return Ok(());
         }
         warn!(
@@ -181,6 +206,7 @@ impl VmExit<'_> {
             VmxExitReason::MSR_READ => self.handle_msr_read(),
             VmxExitReason::MSR_WRITE => self.handle_msr_write(),
             VmxExitReason::EPT_VIOLATION => self.handle_ept_violation(&exit_info),
+            VmxExitReason::PREEMPTION_TIMER => self.handle_preemption_timer(&exit_info),
             VmxExitReason::TRIPLE_FAULT => {
                 error!("Triple fault: {:#x?}", exit_info);
                 self.cpu_data.vcpu.inject_fault()?;
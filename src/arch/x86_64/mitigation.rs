@@ -0,0 +1,120 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Transient-execution mitigations run on every switch between the normal
+//! world and an enclave, so that neither world can sample the data the other
+//! left in the L1D cache, the CPU buffers or the branch predictors.
+
+use bitflags::bitflags;
+use core::arch::asm;
+use x86::msr::{rdmsr, wrmsr};
+
+use super::cpuid::CpuFeatures;
+use super::tables::GDTStruct;
+use crate::config::HvSystemConfig;
+
+const IA32_PRED_CMD: u32 = 0x49;
+const IA32_ARCH_CAPABILITIES: u32 = 0x10a;
+const IA32_FLUSH_CMD: u32 = 0x10b;
+
+/// IA32_PRED_CMD: Indirect Branch Prediction Barrier.
+const PRED_CMD_IBPB: u64 = 1 << 0;
+/// IA32_FLUSH_CMD: writeback and invalidate the L1 data cache.
+const FLUSH_CMD_L1D_FLUSH: u64 = 1 << 0;
+
+bitflags! {
+    /// IA32_ARCH_CAPABILITIES bits telling the CPU is not affected.
+    struct ArchCapabilities: u64 {
+        /// Not susceptible to Rogue Data Cache Load (and L1TF).
+        const RDCL_NO = 1 << 0;
+        /// L1D flush is not needed on VM entry.
+        const SKIP_L1DFL_VMENTRY = 1 << 3;
+        /// Not susceptible to Microarchitectural Data Sampling.
+        const MDS_NO = 1 << 5;
+        /// Not susceptible to TSX Asynchronous Abort.
+        const TAA_NO = 1 << 8;
+    }
+}
+
+bitflags! {
+    /// Mitigations run on enclave transitions.
+    pub struct Mitigations: u32 {
+        /// Flush the L1 data cache with IA32_FLUSH_CMD (L1TF).
+        const L1D_FLUSH = 1 << 0;
+        /// Clear the CPU buffers with VERW (MDS and TAA).
+        const VERW = 1 << 1;
+        /// Issue an Indirect Branch Prediction Barrier (Spectre v2).
+        const IBPB = 1 << 2;
+    }
+}
+
+lazy_static! {
+    static ref MITIGATIONS: Mitigations = {
+        let disabled =
+            Mitigations::from_bits_truncate(HvSystemConfig::get().disabled_mitigations());
+        let mitigations = Mitigations::detect() - disabled;
+        info!("Enclave transition mitigations: {:?}", mitigations);
+        mitigations
+    };
+}
+
+impl Mitigations {
+    /// The mitigations the CPU supports and is affected by.
+    fn detect() -> Self {
+        let features = CpuFeatures::new();
+        let arch_cap = if features.has_arch_capabilities() {
+            ArchCapabilities::from_bits_truncate(unsafe { rdmsr(IA32_ARCH_CAPABILITIES) })
+        } else {
+            ArchCapabilities::empty()
+        };
+
+        let mut mitigations = Self::empty();
+        let l1tf =
+            !arch_cap.intersects(ArchCapabilities::RDCL_NO | ArchCapabilities::SKIP_L1DFL_VMENTRY);
+        if features.has_l1d_flush() && l1tf {
+            mitigations |= Self::L1D_FLUSH;
+        }
+        let mds = !arch_cap.contains(ArchCapabilities::MDS_NO);
+        let taa = features.has_rtm() && !arch_cap.contains(ArchCapabilities::TAA_NO);
+        if features.has_md_clear() && (mds || taa) {
+            mitigations |= Self::VERW;
+        }
+        if features.has_ibpb() {
+            mitigations |= Self::IBPB;
+        }
+        mitigations
+    }
+
+    /// The mitigations enabled on this system.
+    pub fn get() -> Self {
+        *MITIGATIONS
+    }
+
+    /// Run the enabled mitigations, it must be called after the world switch.
+    pub fn apply() {
+        let mitigations = Self::get();
+        if mitigations.contains(Self::IBPB) {
+            unsafe { wrmsr(IA32_PRED_CMD, PRED_CMD_IBPB) };
+        }
+        if mitigations.contains(Self::L1D_FLUSH) {
+            // The L1D flush also overwrites the CPU buffers.
+            unsafe { wrmsr(IA32_FLUSH_CMD, FLUSH_CMD_L1D_FLUSH) };
+        } else if mitigations.contains(Self::VERW) {
+            // Only the memory-operand form of VERW clears the buffers, whether
+            // the selector is writable or not.
+            let selector = GDTStruct::KCODE_SELECTOR.bits();
+            unsafe { asm!("verw word ptr [{}]", in(reg) &selector, options(nostack)) };
+        }
+    }
+}
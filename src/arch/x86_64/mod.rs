@@ -14,25 +14,35 @@
 
 #[macro_use]
 mod context;
+mod cet;
 mod cpuid;
 mod enclave;
 mod entry;
 mod exception;
+mod mitigation;
+mod msr_policy;
 mod page_table;
 mod segmentation;
 mod tables;
 mod xsave;
 
+pub mod apic;
 pub mod cpu;
 pub mod serial;
+pub mod txt;
 pub mod vmm;
 
+pub use cet::CetUserState;
 pub use context::{GuestRegisters, LinuxContext};
+pub use cpuid::{CpuIdEntry, EnclaveCpuIdTable};
 pub use enclave::{EnclaveExceptionInfo, EnclavePFErrorCode, EnclaveThreadState};
-pub use exception::{ExceptionInfo, ExceptionType, PageFaultErrorCode};
+pub use exception::{rdmsr_safe, wrmsr_safe, ExceptionInfo, ExceptionType, PageFaultErrorCode};
+pub use mitigation::Mitigations;
+pub use msr_policy::{MsrAction, MsrPolicy, NormalWorldMsrs};
 pub use page_table::PageTable as HostPageTable;
 pub use page_table::PageTable as GuestPageTable;
 pub use page_table::PageTableImmut as GuestPageTableImmut;
 pub use page_table::{EnclaveGuestPageTableUnlocked, PTEntry};
 pub use vmm::{EnclaveNestedPageTableUnlocked, NPTEntry, NestedPageTable};
-pub use xsave::XsaveRegion;
+pub use xsave::{xsave_size, NormalWorldXsaveRegion, XSAVE_REGION_SIZE};
+pub use xsave::{XsaveRegion, XsaveRegionFrame};
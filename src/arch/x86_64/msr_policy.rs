@@ -0,0 +1,207 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per-MSR access policy, with separate rules for the normal world and for
+//! enclave mode. The VMX MSR bitmap and the SVM MSRPM are built from it.
+
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+use libvmm::msr::Msr;
+
+use super::cpuid::CpuFeatures;
+use super::vmm::VcpuAccessGuestState;
+use crate::error::HvResult;
+use crate::percpu::CpuState;
+use MsrAction::*;
+
+/// Action taken on a guest access to an MSR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsrAction {
+    /// The guest accesses the MSR directly, without VM exits.
+    PassThrough,
+    /// The access causes a VM exit, and hypervisor performs it for the guest.
+    Emulate,
+    /// The access causes a VM exit, and #GP is injected.
+    Deny,
+}
+
+impl MsrAction {
+    /// Whether the access causes a VM exit.
+    pub fn is_intercepted(self) -> bool {
+        self != Self::PassThrough
+    }
+}
+
+/// Actions on reading and writing a range of MSRs.
+struct MsrRule {
+    msrs: RangeInclusive<u32>,
+    read: MsrAction,
+    write: MsrAction,
+}
+
+const fn rule(msrs: RangeInclusive<u32>, read: MsrAction, write: MsrAction) -> MsrRule {
+    MsrRule { msrs, read, write }
+}
+
+const fn one(msr: Msr) -> RangeInclusive<u32> {
+    msr as u32..=msr as u32
+}
+
+/// Rules applied while Linux runs.
+static NORMAL_WORLD_RULES: &[MsrRule] = &[
+    // Platform configuration locked by firmware.
+    rule(one(Msr::IA32_FEATURE_CONTROL), PassThrough, Deny),
+    // SVM configuration and the host state-save area of hypervisor.
+    rule(one(Msr::VM_CR), PassThrough, Deny),
+    rule(one(Msr::VM_HSAVE_PA), Deny, Deny),
+    // Local APIC, memory types and performance monitoring, emulated so that
+    // invalid accesses raise #GP in Linux.
+    rule(one(Msr::IA32_APIC_BASE), PassThrough, Emulate),
+    rule(0x200..=0x276, PassThrough, Emulate), // IA32_MTRR_*
+    rule(one(Msr::IA32_PAT), Emulate, Emulate),
+    rule(one(Msr::IA32_MTRR_DEF_TYPE), Emulate, Emulate),
+    rule(one(Msr::IA32_PERF_GLOBAL_CTRL), PassThrough, Emulate),
+    rule(0xc80..=0xd8f, PassThrough, Emulate),
+    rule(0x802..=0x803, Emulate, PassThrough), // IA32_X2APIC_APICID, VERSION
+    rule(0x808..=0x808, Emulate, Emulate),     // IA32_X2APIC_TPR
+    rule(0x80a..=0x80a, Emulate, PassThrough), // IA32_X2APIC_PPR
+    rule(0x80b..=0x80b, PassThrough, Emulate), // IA32_X2APIC_EOI
+    rule(0x80d..=0x80d, Emulate, PassThrough), // IA32_X2APIC_LDR
+    rule(0x80f..=0x80f, Emulate, Emulate),     // IA32_X2APIC_SIVR
+    rule(0x810..=0x827, Emulate, PassThrough), // IA32_X2APIC_ISR*, TMR*, IRR*
+    rule(0x828..=0x828, Emulate, Emulate),     // IA32_X2APIC_ESR
+    rule(0x82f..=0x830, Emulate, Emulate),     // IA32_X2APIC_LVT_CMCI, ICR
+    rule(0x832..=0x839, Emulate, Emulate),     // IA32_X2APIC_LVT_*, timer counts
+    rule(0x83e..=0x83e, Emulate, Emulate),     // IA32_X2APIC_DIV_CONF
+];
+
+/// A set of rules, with the action for the MSRs not covered by any rule.
+pub struct MsrPolicy {
+    rules: &'static [MsrRule],
+    default: MsrAction,
+}
+
+pub static NORMAL_WORLD_MSR_POLICY: MsrPolicy = MsrPolicy {
+    rules: NORMAL_WORLD_RULES,
+    default: PassThrough,
+};
+
+/// Enclave code runs in ring 3 and never accesses MSRs legitimately, so all
+/// the accesses are denied.
+pub static ENCLAVE_MSR_POLICY: MsrPolicy = MsrPolicy {
+    rules: &[],
+    default: Deny,
+};
+
+impl MsrPolicy {
+    /// The policy for the world the CPU is running.
+    pub fn get(cpu_state: &CpuState) -> &'static Self {
+        if *cpu_state == CpuState::EnclaveRunning {
+            &ENCLAVE_MSR_POLICY
+        } else {
+            &NORMAL_WORLD_MSR_POLICY
+        }
+    }
+
+    /// Action on reading (`is_write` is false) or writing `msr`. The first
+    /// matching rule wins.
+    pub fn action(&self, msr: u32, is_write: bool) -> MsrAction {
+        match self.rules.iter().find(|rule| rule.msrs.contains(&msr)) {
+            Some(rule) if is_write => rule.write,
+            Some(rule) => rule.read,
+            None => self.default,
+        }
+    }
+}
+
+/// Whether `pat` only holds valid memory types, other values raise #GP.
+pub fn pat_valid(pat: u64) -> bool {
+    pat.to_le_bytes()
+        .iter()
+        .all(|&t| matches!(t, 0 | 1 | 4 | 5 | 6 | 7))
+}
+
+/// IA32_DEBUGCTL.
+const IA32_DEBUGCTL: u32 = 0x1d9;
+/// IA32_PERFEVTSEL0, followed by one MSR per general-purpose counter.
+const IA32_PERFEVTSEL0: u32 = 0x186;
+/// IA32_FIXED_CTR_CTRL.
+const IA32_FIXED_CTR_CTRL: u32 = 0x38d;
+/// AMD PerfEvtSel0..3 of the legacy counters.
+const AMD_PERF_EVT_SEL_LEGACY: RangeInclusive<u32> = 0xc001_0000..=0xc001_0003;
+/// Number of AMD core performance counters, with PerfEvtSel0..5 interleaved
+/// with PerfCtr0..5 from `PERF_EVT_SEL0`.
+const AMD_NUM_CORE_COUNTERS: u32 = 6;
+
+/// Maximum number of switched MSRs: SYSENTER_CS, IA32_DEBUGCTL, 8 event
+/// selectors, IA32_FIXED_CTR_CTRL and IA32_PERF_GLOBAL_CTRL.
+const MAX_SWITCHED_MSRS: usize = 12;
+
+lazy_static! {
+    /// MSRs through which Linux could observe or steer an enclave: SYSENTER_CS,
+    /// branch recording and tracing in IA32_DEBUGCTL, and the performance
+    /// counter controls.
+    static ref SWITCHED_MSRS: Vec<u32> = {
+        let features = CpuFeatures::new();
+        let mut msrs = vec![Msr::IA32_SYSENTER_CS as u32, IA32_DEBUGCTL];
+        if cfg!(feature = "amd") {
+            if features.has_perf_ctr_ext_core() {
+                msrs.extend((0..AMD_NUM_CORE_COUNTERS).map(|i| Msr::PERF_EVT_SEL0 as u32 + i * 2));
+            } else {
+                msrs.extend(AMD_PERF_EVT_SEL_LEGACY);
+            }
+        } else if features.perf_monitor_version_id() > 0 {
+            let counters = features.perf_monitor_counters().min(8) as u32;
+            msrs.extend(IA32_PERFEVTSEL0..IA32_PERFEVTSEL0 + counters);
+            if features.perf_monitor_version_id() > 1 {
+                msrs.push(IA32_FIXED_CTR_CTRL);
+                msrs.push(Msr::IA32_PERF_GLOBAL_CTRL as u32);
+            }
+        }
+        msrs
+    };
+}
+
+/// Values of the switched MSRs in the normal world. Linux may still write the
+/// MSRs without VM exits, so they are cleared on every enclave entry and
+/// restored on every enclave exit.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NormalWorldMsrs {
+    values: [u64; MAX_SWITCHED_MSRS],
+}
+
+impl NormalWorldMsrs {
+    /// Saves the values of Linux and clears the MSRs, before running an enclave.
+    pub fn save_and_clear(&mut self, vcpu: &mut impl VcpuAccessGuestState) -> HvResult {
+        for (&msr, value) in SWITCHED_MSRS.iter().zip(self.values.iter_mut()) {
+            *value = vcpu.read_msr(msr)?.unwrap_or(0);
+            if *value != 0 {
+                vcpu.write_msr(msr, 0)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Restores the values of Linux, after leaving an enclave. The enclave
+    /// cannot access the MSRs, so they are still cleared.
+    pub fn restore(&self, vcpu: &mut impl VcpuAccessGuestState) -> HvResult {
+        for (&msr, &value) in SWITCHED_MSRS.iter().zip(self.values.iter()) {
+            if value != 0 {
+                vcpu.write_msr(msr, value)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -147,6 +147,71 @@ impl Debug for PTEntry {
     }
 }
 
+/// Entry of an enclave page table, which may map the shadow stack pages of an
+/// enclave with CET enabled. Those are the only pages the hypervisor maps with
+/// R/W = 0 and Dirty = 1, other page tables never map shadow stack pages.
+#[repr(transparent)]
+#[derive(Clone, Debug)]
+pub struct EnclavePTEntry(PTEntry);
+
+impl GenericPTE for EnclavePTEntry {
+    fn addr(&self) -> PhysAddr {
+        self.0.addr()
+    }
+    fn flags(&self) -> MemFlags {
+        let mut flags = self.0.flags();
+        let ptf = PTF::from_bits_truncate((self.0).0);
+        if ptf.contains(PTF::DIRTY) && !ptf.contains(PTF::WRITABLE) {
+            flags |= MemFlags::SHADOW_STACK;
+        }
+        flags
+    }
+    fn is_unused(&self) -> bool {
+        self.0.is_unused()
+    }
+    fn is_present(&self) -> bool {
+        self.0.is_present()
+    }
+    fn is_leaf(&self) -> bool {
+        self.0.is_leaf()
+    }
+    fn is_young(&self) -> bool {
+        self.0.is_young()
+    }
+    fn set_old(&mut self) {
+        self.0.set_old()
+    }
+    fn set_addr(&mut self, paddr: PhysAddr) {
+        self.0.set_addr(paddr);
+    }
+    fn set_present(&mut self) -> PagingResult {
+        self.0.set_present()
+    }
+    fn set_notpresent(&mut self) -> PagingResult {
+        self.0.set_notpresent()
+    }
+    fn set_flags(&mut self, flags: MemFlags, is_huge: bool) -> PagingResult {
+        self.0.set_flags(flags - MemFlags::SHADOW_STACK, is_huge)?;
+        // Shadow stack pages are marked as R/W = 0 and Dirty = 1.
+        if flags.contains(MemFlags::SHADOW_STACK) {
+            let ptf = PTF::from_bits_truncate((self.0).0) - PTF::WRITABLE | PTF::DIRTY;
+            (self.0).0 = self.0.addr() as u64 | ptf.bits();
+        }
+        Ok(())
+    }
+    fn set_table(
+        &mut self,
+        paddr: PhysAddr,
+        next_level: PageTableLevel,
+        is_present: bool,
+    ) -> PagingResult {
+        self.0.set_table(paddr, next_level, is_present)
+    }
+    fn clear(&mut self) {
+        self.0.clear()
+    }
+}
+
 pub struct X86PagingInstr;
 
 impl PagingInstr for X86PagingInstr {
@@ -167,5 +232,6 @@ impl PagingInstr for X86PagingInstr {
 }
 
 pub type PageTable = Level4PageTable<VirtAddr, PTEntry, X86PagingInstr>;
-pub type EnclaveGuestPageTableUnlocked = Level4PageTableUnlocked<VirtAddr, PTEntry, X86PagingInstr>;
+pub type EnclaveGuestPageTableUnlocked =
+    Level4PageTableUnlocked<VirtAddr, EnclavePTEntry, X86PagingInstr>;
 pub type PageTableImmut = Level4PageTableImmut<VirtAddr, PTEntry>;
@@ -0,0 +1,52 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Detection of a measured launch through Intel TXT, from the status the
+//! chipset reports in the TXT public configuration space.
+
+use super::cpuid::CpuFeatures;
+use crate::memory::{HostPhysAddr, Mmio, PAGE_SIZE};
+
+/// The TXT public configuration space, readable by any software.
+const TXT_PUB_CONFIG_BASE: HostPhysAddr = 0xfed3_0000;
+/// `TXT.STS`, the status of the measured launch.
+const TXT_STS: usize = 0x0;
+/// `TXT.STS.SENTER.DONE`: all CPUs have completed `GETSEC[SENTER]`.
+const TXT_STS_SENTER_DONE: u64 = 1 << 0;
+/// `TXT.STS.LOCALITY2.OPEN`: the launched environment opened locality 2.
+const TXT_STS_LOCALITY2_OPEN: u64 = 1 << 16;
+
+/// Physical address and size of the TXT registers, mapped at the same address
+/// in the hypervisor, or `None` on CPUs without TXT, where the address may
+/// hold anything.
+pub fn public_space_region() -> Option<(HostPhysAddr, usize)> {
+    if CpuFeatures::new().has_smx() {
+        Some((TXT_PUB_CONFIG_BASE, PAGE_SIZE))
+    } else {
+        None
+    }
+}
+
+/// Whether the platform was launched through `GETSEC[SENTER]` and locality 2
+/// is open, so that Linux, left at locality 0, cannot use it.
+pub fn is_launched() -> bool {
+    let (base, _) = match public_space_region() {
+        Some(region) => region,
+        None => return false,
+    };
+    let sts = unsafe { Mmio::<u64>::from_base(base + TXT_STS) }.read();
+    // reads as all ones if the chipset does not decode the space
+    let launched = TXT_STS_SENTER_DONE | TXT_STS_LOCALITY2_OPEN;
+    sts != u64::MAX && sts & launched == launched
+}
@@ -22,12 +22,16 @@ mod vendor;
 
 use x86_64::registers::control::Cr4Flags;
 
-use super::GuestRegisters;
-use crate::{error::HvResult, percpu::PerCpu};
+use super::{EnclaveExceptionInfo, ExceptionType, GuestRegisters, MsrAction, MsrPolicy};
+use crate::config::HvSystemConfig;
+use crate::enclave::{AexException, EnclaveStatsId};
+use crate::error::HvResult;
+use crate::percpu::{CpuState, PerCpu};
+use crate::stats::Instant;
 
 pub use vendor::{
-    check_hypervisor_feature, EnclaveNestedPageTableUnlocked, IoPTEntry, IoPageTable, Iommu,
-    NPTEntry, NestedPageTable, Vcpu,
+    check_hypervisor_feature, enclave_time_slice_supported, EnclaveNestedPageTableUnlocked,
+    IoPTEntry, IoPageTable, Iommu, NPTEntry, NestedPageTable, Vcpu,
 };
 
 #[cfg(feature = "amd")]
@@ -54,6 +58,14 @@ pub trait VcpuAccessGuestState {
     fn efer(&self) -> u64;
     fn cr(&self, cr_idx: usize) -> u64;
     fn set_cr(&mut self, cr_idx: usize, val: u64);
+    /// Reads an MSR of the guest, `None` if the access raises #GP.
+    fn read_msr(&self, msr: u32) -> HvResult<Option<u64>> {
+        Ok(super::rdmsr_safe(msr))
+    }
+    /// Writes an MSR of the guest, returns false if the access raises #GP.
+    fn write_msr(&mut self, msr: u32, val: u64) -> HvResult<bool> {
+        Ok(super::wrmsr_safe(msr, val))
+    }
     fn xcr0(&self) -> u64 {
         unsafe { core::arch::x86_64::_xgetbv(0) }
     }
@@ -83,51 +95,80 @@ impl VmExit<'_> {
     }
 
     pub fn handle_msr_read(&mut self) -> HvResult {
+        let msr = self.cpu_data.vcpu.regs().rcx as u32;
+        if MsrPolicy::get(&self.cpu_data.state).action(msr, false) == MsrAction::Deny {
+            return self.deny_msr_access();
+        }
+        let value = match self.cpu_data.vcpu.read_msr(msr)? {
+            Some(value) => value,
+            None => return self.deny_msr_access(),
+        };
         let guest_regs = self.cpu_data.vcpu.regs_mut();
-        let id = guest_regs.rcx;
-        warn!("VM exit: RDMSR({:#x})", id);
-        // TODO
-        guest_regs.rax = 0;
-        guest_regs.rdx = 0;
+        guest_regs.rax = value & 0xffff_ffff;
+        guest_regs.rdx = value >> 32;
         self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_RDMSR)?;
         Ok(())
     }
 
     pub fn handle_msr_write(&mut self) -> HvResult {
         let guest_regs = self.cpu_data.vcpu.regs();
-        let id = guest_regs.rcx;
-        let value = guest_regs.rax | (guest_regs.rdx << 32);
-        warn!("VM exit: WRMSR({:#x}) <- {:#x}", id, value);
-        // TODO
+        let msr = guest_regs.rcx as u32;
+        let value = (guest_regs.rax & 0xffff_ffff) | (guest_regs.rdx << 32);
+        if MsrPolicy::get(&self.cpu_data.state).action(msr, true) == MsrAction::Deny {
+            return self.deny_msr_access();
+        }
+        if !self.cpu_data.vcpu.write_msr(msr, value)? {
+            return self.deny_msr_access();
+        }
         self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_WRMSR)?;
         Ok(())
     }
 
+    /// A denied or faulting MSR access raises #GP, in the enclave it is
+    /// delivered as an AEX.
+    fn deny_msr_access(&mut self) -> HvResult {
+        let regs = self.cpu_data.vcpu.regs();
+        warn!(
+            "Denied MSR access: MSR={:#x}, RIP={:#x}, {:?}",
+            regs.rcx,
+            self.cpu_data.vcpu.instr_pointer(),
+            self.cpu_data.state
+        );
+        let exception_info = EnclaveExceptionInfo::general_protection(0, &self.cpu_data.state);
+        self.inject_exception(exception_info)
+    }
+
     pub fn handle_cpuid(&mut self) -> HvResult {
         use super::cpuid::{cpuid, CpuIdEax, FeatureInfoFlags};
+        if self.cpu_data.state == CpuState::EnclaveRunning
+            && self.cpu_data.get_current_enclave()?.cpuid_table().is_some()
+        {
+            return self.handle_enclave_cpuid();
+        }
         let signature = unsafe { &*("HyperEnclave".as_ptr() as *const [u32; 3]) };
         let cr4_flags = Cr4Flags::from_bits_truncate(self.cpu_data.vcpu.cr(4));
         let guest_regs = self.cpu_data.vcpu.regs_mut();
         let function = guest_regs.rax as u32;
-        if function == CpuIdEax::HypervisorInfo as _ {
-            guest_regs.rax = CpuIdEax::HypervisorFeatures as u32 as _;
-            guest_regs.rbx = signature[0] as _;
-            guest_regs.rcx = signature[1] as _;
-            guest_regs.rdx = signature[2] as _;
+        let sub_leaf = guest_regs.rcx as u32;
+        let mut res = if function == CpuIdEax::HypervisorInfo as _ {
+            [
+                CpuIdEax::HypervisorFeatures as u32,
+                signature[0],
+                signature[1],
+                signature[2],
+            ]
         } else if function == CpuIdEax::HypervisorFeatures as _ {
-            guest_regs.rax = 0;
-            guest_regs.rbx = 0;
-            guest_regs.rcx = 0;
-            guest_regs.rdx = 0;
+            [hypervisor_features().bits(), 0, 0, 0]
+        } else if function > CpuIdEax::HypervisorFeatures as _
+            && function <= CpuIdEax::HypervisorMax as _
+        {
+            [0; 4]
         } else {
-            let res = cpuid!(guest_regs.rax, guest_regs.rcx);
-            guest_regs.rax = res.eax as _;
-            guest_regs.rbx = res.ebx as _;
-            guest_regs.rcx = res.ecx as _;
-            guest_regs.rdx = res.edx as _;
+            let res = cpuid!(function, sub_leaf);
+            let mut res = [res.eax, res.ebx, res.ecx, res.edx];
 
             if function == CpuIdEax::FeatureInfo as _ || function == CpuIdEax::AmdFeatureInfo as _ {
-                let mut flags = FeatureInfoFlags::from_bits_truncate(guest_regs.rcx as _);
+                let mut flags = FeatureInfoFlags::from_bits_truncate(res[2] as _);
                 if function == CpuIdEax::FeatureInfo as _ {
                     if cr4_flags.contains(Cr4Flags::OSXSAVE) {
                         flags.insert(FeatureInfoFlags::OSXSAVE);
@@ -137,9 +178,35 @@ impl VmExit<'_> {
                 } else if function == CpuIdEax::AmdFeatureInfo as _ {
                     flags.remove(FeatureInfoFlags::SVM);
                 }
-                guest_regs.rcx = flags.bits();
+                res[2] = flags.bits() as _;
+            }
+            res
+        };
+
+        for policy in HvSystemConfig::get().cpuid_policies() {
+            if policy.matches(function, sub_leaf) {
+                policy.apply(&mut res);
             }
         }
+        guest_regs.rax = res[0] as _;
+        guest_regs.rbx = res[1] as _;
+        guest_regs.rcx = res[2] as _;
+        guest_regs.rdx = res[3] as _;
+        self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_CPUID)?;
+        Ok(())
+    }
+
+    /// CPUID inside an enclave that opts in to CPUID emulation is served from the
+    /// CPUID table of the enclave.
+    fn handle_enclave_cpuid(&mut self) -> HvResult {
+        let enclave = self.cpu_data.get_current_enclave()?;
+        let table = enclave.cpuid_table().ok_or_else(|| hv_err!(EINVAL))?;
+        let guest_regs = self.cpu_data.vcpu.regs_mut();
+        let entry = table.get(guest_regs.rax as _, guest_regs.rcx as _);
+        guest_regs.rax = entry.eax as _;
+        guest_regs.rbx = entry.ebx as _;
+        guest_regs.rcx = entry.ecx as _;
+        guest_regs.rdx = entry.edx as _;
         self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_CPUID)?;
         Ok(())
     }
@@ -159,6 +226,45 @@ impl VmExit<'_> {
         Ok(())
     }
 
+    /// AEX out of the running enclave without an exception to report, the
+    /// time spent is also recorded under `stats_id`.
+    pub fn forced_aex(&mut self, now: Instant, stats_id: EnclaveStatsId) -> HvResult {
+        match self.cpu_data.enclave_aex(AexException {
+            vec: ExceptionType::IrqStart,
+            misc: None,
+        }) {
+            Ok(enclave) => {
+                let elapsed = now.elapsed();
+                enclave.atomic_add_stats(EnclaveStatsId::Aex, elapsed);
+                enclave.atomic_add_stats(stats_id, elapsed);
+            }
+            Err(e) => {
+                warn!("Enclave AEX failed!: {:x?}", e);
+                self.cpu_data.fault()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delivers an NMI taken by the hypervisor to Linux, leaving the enclave
+    /// first. It stays pending while it cannot be injected, until a later VM
+    /// exit.
+    fn handle_pending_nmi(&mut self) -> HvResult {
+        if !self.cpu_data.nmi_pending() || !self.cpu_data.vcpu.nmi_injectable()? {
+            return Ok(());
+        }
+        if self.cpu_data.state == CpuState::EnclaveRunning {
+            self.forced_aex(Instant::now(), EnclaveStatsId::ForcedAex)?;
+            if self.cpu_data.state == CpuState::EnclaveRunning {
+                // The AEX failed and a fault is being injected instead.
+                return Ok(());
+            }
+        }
+        self.cpu_data.vcpu.inject_nmi()?;
+        self.cpu_data.clear_nmi_pending();
+        Ok(())
+    }
+
     #[allow(dead_code)]
     fn test_read_guest_memory(&self, gvaddr: usize, size: usize) -> HvResult {
         use crate::cell;
@@ -177,9 +283,31 @@ impl VmExit<'_> {
     }
 }
 
+/// Capabilities of HyperEnclave reported to Linux and enclaves.
+fn hypervisor_features() -> super::cpuid::HypervisorFeatureFlags {
+    use super::cpuid::{CpuFeatures, HypervisorFeatureFlags as Flags};
+    let mut features = Flags::ENCLAVE | Flags::EDMM | Flags::AEX_NOTIFY | Flags::ENCLAVE_CPUID;
+    if enclave_time_slice_supported() {
+        features |= Flags::ENCLAVE_TIME_SLICE;
+    }
+    if CpuFeatures::new().has_cet_ss() {
+        features |= Flags::ENCLAVE_CET_SS;
+    }
+    if cfg!(feature = "enclave_interrupt") {
+        features |= Flags::ENCLAVE_INTR_EXITING;
+    }
+    if !HvSystemConfig::get().cpuid_policies().is_empty() {
+        features |= Flags::CPUID_POLICY;
+    }
+    features
+}
+
 pub(super) fn vmexit_handler() {
     let mut vmexit = VmExit::new();
-    let res = vmexit.handle_exit();
+    let res = vmexit
+        .handle_exit()
+        .and_then(|_| vmexit.cpu_data.handle_tlb_flush_request())
+        .and_then(|_| vmexit.handle_pending_nmi());
     if let Err(err) = res {
         error!(
             "Failed to handle VM exit, inject fault to guest...\n{:?}",
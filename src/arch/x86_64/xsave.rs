@@ -12,18 +12,25 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::enclave::sgx::{GprSgx, MiscSgx, SSA_FRAME_SIZE};
+use super::cpuid::CpuFeatures;
+use crate::enclave::sgx::SSA_XSAVE_MAX_PAGES;
 use crate::error::HvResult;
+use crate::memory::{Frame, PAGE_SIZE};
 use core::convert::TryInto;
 use core::fmt::{Debug, Formatter, Result};
+use core::ops::{Deref, DerefMut};
 
 /// XSAVE legacy region: 512 bytes
 pub const XSAVE_LEGACY_REGION_SIZE: usize = 512;
 /// XSAVE header: 64 bytes
 pub const XSAVE_HEADER_SIZE: usize = 64;
-/// XSAVE region: SSA_FRAME_SIZE - sizeof(MiscSgx) - sizeof(GprSgx) = 3896 bytes
-pub const XSAVE_REGION_SIZE: usize =
-    SSA_FRAME_SIZE - core::mem::size_of::<MiscSgx>() - core::mem::size_of::<GprSgx>();
+/// XSAVE region: large enough to hold all the state components (including AMX)
+/// in standard format.
+pub const XSAVE_REGION_SIZE: usize = SSA_XSAVE_MAX_PAGES * PAGE_SIZE;
+
+/// Normal world XSAVE region: large enough to hold all the state components
+/// (including AMX) in either format.
+pub const NORMAL_WORLD_XSAVE_REGION_SIZE: usize = 3 * PAGE_SIZE;
 
 pub static XSAVE_SYNTHETIC_STATE: XsaveSynteticStateRegion = XsaveSynteticStateRegion::new();
 
@@ -40,7 +47,46 @@ impl XsaveSynteticStateRegion {
     }
 }
 
-#[repr(C)]
+/// Size of the XSAVE region in standard format for the features in `xfrm`.
+///
+/// Iterate all the bits set in xfrm to get the offset and size of CPU extended state component.
+/// Follow the pseudo code provided by Intel SDM, Volume 3, 38.7.2.2
+pub fn xsave_size(xfrm: u64) -> usize {
+    let cpuid = CpuFeatures::new();
+    let mut offset = XSAVE_LEGACY_REGION_SIZE + XSAVE_HEADER_SIZE;
+    let mut size = 0;
+    for sub_leaf in 2..=63 {
+        if xfrm >> sub_leaf & 0b1 == 0b1 {
+            let (offset_res, size_res) = cpuid.xsave_state_info(sub_leaf);
+            if offset_res >= offset + size {
+                offset = offset_res;
+                size = size_res;
+            }
+        }
+    }
+    offset + size
+}
+
+/// Size of the XSAVE region in compacted format for the features in `rfbm`.
+///
+/// Follow the pseudo code provided by Intel SDM, Volume 1, 13.4.3
+pub fn xsaves_size(rfbm: u64) -> usize {
+    let cpuid = CpuFeatures::new();
+    let mut offset = XSAVE_LEGACY_REGION_SIZE + XSAVE_HEADER_SIZE;
+    for sub_leaf in 2..=63 {
+        if rfbm >> sub_leaf & 0b1 == 0b1 {
+            let (size, aligned) = cpuid.xsave_compacted_state_info(sub_leaf);
+            if aligned {
+                offset = (offset + 63) & !63;
+            }
+            offset += size;
+        }
+    }
+    offset
+}
+
+/// XSAVE region in standard format, XSAVE/XRSTOR require it to be 64-byte aligned.
+#[repr(C, align(64))]
 pub struct XsaveRegion([u8; XSAVE_REGION_SIZE]);
 
 impl XsaveRegion {
@@ -48,7 +94,19 @@ impl XsaveRegion {
         Self([0; XSAVE_REGION_SIZE])
     }
 
-    pub fn save(&mut self, xfrm: u64) {
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+
+    /// Save the state components in `xfrm`. The first `size` bytes are cleared first,
+    /// so that components in their init state (not written by XSAVE) do not leak
+    /// the stale contents of the region.
+    pub fn save(&mut self, xfrm: u64, size: usize) {
+        self.0[..size].fill(0);
         unsafe { core::arch::x86_64::_xsave(self.0.as_mut_ptr(), xfrm) };
     }
 
@@ -81,10 +139,113 @@ impl XsaveRegion {
     }
 }
 
+/// Extended state of the normal world, saved on enclave entry and restored on
+/// enclave exit or AEX, so that the enclave can neither observe nor corrupt it.
+///
+/// The compacted format (XSAVES/XRSTORS) is used if the processor supports it,
+/// which also covers the CET user state. Otherwise falls back to the standard
+/// format (XSAVE/XRSTOR). SSA frames always use the standard format.
+pub struct NormalWorldXsaveRegion {
+    frame: Frame,
+    /// Requested-feature bitmap of the last save, 0 if nothing is saved.
+    rfbm: u64,
+    compacted: bool,
+}
+
+impl NormalWorldXsaveRegion {
+    pub fn new() -> HvResult<Self> {
+        let cpuid = CpuFeatures::new();
+        let compacted = cpuid.has_xsaves_xrstors();
+        let xcr0_supported_bits = cpuid.xcr0_supported_bits();
+        let max_size = if compacted {
+            xsaves_size(xcr0_supported_bits)
+        } else {
+            xsave_size(xcr0_supported_bits)
+        };
+        if max_size > NORMAL_WORLD_XSAVE_REGION_SIZE {
+            return hv_result_err!(
+                ENOMEM,
+                format!(
+                    "NormalWorldXsaveRegion::new(): {:#x} bytes needed, but the region is only {:#x} bytes",
+                    max_size, NORMAL_WORLD_XSAVE_REGION_SIZE
+                )
+            );
+        }
+        let mut frame = Frame::new_contiguous(NORMAL_WORLD_XSAVE_REGION_SIZE / PAGE_SIZE, 0)?;
+        frame.zero();
+        Ok(Self {
+            frame,
+            rfbm: 0,
+            compacted,
+        })
+    }
+
+    /// Save the state components enabled in XCR0, must be called while the
+    /// normal world's XCR0 is loaded. The CET user state, the only supervisor
+    /// state the enclave can change, is switched by `CetUserState` instead.
+    pub fn save(&mut self) {
+        self.rfbm = unsafe { core::arch::x86_64::_xgetbv(0) };
+        if self.compacted {
+            unsafe { core::arch::x86_64::_xsaves(self.frame.as_mut_ptr(), self.rfbm) };
+        } else {
+            unsafe { core::arch::x86_64::_xsave(self.frame.as_mut_ptr(), self.rfbm) };
+        }
+    }
+
+    /// Restore the state saved by `save()`, must be called after the normal
+    /// world's XCR0 is reloaded.
+    pub fn restore(&mut self) {
+        if self.rfbm == 0 {
+            return;
+        }
+        if self.compacted {
+            unsafe { core::arch::x86_64::_xrstors(self.frame.as_ptr(), self.rfbm) };
+        } else {
+            unsafe { core::arch::x86_64::_xrstor(self.frame.as_ptr(), self.rfbm) };
+        }
+        self.rfbm = 0;
+    }
+}
+
+impl Debug for NormalWorldXsaveRegion {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        f.debug_struct("NormalWorldXsaveRegion")
+            .field("rfbm", &self.rfbm)
+            .field("compacted", &self.compacted)
+            .finish()
+    }
+}
+
+/// An `XsaveRegion` allocated from physical frames, since it is too large to be
+/// built on the stack.
+pub struct XsaveRegionFrame(Frame);
+
+impl XsaveRegionFrame {
+    pub fn new() -> HvResult<Self> {
+        let mut frame = Frame::new_contiguous(XSAVE_REGION_SIZE / PAGE_SIZE, 0)?;
+        frame.zero();
+        Ok(Self(frame))
+    }
+}
+
+impl Deref for XsaveRegionFrame {
+    type Target = XsaveRegion;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*(self.0.as_ptr() as *const XsaveRegion) }
+    }
+}
+
+impl DerefMut for XsaveRegionFrame {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *(self.0.as_mut_ptr() as *mut XsaveRegion) }
+    }
+}
+
 impl Debug for XsaveRegion {
     fn fmt(&self, f: &mut Formatter) -> Result {
         f.debug_tuple("XsaveRegion")
-            .field(unsafe { &core::mem::transmute::<_, [u8; XSAVE_REGION_SIZE]>(self.0) })
+            .field(&&self.0[..XSAVE_LEGACY_REGION_SIZE + XSAVE_HEADER_SIZE])
             .finish()
     }
 }
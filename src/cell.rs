@@ -139,6 +139,23 @@ impl Cell {
             MemFlags::READ | MemFlags::WRITE,
         ))?;
         println!("tpm mmio is mapped va={:#x}", header.tpm_mmio_pa);
+        // local APIC registers, to send NMIs in xAPIC mode
+        let (xapic_pa, xapic_size) = crate::arch::apic::xapic_region();
+        hvm.insert(MemoryRegion::new_with_offset_mapper(
+            xapic_pa,
+            xapic_pa,
+            xapic_size,
+            MemFlags::READ | MemFlags::WRITE,
+        ))?;
+        // TXT public registers, to find out whether we were launched through TXT
+        if let Some((txt_pa, txt_size)) = crate::arch::txt::public_space_region() {
+            hvm.insert(MemoryRegion::new_with_offset_mapper(
+                txt_pa,
+                txt_pa,
+                txt_size,
+                MemFlags::READ,
+            ))?;
+        }
         for region in sys_config.mem_regions() {
             let flags = region.flags; 
             if flags.contains(MemFlags::DMA) {
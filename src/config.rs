@@ -17,6 +17,7 @@ use core::{mem::size_of, slice};
 
 use crate::consts::HV_BASE;
 use crate::header::HvHeader;
+use crate::logging::HEFeature;
 use crate::memory::MemFlags;
 use crate::percpu::PER_CPU_SIZE;
 
@@ -49,6 +50,42 @@ pub struct HvRmrrRange {
     pub limit: u64,
 }
 
+/// Sub-leaf of `HvCpuIdPolicy` that matches all the sub-leaves.
+pub const HV_CPUID_SUB_LEAF_ANY: u32 = u32::MAX;
+
+/// Adjusts the values of a CPUID leaf returned to Linux.
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct HvCpuIdPolicy {
+    pub leaf: u32,
+    /// Sub-leaf the policy applies to, or `HV_CPUID_SUB_LEAF_ANY`.
+    pub sub_leaf: u32,
+    /// Bits cleared from EAX, EBX, ECX and EDX.
+    pub clear: [u32; 4],
+    /// Bits set in EAX, EBX, ECX and EDX after clearing.
+    pub set: [u32; 4],
+}
+
+impl HvCpuIdPolicy {
+    pub fn matches(&self, leaf: u32, sub_leaf: u32) -> bool {
+        let policy_sub_leaf = self.sub_leaf;
+        self.leaf == leaf
+            && (policy_sub_leaf == HV_CPUID_SUB_LEAF_ANY || policy_sub_leaf == sub_leaf)
+    }
+
+    /// Apply the policy to `regs` (EAX, EBX, ECX, EDX).
+    pub fn apply(&self, regs: &mut [u32; 4]) {
+        let (clear, set) = (self.clear, self.set);
+        for (reg, (clear, set)) in regs.iter_mut().zip(clear.iter().zip(set.iter())) {
+            *reg = *reg & !clear | set;
+        }
+    }
+}
+
+/// MRSIGNER of the enclaves allowed to launch with the PROVISIONKEY attribute.
+pub type HvProvisioningSigner = [u8; 32];
+
 /// 在 AArch64 中依旧复用该结构 
 /// 通过 iommu_units, rmrr_ranges 来处理可用内存 
 // #[cfg(target_arch = "x86_64")]
@@ -82,6 +119,33 @@ pub struct HvSystemConfig {
 #[repr(C, packed)]
 struct ConfigLayout {
     mem_regions: [HvMemoryRegion; 0],
+    // HvSystemConfigExt placed here if the driver declares it.
+    cpuid_policies: [HvCpuIdPolicy; 0],
+    provisioning_signers: [HvProvisioningSigner; 0],
+}
+
+/// Extension of the system descriptor, placed after the memory regions by the
+/// drivers declaring `HEFeature::SYSTEM_CONFIG_EXT`.
+///
+/// `size` is the size of the extension known to the driver. New fields are only
+/// appended: the fields beyond `size` read as 0, and the ones unknown to the
+/// hypervisor are skipped.
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C, packed)]
+struct HvSystemConfigExt {
+    size: u32,
+    num_cpuid_policies: u32,
+    /// Transient-execution mitigations not run on enclave transitions, in bits
+    /// of `arch::Mitigations`. All the applicable ones are run if it is zero.
+    disabled_mitigations: u32,
+    /// PCR extended with the identity of every enclave launched, or 0 if the
+    /// launches are not measured. Only the PCR of the hypervisor (12), or the
+    /// dynamic PCRs 17 and 18 under TXT, are accepted.
+    measured_launch_pcr: u32,
+    num_provisioning_signers: u32,
+    /// Slots of the TPM monotonic counters of enclaves, or 0 for the default
+    /// number. Enough must be left for the other users of the TPM NV.
+    num_counter_slots: u32,
 }
 
 impl HvSystemConfig {
@@ -96,8 +160,43 @@ impl HvSystemConfig {
         unsafe { (self as *const HvSystemConfig).add(1) as _ }
     }
 
-    pub const fn size(&self) -> usize {
-        size_of::<Self>() + self.num_memory_regions as usize * size_of::<HvMemoryRegion>()
+    fn ext_ptr(&self) -> *const u8 {
+        let mem_regions = self.mem_regions();
+        unsafe { mem_regions.as_ptr().add(mem_regions.len()) as _ }
+    }
+
+    /// Size of the extension declared by the driver, 0 if there is none.
+    fn ext_size(&self) -> usize {
+        if !HvHeader::get()
+            .feature_mask
+            .contains(HEFeature::SYSTEM_CONFIG_EXT)
+        {
+            return 0;
+        }
+        let size = unsafe { (self.ext_ptr() as *const u32).read_unaligned() } as usize;
+        size.max(size_of::<u32>())
+    }
+
+    fn ext(&self) -> HvSystemConfigExt {
+        let mut ext = HvSystemConfigExt::default();
+        let len = self.ext_size().min(size_of::<HvSystemConfigExt>());
+        unsafe {
+            core::ptr::copy_nonoverlapping(self.ext_ptr(), &mut ext as *mut _ as *mut u8, len)
+        };
+        ext
+    }
+
+    pub fn size(&self) -> usize {
+        size_of::<Self>()
+            + self.num_memory_regions as usize * size_of::<HvMemoryRegion>()
+            + self.ext_size()
+            + self.ext().num_cpuid_policies as usize * size_of::<HvCpuIdPolicy>()
+            + self.ext().num_provisioning_signers as usize * size_of::<HvProvisioningSigner>()
+    }
+
+    /// The whole descriptor, with its variable-size fields.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const Self as *const u8, self.size()) }
     }
 
     pub fn iommu_units(&self) -> &[HvIommuInfo] {
@@ -118,4 +217,38 @@ impl HvSystemConfig {
     pub fn mem_regions(&self) -> &[HvMemoryRegion] {
         unsafe { slice::from_raw_parts(self.config_ptr(), self.num_memory_regions as usize) }
     }
+
+    pub fn disabled_mitigations(&self) -> u32 {
+        self.ext().disabled_mitigations
+    }
+
+    pub fn measured_launch_pcr(&self) -> u32 {
+        self.ext().measured_launch_pcr
+    }
+
+    pub fn num_counter_slots(&self) -> u32 {
+        self.ext().num_counter_slots
+    }
+
+    /// Policies applied in order to the CPUID leaves returned to Linux.
+    pub fn cpuid_policies(&self) -> &[HvCpuIdPolicy] {
+        unsafe {
+            slice::from_raw_parts(
+                self.ext_ptr().add(self.ext_size()) as _,
+                self.ext().num_cpuid_policies as usize,
+            )
+        }
+    }
+
+    /// The launch policy of enclaves with the PROVISIONKEY attribute: none may
+    /// launch if it is empty.
+    pub fn provisioning_signers(&self) -> &[HvProvisioningSigner] {
+        let cpuid_policies = self.cpuid_policies();
+        unsafe {
+            slice::from_raw_parts(
+                cpuid_policies.as_ptr().add(cpuid_policies.len()) as _,
+                self.ext().num_provisioning_signers as usize,
+            )
+        }
+    }
 }
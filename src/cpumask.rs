@@ -17,7 +17,7 @@ use crate::HvResult;
 
 use core::mem::size_of;
 
-const NR_CPUS: usize = 512;
+pub const NR_CPUS: usize = 512;
 const BITS_PER_BYTE: usize = 8;
 const BITS_PER_USIZE: usize = size_of::<usize>() * BITS_PER_BYTE;
 pub const CPU_MASK_LEN: usize = (NR_CPUS + BITS_PER_USIZE - 1) / BITS_PER_USIZE;
@@ -42,6 +42,11 @@ impl CpuMask {
     pub fn clear(&mut self) {
         self.0 = [0; CPU_MASK_LEN];
     }
+
+    /// IDs of the CPUs in the mask, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..NR_CPUS).filter(move |&cpuid| self.test_cpu(cpuid) != 0)
+    }
 }
 
 pub fn check_max_cpus() -> HvResult {
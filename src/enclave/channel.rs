@@ -0,0 +1,387 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! EPC channels: memory shared between two enclaves that stays in EPC.
+//!
+//! The owner offers a range of its own REG pages, together with the identity
+//! (MRENCLAVE or MRSIGNER) the peer must have. The peer accepts the channel
+//! with the identity it requires from the owner, and the pages are mapped into
+//! a free range of its ELRANGE, in both its GPT and NPT. Channel pages cannot
+//! be reclaimed, and their type or permissions cannot change. A channel is
+//! closed when either enclave is destroyed.
+//!
+//! A running peer does not hold back the destroy of the owner: the channel is
+//! unmapped from the peer and its TLB is flushed before the pages go back to
+//! the owner. The range stays reserved in the ELRANGE of the peer as a dead
+//! channel, so that its next access faults instead of reaching a new page,
+//! until the peer is destroyed as well.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+use spin::mutex::SpinMutex;
+
+use super::epcm::EpcmManager;
+use super::sgx::{SgxEnclPageFlags, SgxEnclPageType, SgxSecInfo};
+use super::structs::{
+    HvEnclChannelAcceptDesc, HvEnclChannelCreateDesc, HvEnclChannelPolicy,
+    HV_ENCL_CHANNEL_POLICY_MRENCLAVE, HV_ENCL_CHANNEL_POLICY_MRSIGNER,
+};
+use super::Enclave;
+use crate::error::HvResult;
+use crate::hypercall::error::HyperCallResult;
+use crate::memory::addr::{is_aligned, GuestPhysAddr, GuestVirtAddr};
+use crate::memory::{GenericPageTable, GenericPageTableImmut, MemFlags, MemoryRegion};
+use crate::memory::{PagingError, PAGE_SIZE};
+use crate::percpu::PerCpu;
+
+/// Maximum number of pages in a channel.
+const MAX_CHANNEL_PAGES: usize = 256;
+
+struct EpcChannel {
+    id: u64,
+    owner: Arc<Enclave>,
+    /// Identity the owner requires from the peer.
+    peer_policy: HvEnclChannelPolicy,
+    /// EPC pages of the owner, in the order of their linear addresses.
+    pages: Vec<GuestPhysAddr>,
+    /// The peer and the start address of the channel in its ELRANGE, `None`
+    /// until the channel is accepted.
+    peer: Option<(Arc<Enclave>, GuestVirtAddr)>,
+}
+
+impl EpcChannel {
+    fn involves(&self, enclave: &Arc<Enclave>) -> bool {
+        Arc::ptr_eq(&self.owner, enclave)
+            || matches!(&self.peer, Some((peer, _)) if Arc::ptr_eq(peer, enclave))
+    }
+
+    /// Unmap the channel from the peer, and unpin the pages of the owner once
+    /// no CPU running the peer can access them any more.
+    fn close(self, cpu_data: &mut PerCpu) -> HvResult {
+        if let Some((peer, addr)) = &self.peer {
+            peer.unmap_channel(*addr, &self.pages);
+            cpu_data.flush_enclave_tlb(peer)?;
+        }
+        for gpaddr in self.pages.iter() {
+            EpcmManager::unshare_page(*gpaddr);
+        }
+        info!(
+            "EPC channel {} of enclave {:#x} closed",
+            self.id, self.owner.id
+        );
+        Ok(())
+    }
+}
+
+/// The range of a channel in the ELRANGE of a peer, after the owner was
+/// destroyed.
+struct DeadChannel {
+    peer: Arc<Enclave>,
+    range: Range<GuestVirtAddr>,
+}
+
+struct ChannelTable {
+    channels: Vec<EpcChannel>,
+    dead: Vec<DeadChannel>,
+    next_id: u64,
+}
+
+static CHANNELS: SpinMutex<ChannelTable> = SpinMutex::new(ChannelTable {
+    channels: Vec::new(),
+    dead: Vec::new(),
+    next_id: 1,
+});
+
+fn overlaps_dead_channel(
+    dead: &[DeadChannel],
+    enclave: &Arc<Enclave>,
+    range: &Range<GuestVirtAddr>,
+) -> bool {
+    dead.iter().any(|d| {
+        Arc::ptr_eq(&d.peer, enclave) && d.range.start < range.end && range.start < d.range.end
+    })
+}
+
+fn check_policy(policy: &HvEnclChannelPolicy, enclave: &Enclave) -> HvResult {
+    let policy_type = policy.policy_type;
+    let matched = match policy_type {
+        HV_ENCL_CHANNEL_POLICY_MRENCLAVE => enclave.measurement() == policy.value,
+        HV_ENCL_CHANNEL_POLICY_MRSIGNER => enclave.mr_signer() == policy.value.as_slice(),
+        _ => {
+            return hv_result_err!(
+                EINVAL,
+                format!("EPC channel: invalid policy type {:#x}", policy_type)
+            )
+        }
+    };
+    if !matched {
+        return hv_result_err!(
+            EPERM,
+            format!(
+                "EPC channel: enclave {:#x} does not match the policy",
+                enclave.id
+            )
+        );
+    }
+    Ok(())
+}
+
+impl Enclave {
+    /// Validate a page-aligned range of `size` bytes at `start` in ELRANGE,
+    /// returns the number of pages.
+    fn channel_range(&self, start: GuestVirtAddr, size: usize) -> HvResult<usize> {
+        let num_pages = size / PAGE_SIZE;
+        if !is_aligned(start) || !is_aligned(size) || num_pages == 0 {
+            return hv_result_err!(
+                EINVAL,
+                format!("EPC channel: bad range {:#x}, size {:#x}", start, size)
+            );
+        }
+        if num_pages > MAX_CHANNEL_PAGES {
+            return hv_result_err!(
+                EINVAL,
+                format!("EPC channel: too many pages ({})", num_pages)
+            );
+        }
+        let range: Range<GuestVirtAddr> = start..start + size;
+        if range.start < self.elrange.start || range.end > self.elrange.end {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "EPC channel: {:#x?} is out of ELRANGE {:#x?}",
+                    range, self.elrange
+                )
+            );
+        }
+        Ok(num_pages)
+    }
+
+    /// Offer the owner's pages at `desc.start_addr` as a channel, returns the
+    /// channel ID.
+    pub fn create_channel(
+        self: &Arc<Self>,
+        desc: &HvEnclChannelCreateDesc,
+    ) -> HyperCallResult<u64> {
+        if !self.is_init() {
+            return hypercall_hv_err_result!(
+                EINVAL,
+                "Enclave::create_channel(): enclave is not initialized"
+            );
+        }
+        let peer_policy = desc.peer_policy;
+        let policy_type = peer_policy.policy_type;
+        if policy_type != HV_ENCL_CHANNEL_POLICY_MRENCLAVE
+            && policy_type != HV_ENCL_CHANNEL_POLICY_MRSIGNER
+        {
+            return hypercall_hv_err_result!(
+                EINVAL,
+                format!(
+                    "Enclave::create_channel(): invalid policy type {:#x}",
+                    policy_type
+                )
+            );
+        }
+        let start = desc.start_addr as usize;
+        let num_pages = self.channel_range(start, desc.size as usize)?;
+
+        let mut pages = Vec::with_capacity(num_pages);
+        {
+            let _encl_mem_lock = self.encl_mem_lock.lock();
+            let gpt = self.gpt.read();
+            for gvaddr in (start..).step_by(PAGE_SIZE).take(num_pages) {
+                let res = match gpt.query(gvaddr) {
+                    Ok((gpaddr, _, _)) => self
+                        .settle_cow_page_or_retry(gvaddr, gpaddr)
+                        .and_then(|_| EpcmManager::share_page(gvaddr, gpaddr, self))
+                        .map(|_| gpaddr),
+                    Err(e) => Err(e.into()),
+                };
+                match res {
+                    Ok(gpaddr) => pages.push(gpaddr),
+                    Err(e) => {
+                        for gpaddr in pages.iter() {
+                            EpcmManager::unshare_page(*gpaddr);
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        let mut table = CHANNELS.lock();
+        let id = table.next_id;
+        table.next_id += 1;
+        table.channels.push(EpcChannel {
+            id,
+            owner: self.clone(),
+            peer_policy,
+            pages,
+            peer: None,
+        });
+        info!(
+            "EPC channel {} created by enclave {:#x}: {:#x} pages at {:#x}",
+            id, self.id, num_pages, start
+        );
+        Ok(id)
+    }
+
+    /// Accept the channel `desc.id` and map it at `desc.addr`, returns the size
+    /// of the channel.
+    pub fn accept_channel(
+        self: &Arc<Self>,
+        desc: &HvEnclChannelAcceptDesc,
+    ) -> HyperCallResult<usize> {
+        if !self.is_init() {
+            return hypercall_hv_err_result!(
+                EINVAL,
+                "Enclave::accept_channel(): enclave is not initialized"
+            );
+        }
+        let id = desc.id;
+        let mut guard = CHANNELS.lock();
+        let table = &mut *guard;
+        let channel = match table.channels.iter_mut().find(|c| c.id == id) {
+            Some(channel) if channel.peer.is_none() && !Arc::ptr_eq(&channel.owner, self) => {
+                channel
+            }
+            _ => {
+                return hypercall_hv_err_result!(
+                    ENOENT,
+                    format!("Enclave::accept_channel(): no channel {} to accept", id)
+                )
+            }
+        };
+        // Mutual attestation: each side must match the policy of the other.
+        check_policy(&channel.peer_policy, self)?;
+        check_policy(&desc.owner_policy, &channel.owner)?;
+
+        let size = channel.pages.len() * PAGE_SIZE;
+        let addr = desc.addr as usize;
+        self.channel_range(addr, size)?;
+        if overlaps_dead_channel(&table.dead, self, &(addr..addr + size)) {
+            return hypercall_hv_err_result!(
+                EEXIST,
+                format!(
+                    "Enclave::accept_channel(): {:#x} is in a closed EPC channel",
+                    addr
+                )
+            );
+        }
+        self.map_channel(addr, &channel.pages)?;
+        channel.peer = Some((self.clone(), addr));
+        info!(
+            "EPC channel {} of enclave {:#x} accepted by enclave {:#x} at {:#x}",
+            id, channel.owner.id, self.id, addr
+        );
+        Ok(size)
+    }
+
+    fn map_channel(&self, addr: GuestVirtAddr, pages: &[GuestPhysAddr]) -> HyperCallResult {
+        let sec_info = SgxSecInfo::new(
+            SgxEnclPageFlags::R | SgxEnclPageFlags::W,
+            SgxEnclPageType::REG,
+        );
+        let gpt_flags: MemFlags = sec_info.into();
+        let npt_flags = sec_info.npt_flags() | MemFlags::ENCRYPTED;
+
+        let _encl_mem_lock = self.encl_mem_lock.lock();
+        let mut gpt = self.gpt.write();
+        for gvaddr in (addr..).step_by(PAGE_SIZE).take(pages.len()) {
+            match gpt.query(gvaddr) {
+                Err(PagingError::NotMapped(_)) => {}
+                _ => {
+                    return hypercall_hv_err_result!(
+                        EEXIST,
+                        format!("Enclave::map_channel(): {:#x} is already in use", gvaddr)
+                    )
+                }
+            }
+        }
+        let mut npt = self.npt.write();
+        for (i, (gvaddr, gpaddr)) in (addr..).step_by(PAGE_SIZE).zip(pages).enumerate() {
+            let res = npt
+                .map(&MemoryRegion::new_with_offset_mapper(
+                    *gpaddr, *gpaddr, PAGE_SIZE, npt_flags,
+                ))
+                .and_then(|_| {
+                    gpt.map(&MemoryRegion::new_with_offset_mapper(
+                        gvaddr, *gpaddr, PAGE_SIZE, gpt_flags,
+                    ))
+                });
+            if let Err(e) = res {
+                error!("Enclave::map_channel(): error when new mapping: {:?}", e);
+                drop((gpt, npt, _encl_mem_lock));
+                self.unmap_channel(addr, &pages[..=i]);
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+
+    fn unmap_channel(&self, addr: GuestVirtAddr, pages: &[GuestPhysAddr]) {
+        let _encl_mem_lock = self.encl_mem_lock.lock();
+        let mut gpt = self.gpt.write();
+        let mut npt = self.npt.write();
+        for (gvaddr, gpaddr) in (addr..).step_by(PAGE_SIZE).zip(pages) {
+            let _ = gpt.unmap(&MemoryRegion::new_with_offset_mapper(
+                gvaddr,
+                0,
+                PAGE_SIZE,
+                MemFlags::empty(),
+            ));
+            let _ = npt.unmap(&MemoryRegion::new_with_offset_mapper(
+                *gpaddr,
+                0,
+                PAGE_SIZE,
+                MemFlags::empty(),
+            ));
+        }
+    }
+
+    /// Whether `gvaddr` is in a channel whose owner was destroyed, it must not
+    /// be mapped again.
+    pub(super) fn in_dead_channel(self: &Arc<Self>, gvaddr: GuestVirtAddr) -> bool {
+        overlaps_dead_channel(&CHANNELS.lock().dead, self, &(gvaddr..gvaddr + PAGE_SIZE))
+    }
+
+    pub(super) fn has_channels(self: &Arc<Self>) -> bool {
+        CHANNELS.lock().channels.iter().any(|c| c.involves(self))
+    }
+
+    /// Close all the channels of the enclave before it is destroyed. Running
+    /// peers are not waited for, the channels are taken away from them.
+    pub(super) fn close_channels(self: &Arc<Self>, cpu_data: &mut PerCpu) -> HvResult {
+        let mut table = CHANNELS.lock();
+        table.dead.retain(|d| !Arc::ptr_eq(&d.peer, self));
+        let (closed, kept): (Vec<_>, Vec<_>) = core::mem::take(&mut table.channels)
+            .into_iter()
+            .partition(|c| c.involves(self));
+        table.channels = kept;
+        for channel in closed.iter().filter(|c| Arc::ptr_eq(&c.owner, self)) {
+            if let Some((peer, addr)) = &channel.peer {
+                table.dead.push(DeadChannel {
+                    peer: peer.clone(),
+                    range: *addr..*addr + channel.pages.len() * PAGE_SIZE,
+                });
+            }
+        }
+        // Flushing waits for the CPUs running the peers, which may be waiting
+        // for the table.
+        drop(table);
+        closed
+            .into_iter()
+            .try_for_each(|channel| channel.close(cpu_data))
+    }
+}
@@ -0,0 +1,509 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Copy-on-write clones of an initialized template enclave.
+//!
+//! A clone has the SECS of the template, and thus the same MRENCLAVE, but its
+//! own ID. Linux backs each page of the template with an EPC page of the clone:
+//! the GPT of the clone maps the page as the template does, while its NPT maps
+//! the EPC page read-only to the template page. TCS pages are copied at once
+//! since hypervisor accesses them by their physical address.
+//!
+//! On the first write, the template page is copied and the EPC page is unmapped.
+//! Threads of the clone that entered before the copy may still cache the
+//! translation to the template page, so the page is mapped back writable only
+//! after all of them have left the enclave. In the meantime the writing thread
+//! is forced out of the enclave to retry, so clones should run with interrupt
+//! exiting or a time slice to make progress.
+//!
+//! Once cloned, the template never runs again. Each of its pages is pinned
+//! while a clone is backed by it. Clones get its identity through
+//! `EnclaveGetIdentity`.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+
+use sha2::{Digest, Sha256};
+use spin::mutex::SpinMutex;
+
+use super::epcm::EpcmManager;
+use super::sgx::{SgxEnclPageType, SgxSecInfo};
+use super::structs::{HvEnclClonePage, HvEnclCreateFlags, Sha256Value};
+use super::{Enclave, EnclaveStatsId, STATE_INIT_OK, STATE_INIT_TRY, STATE_UNINIT};
+use crate::arch::{EnclaveExceptionInfo, PageFaultErrorCode};
+use crate::error::HvResult;
+use crate::hypercall::error::HyperCallResult;
+use crate::hypercall::eventlog;
+use crate::memory::addr::{is_aligned, phys_to_virt, GuestPhysAddr, GuestVirtAddr};
+use crate::memory::{GenericPageTable, GenericPageTableImmut, MemFlags, MemoryRegion, PAGE_SIZE};
+use crate::stats::Instant;
+
+/// A page of a clone still shared with the template.
+#[derive(Debug)]
+struct CowPage {
+    /// The template page.
+    src: GuestPhysAddr,
+    /// NPT flags of the template page.
+    flags: MemFlags,
+    /// Epoch the page was copied at, `None` if it is not copied yet.
+    epoch: Option<u64>,
+}
+
+/// Epochs the threads running a clone entered at.
+#[derive(Debug, Default)]
+struct CowEpochs {
+    current: u64,
+    inside: BTreeMap<usize, u64>,
+}
+
+impl CowEpochs {
+    fn update(&mut self, is_enter: bool, cpuid: usize) {
+        if is_enter {
+            self.inside.insert(cpuid, self.current);
+        } else {
+            self.inside.remove(&cpuid);
+        }
+    }
+
+    /// Start a new epoch, the translations cached before it may be stale.
+    fn advance(&mut self) -> u64 {
+        self.current += 1;
+        self.current
+    }
+
+    /// Whether all the running threads entered at or after `epoch`.
+    fn passed(&self, epoch: u64) -> bool {
+        self.inside.values().all(|&e| e >= epoch)
+    }
+}
+
+pub(super) struct CloneState {
+    template: Arc<Enclave>,
+    /// Template pages pinned by the clone, unpinned when it is dropped.
+    pinned: SpinMutex<Vec<GuestPhysAddr>>,
+    pages: SpinMutex<BTreeMap<GuestPhysAddr, CowPage>>,
+    epochs: SpinMutex<CowEpochs>,
+}
+
+impl CloneState {
+    fn new(template: Arc<Enclave>) -> Self {
+        template.clones.fetch_add(1, Ordering::AcqRel);
+        Self {
+            template,
+            pinned: SpinMutex::new(Vec::new()),
+            pages: SpinMutex::new(BTreeMap::new()),
+            epochs: SpinMutex::new(Default::default()),
+        }
+    }
+}
+
+impl Drop for CloneState {
+    fn drop(&mut self) {
+        for src in self.pinned.get_mut().iter() {
+            EpcmManager::unpin_template_page(*src);
+        }
+        self.template.clones.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl Enclave {
+    pub fn is_template(&self) -> bool {
+        self.is_template.load(Ordering::Acquire)
+    }
+
+    pub(super) fn has_clones(&self) -> bool {
+        self.clones.load(Ordering::Acquire) != 0
+    }
+
+    fn clone_state(&self) -> HyperCallResult<&CloneState> {
+        match &self.clone {
+            Some(clone) => Ok(clone),
+            None => {
+                hypercall_hv_err_result!(EINVAL, format!("Enclave {:#x} is not a clone", self.id))
+            }
+        }
+    }
+
+    /// Identity of the template the enclave is cloned from, the SHA-256 digest
+    /// of its MRENCLAVE and ID. All 0 if the enclave is not a clone.
+    pub fn template_digest(&self) -> Sha256Value {
+        let mut digest = Sha256Value::default();
+        if let Some(clone) = &self.clone {
+            let mut hasher = Sha256::new();
+            hasher.update(clone.template.measurement().as_slice());
+            hasher.update(clone.template.id.to_le_bytes());
+            digest
+                .as_mut_slice()
+                .copy_from_slice(hasher.finalize().as_slice());
+        }
+        digest
+    }
+
+    /// Create a clone of the template with SECS at `secs_paddr`, the template
+    /// is frozen from now on.
+    pub fn clone_enclave(
+        self: &Arc<Self>,
+        secs_paddr: GuestPhysAddr,
+        secs_vaddr: GuestVirtAddr,
+    ) -> HyperCallResult<Arc<Self>> {
+        if !self.is_init() || self.clone.is_some() {
+            return hypercall_hv_err_result!(
+                EINVAL,
+                format!(
+                    "Enclave::clone_enclave(): enclave {:#x} is not an initialized template",
+                    self.id
+                )
+            );
+        }
+        if self.has_channels() {
+            return hypercall_hv_err_result!(
+                EBUSY,
+                format!(
+                    "Enclave::clone_enclave(): enclave {:#x} has EPC channels",
+                    self.id
+                )
+            );
+        }
+        if self.has_dedup_pages() {
+            return hypercall_hv_err_result!(
+                EBUSY,
+                format!(
+                    "Enclave::clone_enclave(): enclave {:#x} has pages shared with other enclaves",
+                    self.id
+                )
+            );
+        }
+        // Freeze the template before checking for running threads, so that no
+        // thread enters meanwhile, and thaw it again if the clone fails.
+        let was_template = self.is_template.swap(true, Ordering::AcqRel);
+        let clone = self.new_clone(secs_paddr, secs_vaddr);
+        if clone.is_err() && !was_template {
+            self.is_template.store(false, Ordering::Release);
+        }
+        clone
+    }
+
+    fn new_clone(
+        self: &Arc<Self>,
+        secs_paddr: GuestPhysAddr,
+        secs_vaddr: GuestVirtAddr,
+    ) -> HyperCallResult<Arc<Self>> {
+        let active_thread_num = self.tracking_state.read().active_thread_num();
+        if active_thread_num != 0 {
+            let msg = format!(
+                "Enclave::clone_enclave(): There still exist thread (num = {:?}) in enclave mode",
+                active_thread_num
+            );
+            warn!("{}", msg);
+            return Err(hypercall_enclave_err!(EENCLAVEACT, msg));
+        }
+
+        let flags = if self.intr_exiting {
+            HvEnclCreateFlags::INTR_EXITING
+        } else {
+            HvEnclCreateFlags::NO_INTR_EXITING
+        };
+        let clone = Self::new_inner(
+            secs_paddr,
+            secs_vaddr,
+            *self.secs(),
+            flags,
+            Some(CloneState::new(Arc::clone(self))),
+        )?;
+        clone.time_slice.store(self.time_slice(), Ordering::Release);
+        info!("Enclave {:#x} cloned from {:#x}", secs_paddr, self.id);
+        Ok(clone)
+    }
+
+    /// Back the template pages `pages` with EPC pages of the clone.
+    pub fn clone_add_pages(self: &Arc<Self>, pages: &[HvEnclClonePage]) -> HyperCallResult {
+        let clone = self.clone_state()?;
+        if self.state.load(Ordering::SeqCst) != STATE_UNINIT {
+            return hypercall_hv_err_result!(
+                EBUSY,
+                "Enclave::clone_add_pages(): enclave is already initialized"
+            );
+        }
+        let template = &clone.template;
+        for page in pages {
+            let gvaddr = page.enclave_lin_addr as usize;
+            let gpaddr = page.epc_page_pa as usize;
+            if !is_aligned(gvaddr) || !is_aligned(gpaddr) || !self.elrange.contains(&gvaddr) {
+                return hypercall_hv_err_result!(
+                    EINVAL,
+                    format!(
+                        "Enclave::clone_add_pages(): invalid page {:#x} -> {:#x}",
+                        gvaddr, gpaddr
+                    )
+                );
+            }
+
+            let _encl_mem_lock = self.encl_mem_lock.lock();
+            let (src, gpt_flags, _) = match template.gpt.read().query(gvaddr) {
+                Ok(res) => res,
+                Err(e) => {
+                    return hypercall_hv_err_result!(
+                        EINVAL,
+                        format!(
+                            "Enclave::clone_add_pages(): template page {:#x} is not resident: {:?}",
+                            gvaddr, e
+                        )
+                    )
+                }
+            };
+            let sec_info = EpcmManager::pin_template_page(gvaddr, src, template)?;
+            let npt_flags = match self.map_clone_page(gvaddr, gpaddr, src, gpt_flags, &sec_info) {
+                Ok(npt_flags) => npt_flags,
+                Err(e) => {
+                    EpcmManager::unpin_template_page(src);
+                    return Err(e);
+                }
+            };
+            clone.pinned.lock().push(src);
+
+            match npt_flags {
+                None => {
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(
+                            phys_to_virt(src) as *const u8,
+                            phys_to_virt(gpaddr) as *mut u8,
+                            PAGE_SIZE,
+                        );
+                    }
+                    self.tcs_count.fetch_add(1, Ordering::Release);
+                }
+                Some(flags) => {
+                    clone.pages.lock().insert(
+                        gpaddr,
+                        CowPage {
+                            src,
+                            flags,
+                            epoch: None,
+                        },
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Map the EPC page `gpaddr` backing the template page `src` into the
+    /// clone, and add it to the EPCM. Returns the NPT flags of the template
+    /// page, `None` for a TCS page. Nothing is left mapped on failure.
+    fn map_clone_page(
+        &self,
+        gvaddr: GuestVirtAddr,
+        gpaddr: GuestPhysAddr,
+        src: GuestPhysAddr,
+        gpt_flags: MemFlags,
+        sec_info: &SgxSecInfo,
+    ) -> HyperCallResult<Option<MemFlags>> {
+        let template = &self.clone_state()?.template;
+        let npt_flags = if sec_info.page_type == SgxEnclPageType::TCS {
+            None
+        } else {
+            Some(template.npt.read().query(src)?.1)
+        };
+        // Fails if the page is backed already.
+        self.gpt.write().map(&MemoryRegion::new_with_offset_mapper(
+            gvaddr, gpaddr, PAGE_SIZE, gpt_flags,
+        ))?;
+        let mut res = Ok(());
+        if let Some(flags) = npt_flags {
+            res = self.npt.write().map(&MemoryRegion::new_with_offset_mapper(
+                gpaddr,
+                src,
+                PAGE_SIZE,
+                flags - MemFlags::WRITE,
+            ));
+        }
+        let res = res
+            .map_err(|e| e.into())
+            .and_then(|_| EpcmManager::add_page(gvaddr, gpaddr, sec_info, self));
+        if let Err(e) = res {
+            let unmapped = |vaddr| {
+                MemoryRegion::new_with_offset_mapper(vaddr, 0, PAGE_SIZE, MemFlags::empty())
+            };
+            if npt_flags.is_some() {
+                let _ = self.npt.write().unmap(&unmapped(gpaddr));
+            }
+            let _ = self.gpt.write().unmap(&unmapped(gvaddr));
+            return Err(e);
+        }
+        Ok(npt_flags)
+    }
+
+    /// Finish the clone once all the template pages are backed.
+    pub fn clone_init(&self) -> HyperCallResult {
+        let clone = self.clone_state()?;
+        let init_inner = || -> HvResult {
+            let template_page_num = clone.template.epc_page_num();
+            if self.epc_page_num() != template_page_num {
+                return hv_result_err!(
+                    EINVAL,
+                    format!(
+                        "Enclave::clone_init(): {} of the {} template pages are backed",
+                        self.epc_page_num(),
+                        template_page_num
+                    )
+                );
+            }
+            self.map_gpt_frames()?;
+            unsafe { *self.secs_mut() = *clone.template.secs() };
+            // clones are launches too, with the identity of their template
+            eventlog::measure_enclave_launch(self.secs())
+        };
+
+        if self
+            .state
+            .compare_exchange(
+                STATE_UNINIT,
+                STATE_INIT_TRY,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .is_err()
+        {
+            return hypercall_hv_err_result!(
+                EBUSY,
+                "Enclave::clone_init(): enclave is already initialized"
+            );
+        }
+        let res = init_inner();
+        if res.is_ok() {
+            self.state.store(STATE_INIT_OK, Ordering::SeqCst);
+        } else {
+            self.state.store(STATE_UNINIT, Ordering::SeqCst);
+        }
+        Ok(res?)
+    }
+
+    pub(super) fn update_cow_epochs(&self, is_enter: bool, cpuid: usize) {
+        if let Some(clone) = &self.clone {
+            clone.epochs.lock().update(is_enter, cpuid);
+        }
+    }
+
+    /// Whether `gpaddr` is a page of the clone still shared with the template.
+    pub(super) fn is_cow_page(&self, gpaddr: GuestPhysAddr) -> bool {
+        match &self.clone {
+            Some(clone) => clone.pages.lock().contains_key(&gpaddr),
+            None => false,
+        }
+    }
+
+    /// Make the page `gpaddr` private to the clone, returns false if it is
+    /// copied but not ready yet. It must be called with `encl_mem_lock` held.
+    pub(super) fn settle_cow_page(&self, gpaddr: GuestPhysAddr) -> HvResult<bool> {
+        let clone = match &self.clone {
+            Some(clone) => clone,
+            None => return Ok(true),
+        };
+        let mut pages = clone.pages.lock();
+        let page = match pages.get_mut(&gpaddr) {
+            Some(page) => page,
+            None => return Ok(true),
+        };
+        let epoch = match page.epoch {
+            Some(epoch) => epoch,
+            None => {
+                let now = Instant::now();
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        phys_to_virt(page.src) as *const u8,
+                        phys_to_virt(gpaddr) as *mut u8,
+                        PAGE_SIZE,
+                    );
+                }
+                self.npt
+                    .write()
+                    .unmap(&MemoryRegion::new_with_offset_mapper(
+                        gpaddr,
+                        0,
+                        PAGE_SIZE,
+                        MemFlags::empty(),
+                    ))?;
+                let epoch = clone.epochs.lock().advance();
+                page.epoch = Some(epoch);
+                self.atomic_add_stats(EnclaveStatsId::CowCopy, now.elapsed());
+                epoch
+            }
+        };
+        if !clone.epochs.lock().passed(epoch) {
+            return Ok(false);
+        }
+        self.npt.write().map(&MemoryRegion::new_with_offset_mapper(
+            gpaddr, gpaddr, PAGE_SIZE, page.flags,
+        ))?;
+        pages.remove(&gpaddr);
+        Ok(true)
+    }
+
+    /// Settle the page for an operation issued by Linux, which retries on EBUSY.
+    pub(super) fn settle_cow_page_or_busy(&self, gpaddr: GuestPhysAddr) -> HyperCallResult {
+        if !self.settle_cow_page(gpaddr)? {
+            return hypercall_hv_err_result!(
+                EBUSY,
+                format!("Enclave: page {:#x} of the clone is being copied", gpaddr)
+            );
+        }
+        Ok(())
+    }
+
+    /// Settle the page for an operation issued by the enclave. A #PF that Linux
+    /// finds spurious makes the thread leave the enclave, and retry on ERESUME.
+    pub(super) fn settle_cow_page_or_retry(
+        &self,
+        gvaddr: GuestVirtAddr,
+        gpaddr: GuestPhysAddr,
+    ) -> HyperCallResult {
+        if !self.settle_cow_page(gpaddr)? {
+            let error_code = PageFaultErrorCode::USER_MODE.bits();
+            return Err(hypercall_excep_err!(
+                EnclaveExceptionInfo::page_fault_in_encl(error_code, error_code, gvaddr),
+                format!("Enclave: page {:#x} of the clone is being copied", gvaddr)
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cow_epochs() {
+        let mut epochs = CowEpochs::default();
+        epochs.update(true, 0);
+        epochs.update(true, 1);
+        let epoch = epochs.advance();
+        assert!(!epochs.passed(epoch));
+
+        // CPU 0 leaves and enters again, CPU 1 still runs since the copy.
+        epochs.update(false, 0);
+        epochs.update(true, 0);
+        assert!(!epochs.passed(epoch));
+
+        epochs.update(false, 1);
+        assert!(epochs.passed(epoch));
+
+        // Threads entering after the copy never see the template page.
+        epochs.update(true, 1);
+        assert!(epochs.passed(epoch));
+        assert!(!epochs.passed(epochs.advance()));
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Quotes in the version 3 and 4 layouts of Intel SGX DCAP, so that DCAP
+//! verifiers only need a plug-in for the collateral of the hypervisor.
+//!
+//! The report body is signed with ECDSA P-256 by an attestation key derived
+//! from the root secret. A QE report made up by the hypervisor certifies the
+//! key: its report data is the SHA-256 digest of the key and of the QE
+//! authentication data. In place of a PCK certificate chain, the QE report is
+//! signed with SM2 by the hypervisor attestation key, and the certification
+//! data holds what follows `encl_quote` in an `SgxQuote`: `hv_att_pub`, the
+//! TPM quote of the QE report data and the AK certificate.
+
+use alloc::vec::Vec;
+
+use sha2::{Digest, Sha256};
+
+use super::report::SgxReportBody;
+
+pub const DCAP_QUOTE_VERSION_3: u16 = 3;
+pub const DCAP_QUOTE_VERSION_4: u16 = 4;
+/// Uncompressed P-256 public keys and signatures, without the leading 0x04 of
+/// keys.
+pub const DCAP_ATT_KEY_LEN: usize = 64;
+pub const DCAP_SIG_LEN: usize = 64;
+
+/// `SGX_QL_ALG_ECDSA_P256`.
+const ATT_KEY_TYPE_ECDSA_P256: u16 = 2;
+/// The TEE type of version 4 quotes.
+const TEE_TYPE_SGX: u32 = 0;
+/// The QE vendor ID of the hypervisor, in place of that of the Intel QE.
+const HE_QE_VENDOR_ID: [u8; 16] = [
+    0xa7, 0x58, 0x29, 0xd9, 0x99, 0x4c, 0x40, 0x2c, 0x85, 0x62, 0xcc, 0x89, 0x49, 0xf2, 0x41, 0xc2,
+];
+/// Certification data wrapping the QE report of version 4 quotes.
+const CERT_DATA_TYPE_QE_REPORT: u16 = 6;
+/// Certification data holding the TPM evidence for the hypervisor
+/// attestation key.
+const CERT_DATA_TYPE_HE_TPM_EVIDENCE: u16 = 0x4845;
+/// `ISVPRODID` of the QE report.
+const HE_QE_ISV_PROD_ID: u16 = 1;
+const HE_QE_AUTH_DATA: &[u8] = &[];
+
+/// A DCAP quote of a report body, completed once it is signed.
+pub struct DcapQuote {
+    version: u16,
+    buf: Vec<u8>,
+}
+
+impl DcapQuote {
+    /// Starts a quote of `report_body`, or returns `None` if `version` is not
+    /// supported.
+    pub fn new(version: u16, report_body: &SgxReportBody) -> Option<Self> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&version.to_le_bytes());
+        buf.extend_from_slice(&ATT_KEY_TYPE_ECDSA_P256.to_le_bytes());
+        match version {
+            // reserved, QE SVN and PCE SVN
+            DCAP_QUOTE_VERSION_3 => buf.extend_from_slice(&[0; 8]),
+            DCAP_QUOTE_VERSION_4 => {
+                buf.extend_from_slice(&TEE_TYPE_SGX.to_le_bytes());
+                buf.extend_from_slice(&[0; 4]);
+            }
+            _ => return None,
+        }
+        buf.extend_from_slice(&HE_QE_VENDOR_ID);
+        // user data
+        buf.extend_from_slice(&[0; 20]);
+        buf.extend_from_slice(report_body.as_bytes());
+        Some(Self { version, buf })
+    }
+
+    /// The header and the report body, signed by the attestation key.
+    pub fn signed_data(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Appends the signature data: `isv_sig` of `signed_data` by `att_key`,
+    /// then the QE report, its signature by the hypervisor attestation key
+    /// and the TPM evidence for that key.
+    pub fn finish(
+        mut self,
+        isv_sig: &[u8; DCAP_SIG_LEN],
+        att_key: &[u8; DCAP_ATT_KEY_LEN],
+        qe_report: &SgxReportBody,
+        qe_report_sig: &[u8; DCAP_SIG_LEN],
+        tpm_evidence: &[u8],
+    ) -> Vec<u8> {
+        let mut qe_cert_data = Vec::new();
+        qe_cert_data.extend_from_slice(qe_report.as_bytes());
+        qe_cert_data.extend_from_slice(qe_report_sig);
+        qe_cert_data.extend_from_slice(&(HE_QE_AUTH_DATA.len() as u16).to_le_bytes());
+        qe_cert_data.extend_from_slice(HE_QE_AUTH_DATA);
+        push_cert_data(
+            &mut qe_cert_data,
+            CERT_DATA_TYPE_HE_TPM_EVIDENCE,
+            tpm_evidence,
+        );
+
+        let mut sig = Vec::new();
+        sig.extend_from_slice(isv_sig);
+        sig.extend_from_slice(att_key);
+        if self.version == DCAP_QUOTE_VERSION_4 {
+            push_cert_data(&mut sig, CERT_DATA_TYPE_QE_REPORT, &qe_cert_data);
+        } else {
+            sig.extend_from_slice(&qe_cert_data);
+        }
+        self.buf
+            .extend_from_slice(&(sig.len() as u32).to_le_bytes());
+        self.buf.extend_from_slice(&sig);
+        self.buf
+    }
+}
+
+fn push_cert_data(buf: &mut Vec<u8>, cert_type: u16, data: &[u8]) {
+    buf.extend_from_slice(&cert_type.to_le_bytes());
+    buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    buf.extend_from_slice(data);
+}
+
+/// The QE report certifying the attestation key `att_key`.
+pub fn qe_report_body(att_key: &[u8; DCAP_ATT_KEY_LEN]) -> SgxReportBody {
+    let mut body = SgxReportBody::default();
+    body.isv_prod_id = HE_QE_ISV_PROD_ID;
+    let mut hasher = Sha256::new();
+    hasher.update(att_key);
+    hasher.update(HE_QE_AUTH_DATA);
+    body.report_data[..32].copy_from_slice(&hasher.finalize());
+    body
+}
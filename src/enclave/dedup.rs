@@ -0,0 +1,459 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Deduplication of read-only EPC pages across enclaves.
+//!
+//! Enclaves created with `HvEnclCreateFlags::EPC_DEDUP` offer the REG pages
+//! they EADD without write permission for sharing, indexed by the SM3 hash of
+//! their content and their SECINFO. When another such enclave adds a page with
+//! the same SECINFO and content, its GPT and NPT map the existing frame instead,
+//! and `EnclaveAddPage` returns 1 to tell Linux the EPC page it passed is unused.
+//!
+//! The EPCM entry of the frame counts the other enclaves mapping it. While it is
+//! shared, the frame cannot be blocked, removed, or have its type or permissions
+//! changed: Linux gets EBUSY, and an enclave thread extending its permissions
+//! faults with a protection violation. In both cases, Linux gives the enclave a
+//! private copy of the page with `EnclaveDedupBreak` and retries. The owner of a
+//! frame may get a copy too, but the frame itself can only be removed after all
+//! the other enclaves have got theirs or have been destroyed.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use spin::mutex::SpinMutex;
+use yogcrypt::sm3::{sm3_enc, HashValue};
+
+use super::epcm::EpcmManager;
+use super::sgx::{SgxEnclPageFlags, SgxEnclPageType, SgxSecInfo};
+use super::{Enclave, EnclaveStatsId};
+use crate::arch::{EnclaveExceptionInfo, PageFaultErrorCode};
+use crate::hypercall::error::HyperCallResult;
+use crate::memory::addr::{is_aligned, phys_to_virt, GuestPhysAddr, GuestVirtAddr};
+use crate::memory::{GenericPageTable, GenericPageTableImmut, MemFlags, MemoryRegion, PAGE_SIZE};
+use crate::memory::PagingResult;
+use crate::stats::Instant;
+
+/// Content hash and SECINFO of a page offered for sharing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) struct DedupKey {
+    hash: HashValue,
+    sec_info: u64,
+}
+
+impl DedupKey {
+    /// Key of `page` added with `sec_info`, `None` if the page cannot be shared.
+    fn new(sec_info: &SgxSecInfo, page: &[u8; PAGE_SIZE]) -> Option<Self> {
+        let perm = sec_info.flags & SgxEnclPageFlags::PERM_MASK;
+        if sec_info.page_type != SgxEnclPageType::REG
+            || !perm.contains(SgxEnclPageFlags::R)
+            || perm.contains(SgxEnclPageFlags::W)
+        {
+            return None;
+        }
+        Some(Self {
+            hash: sm3_enc(page),
+            sec_info: SgxSecInfo::new(perm, sec_info.page_type).into(),
+        })
+    }
+}
+
+/// Frames offered for sharing. An entry may be stale, the frame is checked
+/// again before it is shared.
+struct DedupIndex {
+    frames: BTreeMap<DedupKey, GuestPhysAddr>,
+    keys: BTreeMap<GuestPhysAddr, DedupKey>,
+}
+
+impl DedupIndex {
+    fn insert(&mut self, key: DedupKey, gpaddr: GuestPhysAddr) {
+        if let Some(old) = self.frames.insert(key, gpaddr) {
+            self.keys.remove(&old);
+        }
+        if let Some(old_key) = self.keys.insert(gpaddr, key) {
+            if old_key != key {
+                self.frames.remove(&old_key);
+            }
+        }
+    }
+
+    fn remove(&mut self, gpaddr: GuestPhysAddr) {
+        if let Some(key) = self.keys.remove(&gpaddr) {
+            self.frames.remove(&key);
+        }
+    }
+}
+
+static DEDUP_INDEX: SpinMutex<DedupIndex> = SpinMutex::new(DedupIndex {
+    frames: BTreeMap::new(),
+    keys: BTreeMap::new(),
+});
+
+/// Drop the EPC page at `gpaddr` from the index after it is removed.
+pub(super) fn forget_dedup_frame(gpaddr: GuestPhysAddr) {
+    DEDUP_INDEX.lock().remove(gpaddr);
+}
+
+/// Map the shared `frame` at `gvaddr`, and into the NPT unless it is mapped
+/// already. Nothing is left mapped on failure, such as a page added twice.
+fn map_dedup_frame<N, G>(
+    npt: &mut N,
+    gpt: &mut G,
+    gvaddr: GuestVirtAddr,
+    frame: GuestPhysAddr,
+    sec_info: &SgxSecInfo,
+    npt_mapped: bool,
+) -> PagingResult
+where
+    N: GenericPageTable<VA = GuestPhysAddr>,
+    G: GenericPageTable<VA = GuestVirtAddr>,
+{
+    if !npt_mapped {
+        let npt_flags = sec_info.npt_flags() | MemFlags::ENCRYPTED;
+        npt.map(&MemoryRegion::new_with_offset_mapper(
+            frame, frame, PAGE_SIZE, npt_flags,
+        ))?;
+    }
+    let res = gpt.map(&MemoryRegion::new_with_offset_mapper(
+        gvaddr,
+        frame,
+        PAGE_SIZE,
+        (*sec_info).into(),
+    ));
+    if res.is_err() && !npt_mapped {
+        let _ = npt.unmap(&MemoryRegion::new_with_offset_mapper(
+            frame,
+            0,
+            PAGE_SIZE,
+            MemFlags::empty(),
+        ));
+    }
+    res
+}
+
+impl Enclave {
+    pub fn epc_dedup(&self) -> bool {
+        self.epc_dedup
+    }
+
+    /// Key to index the page `src` added at EADD with, `None` if the enclave
+    /// does not share its pages or the page cannot be shared.
+    pub(super) fn dedup_key(
+        &self,
+        sec_info: &SgxSecInfo,
+        src: &[u8; PAGE_SIZE],
+    ) -> Option<DedupKey> {
+        if !self.epc_dedup {
+            return None;
+        }
+        DedupKey::new(sec_info, src)
+    }
+
+    /// Map the frame of another enclave with the same SECINFO and content as
+    /// `src` at `gvaddr`, returns the frame, or `None` if there is no such frame.
+    pub(super) fn dedup_add_page(
+        self: &Arc<Self>,
+        gvaddr: GuestVirtAddr,
+        sec_info: &SgxSecInfo,
+        key: &DedupKey,
+        src: &[u8; PAGE_SIZE],
+    ) -> HyperCallResult<Option<GuestPhysAddr>> {
+        let frame = {
+            let index = DEDUP_INDEX.lock();
+            match index.frames.get(key) {
+                Some(&frame) if EpcmManager::get_dedup_frame(frame, sec_info, src, self) => frame,
+                _ => return Ok(None),
+            }
+        };
+
+        let mut dedup_pages = self.dedup_pages.lock();
+        let npt_mapped = dedup_pages.values().any(|&f| f == frame);
+        let mut npt = self.npt.write();
+        let mut gpt = self.gpt.write();
+        if let Err(e) = map_dedup_frame(&mut *npt, &mut *gpt, gvaddr, frame, sec_info, npt_mapped) {
+            EpcmManager::put_dedup_frame(frame);
+            return Err(e.into());
+        }
+        dedup_pages.insert(gvaddr, frame);
+        debug!(
+            "Enclave {:#x}: page {:#x} shares frame {:#x}",
+            self.id, gvaddr, frame
+        );
+        Ok(Some(frame))
+    }
+
+    /// Offer the page at `gpaddr`, just added with `key`, for sharing.
+    pub(super) fn dedup_index_page(&self, key: DedupKey, gpaddr: GuestPhysAddr) {
+        DEDUP_INDEX.lock().insert(key, gpaddr);
+    }
+
+    pub(super) fn has_dedup_pages(&self) -> bool {
+        !self.dedup_pages.lock().is_empty()
+    }
+
+    fn is_dedup_page(&self, gvaddr: GuestVirtAddr) -> bool {
+        self.dedup_pages.lock().contains_key(&gvaddr)
+    }
+
+    /// Fail with EBUSY if the page at `gvaddr` is a frame of another enclave.
+    pub(super) fn check_dedup_page_or_busy(&self, gvaddr: GuestVirtAddr) -> HyperCallResult {
+        if self.is_dedup_page(gvaddr) {
+            return hypercall_hv_err_result!(
+                EBUSY,
+                format!(
+                    "Enclave::check_dedup_page_or_busy(): page {:#x} is shared with another enclave",
+                    gvaddr
+                )
+            );
+        }
+        Ok(())
+    }
+
+    /// Fault if the page at `gvaddr` is a frame of another enclave, so that
+    /// Linux breaks the sharing before the enclave retries.
+    pub(super) fn check_dedup_page_or_fault(&self, gvaddr: GuestVirtAddr) -> HyperCallResult {
+        if self.is_dedup_page(gvaddr) {
+            let error_code =
+                (PageFaultErrorCode::USER_MODE | PageFaultErrorCode::PROTECTION_VIOLATION).bits();
+            return Err(hypercall_excep_err!(
+                EnclaveExceptionInfo::page_fault_in_encl(error_code, error_code, gvaddr),
+                format!(
+                    "Enclave::check_dedup_page_or_fault(): page {:#x} is shared with another enclave",
+                    gvaddr
+                )
+            ));
+        }
+        Ok(())
+    }
+
+    /// Give the enclave a private copy at `gpaddr` of the shared frame mapped at
+    /// `gvaddr`. Returns 1 if the frame belongs to the enclave, which must still
+    /// remove it at destroy, or 0 if it belongs to another enclave.
+    pub fn break_dedup_page(
+        self: &Arc<Self>,
+        gvaddr: GuestVirtAddr,
+        gpaddr: GuestPhysAddr,
+    ) -> HyperCallResult<usize> {
+        let now = Instant::now();
+        if !is_aligned(gvaddr) || !is_aligned(gpaddr) || !self.elrange.contains(&gvaddr) {
+            return hypercall_hv_err_result!(
+                EINVAL,
+                format!(
+                    "Enclave::break_dedup_page(): invalid page {:#x} -> {:#x}, ELRANGE: {:#x?}",
+                    gvaddr, gpaddr, self.elrange
+                )
+            );
+        }
+
+        let _encl_mem_lock = self.encl_mem_lock.lock();
+        // Threads inside may cache the translation to the shared frame, keep
+        // them out until the new mapping is in place.
+        let tracking_state = self.tracking_state.read();
+        let active_thread_num = tracking_state.active_thread_num();
+        if active_thread_num != 0 {
+            let msg = format!(
+                "Enclave::break_dedup_page(): There still exist thread (num = {:?}) in enclave mode",
+                active_thread_num
+            );
+            warn!("{}", msg);
+            return Err(hypercall_enclave_err!(EENCLAVEACT, msg));
+        }
+
+        let (frame, gpt_flags, _) = self.gpt.read().query(gvaddr)?;
+        let mut dedup_pages = self.dedup_pages.lock();
+        let is_owner = dedup_pages.get(&gvaddr) != Some(&frame);
+        if is_owner {
+            EpcmManager::check_dedup_frame(gvaddr, frame, self)?;
+        }
+        let sec_info = EpcmManager::query_sec_info(frame)?;
+        let sec_info = SgxSecInfo::new(
+            sec_info.flags & SgxEnclPageFlags::PERM_MASK,
+            sec_info.page_type,
+        );
+        EpcmManager::add_page(gvaddr, gpaddr, &sec_info, self)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                phys_to_virt(frame) as *const u8,
+                phys_to_virt(gpaddr) as *mut u8,
+                PAGE_SIZE,
+            );
+        }
+
+        if !is_owner {
+            dedup_pages.remove(&gvaddr);
+        }
+        {
+            let mut npt = self.npt.write();
+            if !dedup_pages.values().any(|&f| f == frame) {
+                npt.unmap(&MemoryRegion::new_with_offset_mapper(
+                    frame,
+                    0,
+                    PAGE_SIZE,
+                    MemFlags::empty(),
+                ))?;
+            }
+            let npt_flags = sec_info.npt_flags() | MemFlags::ENCRYPTED;
+            npt.map(&MemoryRegion::new_with_offset_mapper(
+                gpaddr, gpaddr, PAGE_SIZE, npt_flags,
+            ))?;
+        }
+        {
+            let mut gpt = self.gpt.write();
+            gpt.unmap(&MemoryRegion::new_with_offset_mapper(
+                gvaddr,
+                0,
+                PAGE_SIZE,
+                MemFlags::empty(),
+            ))?;
+            gpt.map(&MemoryRegion::new_with_offset_mapper(
+                gvaddr, gpaddr, PAGE_SIZE, gpt_flags,
+            ))?;
+        }
+        if !is_owner {
+            EpcmManager::put_dedup_frame(frame);
+        }
+        info!(
+            "Enclave {:#x}: page {:#x} no longer shares frame {:#x}",
+            self.id, gvaddr, frame
+        );
+        self.atomic_add_stats(EnclaveStatsId::DedupBreak, now.elapsed());
+        Ok(is_owner as usize)
+    }
+
+    /// Drop the references to the frames of other enclaves before the enclave
+    /// is destroyed.
+    pub(super) fn release_dedup_pages(&self) {
+        let dedup_pages = core::mem::take(&mut *self.dedup_pages.lock());
+        for frame in dedup_pages.values() {
+            EpcmManager::put_dedup_frame(*frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::{PageSize, PagingError, PhysAddr};
+    use alloc::vec::Vec;
+
+    /// Page table that only records which pages are mapped.
+    struct MockPageTable(BTreeMap<usize, MemFlags>);
+
+    impl GenericPageTableImmut for MockPageTable {
+        type VA = usize;
+
+        unsafe fn from_root(_root_paddr: PhysAddr) -> Self {
+            Self::new()
+        }
+
+        fn root_paddr(&self) -> PhysAddr {
+            0
+        }
+
+        fn query(&self, vaddr: usize) -> PagingResult<(PhysAddr, MemFlags, PageSize)> {
+            match self.0.get(&vaddr) {
+                Some(&flags) => Ok((0, flags, PageSize::Size4K)),
+                None => Err(PagingError::NotMapped(vaddr)),
+            }
+        }
+    }
+
+    impl GenericPageTable for MockPageTable {
+        fn new() -> Self {
+            Self(BTreeMap::new())
+        }
+
+        fn map(&mut self, region: &MemoryRegion<usize>) -> PagingResult {
+            if let Ok((paddr, flags, size)) = self.query(region.start) {
+                let vaddr = region.start;
+                return Err(PagingError::AlreadyMapped((vaddr, paddr, flags, size)));
+            }
+            self.0.insert(region.start, region.flags);
+            Ok(())
+        }
+
+        fn unmap(
+            &mut self,
+            region: &MemoryRegion<usize>,
+        ) -> PagingResult<Vec<(PhysAddr, PageSize)>> {
+            match self.0.remove(&region.start) {
+                Some(_) => Ok(alloc::vec![(0, PageSize::Size4K)]),
+                None => Err(PagingError::NotMapped(region.start)),
+            }
+        }
+
+        fn update(&mut self, region: &MemoryRegion<usize>) -> PagingResult {
+            self.0.insert(region.start, region.flags);
+            Ok(())
+        }
+
+        fn clone(&self) -> Self {
+            Self(self.0.clone())
+        }
+
+        unsafe fn activate(&self) {}
+
+        fn flush(&self, _vaddr: Option<usize>) {}
+    }
+
+    fn key(hash: u32) -> DedupKey {
+        DedupKey {
+            hash: [hash; 8],
+            sec_info: 0,
+        }
+    }
+
+    #[test]
+    fn test_dedup_index() {
+        let mut index = DedupIndex {
+            frames: BTreeMap::new(),
+            keys: BTreeMap::new(),
+        };
+        index.insert(key(1), 0x1000);
+        index.insert(key(2), 0x2000);
+        assert_eq!(index.frames.get(&key(1)), Some(&0x1000));
+
+        // A later page with the same key replaces the frame.
+        index.insert(key(1), 0x3000);
+        assert_eq!(index.frames.get(&key(1)), Some(&0x3000));
+        assert!(!index.keys.contains_key(&0x1000));
+
+        // A frame reused for another content is indexed by the new key only.
+        index.insert(key(3), 0x2000);
+        assert!(!index.frames.contains_key(&key(2)));
+        assert_eq!(index.frames.get(&key(3)), Some(&0x2000));
+
+        index.remove(0x3000);
+        index.remove(0x2000);
+        assert!(index.frames.is_empty());
+        assert!(index.keys.is_empty());
+    }
+
+    #[test]
+    fn test_map_dedup_frame_duplicate_add() {
+        let sec_info = SgxSecInfo::new(SgxEnclPageFlags::R, SgxEnclPageType::REG);
+        let gvaddr = 0x7f00_0000_0000;
+        let mut npt = MockPageTable::new();
+        let mut gpt = MockPageTable::new();
+        map_dedup_frame(&mut npt, &mut gpt, gvaddr, 0x8000, &sec_info, false).unwrap();
+
+        // Adding the page again keeps the first mapping only.
+        assert!(map_dedup_frame(&mut npt, &mut gpt, gvaddr, 0x8000, &sec_info, true).is_err());
+        assert!(npt.query(0x8000).is_ok());
+
+        // A frame mapped for the failed add is unmapped from the NPT again.
+        assert!(map_dedup_frame(&mut npt, &mut gpt, gvaddr, 0x9000, &sec_info, false).is_err());
+        assert!(npt.query(0x9000).is_err());
+        assert_eq!(npt.0.len(), 1);
+        assert_eq!(gpt.0.len(), 1);
+    }
+}
@@ -25,6 +25,7 @@ use crate::memory::{
 };
 use crate::percpu::CpuState;
 
+use super::dedup;
 use super::epcm::EpcmManager;
 use super::{Enclave, SgxEnclPageFlags, SgxEnclPageType, SgxSecInfo, SgxTcs};
 
@@ -32,6 +33,7 @@ pub(crate) enum PageTypeModifyType {
     RegToTcs,
     RegToTrim,
     TcsToTrim,
+    SsToTrim,
 }
 
 #[derive(PartialEq)]
@@ -49,33 +51,60 @@ impl Enclave {
         sec_info: usize,
     ) -> HyperCallResult<usize> {
         self.validate_state_and_vaddr(gvaddr)?;
-
-        if !is_aligned(gpaddr) {
+        if self.in_dead_channel(gvaddr) {
             return hypercall_hv_err_result!(
-                EINVAL,
+                EFAULT,
                 format!(
-                    "Enclave::augment_page(): guest physical address {:#x} is not aligned",
-                    gpaddr,
+                    "Enclave::augment_page(): {:#x} is in a closed EPC channel",
+                    gvaddr
                 )
             );
         }
 
-        if sec_info != 0 {
+        if !is_aligned(gpaddr) {
             return hypercall_hv_err_result!(
                 EINVAL,
                 format!(
-                    "Enclave::augment_page(): The sec_info is not 0: {:#x?}",
-                    sec_info
+                    "Enclave::augment_page(): guest physical address {:#x} is not aligned",
+                    gpaddr,
                 )
             );
         }
 
+        // The sec_info is either 0 for a regular page, or only specifies a shadow stack
+        // page type when shadow stacks are enabled.
+        let page_type = if sec_info == 0 {
+            SgxEnclPageType::REG
+        } else {
+            match SgxSecInfo::try_from(sec_info) {
+                Ok(info)
+                    if info.flags.is_empty()
+                        && info.page_type.is_shadow_stack()
+                        && sec_info >> 16 == 0
+                        && self.shadow_stack_enabled() =>
+                {
+                    info.page_type
+                }
+                _ => {
+                    return hypercall_hv_err_result!(
+                        EINVAL,
+                        format!(
+                            "Enclave::augment_page(): Invalid sec_info: {:#x?}",
+                            sec_info
+                        )
+                    );
+                }
+            }
+        };
+
         {
             let _encl_mem_lock = self.encl_mem_lock.lock();
 
-            let gpt_flags = EpcmManager::augment_page(gvaddr, gpaddr, self)?.into();
+            let gpt_flags = EpcmManager::augment_page(gvaddr, gpaddr, page_type, self)?.into();
             unsafe {
-                core::ptr::write_bytes(phys_to_virt(gpaddr as _) as *mut u8, 0_u8, PAGE_SIZE);
+                let page = &mut *(phys_to_virt(gpaddr as _) as *mut [u64; PAGE_SIZE / 8]);
+                page.fill(0);
+                page[PAGE_SIZE / 8 - 1] = page_type.shadow_stack_token(gvaddr);
             }
             self.gpt.write().map(&MemoryRegion::new_with_offset_mapper(
                 gvaddr, gpaddr, PAGE_SIZE, gpt_flags,
@@ -123,6 +152,8 @@ impl Enclave {
                 }
 
                 let gpaddr_aligned = pte.addr();
+                self.check_dedup_page_or_busy(gvaddr)?;
+                self.settle_cow_page_or_busy(gpaddr_aligned)?;
                 let (new_sec_info, page_type_modify_type) = EpcmManager::modify_page_type(
                     gvaddr,
                     gpaddr_aligned,
@@ -130,7 +161,9 @@ impl Enclave {
                     self,
                 )?;
                 match page_type_modify_type {
-                    PageTypeModifyType::RegToTcs | PageTypeModifyType::RegToTrim => {
+                    PageTypeModifyType::RegToTcs
+                    | PageTypeModifyType::RegToTrim
+                    | PageTypeModifyType::SsToTrim => {
                         self.npt
                             .write()
                             .unmap(&MemoryRegion::new_with_offset_mapper(
@@ -202,6 +235,8 @@ impl Enclave {
                 }
 
                 let gpaddr_aligned = pte.addr();
+                self.check_dedup_page_or_busy(gvaddr)?;
+                self.settle_cow_page_or_busy(gpaddr_aligned)?;
                 let new_sec_info = EpcmManager::restrict_page_perm(
                     gvaddr,
                     gpaddr_aligned,
@@ -292,6 +327,7 @@ impl Enclave {
 
                 let gpaddr_aligned = pte.addr();
                 EpcmManager::remove_page_at_runtime(gvaddr, gpaddr_aligned, self)?;
+                dedup::forget_dedup_frame(gpaddr_aligned);
                 pte.clear();
             }
         }
@@ -317,7 +353,7 @@ impl Enclave {
         };
 
         let page_accept_type = {
-            if sec_info.page_type == SgxEnclPageType::REG
+            if (sec_info.page_type == SgxEnclPageType::REG || sec_info.page_type.is_shadow_stack())
                 && sec_info.flags.contains(SgxEnclPageFlags::PENDING)
                 && !sec_info.flags.contains(SgxEnclPageFlags::MODIFIED)
                 && !sec_info.flags.contains(SgxEnclPageFlags::PR)
@@ -423,7 +459,7 @@ impl Enclave {
                 )?;
 
                 if page_accept_type == PageAcceptType::Augment {
-                    let npt_flags: MemFlags = new_sec_info.into();
+                    let npt_flags = new_sec_info.npt_flags();
                     self.npt.write().map(&MemoryRegion::new_with_offset_mapper(
                         gpaddr_aligned,
                         gpaddr_aligned,
@@ -517,6 +553,7 @@ impl Enclave {
                     let new_sec_info =
                         EpcmManager::access_page_check(gvaddr_src, pte.addr(), self, false, true)?;
                     pte.set_flags(new_sec_info.into(), false)?;
+                    self.settle_cow_page_or_retry(gvaddr_src, pte.addr())?;
                     pte.addr()
                 };
 
@@ -652,6 +689,8 @@ impl Enclave {
                 }
 
                 let gpaddr_aligned = pte.addr();
+                self.check_dedup_page_or_fault(gvaddr)?;
+                self.settle_cow_page_or_retry(gvaddr, gpaddr_aligned)?;
                 let new_sec_info = EpcmManager::extend_page_perm(
                     gvaddr,
                     gpaddr_aligned,
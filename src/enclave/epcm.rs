@@ -18,7 +18,8 @@ use crate::arch::{EnclaveExceptionInfo, EnclavePFErrorCode, PageFaultErrorCode};
 use crate::error::{HvError, HvResult};
 use crate::hypercall::error::HyperCallResult;
 use crate::memory::cmr::{ConvMemManager, PageStatus};
-use crate::memory::{GenericPTE, GuestPhysAddr, GuestVirtAddr};
+use crate::memory::addr::phys_to_virt;
+use crate::memory::{GenericPTE, GuestPhysAddr, GuestVirtAddr, PAGE_SIZE};
 use crate::percpu::CpuState;
 
 use super::edmm::{PageAcceptType, PageTypeModifyType};
@@ -36,8 +37,12 @@ pub struct EpcmEntry {
     flags: SgxEnclPageFlags,
     /// EPCM page type (PT_SECS, PT_TCS, PT_REG, PT_VA, PT_TRIM, PT_SS_FIRST, PT_SS_REST).
     page_type: SgxEnclPageType,
-    /// Reserved area.
-    _reserved: [u8; 5],
+    /// Whether the page is mapped into another enclave by an EPC channel.
+    shared: bool,
+    /// Number of clones backed by the page of their template.
+    clone_refs: u32,
+    /// Number of other enclaves mapping the page, deduplicated with their own.
+    dedup_refs: u32,
     /// Linear enclave address of the EPC page.
     vaddr: GuestVirtAddr,
     /// Smart pointer of the `Enclave` owning the page, `None` if not initialized.
@@ -48,8 +53,10 @@ impl EpcmEntry {
     pub const EMPTY: Self = Self {
         page_status: PageStatus::Secure,
         flags: SgxEnclPageFlags::empty(),
-        _reserved: [0; 5],
         page_type: SgxEnclPageType::SECS,
+        shared: false,
+        clone_refs: 0,
+        dedup_refs: 0,
         enclave: None,
         vaddr: 0,
     };
@@ -66,6 +73,11 @@ impl EpcmEntry {
         self.vaddr = vaddr;
         self.enclave = Some(Arc::clone(enclave));
     }
+
+    /// Whether the page is mapped into another enclave for any reason.
+    fn is_shared(&self) -> bool {
+        self.shared || self.clone_refs != 0 || self.dedup_refs != 0
+    }
 }
 
 pub struct EpcmManager;
@@ -153,6 +165,17 @@ impl EpcmManager {
             .is_ok()
     }
 
+    pub fn is_shared(gpaddr: GuestPhysAddr) -> bool {
+        ConvMemManager::get()
+            .with_epcm_entry(gpaddr, |entry| {
+                if !entry.flags.contains(SgxEnclPageFlags::VALID) || !entry.is_shared() {
+                    return hv_result_err!(EINVAL);
+                }
+                Ok(())
+            })
+            .is_ok()
+    }
+
     pub fn query_sec_info(gpaddr: GuestPhysAddr) -> HvResult<SgxSecInfo> {
         ConvMemManager::get().with_epcm_entry(gpaddr, |entry| {
             if !entry.flags.contains(SgxEnclPageFlags::VALID) {
@@ -176,6 +199,161 @@ impl EpcmManager {
         });
     }
 
+    /// Pin a page of `enclave` for an EPC channel. Only readable and writable,
+    /// non-executable REG pages that are not being modified can be shared.
+    pub fn share_page(
+        gvaddr: GuestVirtAddr,
+        gpaddr: GuestPhysAddr,
+        enclave: &Arc<Enclave>,
+    ) -> HyperCallResult {
+        Self::validate_epcm_entry_and_mut(gvaddr, gpaddr, enclave, |entry| {
+            let perm = entry.flags & SgxEnclPageFlags::PERM_MASK;
+            if entry.page_type != SgxEnclPageType::REG
+                || perm != SgxEnclPageFlags::R | SgxEnclPageFlags::W
+                || entry.flags.intersects(
+                    SgxEnclPageFlags::PENDING
+                        | SgxEnclPageFlags::MODIFIED
+                        | SgxEnclPageFlags::BLOCKED,
+                )
+            {
+                return hypercall_hv_err_result!(
+                    EINVAL,
+                    format!(
+                        "EpcmManager::share_page(): page cannot be shared, gvaddr: {:#x?}, flags: {:?}, type: {:?}",
+                        gvaddr, entry.flags, entry.page_type
+                    )
+                );
+            }
+            if entry.shared {
+                return hypercall_hv_err_result!(
+                    EBUSY,
+                    format!(
+                        "EpcmManager::share_page(): page is already in an EPC channel, gvaddr: {:#x?}",
+                        gvaddr
+                    )
+                );
+            }
+            entry.shared = true;
+            Ok(())
+        })
+    }
+
+    pub fn unshare_page(gpaddr: GuestPhysAddr) {
+        let _res: HyperCallResult = ConvMemManager::get().with_epcm_entry_mut(gpaddr, |entry| {
+            entry.shared = false;
+            Ok(())
+        });
+    }
+
+    /// Pin a page of a template for its clones, returns its attributes. Pages
+    /// that are being modified cannot be cloned.
+    pub fn pin_template_page(
+        gvaddr: GuestVirtAddr,
+        gpaddr: GuestPhysAddr,
+        enclave: &Arc<Enclave>,
+    ) -> HyperCallResult<SgxSecInfo> {
+        Self::validate_epcm_entry_and_mut(gvaddr, gpaddr, enclave, |entry| {
+            if entry.flags.intersects(
+                SgxEnclPageFlags::PENDING | SgxEnclPageFlags::MODIFIED | SgxEnclPageFlags::BLOCKED,
+            ) {
+                return hypercall_hv_err_result!(
+                    EBUSY,
+                    format!(
+                        "EpcmManager::pin_template_page(): page cannot be cloned, gvaddr: {:#x?}, flags: {:?}",
+                        gvaddr, entry.flags
+                    )
+                );
+            }
+            if entry.clone_refs == u32::MAX {
+                return hypercall_hv_err_result!(
+                    EBUSY,
+                    format!(
+                        "EpcmManager::pin_template_page(): too many clones, gvaddr: {:#x?}",
+                        gvaddr
+                    )
+                );
+            }
+            entry.clone_refs += 1;
+            Ok(SgxSecInfo::new(
+                entry.flags & SgxEnclPageFlags::PERM_MASK,
+                entry.page_type,
+            ))
+        })
+    }
+
+    /// Drop the pin a clone took with `pin_template_page`.
+    pub fn unpin_template_page(gpaddr: GuestPhysAddr) {
+        let _res: HyperCallResult = ConvMemManager::get().with_epcm_entry_mut(gpaddr, |entry| {
+            entry.clone_refs = entry.clone_refs.saturating_sub(1);
+            Ok(())
+        });
+    }
+
+    /// Take a reference to the frame at `gpaddr` for `enclave`, if the frame
+    /// belongs to another enclave sharing its pages, and has `sec_info` and the
+    /// content `page`.
+    pub fn get_dedup_frame(
+        gpaddr: GuestPhysAddr,
+        sec_info: &SgxSecInfo,
+        page: &[u8; PAGE_SIZE],
+        enclave: &Arc<Enclave>,
+    ) -> bool {
+        let res: HvResult = ConvMemManager::get().with_epcm_entry_mut(gpaddr, |entry| {
+            let state_mask = SgxEnclPageFlags::PERM_MASK
+                | SgxEnclPageFlags::PENDING
+                | SgxEnclPageFlags::MODIFIED
+                | SgxEnclPageFlags::PR
+                | SgxEnclPageFlags::BLOCKED
+                | SgxEnclPageFlags::VALID;
+            let owner = match &entry.enclave {
+                Some(owner) if !Arc::ptr_eq(owner, enclave) && owner.epc_dedup() => owner,
+                _ => return hv_result_err!(EINVAL),
+            };
+            if entry.flags & state_mask != sec_info.flags | SgxEnclPageFlags::VALID
+                || entry.page_type != sec_info.page_type
+                || entry.dedup_refs == u32::MAX
+                || unsafe { &*(phys_to_virt(gpaddr) as *const [u8; PAGE_SIZE]) } != page
+            {
+                return hv_result_err!(EINVAL);
+            }
+            entry.dedup_refs += 1;
+            debug!(
+                "EpcmManager::get_dedup_frame(): frame {:#x} of enclave {:#x} shared {} times",
+                gpaddr, owner.id, entry.dedup_refs
+            );
+            Ok(())
+        });
+        res.is_ok()
+    }
+
+    pub fn put_dedup_frame(gpaddr: GuestPhysAddr) {
+        let _res: HyperCallResult = ConvMemManager::get().with_epcm_entry_mut(gpaddr, |entry| {
+            entry.dedup_refs = entry.dedup_refs.saturating_sub(1);
+            Ok(())
+        });
+    }
+
+    /// Check that the page of `enclave` is mapped into other enclaves by
+    /// deduplication.
+    pub fn check_dedup_frame(
+        gvaddr: GuestVirtAddr,
+        gpaddr: GuestPhysAddr,
+        enclave: &Arc<Enclave>,
+    ) -> HyperCallResult {
+        Self::validate_epcm_entry_and_mut(gvaddr, gpaddr, enclave, |entry| {
+            if entry.dedup_refs == 0 {
+                return hypercall_hv_err_result!(
+                    EINVAL,
+                    format!(
+                        "EpcmManager::check_dedup_frame(): page is not shared, gvaddr: {:#x?}",
+                        gvaddr
+                    )
+                );
+            }
+            Ok(())
+        })
+    }
+
     pub fn add_page(
         gvaddr: GuestVirtAddr,
         gpaddr: GuestPhysAddr,
@@ -239,6 +417,16 @@ impl EpcmManager {
                     );
                 }
 
+                if entry.is_shared() {
+                    return hypercall_hv_err_result!(
+                        EBUSY,
+                        format!(
+                            "EpcmManager::write_back_page(): page {:#x} is shared with another enclave",
+                            gpaddr
+                        )
+                    );
+                }
+
                 enclave.dec_epc_page_num();
                 *entry = EpcmEntry::EMPTY;
 
@@ -271,8 +459,18 @@ impl EpcmManager {
                 );
             }
 
+            if entry.dedup_refs != 0 {
+                return hypercall_hv_err_result!(
+                    EBUSY,
+                    format!(
+                        "EpcmManager::remove_page_at_destroy(): page {:#x} is still mapped into other enclaves",
+                        gpaddr
+                    )
+                );
+            }
+
             let page_type = entry.page_type;
-            if page_type != SgxEnclPageType::VA && page_type != SgxEnclPageType::TCS && page_type != SgxEnclPageType::REG && page_type != SgxEnclPageType::TRIM {
+            if page_type != SgxEnclPageType::VA && page_type != SgxEnclPageType::TCS && page_type != SgxEnclPageType::REG && page_type != SgxEnclPageType::TRIM && !page_type.is_shadow_stack() {
                 return hypercall_hv_err_result!(
                     EINVAL,
                     format!(
@@ -292,6 +490,7 @@ impl EpcmManager {
     pub fn augment_page(
         gvaddr: GuestVirtAddr,
         gpaddr: GuestPhysAddr,
+        page_type: SgxEnclPageType,
         enclave: &Arc<Enclave>,
     ) -> HyperCallResult<SgxSecInfo> {
         ConvMemManager::get().with_epcm_entry_mut(gpaddr, |entry| {
@@ -310,7 +509,7 @@ impl EpcmManager {
                     | SgxEnclPageFlags::W
                     | SgxEnclPageFlags::PENDING
                     | SgxEnclPageFlags::VALID,
-                SgxEnclPageType::REG,
+                page_type,
                 gvaddr,
                 enclave,
             );
@@ -338,6 +537,16 @@ impl EpcmManager {
                 ));
             }
 
+            if entry.is_shared() {
+                return hypercall_hv_err_result!(
+                    EBUSY,
+                    format!(
+                        "EpcmManager::modify_page_type(): page is shared with another enclave, gvaddr: {:#x?}",
+                        gvaddr
+                    )
+                );
+            }
+
             if entry.flags.contains(SgxEnclPageFlags::BLOCKED) {
                 return hypercall_hv_err_result!(
                     EINVAL,
@@ -359,6 +568,8 @@ impl EpcmManager {
                     && page_type == SgxEnclPageType::TRIM
                 {
                     PageTypeModifyType::TcsToTrim
+                } else if entry.page_type.is_shadow_stack() && page_type == SgxEnclPageType::TRIM {
+                    PageTypeModifyType::SsToTrim
                 } else {
                     return hypercall_hv_err_result!(
                         EINVAL,
@@ -409,6 +620,16 @@ impl EpcmManager {
                 ));
             }
 
+            if entry.is_shared() {
+                return hypercall_hv_err_result!(
+                    EBUSY,
+                    format!(
+                        "EpcmManager::restrict_page_perm(): page is shared with another enclave, gvaddr: {:#x?}",
+                        gvaddr
+                    )
+                );
+            }
+
             if entry.flags.contains(SgxEnclPageFlags::BLOCKED) {
                 return hypercall_hv_err_result!(
                     EINVAL,
@@ -474,6 +695,7 @@ impl EpcmManager {
             if entry.page_type != SgxEnclPageType::REG
                 && entry.page_type != SgxEnclPageType::TCS
                 && entry.page_type != SgxEnclPageType::TRIM
+                && !entry.page_type.is_shadow_stack()
             {
                 return hypercall_hv_err_result!(
                     EINVAL,
@@ -595,6 +817,21 @@ impl EpcmManager {
                 ));
             }
 
+            if entry.dedup_refs != 0 {
+                let error_code = (PageFaultErrorCode::USER_MODE
+                    | PageFaultErrorCode::PROTECTION_VIOLATION)
+                    .bits();
+                let enclave_excep =
+                    EnclaveExceptionInfo::page_fault_in_encl(error_code, error_code, gvaddr);
+                return Err(hypercall_excep_err!(
+                    enclave_excep,
+                    format!(
+                        "EpcmManager::extend_page_perm(): page is shared with another enclave, gvaddr {:#x?}",
+                        gvaddr
+                    )
+                ));
+            }
+
             // If page is beng reclaimed, cancel its reclaim by unmark the BLOCKED state in EPCM
             entry.flags -= SgxEnclPageFlags::BLOCKED;
 
@@ -787,12 +1024,22 @@ impl EpcmManager {
                         EnclavePFErrorCode::EPCM_ATTR_MISMATCH.bits(),
                     fault_gvaddr,
                 )))
-            } else if entry.page_type != SgxEnclPageType::REG {
+            } else if entry.page_type != SgxEnclPageType::REG && !entry.page_type.is_shadow_stack() {
                 error!(
                     "Hypervisor error, invalid page type configuration, fault_gvaddr: {:#x?}, page_type: {:?}",
                     fault_gvaddr, entry.page_type
                 );
                 Ok(Some(EnclaveExceptionInfo::general_protection(0, &CpuState::EnclaveRunning)))
+            } else if error_code.contains(PageFaultErrorCode::SHADOW_STACK) != entry.page_type.is_shadow_stack()
+                && (error_code.contains(PageFaultErrorCode::SHADOW_STACK) || error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+                    || error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)) {
+                // Shadow stack access to a non shadow stack page, or normal write or code
+                // execution on a shadow stack page.
+                Ok(Some(EnclaveExceptionInfo::page_fault_in_encl(
+                    (error_code | PageFaultErrorCode::PROTECTION_VIOLATION).bits(),
+                    (error_code | PageFaultErrorCode::PROTECTION_VIOLATION).bits(),
+                    fault_gvaddr,
+                )))
             } else if (error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && !entry.flags.contains(SgxEnclPageFlags::W)) ||
                 (error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) && !entry.flags.contains(SgxEnclPageFlags::X)) {
                 // Illegal write or illegal code execution.
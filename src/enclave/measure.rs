@@ -61,6 +61,19 @@ impl Measure {
         self.state = State::Started;
     }
 
+    /// Measures the HyperEnclave features of the enclave right after ECREATE,
+    /// only if any is set so that the MRENCLAVE of other enclaves is as in SGX.
+    pub fn extend_features(&mut self, features: u64) {
+        let features_val = "HEFEAT";
+        let mut data_block = vec![0; Self::DATA_BLOCK_SIZE];
+
+        data_block[..features_val.len()].clone_from_slice(features_val.as_bytes());
+        let offset = Self::SIZE_NAMED_VALUE;
+        data_block[offset..(offset + size_of::<u64>())].clone_from_slice(&features.to_ne_bytes());
+
+        self.hasher.update(data_block.as_slice());
+    }
+
     pub fn update(
         &mut self,
         page_offset: u64,
@@ -12,10 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod channel;
+mod clone;
+pub mod dcap;
+mod dedup;
 mod edmm;
 pub mod epcm;
 mod manager;
 mod measure;
+mod monitor;
+pub mod ratls;
 pub mod reclaim;
 pub mod report;
 pub mod sgx;
@@ -24,41 +30,51 @@ pub mod structs;
 mod thread;
 mod tlb_track;
 
+use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::cell::UnsafeCell;
 use core::fmt::{Debug, Formatter, Result};
 use core::mem::{size_of, transmute};
-use core::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicIsize, AtomicU64, AtomicUsize, Ordering};
 
 use sha2::{Digest, Sha256};
 use spin::{mutex::SpinMutex, RwLock};
 
 use crate::arch::{
-    EnclaveExceptionInfo, EnclaveGuestPageTableUnlocked, EnclaveNestedPageTableUnlocked,
-    GuestPageTableImmut, PageFaultErrorCode,
+    CetUserState, EnclaveCpuIdTable, EnclaveExceptionInfo, EnclaveGuestPageTableUnlocked,
+    EnclaveNestedPageTableUnlocked, GuestPageTableImmut, Mitigations, PageFaultErrorCode,
 };
+use crate::config::HvSystemConfig;
 use crate::error::HvResult;
 use crate::hypercall::error::{HyperCallErrorType, HyperCallResult};
+use crate::hypercall::eventlog;
 use crate::hypercall::PrivilegeLevel;
 use crate::intervaltree::IntervalTree;
-use crate::memory::addr::{is_aligned, phys_to_virt, GuestPhysAddr, GuestVirtAddr, HostPhysAddr};
+use crate::memory::addr::{
+    align_down, is_aligned, phys_to_virt, GuestPhysAddr, GuestVirtAddr, HostPhysAddr,
+};
 use crate::memory::cmr::NR_INIT_EPC_RANGES;
 use crate::memory::gaccess::{AsGuestPtr, GuestPtr};
 use crate::memory::{GenericPTE, GenericPageTable, GenericPageTableImmut, GenericPageTableMut};
 use crate::memory::{MemFlags, MemoryRegion, PageSize, PagingError, PhysAddr, PAGE_SIZE};
-use crate::percpu::CpuState;
+use crate::percpu::{CpuState, PerCpu};
 use crate::stats::{Instant, StatsValue};
 
+use clone::CloneState;
 use epcm::EpcmManager;
 use measure::Measure;
+use monitor::AttackMonitor;
 use reclaim::{Nonce, VaSlot};
 use sgx::{
-    ElRange, EnclaveErrorCode, MiscSgx, SgxAttributeFlags, SgxEnclPageFlags, SgxEnclPageType,
-    SgxPcmd, SgxSecInfo, SgxSecs, SgxTcs, SigStruct,
+    CetSsaFrame, ElRange, EnclaveErrorCode, MiscSgx, SgxAttributeFlags, SgxCetAttributes,
+    SgxEnclPageFlags, SgxEnclPageType, SgxPcmd, SgxSecInfo, SgxSecs, SgxTcs, SgxTcsFlags,
+    SigStruct,
 };
 use structs::{
-    EnclPageAttributes, HvEnclNewPageDesc, HvEnclRemovePagesAtDestroyPageArray,
-    HvEnclRemovePagesAtDestroyResArray, Sha256Value,
+    EnclPageAttributes, HvEnclCreateFlags, HvEnclFeatures, HvEnclIdentity, HvEnclNewPageDesc,
+    HvEnclRemovePagesAtDestroyPageArray, HvEnclRemovePagesAtDestroyResArray, HvSharedMemoryFlags,
+    Sha256Value,
 };
 use tlb_track::TLBFlushTrackingState;
 
@@ -126,7 +142,16 @@ pub enum EnclaveStatsId {
     PrepareDestroy = 51,
     RemovePagesAtDestroy = 52,
 
-    MaxId = 53,
+    ForcedAex = 53,
+    DecCssa = 54,
+
+    Mitigations = 55,
+
+    CowCopy = 56,
+    DedupBreak = 57,
+    CowRetryAex = 58,
+
+    MaxId = 59,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -196,7 +221,7 @@ pub struct Enclave {
     encl_mem_lock: SpinMutex<()>,
 
     /// Shared memory Ranges
-    shmem: RwLock<IntervalTree>,
+    shmem: RwLock<IntervalTree<HvSharedMemoryFlags>>,
 
     /// Sync between shared memory map and unmap
     shmem_lock: RwLock<()>,
@@ -207,6 +232,37 @@ pub struct Enclave {
     /// - Linux finishes invalidating shared memory, and notifies hypervisor:
     ///     shmem_invalidating_cnt - 1
     shmem_invalidating_cnt: AtomicIsize,
+
+    /// Size of the XSAVE region in each SSA frame, determined by `SECS.ATTRIBUTES.XFRM`.
+    ssa_xsave_size: usize,
+
+    /// Whether interrupts arriving during enclave running cause an AEX.
+    intr_exiting: bool,
+
+    /// Maximum TSC cycles an enclave thread may run before a forced AEX.
+    /// Zero means the enclave runs until it exits or is interrupted.
+    time_slice: AtomicU64,
+
+    /// CPUID leaves served to the enclave, `None` if CPUID raises #UD as in SGX.
+    cpuid_table: Option<EnclaveCpuIdTable>,
+
+    /// AEX and page-fault rates, for controlled-channel attack detection.
+    attack_monitor: SpinMutex<AttackMonitor>,
+
+    /// Copy-on-write state, `None` if the enclave is not cloned from a template.
+    clone: Option<CloneState>,
+    /// Whether the enclave has been cloned. A template never runs again, so that
+    /// the pages its clones share stay unmodified.
+    is_template: AtomicBool,
+    /// Number of clones alive of the template.
+    clones: AtomicUsize,
+
+    /// Whether the read-only pages of the enclave can be deduplicated with those
+    /// of other enclaves.
+    epc_dedup: bool,
+    /// Pages mapped to frames of other enclaves by deduplication, by their
+    /// linear addresses.
+    dedup_pages: SpinMutex<BTreeMap<GuestVirtAddr, GuestPhysAddr>>,
 }
 
 unsafe impl Sync for Enclave {}
@@ -216,12 +272,35 @@ impl Enclave {
         secs_paddr: GuestPhysAddr,
         secs_vaddr: GuestVirtAddr,
         secs: SgxSecs,
+        flags: HvEnclCreateFlags,
+    ) -> HvResult<Arc<Self>> {
+        Self::new_inner(secs_paddr, secs_vaddr, secs, flags, None)
+    }
+
+    fn new_inner(
+        secs_paddr: GuestPhysAddr,
+        secs_vaddr: GuestVirtAddr,
+        secs: SgxSecs,
+        flags: HvEnclCreateFlags,
+        clone: Option<CloneState>,
     ) -> HvResult<Arc<Self>> {
         secs.validate()?;
+        let intr_exiting = match flags.intr_exiting() {
+            Some(intr_exiting) => intr_exiting,
+            None => {
+                return hv_result_err!(
+                    EINVAL,
+                    format!("Enclave::new(): conflicting create flags {:?}", flags)
+                )
+            }
+        };
 
         let elrange = secs.base_addr as _..(secs.base_addr + secs.size) as _;
         let mut measure = Measure::new();
         measure.start(secs.size, secs.ssa_frame_size);
+        if !secs.he_features.is_empty() {
+            measure.extend_features(secs.he_features.bits());
+        }
         let mut secs_verified = secs;
         secs_verified.attributes.flags -= SgxAttributeFlags::INIT;
         let gpt = RwLock::new(EnclaveGuestPageTableUnlocked::new());
@@ -244,6 +323,20 @@ impl Enclave {
             shmem: RwLock::new(IntervalTree::new()),
             shmem_lock: RwLock::new(()),
             shmem_invalidating_cnt: AtomicIsize::new(0),
+            ssa_xsave_size: crate::arch::xsave_size(secs.attributes.xfrm),
+            intr_exiting,
+            time_slice: AtomicU64::new(0),
+            cpuid_table: if secs.he_features.contains(HvEnclFeatures::CPUID_EMULATION) {
+                Some(EnclaveCpuIdTable::new(secs.attributes.xfrm))
+            } else {
+                None
+            },
+            attack_monitor: SpinMutex::new(Default::default()),
+            clone,
+            is_template: AtomicBool::new(false),
+            clones: AtomicUsize::new(0),
+            epc_dedup: flags.contains(HvEnclCreateFlags::EPC_DEDUP),
+            dedup_pages: SpinMutex::new(BTreeMap::new()),
         });
         debug!("NR_INIT_EPC_RANGES: {:#x?}", *NR_INIT_EPC_RANGES);
         debug!("Enclave::new() OK: {:#x?}", enclave);
@@ -254,7 +347,7 @@ impl Enclave {
         &self.elrange
     }
 
-    pub fn shmem(&self) -> &RwLock<IntervalTree> {
+    pub fn shmem(&self) -> &RwLock<IntervalTree<HvSharedMemoryFlags>> {
         &self.shmem
     }
 
@@ -292,10 +385,98 @@ impl Enclave {
         )
     }
 
+    /// AEX-Notify is enabled for a thread if both the enclave and its TCS opt in.
+    pub fn aex_notify_enabled(&self, tcs: &SgxTcs) -> bool {
+        self.secs()
+            .attributes
+            .flags
+            .contains(SgxAttributeFlags::AEXNOTIFY)
+            && tcs.flags().contains(SgxTcsFlags::AEXNOTIFY)
+    }
+
+    /// Whether the enclave requests CET in its attributes.
+    pub fn cet_enabled(&self) -> bool {
+        let secs = self.secs();
+        secs.attributes.flags.contains(SgxAttributeFlags::CET)
+    }
+
+    /// Initial CET user state of the enclave threads with shadow stack pointer `ssp`,
+    /// `None` if CET is not enabled for the enclave.
+    pub fn cet_user_state(&self, ssp: u64) -> Option<CetUserState> {
+        if !self.cet_enabled() {
+            return None;
+        }
+        let secs = self.secs();
+        let leg_bitmap_base = secs.base_addr + secs.cet_leg_bitmap_offset;
+        Some(CetUserState::new(secs.cet_attributes, leg_bitmap_base, ssp))
+    }
+
+    pub fn shadow_stack_enabled(&self) -> bool {
+        let secs = self.secs();
+        let cet_attributes = SgxCetAttributes::from_bits_truncate(secs.cet_attributes);
+        secs.attributes.flags.contains(SgxAttributeFlags::CET)
+            && cet_attributes.contains(SgxCetAttributes::SH_STK_EN)
+    }
+
     pub fn isv(&self) -> (u16, u16) {
         (self.secs().isv_prod_id, self.secs().isv_svn)
     }
 
+    /// MISCSELECT and CONFIGSVN of the enclave.
+    pub fn misc_config(&self) -> (u32, u16) {
+        (self.secs().misc_select, self.secs().config_svn)
+    }
+
+    pub fn ssa_xsave_size(&self) -> usize {
+        self.ssa_xsave_size
+    }
+
+    pub fn intr_exiting(&self) -> bool {
+        self.intr_exiting
+    }
+
+    pub fn cpuid_table(&self) -> Option<&EnclaveCpuIdTable> {
+        self.cpuid_table.as_ref()
+    }
+
+    /// SHA-256 digest of the CPUID table, all 0 if CPUID emulation is disabled.
+    pub fn cpuid_table_digest(&self) -> Sha256Value {
+        let mut digest = Sha256Value::default();
+        if let Some(table) = self.cpuid_table() {
+            digest
+                .as_mut_slice()
+                .copy_from_slice(Sha256::digest(table.as_bytes()).as_slice());
+        }
+        digest
+    }
+
+    /// The HyperEnclave part of the identity of the enclave, which the enclave
+    /// binds into the report data of its reports to attest it.
+    pub fn he_identity(&self) -> HvEnclIdentity {
+        HvEnclIdentity {
+            features: self.secs().he_features.bits(),
+            cpuid_table_digest: self.cpuid_table_digest(),
+            template_digest: self.template_digest(),
+        }
+    }
+
+    pub fn time_slice(&self) -> u64 {
+        self.time_slice.load(Ordering::Acquire)
+    }
+
+    /// Bound the execution time of each enclave thread to `time_slice` TSC cycles,
+    /// takes effect on the next EENTER/ERESUME.
+    pub fn set_time_slice(&self, time_slice: u64) -> HvResult {
+        if time_slice != 0 && !crate::arch::vmm::enclave_time_slice_supported() {
+            return hv_result_err!(
+                ENODEV,
+                "Enclave::set_time_slice(): time slice is not supported on this CPU"
+            );
+        }
+        self.time_slice.store(time_slice, Ordering::Release);
+        Ok(())
+    }
+
     pub fn nested_page_table_root(&self) -> HostPhysAddr {
         self.npt.read().root_paddr()
     }
@@ -373,6 +554,28 @@ impl Enclave {
             .metadata
             .as_guest_ptr_ns::<SgxSecInfo>(gpt, PrivilegeLevel::Supervisor);
         let sec_info = sec_info_ptr.read()?;
+        if sec_info.page_type.is_shadow_stack() {
+            let src = unsafe { &*(phys_to_virt(page_desc.source_address as _) as *const _) };
+            self.validate_shadow_stack_page(gvaddr, &sec_info, src)?;
+        }
+        let src = unsafe { &*(phys_to_virt(page_desc.source_address as _) as *const _) };
+        let dedup_key = self.dedup_key(&sec_info, src);
+        if let Some(key) = &dedup_key {
+            if let Some(frame) = self.dedup_add_page(gvaddr, &sec_info, key, src)? {
+                let attr = page_desc.attr;
+                let page_data = if attr.contains(EnclPageAttributes::EEXTEND) {
+                    Some(unsafe { &*(phys_to_virt(frame) as *const [u8; PAGE_SIZE]) })
+                } else {
+                    None
+                };
+                self.measure.write().update(
+                    (gvaddr - self.elrange.start) as _,
+                    sec_info,
+                    page_data,
+                );
+                return Ok(1);
+            }
+        }
         EpcmManager::add_page(gvaddr, gpaddr, &sec_info, self)?;
 
         let gpt_flags = sec_info.into();
@@ -380,7 +583,7 @@ impl Enclave {
         if sec_info.page_type == SgxEnclPageType::TCS {
             let tcs_gpaddr = page_desc.source_address as _;
             let tcs: &SgxTcs = GuestPtr::gpaddr_to_ref(&tcs_gpaddr, false)?;
-            if !tcs.validate_at_creation() {
+            if !tcs.validate_at_creation() || !self.validate_tcs_cet_ssa(tcs) {
                 return hypercall_hv_err_result!(
                     EINVAL,
                     format!("Enclave::add_page(): Invalid TCS: {:#x?}", tcs)
@@ -390,7 +593,7 @@ impl Enclave {
             self.tcs_count.fetch_add(1, Ordering::Release);
         } else {
             let hpaddr = gpaddr;
-            let npt_flags = gpt_flags | MemFlags::ENCRYPTED;
+            let npt_flags = sec_info.npt_flags() | MemFlags::ENCRYPTED;
             self.npt.write().map(&MemoryRegion::new_with_offset_mapper(
                 gpaddr, hpaddr, PAGE_SIZE, npt_flags,
             ))?;
@@ -414,23 +617,68 @@ impl Enclave {
         self.measure
             .write()
             .update((gvaddr - self.elrange.start) as _, sec_info, page_data);
+        if let Some(key) = dedup_key {
+            self.dedup_index_page(key, gpaddr);
+        }
 
         Ok(0)
     }
 
+    /// Intel SDM, Volume 3, 38.7.2: a shadow stack page must be readable and writable
+    /// (by shadow stack accesses only), and is filled with 0 except for the token in
+    /// its last 8 bytes.
+    fn validate_shadow_stack_page(
+        &self,
+        gvaddr: GuestVirtAddr,
+        sec_info: &SgxSecInfo,
+        src: &[u64; PAGE_SIZE / 8],
+    ) -> HyperCallResult {
+        if !self.shadow_stack_enabled() {
+            return hypercall_hv_err_result!(
+                EINVAL,
+                format!(
+                    "Enclave::validate_shadow_stack_page(): shadow stacks are not enabled, gvaddr: {:#x}",
+                    gvaddr
+                )
+            );
+        }
+        if sec_info.flags & SgxEnclPageFlags::PERM_MASK != SgxEnclPageFlags::R | SgxEnclPageFlags::W
+        {
+            return hypercall_hv_err_result!(
+                EINVAL,
+                format!(
+                    "Enclave::validate_shadow_stack_page(): invalid flags {:?}, gvaddr: {:#x}",
+                    sec_info.flags, gvaddr
+                )
+            );
+        }
+        let (token, content) = src.split_last().unwrap();
+        let expected_token = sec_info.page_type.shadow_stack_token(gvaddr);
+        if *token != expected_token || content.iter().any(|&v| v != 0) {
+            return hypercall_hv_err_result!(
+                EINVAL,
+                format!(
+                    "Enclave::validate_shadow_stack_page(): invalid {:?} page content, gvaddr: {:#x}, token: {:#x}",
+                    sec_info.page_type, gvaddr, token
+                )
+            );
+        }
+        Ok(())
+    }
+
+    /// If CET is enabled, the CET SSA frames of `tcs` must be aligned and inside ELRANGE.
+    fn validate_tcs_cet_ssa(&self, tcs: &SgxTcs) -> bool {
+        if self.cet_user_state(0).is_none() {
+            return true;
+        }
+        let frame_size = core::mem::size_of::<CetSsaFrame>() as u64;
+        let end = tcs.ocet_ssa + tcs.nssa as u64 * frame_size;
+        tcs.ocet_ssa % frame_size == 0 && end <= self.secs().size
+    }
+
     pub fn init(&self, sigstruct: &SigStruct) -> HvResult {
         let init_inner = || -> HvResult {
-            // map page table frames of GPT of the enclave into NPT of the enclave
-            for frame in self.gpt.read().all_frames() {
-                let gpaddr = frame.start_paddr();
-                let hpaddr = gpaddr;
-                self.npt.write().map(&MemoryRegion::new_with_offset_mapper(
-                    gpaddr,
-                    hpaddr,
-                    PAGE_SIZE,
-                    MemFlags::READ | MemFlags::WRITE | MemFlags::USER,
-                ))?;
-            }
+            self.map_gpt_frames()?;
 
             debug!("{:#x?}", sigstruct);
             let secs_mut = unsafe { self.secs_mut() };
@@ -456,9 +704,28 @@ impl Enclave {
                 .mr_signer
                 .as_mut_slice()
                 .clone_from_slice(hash.as_slice());
+            // the launch policy: only the configured signers may get provisioning keys
+            if secs_mut
+                .attributes
+                .flags
+                .contains(SgxAttributeFlags::PROVISIONKEY)
+                && !HvSystemConfig::get()
+                    .provisioning_signers()
+                    .iter()
+                    .any(|signer| signer[..] == *secs_mut.mr_signer.as_slice())
+            {
+                return hv_result_err!(
+                    EPERM,
+                    format!(
+                        "Enclave::init(): signer {:?} may not launch with PROVISIONKEY",
+                        secs_mut.mr_signer
+                    )
+                );
+            }
             secs_mut.isv_prod_id = sigstruct.body.isv_prod_id;
             secs_mut.isv_svn = sigstruct.body.isv_svn;
             secs_mut.attributes.flags |= SgxAttributeFlags::INIT;
+            eventlog::measure_enclave_launch(secs_mut)?;
             info!("Enclave::init(): OK {:#x?}", secs_mut);
             Ok(())
         };
@@ -485,6 +752,21 @@ impl Enclave {
         }
     }
 
+    /// Map page table frames of GPT of the enclave into NPT of the enclave.
+    fn map_gpt_frames(&self) -> HvResult {
+        for frame in self.gpt.read().all_frames() {
+            let gpaddr = frame.start_paddr();
+            let hpaddr = gpaddr;
+            self.npt.write().map(&MemoryRegion::new_with_offset_mapper(
+                gpaddr,
+                hpaddr,
+                PAGE_SIZE,
+                MemFlags::READ | MemFlags::WRITE | MemFlags::USER,
+            ))?;
+        }
+        Ok(())
+    }
+
     pub fn add_version_array(self: &Arc<Self>, gpaddr: GuestPhysAddr) -> HyperCallResult<usize> {
         if !is_aligned(gpaddr) {
             return hypercall_hv_err_result!(
@@ -649,6 +931,7 @@ impl Enclave {
                     ))?;
             }
             EpcmManager::write_back_page(gvaddr_src, gpaddr_src, self)?;
+            dedup::forget_dedup_frame(gpaddr_src);
         }
         let time_unmap = now.elapsed();
 
@@ -755,8 +1038,11 @@ impl Enclave {
 
         let gpt_flags = metadata.sec_info.into();
 
-        if metadata.sec_info.page_type == SgxEnclPageType::REG {
-            let npt_flags = (gpt_flags - MemFlags::NO_PRESENT) | MemFlags::ENCRYPTED;
+        if metadata.sec_info.page_type == SgxEnclPageType::REG
+            || metadata.sec_info.page_type.is_shadow_stack()
+        {
+            let npt_flags =
+                (metadata.sec_info.npt_flags() - MemFlags::NO_PRESENT) | MemFlags::ENCRYPTED;
             self.npt.write().map(&MemoryRegion::new_with_offset_mapper(
                 gpaddr_dst, gpaddr_dst, PAGE_SIZE, npt_flags,
             ))?;
@@ -819,6 +1105,21 @@ impl Enclave {
             );
             return Err(hypercall_enclave_err!(EBLKSTATE));
         }
+        if EpcmManager::is_shared(gpaddr) {
+            return hypercall_hv_err_result!(
+                EBUSY,
+                format!(
+                    "Enclave::block(): paddr {:#x} is shared with another enclave",
+                    gpaddr
+                )
+            );
+        }
+
+        {
+            let _encl_mem_lock = self.encl_mem_lock.lock();
+            self.check_dedup_page_or_busy(gvaddr)?;
+            self.settle_cow_page_or_busy(gpaddr)?;
+        }
 
         {
             let mut tracking_state = self.tracking_state.write();
@@ -888,6 +1189,17 @@ impl Enclave {
 
     pub fn update_tracking_state(&self, is_enter: bool, cpuid: usize) {
         self.tracking_state.write().update(is_enter, cpuid);
+        self.update_cow_epochs(is_enter, cpuid);
+    }
+
+    /// Whether a thread of the enclave is running on CPU `cpuid`.
+    pub fn is_running_on(&self, cpuid: usize) -> bool {
+        self.tracking_state.read().running_cpus().test_cpu(cpuid) != 0
+    }
+
+    /// The CPUs running threads of the enclave.
+    pub fn running_cpus(&self) -> Vec<usize> {
+        self.tracking_state.read().running_cpus().iter().collect()
     }
 
     pub fn inc_epc_page_num(&self) {
@@ -902,11 +1214,24 @@ impl Enclave {
         self.epc_page_num.load(Ordering::Acquire)
     }
 
+    /// Handle an NPT violation in enclave mode, returns true if the thread must
+    /// leave the enclave before retrying the access.
     pub fn handle_npt_violation(
         self: &Arc<Self>,
         gpaddr: GuestPhysAddr,
         final_translation: bool,
-    ) -> HvResult {
+    ) -> HvResult<bool> {
+        if final_translation && self.clone.is_some() {
+            let gpaddr = align_down(gpaddr);
+            let _encl_mem_lock = self.encl_mem_lock.lock();
+            if self.is_cow_page(gpaddr) {
+                return Ok(!self.settle_cow_page(gpaddr)?);
+            }
+            // Another thread has settled the page.
+            if self.npt.read().query(gpaddr).is_ok() {
+                return Ok(false);
+            }
+        }
         let npt_flags = if final_translation {
             if EpcmManager::is_valid_epc(gpaddr) {
                 return hv_result_err!(
@@ -937,7 +1262,7 @@ impl Enclave {
                 }
             }
         }
-        Ok(())
+        Ok(false)
     }
 
     pub fn atomic_add_stats(&self, id: EnclaveStatsId, value: u64) {
@@ -1004,6 +1329,11 @@ impl Enclave {
                             ))
                         } else {
                             let gpfn = pte.addr();
+                            if !self.settle_cow_page(gpfn)? {
+                                // The page of the clone is being copied, retry later.
+                                let error_code = PageFaultErrorCode::USER_MODE.bits();
+                                return generate_pf(error_code, gvaddr, is_encl_mode);
+                            }
                             Ok((
                                 gpfn + PageSize::Size4K.page_offset(gvaddr),
                                 pte.flags(),
@@ -1027,7 +1357,13 @@ impl Enclave {
         }
     }
 
-    pub fn prepare_destroy(self: &Arc<Self>) -> HyperCallResult<usize> {
+    pub fn prepare_destroy(self: &Arc<Self>, cpu_data: &mut PerCpu) -> HyperCallResult<usize> {
+        if self.has_clones() {
+            return hypercall_hv_err_result!(
+                EBUSY,
+                "Enclave::prepare_destroy(): clones of the template are alive"
+            );
+        }
         self.state.store(STATE_TRY_DESTROY, Ordering::SeqCst);
         let active_thread_num = self.tracking_state.read().active_thread_num();
         if active_thread_num != 0 {
@@ -1038,6 +1374,8 @@ impl Enclave {
             warn!("{}", msg);
             return Err(hypercall_enclave_err!(EENCLAVEACT, msg));
         }
+        self.close_channels(cpu_data)?;
+        self.release_dedup_pages();
         self.state.store(STATE_IN_DESTROY, Ordering::SeqCst);
         Ok(0)
     }
@@ -1062,6 +1400,7 @@ impl Enclave {
             //      there is no thread in enclave mode and access the memory.
             match EpcmManager::remove_page_at_destroy(gpaddr, self) {
                 Ok(()) => {
+                    dedup::forget_dedup_frame(gpaddr);
                     unsafe {
                         core::ptr::write_bytes(phys_to_virt(gpaddr) as *mut u8, 0, PAGE_SIZE)
                     };
@@ -1083,6 +1422,7 @@ impl Enclave {
         error_code: u32,
         fault_gvaddr: GuestPhysAddr,
     ) -> HvResult<Option<EnclaveExceptionInfo>> {
+        self.record_page_fault();
         let _encl_mem_lock = self.encl_mem_lock.lock();
         {
             let mut secure_gpt = self.gpt.write();
@@ -1125,6 +1465,7 @@ impl Enclave {
 
         println!("Enclave {:#x} stats:", self.id);
         println!("  TCS: count = {:?}", self.tcs_count);
+        println!("  Mitigations: {:?}", Mitigations::get());
         for (i, value) in self.stats.0.iter().enumerate() {
             let id: EnclaveStatsId = unsafe { core::mem::transmute(i) };
             println!("  {:?}: {}", id, value.as_string());
@@ -1157,6 +1498,10 @@ impl Debug for Enclave {
             .field("epc_page_num", &self.epc_page_num.load(Ordering::Acquire))
             .field("tcs_count", &self.tcs_count)
             .field("shmem", &self.shmem)
+            .field("intr_exiting", &self.intr_exiting)
+            .field("time_slice", &self.time_slice())
+            .field("cpuid_emulation", &self.cpuid_table.is_some())
+            .field("epc_dedup", &self.epc_dedup)
             .finish()
     }
 }
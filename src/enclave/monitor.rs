@@ -0,0 +1,253 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Controlled-channel attack detection.
+//!
+//! Single-stepping an enclave causes an AEX per instruction, and page-fault
+//! side channels cause a page fault per observed page access. Both show up as
+//! abnormal AEX or page-fault rates, which are counted in a sliding window per
+//! enclave and compared with the thresholds the enclave sets.
+
+use crate::error::HvResult;
+
+use super::structs::{
+    HvEnclAttackEvents, HvEnclAttackPolicy, HvEnclAttackPolicyFlags, HvEnclAttackStatus,
+};
+use super::Enclave;
+
+/// Number of sub-windows the sliding window is divided into.
+const NUM_BUCKETS: u64 = 8;
+
+/// Counts the events in a sliding window, with the resolution of a sub-window.
+#[derive(Debug, Default)]
+struct RateCounter {
+    buckets: [u64; NUM_BUCKETS as usize],
+    /// Index of the sub-window of the latest event.
+    current: u64,
+}
+
+impl RateCounter {
+    /// Drop the sub-windows that slid out of the window at sub-window `slot`.
+    fn advance(&mut self, slot: u64) {
+        if slot <= self.current {
+            return;
+        }
+        if slot - self.current >= NUM_BUCKETS {
+            self.buckets = Default::default();
+        } else {
+            for s in self.current + 1..=slot {
+                self.buckets[(s % NUM_BUCKETS) as usize] = 0;
+            }
+        }
+        self.current = slot;
+    }
+
+    /// Record an event at sub-window `slot`, returns the events in the window.
+    fn record(&mut self, slot: u64) -> u64 {
+        self.advance(slot);
+        self.buckets[(self.current % NUM_BUCKETS) as usize] += 1;
+        self.count()
+    }
+
+    fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    fn reset(&mut self) {
+        *self = Default::default();
+    }
+}
+
+#[derive(Debug, Default)]
+pub(super) struct AttackMonitor {
+    policy: HvEnclAttackPolicy,
+    aex: RateCounter,
+    page_faults: RateCounter,
+    /// Events not read by the enclave yet.
+    events: HvEnclAttackEvents,
+    total_events: u64,
+}
+
+impl AttackMonitor {
+    /// Sub-window of the TSC value `now`, `None` if detection is disabled.
+    fn slot(&self, now: u64) -> Option<u64> {
+        let window = self.policy.window;
+        if window == 0 {
+            return None;
+        }
+        Some(now / (window / NUM_BUCKETS).max(1))
+    }
+
+    fn raise(&mut self, event: HvEnclAttackEvents) -> bool {
+        if self.events.contains(event) {
+            return false;
+        }
+        self.events |= event;
+        self.total_events += 1;
+        true
+    }
+
+    fn record(&mut self, now: u64, event: HvEnclAttackEvents) -> Option<u64> {
+        let slot = self.slot(now)?;
+        let (counter, max) = if event == HvEnclAttackEvents::AEX_RATE {
+            (&mut self.aex, self.policy.max_aex)
+        } else {
+            (&mut self.page_faults, self.policy.max_page_faults)
+        };
+        let count = counter.record(slot);
+        if max != 0 && count > max && self.raise(event) {
+            Some(count)
+        } else {
+            None
+        }
+    }
+
+    fn status(&mut self, now: u64) -> HvEnclAttackStatus {
+        if let Some(slot) = self.slot(now) {
+            self.aex.advance(slot);
+            self.page_faults.advance(slot);
+        }
+        let status = HvEnclAttackStatus {
+            aex: self.aex.count(),
+            page_faults: self.page_faults.count(),
+            events: self.events.bits(),
+            total_events: self.total_events,
+        };
+        self.events = HvEnclAttackEvents::empty();
+        status
+    }
+
+    fn resume_refused(&self) -> bool {
+        let flags = HvEnclAttackPolicyFlags::from_bits_truncate(self.policy.flags);
+        flags.contains(HvEnclAttackPolicyFlags::REFUSE_RESUME) && !self.events.is_empty()
+    }
+}
+
+impl Enclave {
+    fn record_attack_signal(&self, event: HvEnclAttackEvents) {
+        let now = crate::arch::cpu::time_now();
+        if let Some(count) = self.attack_monitor.lock().record(now, event) {
+            warn!(
+                "Enclave {:#x}: possible controlled-channel attack, {:?} threshold crossed \
+                 ({} in the window)",
+                self.id, event, count
+            );
+        }
+    }
+
+    /// Count an AEX of the enclave.
+    pub fn record_aex(&self) {
+        self.record_attack_signal(HvEnclAttackEvents::AEX_RATE);
+    }
+
+    /// Count a page fault taken in the enclave.
+    pub fn record_page_fault(&self) {
+        self.record_attack_signal(HvEnclAttackEvents::PAGE_FAULT_RATE);
+    }
+
+    /// Set the detection policy, counting starts over.
+    pub fn set_attack_policy(&self, policy: HvEnclAttackPolicy) -> HvResult {
+        let flags = policy.flags;
+        if HvEnclAttackPolicyFlags::from_bits(flags).is_none() {
+            return hv_result_err!(
+                EINVAL,
+                format!("Enclave::set_attack_policy(): invalid flags {:#x}", flags)
+            );
+        }
+        let mut monitor = self.attack_monitor.lock();
+        monitor.policy = policy;
+        monitor.aex.reset();
+        monitor.page_faults.reset();
+        Ok(())
+    }
+
+    /// Read the status, and clear the pending events.
+    pub fn attack_status(&self) -> HvEnclAttackStatus {
+        let now = crate::arch::cpu::time_now();
+        self.attack_monitor.lock().status(now)
+    }
+
+    /// Whether ERESUME is refused since the enclave asked for it and there are
+    /// pending events.
+    pub fn resume_refused(&self) -> bool {
+        self.attack_monitor.lock().resume_refused()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn monitor(window: u64, max_aex: u64, flags: HvEnclAttackPolicyFlags) -> AttackMonitor {
+        AttackMonitor {
+            policy: HvEnclAttackPolicy {
+                window,
+                max_aex,
+                max_page_faults: 0,
+                flags: flags.bits(),
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_rate_counter_slides() {
+        let mut counter = RateCounter::default();
+        for slot in 0..NUM_BUCKETS {
+            assert_eq!(counter.record(slot), slot + 1);
+        }
+        // The first sub-window slides out.
+        assert_eq!(counter.record(NUM_BUCKETS), NUM_BUCKETS);
+        // All the sub-windows slide out.
+        assert_eq!(counter.record(NUM_BUCKETS * 3), 1);
+    }
+
+    #[test]
+    fn test_threshold() {
+        let mut monitor = monitor(800, 3, HvEnclAttackPolicyFlags::REFUSE_RESUME);
+        for now in 0..3 {
+            assert_eq!(monitor.record(now, HvEnclAttackEvents::AEX_RATE), None);
+        }
+        assert!(!monitor.resume_refused());
+        assert_eq!(monitor.record(3, HvEnclAttackEvents::AEX_RATE), Some(4));
+        // Reported once until the enclave reads the status.
+        assert_eq!(monitor.record(4, HvEnclAttackEvents::AEX_RATE), None);
+        assert!(monitor.resume_refused());
+
+        let status = monitor.status(5);
+        assert_eq!(status.aex, 5);
+        assert_eq!(status.events, HvEnclAttackEvents::AEX_RATE.bits());
+        assert_eq!(status.total_events, 1);
+        assert!(!monitor.resume_refused());
+
+        // No page-fault threshold.
+        for now in 0..100 {
+            assert_eq!(
+                monitor.record(now, HvEnclAttackEvents::PAGE_FAULT_RATE),
+                None
+            );
+        }
+        // The window slid, the AEXs are forgotten.
+        assert_eq!(monitor.status(10_000).aex, 0);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut monitor = monitor(0, 1, HvEnclAttackPolicyFlags::empty());
+        for now in 0..10 {
+            assert_eq!(monitor.record(now, HvEnclAttackEvents::AEX_RATE), None);
+        }
+        assert_eq!(monitor.status(10).aex, 0);
+    }
+}
@@ -0,0 +1,247 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! X.509 certificates for attestation within TLS (RA-TLS).
+//!
+//! The certificate of a public key generated by an enclave is signed with SM2
+//! by the hypervisor attestation key and carries, in a non-critical
+//! extension, the `SgxQuote` of a report of the enclave whose report data
+//! starts with the SHA-256 digest of the DER `SubjectPublicKeyInfo`. A TLS
+//! peer checks the certificate signature with the `hv_att_pub` of the quote,
+//! then verifies the quote, as `Verifier::verify_ratls` of `quote-verify`
+//! does. Its tests build certificates with this module.
+
+use alloc::vec::Vec;
+
+use sha2::{Digest, Sha256};
+
+/// Longest `SubjectPublicKeyInfo` certified, enough for RSA-4096 keys.
+pub const RATLS_PUB_KEY_MAX_LEN: usize = 1024;
+
+/// `2.25.53311593051785037378517846825463353372`, the extension holding the
+/// `SgxQuote`.
+const OID_HE_QUOTE: &[u8] = &[
+    0x69, 0xd0, 0x9b, 0xb8, 0x9e, 0x9b, 0xb9, 0x8a, 0xa0, 0x81, 0xad, 0x9c, 0x8e, 0xb6, 0x92, 0xfc,
+    0xda, 0xa0, 0x1c,
+];
+/// `1.2.156.10197.1.501`, SM2 with SM3.
+const OID_SM2_WITH_SM3: &[u8] = &[0x2a, 0x81, 0x1c, 0xcf, 0x55, 0x01, 0x83, 0x75];
+/// `2.5.4.3`, the common name.
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+const ISSUER_NAME: &str = "HyperEnclave Attestation Key";
+const SUBJECT_NAME: &str = "HyperEnclave RA-TLS";
+/// The hypervisor has no trusted clock, so the certificates are valid from
+/// the epoch and have no expiration date (RFC 5280 4.1.2.5).
+const NOT_BEFORE: &str = "700101000000Z";
+const NOT_AFTER: &str = "99991231235959Z";
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_UTF8_STRING: u8 = 0x0c;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+const TAG_VERSION: u8 = 0xa0;
+const TAG_EXTENSIONS: u8 = 0xa3;
+const X509_V3: u8 = 2;
+
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(content.len() + 6);
+    out.push(tag);
+    if content.len() < 0x80 {
+        out.push(content.len() as u8);
+    } else {
+        let len = content.len().to_be_bytes();
+        let skip = len.iter().take_while(|b| **b == 0).count();
+        out.push(0x80 | (len.len() - skip) as u8);
+        out.extend_from_slice(&len[skip..]);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+fn sequence(items: &[&[u8]]) -> Vec<u8> {
+    der(TAG_SEQUENCE, &items.concat())
+}
+
+/// A non-negative INTEGER of the big-endian `value`.
+fn integer(value: &[u8]) -> Vec<u8> {
+    let skip = value.iter().take_while(|b| **b == 0).count();
+    let value = &value[skip.min(value.len().saturating_sub(1))..];
+    let mut content = Vec::with_capacity(value.len() + 1);
+    if value.first().is_none_or(|b| b & 0x80 != 0) {
+        content.push(0);
+    }
+    content.extend_from_slice(value);
+    der(TAG_INTEGER, &content)
+}
+
+fn name(common_name: &str) -> Vec<u8> {
+    let attribute = sequence(&[
+        &der(TAG_OID, OID_COMMON_NAME),
+        &der(TAG_UTF8_STRING, common_name.as_bytes()),
+    ]);
+    sequence(&[&der(TAG_SET, &attribute)])
+}
+
+/// Whether `key` is exactly one DER SEQUENCE, as a `SubjectPublicKeyInfo`
+/// is; the key itself is left to the TLS peer.
+fn is_der_sequence(key: &[u8]) -> bool {
+    let (len, header) = match *key {
+        [TAG_SEQUENCE, len, ..] if len < 0x80 => (len as usize, 2),
+        [TAG_SEQUENCE, 0x81, len, ..] if len >= 0x80 => (len as usize, 3),
+        [TAG_SEQUENCE, 0x82, hi, lo, ..] if hi != 0 => (u16::from_be_bytes([hi, lo]) as usize, 4),
+        _ => return false,
+    };
+    key.len() == header + len
+}
+
+/// The report data binding a report to the public key `key`.
+pub fn public_key_report_data(key: &[u8]) -> [u8; 64] {
+    let mut report_data = [0; 64];
+    report_data[..32].copy_from_slice(&Sha256::digest(key));
+    report_data
+}
+
+/// An RA-TLS certificate, completed once its `tbs_certificate` is signed.
+pub struct RaTlsCert {
+    tbs: Vec<u8>,
+}
+
+impl RaTlsCert {
+    /// Starts the certificate of the DER `SubjectPublicKeyInfo` `public_key`
+    /// with the SgxQuote `quote`, or returns `None` if the key is malformed.
+    pub fn new(public_key: &[u8], quote: &[u8], serial: &[u8]) -> Option<Self> {
+        if public_key.len() > RATLS_PUB_KEY_MAX_LEN || !is_der_sequence(public_key) {
+            return None;
+        }
+        let algorithm = sequence(&[&der(TAG_OID, OID_SM2_WITH_SM3)]);
+        let validity = sequence(&[
+            &der(TAG_UTC_TIME, NOT_BEFORE.as_bytes()),
+            &der(TAG_GENERALIZED_TIME, NOT_AFTER.as_bytes()),
+        ]);
+        let quote_extension =
+            sequence(&[&der(TAG_OID, OID_HE_QUOTE), &der(TAG_OCTET_STRING, quote)]);
+        let extensions = der(TAG_EXTENSIONS, &sequence(&[&quote_extension]));
+        let tbs = sequence(&[
+            &der(TAG_VERSION, &integer(&[X509_V3])),
+            &integer(serial),
+            &algorithm,
+            &name(ISSUER_NAME),
+            &validity,
+            &name(SUBJECT_NAME),
+            public_key,
+            &extensions,
+        ]);
+        Some(Self { tbs })
+    }
+
+    /// The `TBSCertificate`, to be signed by the hypervisor attestation key.
+    pub fn tbs_certificate(&self) -> &[u8] {
+        &self.tbs
+    }
+
+    /// Completes the certificate with the SM2 signature `r || s`, each
+    /// big-endian.
+    pub fn finish(self, sig: &[u8; 64]) -> Vec<u8> {
+        let sig_value = sequence(&[&integer(&sig[..32]), &integer(&sig[32..])]);
+        let mut bit_string = Vec::with_capacity(sig_value.len() + 1);
+        // no unused bits
+        bit_string.push(0);
+        bit_string.extend_from_slice(&sig_value);
+        sequence(&[
+            &self.tbs,
+            &sequence(&[&der(TAG_OID, OID_SM2_WITH_SM3)]),
+            &der(TAG_BIT_STRING, &bit_string),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_der() {
+        assert_eq!(der(TAG_OCTET_STRING, &[]), [TAG_OCTET_STRING, 0]);
+        assert_eq!(
+            der(TAG_OCTET_STRING, &[0; 0x7f])[..2],
+            [TAG_OCTET_STRING, 0x7f]
+        );
+        // the long form takes as few length bytes as possible
+        assert_eq!(
+            der(TAG_OCTET_STRING, &[0; 0x80])[..3],
+            [TAG_OCTET_STRING, 0x81, 0x80]
+        );
+        let long = der(TAG_OCTET_STRING, &[0; 0x100]);
+        assert_eq!(long[..4], [TAG_OCTET_STRING, 0x82, 1, 0]);
+        assert_eq!(long.len(), 4 + 0x100);
+    }
+
+    #[test]
+    fn test_integer() {
+        assert_eq!(integer(&[X509_V3]), [TAG_INTEGER, 1, 2]);
+        // leading zeros are dropped, but a value keeps at least one byte
+        assert_eq!(integer(&[0, 0, 1]), [TAG_INTEGER, 1, 1]);
+        assert_eq!(integer(&[0, 0]), [TAG_INTEGER, 1, 0]);
+        assert_eq!(integer(&[]), [TAG_INTEGER, 1, 0]);
+        // a zero keeps values with the top bit set non-negative
+        assert_eq!(integer(&[0x80, 1]), [TAG_INTEGER, 3, 0, 0x80, 1]);
+        assert_eq!(integer(&[0, 0, 0xff]), [TAG_INTEGER, 2, 0, 0xff]);
+    }
+
+    #[test]
+    fn test_is_der_sequence() {
+        assert!(is_der_sequence(&[TAG_SEQUENCE, 0]));
+        assert!(is_der_sequence(&sequence(&[&[0; 0x7f]])));
+        assert!(is_der_sequence(&sequence(&[&[0; 0x80]])));
+        assert!(is_der_sequence(&sequence(&[&[0; 0x100]])));
+
+        assert!(!is_der_sequence(&[]));
+        assert!(!is_der_sequence(&der(TAG_SET, &[0; 4])));
+        // truncated and trailing bytes
+        assert!(!is_der_sequence(&[TAG_SEQUENCE, 2, 0]));
+        assert!(!is_der_sequence(&[TAG_SEQUENCE, 0, 0]));
+        // lengths not in the shortest form
+        assert!(!is_der_sequence(&[TAG_SEQUENCE, 0x81, 1, 0]));
+        let mut padded = vec![TAG_SEQUENCE, 0x82, 0, 0x80];
+        padded.resize(4 + 0x80, 0);
+        assert!(!is_der_sequence(&padded));
+    }
+
+    #[test]
+    fn test_oid_he_quote() {
+        // the first byte holds the first two arcs, 40 * 2 + 25
+        assert_eq!(OID_HE_QUOTE[0], 2 * 40 + 25);
+        let mut arc: u128 = 0;
+        for (i, byte) in OID_HE_QUOTE[1..].iter().enumerate() {
+            // bit 7 is set on all bytes but the last
+            assert_eq!(byte & 0x80 != 0, i + 2 < OID_HE_QUOTE.len());
+            arc = arc << 7 | (byte & 0x7f) as u128;
+        }
+        assert_eq!(arc, 53_311_593_051_785_037_378_517_846_825_463_353_372);
+    }
+
+    #[test]
+    fn test_malformed_key() {
+        assert!(RaTlsCert::new(&[TAG_SEQUENCE, 1], &[], &[1]).is_none());
+        let too_long = sequence(&[&[0; RATLS_PUB_KEY_MAX_LEN]]);
+        assert!(RaTlsCert::new(&too_long, &[], &[1]).is_none());
+        assert!(RaTlsCert::new(&sequence(&[&[0; 8]]), &[], &[1]).is_some());
+    }
+}
@@ -13,12 +13,13 @@
 // limitations under the License.
 
 extern crate yogcrypt;
+use super::sgx::SgxAttributeFlags;
+use crate::hypercall::tc;
 use alloc::vec::Vec;
 use core::convert::TryInto;
 use core::{mem::size_of, slice};
-use cty::{uint32_t, uint8_t};
 use yogcrypt::sm2::*;
-use yogcrypt::sm3::sm3_enc;
+use yogcrypt::sm3::sm3_hmac;
 
 pub const SGX_HASH_SIZE: u32 = 32;
 pub const SGX_MAC_SIZE: u32 = 16;
@@ -52,6 +53,8 @@ pub const SGX_KEY_REQUEST_RESERVED2_BYTES: u32 = 434;
 pub const OWNEREPOCH_SIZE: u32 = 32;
 pub const SGX_KEYPOLICY_MRENCLAVE: u16 = 0x0001;
 pub const SGX_KEYPOLICY_MRSIGNER: u16 = 0x0002;
+pub const SGX_KEYSELECT_PROVISION: u16 = 0x0001;
+pub const SGX_KEYSELECT_PROVISION_SEAL: u16 = 0x0002;
 pub const SGX_KEYSELECT_SEAL: u16 = 0x0004;
 pub const SGX_KEYSELECT_REPORT: u16 = 0x0003;
 pub const CSR_BUF_LEN: u32 = 512;
@@ -59,6 +62,12 @@ pub const PCR_LIST_BUF_SIZE: u32 = 96; //3*32
 pub const TPM_AK_CERT_BUF_LEN: u32 = HE_CERT_BUF_LEN;
 pub const HE_QUOTE_VER: u16 = 1;
 pub const HE_SIGN_TYPE: u16 = 4;
+/// CPUSVN of the reports and keys: the security version of the hypervisor,
+/// raised by the releases that fix vulnerabilities.
+pub const HE_CPU_SVN: SgxCpuSvn = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+/// CPUSVN reported by the releases before `HE_CPU_SVN`. The keys requested for it
+/// are derived as those releases did, so data sealed by them stays unsealable.
+pub const HE_LEGACY_CPU_SVN: SgxCpuSvn = [0; SGX_CPUSVN_SIZE as usize];
 pub type SgxMeasurement = [u8; SGX_HASH_SIZE as usize];
 pub type SgxMac = [u8; SGX_MAC_SIZE as usize];
 pub type SgxReportData = [u8; SGX_REPORT_DATA_SIZE as usize];
@@ -67,13 +76,9 @@ pub type SgxKeyId = [u8; SGX_KEYID_SIZE as usize];
 pub type SgxBasename = [u8; SGX_BASE_NAME_SIZE as usize];
 //pub type SMSignBuf = [u8; SGX_REPORT_DATA_SIZE as usize + SGX_HASH_SIZE as usize];
 pub type SgxKey128Bit = [u8; SGX_ENCLAVE_KEY_SIZE as usize];
+pub type SgxCpuSvn = [u8; SGX_CPUSVN_SIZE as usize];
 pub type SgxOwnerEpoch = [u8; OWNEREPOCH_SIZE as usize];
 
-extern "C" {
-    pub fn he_get_key_derivation_secret(buf_ptr: *mut uint8_t, buf_size: uint32_t) -> uint32_t;
-    pub fn he_get_report_key_id(buf: *mut uint8_t, buf_len: uint32_t) -> uint32_t;
-}
-
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Default)]
 pub struct SgxAttrs {
@@ -94,8 +99,26 @@ pub struct SgxTargetInfo {
     reserved3: [u8; SGX_TARGET_INFO_RESERVED3_BYTES as usize],
 }
 
-impl Default for SgxTargetInfo {
-    fn default() -> Self {
+impl SgxTargetInfo {
+    /// The target info of the enclave that made `report`, as
+    /// `sgx_self_target` gets it.
+    pub fn from_report(report: &SgxReport) -> Self {
+        let body = &report.body;
+        SgxTargetInfo {
+            mr_enclave: body.mr_enclave,
+            attributes: body.attributes,
+            reserved1: [0; SGX_TARGET_INFO_RESERVED1_BYTES as usize],
+            config_svn: body.config_svn,
+            misc_select: body.misc_select,
+            reserved2: [0; SGX_TARGET_INFO_RESERVED2_BYTES as usize],
+            config_id: body.config_id,
+            reserved3: [0; SGX_TARGET_INFO_RESERVED3_BYTES as usize],
+        }
+    }
+
+    /// The identity the SDK targets the reports to be quoted to, since the
+    /// hypervisor quotes them in place of a quoting enclave.
+    pub fn quoting_enclave() -> Self {
         SgxTargetInfo {
             mr_enclave: [0xee; SGX_HASH_SIZE as usize],
             attributes: SgxAttrs { flags: 1, xfrm: 3 },
@@ -109,21 +132,11 @@ impl Default for SgxTargetInfo {
     }
 }
 
-impl SgxTargetInfo {
-    pub fn get_attributes(&self) -> (u64, u64) {
-        (self.attributes.flags, self.attributes.xfrm)
-    }
-
-    pub fn get_mr_enclave(&self) -> &[u8] {
-        &self.mr_enclave
-    }
-}
-
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
 pub struct SgxReportBody {
-    cpu_svn: [u8; SGX_CPUSVN_SIZE as usize],
-    misc_select: u32, //reserved
+    cpu_svn: SgxCpuSvn,
+    misc_select: u32,
     reserved1: [u8; SGX_REPORT_BODY_RESERVED1_BYTES as usize],
     isv_ext_prod_id: [u8; SGX_ISVEXT_PROD_ID_SIZE as usize], //reserved
     pub attributes: SgxAttrs,
@@ -134,7 +147,7 @@ pub struct SgxReportBody {
     config_id: SgxConfigId, //reserved
     pub isv_prod_id: u16,
     pub isv_svn: u16,
-    config_svn: u16, //reserved
+    config_svn: u16,
     reserved4: [u8; SGX_REPORT_BODY_RESERVED4_BYTES as usize],
     isv_family_id: [u8; SGX_ISV_FAMILY_ID_SIZE as usize], //reserved
     pub report_data: SgxReportData,
@@ -187,24 +200,6 @@ pub fn reverse_byte_array_copy(dest: &mut [u8], start_index: usize, src: &[u8])
     dest_pos
 }
 
-pub fn convert_sm3_hash_bytes_order(dest: &mut [u8], src: &[u8]) -> usize {
-    if dest.len() != src.len() {
-        return 0;
-    }
-    let step: usize = 4;
-    let mut dst_pos: usize = 0;
-    let mut src_pos: usize;
-    let mut remain: usize;
-    while dst_pos < dest.len() {
-        remain = dst_pos % step;
-        src_pos = (dst_pos - remain) + (step - remain - 1);
-        dest[dst_pos] = src[src_pos];
-        dst_pos += 1;
-    }
-
-    dest.len()
-}
-
 pub fn u64x4_to_bytes(l: &[u64; 4]) -> [u8; 32] {
     let result: Vec<u8> = l.iter().rev().flat_map(|var| var.to_be_bytes()).collect();
     result.try_into().unwrap()
@@ -242,10 +237,13 @@ impl Default for SgxReportBody {
 }
 
 impl SgxReportBody {
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const Self as *const u8, size_of::<Self>()) }
+    }
+
     pub fn set_basics(&mut self, isv_prod_id: u16, isv_svn: u16, attr_flags: u64, attr_xfrm: u64) {
-        let cpu_svn: [u8; SGX_CPUSVN_SIZE as usize] = [0; SGX_CPUSVN_SIZE as usize];
-        self.cpu_svn.copy_from_slice(&cpu_svn);
-        self.misc_select = 0; // reserved in sgx
+        self.cpu_svn = HE_CPU_SVN;
+        self.misc_select = 0;
         let reserved1: [u8; SGX_REPORT_BODY_RESERVED1_BYTES as usize] =
             [0; SGX_REPORT_BODY_RESERVED1_BYTES as usize];
         self.reserved1.copy_from_slice(&reserved1);
@@ -273,24 +271,20 @@ impl SgxReportBody {
         self.isv_family_id.copy_from_slice(&isv_family_id);
     }
 
-    pub fn mac(&mut self, key: &[u8], key_id_size: usize, mac: &mut [u8]) -> usize {
-        if self.cpu_svn.len() == key.len() {
-            self.cpu_svn.copy_from_slice(key);
-        }
-        let hash_bytes;
-        unsafe {
-            let body_bytes = slice::from_raw_parts(
-                self.cpu_svn.as_ptr() as *const u8,
-                size_of::<SgxReportBody>() + key_id_size,
-            );
-            let hash = sm3_enc(body_bytes);
-            hash_bytes = slice::from_raw_parts(hash.as_ptr() as *const u8, SGX_MAC_SIZE as usize);
-        }
+    /// The HMAC-SM3 of the body and `key_id` keyed by `key`, truncated to the
+    /// MAC size.
+    pub fn mac(&self, key: &[u8], key_id: &[u8], mac: &mut [u8]) -> usize {
+        let mut mac_data = Vec::with_capacity(size_of::<Self>() + key_id.len());
+        mac_data.extend_from_slice(self.as_bytes());
+        mac_data.extend_from_slice(key_id);
+        let hash_bytes: Vec<u8> = sm3_hmac(key, &mac_data)
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .take(SGX_MAC_SIZE as usize)
+            .collect();
         if mac.len() == hash_bytes.len() {
-            mac.copy_from_slice(hash_bytes);
+            mac.copy_from_slice(&hash_bytes);
         }
-        let cpu_svn: [u8; SGX_CPUSVN_SIZE as usize] = [0; SGX_CPUSVN_SIZE as usize];
-        self.cpu_svn.copy_from_slice(&cpu_svn);
         hash_bytes.len()
     }
 }
@@ -359,10 +353,13 @@ impl SgxReport {
             .set_basics(isv_prod_id, isv_svn, attr_flags, attr_xfrm);
     }
 
+    pub fn set_misc_config(&mut self, misc_select: u32, config_svn: u16) {
+        self.body.misc_select = misc_select;
+        self.body.config_svn = config_svn;
+    }
+
     pub fn set_key_id(&mut self) {
-        unsafe {
-            he_get_report_key_id(self.key_id.as_mut_ptr(), self.key_id.len() as uint32_t);
-        }
+        tc::report_key_id(&mut self.key_id);
     }
     pub fn get_key_id(&self) -> &[u8] {
         &self.key_id
@@ -382,7 +379,7 @@ impl SgxReport {
     }
     pub fn mac(&mut self, key: &SgxKey128Bit) -> u32 {
         let mut mac_buf: [u8; SGX_MAC_SIZE as usize] = [0; SGX_MAC_SIZE as usize];
-        let mac_len = self.body.mac(key, size_of::<SgxKeyId>(), &mut mac_buf);
+        let mac_len = self.body.mac(key, &self.key_id, &mut mac_buf);
         if mac_len == 0 {
             return 0;
         }
@@ -481,8 +478,10 @@ impl SgxQuote {
         hv_quote.len() as u32
     }
 
-    pub fn get_hv_quote_mut_ptr(&mut self) -> *mut u8 {
-        self.hv_quote.as_mut_ptr() as *mut u8
+    /// The quote as laid out in memory, of which only the first `SGX_QUOTE_SIZE
+    /// + sig_len` bytes are used.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const Self as *const u8, size_of::<Self>()) }
     }
 
     pub fn get_data_to_sign(&self) -> &[u8] {
@@ -528,7 +527,7 @@ impl Default for SgxKeyRequest {
 }
 
 #[repr(C)]
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct DerivationData {
     key_name: u16,
     isv_svn: u16,
@@ -540,26 +539,44 @@ pub struct DerivationData {
     mr_signer: SgxMeasurement,
     key_id: SgxKeyId,
     epoch: SgxOwnerEpoch,
+    cpu_svn: SgxCpuSvn,
+    misc_select: u32,
+    config_svn: u16,
+    reserved: u16,
+    config_id: SgxConfigId,
+}
+
+impl Default for DerivationData {
+    fn default() -> Self {
+        DerivationData {
+            key_name: 0,
+            isv_svn: 0,
+            isv_prod_id: 0,
+            tcb_svn: 0,
+            attributes: Default::default(),
+            attr_mask: Default::default(),
+            mr_enclave: [0; SGX_HASH_SIZE as usize],
+            mr_signer: [0; SGX_HASH_SIZE as usize],
+            key_id: [0; SGX_KEYID_SIZE as usize],
+            epoch: [0; OWNEREPOCH_SIZE as usize],
+            cpu_svn: [0; SGX_CPUSVN_SIZE as usize],
+            misc_select: 0,
+            config_svn: 0,
+            reserved: 0,
+            config_id: [0; SGX_CONFIGID_SIZE as usize],
+        }
+    }
 }
 
 impl DerivationData {
-    pub fn init_with_target_info(
-        &mut self,
-        target_info: &SgxTargetInfo,
-        report: &SgxReport,
-    ) -> bool {
+    /// Report keys, of the target enclave and the key ID of the report.
+    pub fn init_with_target_info(&mut self, target_info: &SgxTargetInfo, key_id: &[u8]) -> bool {
         self.key_name = SGX_KEYSELECT_REPORT;
         self.isv_prod_id = 0; // defined by SGX speficiation
         self.isv_svn = 0; //defined by SGX speficiation
         let mr_signer: [u8; SGX_HASH_SIZE as usize] = [0; SGX_HASH_SIZE as usize]; // mr_signer must be 0
-        unsafe {
-            if he_get_key_derivation_secret(
-                self.epoch.as_mut_ptr() as *mut uint8_t,
-                OWNEREPOCH_SIZE,
-            ) != OWNEREPOCH_SIZE
-            {
-                return false;
-            }
+        if !tc::key_derivation_secret(&mut self.epoch) {
+            return false;
         }
         self.attributes.flags = target_info.attributes.flags;
         self.attributes.xfrm = target_info.attributes.xfrm;
@@ -567,11 +584,26 @@ impl DerivationData {
         self.attr_mask.xfrm = 0;
         self.mr_enclave.copy_from_slice(&target_info.mr_enclave);
         self.mr_signer.copy_from_slice(&mr_signer);
-        self.key_id.copy_from_slice(report.get_key_id());
+        self.key_id.copy_from_slice(key_id);
+        self.cpu_svn = HE_CPU_SVN;
+        self.misc_select = target_info.misc_select;
+        self.config_svn = target_info.config_svn;
+        self.config_id = target_info.config_id;
 
         true
     }
 
+    /// The bytes the key is derived from. The fields from `cpu_svn` on are left
+    /// out for the legacy CPUSVN, which the releases before them had.
+    pub fn as_bytes(&self) -> &[u8] {
+        let len = if self.cpu_svn == HE_LEGACY_CPU_SVN {
+            &self.cpu_svn as *const SgxCpuSvn as usize - self as *const Self as usize
+        } else {
+            size_of::<Self>()
+        };
+        unsafe { slice::from_raw_parts(self as *const Self as *const u8, len) }
+    }
+
     pub fn init_with_key_request(
         &mut self,
         kr: &SgxKeyRequest,
@@ -582,7 +614,9 @@ impl DerivationData {
         xfrm: u64,
     ) -> bool {
         self.key_name = kr.key_name;
-        if kr.key_name != SGX_KEYSELECT_REPORT && kr.key_name != SGX_KEYSELECT_SEAL {
+        let is_provision_key =
+            kr.key_name == SGX_KEYSELECT_PROVISION || kr.key_name == SGX_KEYSELECT_PROVISION_SEAL;
+        if kr.key_name != SGX_KEYSELECT_SEAL && !is_provision_key {
             error!("HyperEnclave:the enclave key type is not supported");
             println!(
                 "HyperEnclave:the enclave key type is not supported {}",
@@ -590,37 +624,36 @@ impl DerivationData {
             );
             return false;
         }
-        if kr.key_name == SGX_KEYSELECT_REPORT {
-            self.isv_prod_id = 0; // all those four values are defined by SGX specification
-            self.isv_svn = 0;
-            self.attr_mask.flags = 0;
-            self.attr_mask.xfrm = 0;
+        // the launch policy was checked by EINIT for enclaves with PROVISIONKEY
+        if is_provision_key && flags & SgxAttributeFlags::PROVISIONKEY.bits() == 0 {
+            println!("HyperEnclave: the enclave has no PROVISIONKEY attribute");
+            return false;
         }
-        if kr.key_name == SGX_KEYSELECT_SEAL {
-            self.isv_prod_id = isv_prod_id;
-            self.isv_svn = kr.isv_svn;
-            self.attr_mask.flags = kr.attr_mask.flags;
-            self.attr_mask.xfrm = kr.attr_mask.xfrm;
+        if u128::from_le_bytes(kr.cpu_svn) > u128::from_le_bytes(HE_CPU_SVN) {
+            println!("HyperEnclave: the requested CPUSVN is above that of the hypervisor");
+            return false;
         }
+        self.isv_prod_id = isv_prod_id;
+        self.isv_svn = kr.isv_svn;
+        self.attr_mask.flags = kr.attr_mask.flags;
+        self.attr_mask.xfrm = kr.attr_mask.xfrm;
+        self.cpu_svn = kr.cpu_svn;
         self.attributes.flags = flags;
         self.attributes.xfrm = xfrm;
-        self.key_id.copy_from_slice(&kr.key_id);
-        unsafe {
-            if he_get_key_derivation_secret(
-                self.epoch.as_mut_ptr() as *mut uint8_t,
-                OWNEREPOCH_SIZE,
-            ) != OWNEREPOCH_SIZE
-            {
-                error!("HyperEnclave: he_get_key_derivation_secret failed");
-                return false;
-            }
+        if !is_provision_key {
+            // as in SGX, provisioning keys do not depend on the key ID
+            self.key_id.copy_from_slice(&kr.key_id);
         }
-        if kr.key_name == SGX_KEYSELECT_REPORT {
-            self.mr_enclave.copy_from_slice(mr_enclave);
-            let mr_signer: [u8; SGX_HASH_SIZE as usize] = [0; SGX_HASH_SIZE as usize]; // for report_key, signer must be zero
-            self.mr_signer.copy_from_slice(&mr_signer);
+        if !tc::key_derivation_secret(&mut self.epoch) {
+            error!("HyperEnclave: failed to get the key derivation secret");
+            return false;
         }
-        if kr.key_name == SGX_KEYSELECT_SEAL {
+        if is_provision_key {
+            // as in SGX, KEYPOLICY is ignored: provisioning keys are bound to
+            // MRSIGNER and never to MRENCLAVE
+            self.mr_enclave = [0; SGX_HASH_SIZE as usize];
+            self.mr_signer.copy_from_slice(mr_signer);
+        } else {
             if kr.key_policy & SGX_KEYPOLICY_MRENCLAVE > 0 {
                 self.mr_enclave.copy_from_slice(mr_enclave);
             }
@@ -22,14 +22,16 @@ use core::fmt::{Debug, Formatter, Result};
 use core::mem::size_of;
 use core::{convert::TryFrom, mem::MaybeUninit, ops::Range};
 
-use super::structs::{Sha256Value, SigKey3072Value};
+use super::structs::{HvEnclFeatures, Sha256Value, SigKey3072Value};
 use crate::arch::XsaveRegion;
 use crate::enclave::reclaim::HmacValue;
 use crate::enclave::Enclave;
 use crate::error::{HvError, HvResult};
+use crate::hypercall::error::HyperCallResult;
 use crate::hypercall::PrivilegeLevel;
+use crate::memory::addr::{align_down, align_up, is_aligned};
 use crate::memory::gaccess::{AsGuestPtr, GuestPtr};
-use crate::memory::{addr::is_aligned, GuestVirtAddr, MemFlags, PAGE_SIZE};
+use crate::memory::{GuestPhysAddr, GuestVirtAddr, MemFlags, PAGE_SIZE};
 use crate::percpu::CpuState;
 
 /// Enclave Linear Address Range (ELRANGE).
@@ -68,6 +70,28 @@ bitflags! {
         /// 
         /// 启用 Key Separation 和共享功能 
         const KSS               = 1 << 7;
+        /// Threads may opt in to be notified of AEXs.
+        ///
+        /// 允许 enclave 线程在 AEX 之后收到通知
+        const AEXNOTIFY         = 1 << 10;
+    }
+}
+
+bitflags! {
+    /// CET features enabled for the enclave, in the same layout as the low bits of IA32_U_CET.
+    pub struct SgxCetAttributes: u8 {
+        /// Enable shadow stacks.
+        const SH_STK_EN     = 1 << 0;
+        /// Enable WRSS{D,Q}W instructions.
+        const WR_SHSTK_EN   = 1 << 1;
+        /// Enable indirect branch tracking.
+        const ENDBR_EN      = 1 << 2;
+        /// Enable legacy compatibility treatment for indirect branch tracking.
+        const LEG_IW_EN     = 1 << 3;
+        /// Enable use of no-track prefix for indirect branch tracking.
+        const NO_TRACK_EN   = 1 << 4;
+        /// Disable suppression of CET indirect branch tracking on legacy compatibility.
+        const SUPPRESS_DIS  = 1 << 5;
     }
 }
 
@@ -174,6 +198,23 @@ pub enum SgxEnclPageType {
 use num_derive::FromPrimitive;    
 use num_traits::FromPrimitive;
 
+impl SgxEnclPageType {
+    pub fn is_shadow_stack(self) -> bool {
+        self == Self::SS_FIRST || self == Self::SS_REST
+    }
+
+    /// Initial value of the last 8 bytes of a shadow stack page at `gvaddr`. For
+    /// PT_SS_FIRST, it is a restore token pointing to the top of the page with the
+    /// 64-bit mode bit set. For PT_SS_REST, it is 0 as the rest of the page.
+    pub fn shadow_stack_token(self, gvaddr: GuestVirtAddr) -> u64 {
+        if self == Self::SS_FIRST {
+            (gvaddr + PAGE_SIZE) as u64 | 1
+        } else {
+            0
+        }
+    }
+}
+
 impl TryFrom<u8> for SgxEnclPageType {
     type Error = HvError;
 
@@ -242,6 +283,17 @@ impl SgxSecInfo {
             _reserved: [0; 3],
         }
     }
+
+    /// Flags of the NPT mapping. Shadow stack accesses are treated as writes by
+    /// nested paging, so shadow stack pages stay writable in NPT.
+    pub fn npt_flags(self) -> MemFlags {
+        let mut flags = MemFlags::from(self);
+        if self.page_type.is_shadow_stack() {
+            flags -= MemFlags::SHADOW_STACK;
+            flags |= MemFlags::WRITE;
+        }
+        flags
+    }
 }
 
 impl From<SgxSecInfo> for MemFlags {
@@ -280,6 +332,13 @@ impl From<SgxSecInfo> for MemFlags {
             }
         }
 
+        // Shadow stack pages are read-only to ordinary writes, and only writable by
+        // shadow stack accesses.
+        if sec_info.page_type.is_shadow_stack() {
+            ret -= MemFlags::WRITE;
+            ret |= MemFlags::SHADOW_STACK;
+        }
+
         ret
     }
 }
@@ -331,7 +390,8 @@ impl SgxExitInfo {
             | ExceptionType::PageFault
             | ExceptionType::FloatingPointException
             | ExceptionType::AlignmentCheck
-            | ExceptionType::SIMDFloatingPointException => {
+            | ExceptionType::SIMDFloatingPointException
+            | ExceptionType::ControlProtectionException => {
                 info |= Self::TYPE_HARD_EXCEPTION | Self::VALID
             }
             _ => {}
@@ -353,8 +413,13 @@ pub struct SgxSecs {
     /// ( 20) Bit vector specifying which extended features are saved to the MISC region of the SSA
     /// frame when an AEX occurs.
     pub misc_select: u32,
-    /// ( 24) Reserved
-    _reserved1: [u8; 16],
+    /// ( 24) Page aligned offset of the legacy code page bitmap of indirect branch tracking,
+    /// relative to the enclave base.
+    pub cet_leg_bitmap_offset: u64,
+    /// ( 32) CET features enabled for the enclave, valid if `ATTRIBUTES.CET` is set.
+    pub cet_attributes: u8,
+    /// ( 33) Reserved
+    _reserved1: [u8; 7],
     /// ( 40) Marshalling buffer size for each TCS.
     pub ms_buf_size: u64,
     /// ( 48) Attributes of the Enclave.
@@ -374,7 +439,12 @@ pub struct SgxSecs {
     /// (258) Security version number (SVN) of the enclave.
     pub isv_svn: u16,
     /// (260) Post EINIT configuration security version number (SVN).
-    config_svn: u16,
+    pub config_svn: u16,
+    /// (262) Reserved
+    _reserved4: [u8; 2],
+    /// (264) HyperEnclave extension, reserved in SGX: features the enclave opts in to,
+    /// measured into MRENCLAVE.
+    pub he_features: HvEnclFeatures,
 }
 
 /// Thread Control Structure (TCS).
@@ -406,16 +476,36 @@ pub struct SgxTcs {
     fs_limit: u32,
     /// (68) Size to become the new GS limit in 32-bit mode.
     gs_limit: u32,
-    /// (72) Rerserved field in TCS, must be 0 at creation.
-    _reserved: [u8; 4024],
+    /// (72) Offset of the CET state save area stack, relative to the enclave base. Must be
+    /// aligned to the size of a CET SSA frame.
+    pub ocet_ssa: u64,
+    /// (80) The shadow stack pointer saved at the last EEXIT, loaded on EENTER if shadow
+    /// stacks are enabled. Must be 0 at creation.
+    pub prev_ssp: u64,
+    /// (88) Rerserved field in TCS, must be 0 at creation.
+    _reserved: [u8; 4008],
 }
 static_assertions::const_assert_eq!(core::mem::size_of::<SgxTcs>(), 4096);
 
+bitflags! {
+    /// The thread’s execution flags in TCS.
+    pub struct SgxTcsFlags: u64 {
+        /// Allow debugging features while executing in the enclave on this TCS.
+        const DBGOPTIN  = 1 << 0;
+        /// The thread opts in to AEX-Notify, if the enclave supports it.
+        const AEXNOTIFY = 1 << 1;
+    }
+}
+
 impl SgxTcs {
+    pub fn flags(&self) -> SgxTcsFlags {
+        SgxTcsFlags::from_bits_truncate(self.flags)
+    }
+
     /// Check whether the MBZ(Must Be Zero) bits and reserved bits is 0
     /// when newing a TCS.
     pub fn validate_at_creation(&self) -> bool {
-        if self.stage != 0 || self.aep != 0 {
+        if self.stage != 0 || self.aep != 0 || self.prev_ssp != 0 {
             return false;
         }
         for i in self._reserved {
@@ -427,44 +517,169 @@ impl SgxTcs {
     }
 }
 
-/// The size of SSA region equals to PAGE_SIZE (4096 bytes) now,
-/// since the `SSA_FRAME_SIZE` input from SDK always equals to one 4k page (4096 bytes).
-/// With such constriction, it is easy to specify the size of `XsaveRegion` at its definition.
-/// Another benefit we gain from that is there only needs one GVA -> GPA (Walk GPT) translation when
-/// switching from normal world to secure world.
-pub const SSA_FRAME_SIZE: usize = PAGE_SIZE;
+/// Maximum number of pages taken by the XSAVE region of an SSA frame, enough for
+/// all the state components (including AMX) of current processors. The rest of
+/// a larger SSA frame is not touched by the hypervisor.
+pub const SSA_XSAVE_MAX_PAGES: usize = 4;
 
-#[repr(C)]
-pub struct StateSaveArea {
-    pub xsave: XsaveRegion,
-    pub misc: MiscSgx,
-    pub gpr: GprSgx,
+/// An SSA frame of `SECS.SSAFRAMESIZE` pages. The XSAVE region starts at the
+/// beginning of the frame, while GPRSGX is at the end of the frame with the MISC
+/// region right below it:
+///
+/// ```text
+/// +--------------+ <- ossa + cssa * SSAFRAMESIZE
+/// | XSAVE        |
+/// | ...          |
+/// | MISC         |
+/// | GPRSGX       |
+/// +--------------+ <- ossa + (cssa + 1) * SSAFRAMESIZE
+/// ```
+///
+/// The pages of a frame are not contiguous in guest physical memory, so the
+/// pages covering the XSAVE region and the last page are translated one by one
+/// when the frame is located, and stay valid until the thread leaves the frame.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SsaFrame {
+    /// Guest physical addresses of the pages holding the XSAVE region.
+    xsave_pages: [GuestPhysAddr; SSA_XSAVE_MAX_PAGES],
+    /// Size of the XSAVE region in bytes.
+    xsave_size: usize,
+    /// Guest physical address of MISC.
+    misc_paddr: GuestPhysAddr,
+    /// Guest physical address of GPRSGX.
+    gpr_paddr: GuestPhysAddr,
+    /// Guest physical address of the CET state save area frame, if CET is enabled.
+    cet_paddr: Option<GuestPhysAddr>,
 }
-// const_assert!(size_of::<StateSaveArea>() == 4096); 
 
-impl StateSaveArea {
-    pub fn ssa_ptr<'a>(
-        enclave: &'a Arc<Enclave>,
+impl SsaFrame {
+    /// Locate the SSA frame `cssa` of `tcs`, and make sure all of its pages
+    /// that hypervisor accesses are present and writable.
+    pub fn new(
+        enclave: &Arc<Enclave>,
         tcs: &SgxTcs,
         cssa: u32,
-        cpu_state: &'a CpuState,
+        cpu_state: &CpuState,
         privilege_level: PrivilegeLevel,
-    ) -> HvResult<GuestPtr<'a, StateSaveArea>> {
+    ) -> HyperCallResult<Self> {
         if cssa >= tcs.nssa {
-            return hv_result_err!(
+            return hypercall_hv_err_result!(
                 EINVAL,
                 format!("tcs.cssa {:#x?} >= tcs.nssa {:#x?}", cssa, tcs.nssa)
             );
         }
+        let frame_size = enclave.secs().ssa_frame_size as usize * PAGE_SIZE;
         let start_addr =
-            enclave.secs().base_addr as usize + tcs.ossa as usize + cssa as usize * SSA_FRAME_SIZE;
+            enclave.secs().base_addr as usize + tcs.ossa as usize + cssa as usize * frame_size;
         if !is_aligned(start_addr) {
-            return hv_result_err!(EINVAL, format!("tcs.ossa {:#x?} is not aligned", tcs.ossa));
+            return hypercall_hv_err_result!(
+                EINVAL,
+                format!("tcs.ossa {:#x?} is not aligned", tcs.ossa)
+            );
+        }
+
+        let page_gpaddr = |gvaddr: GuestVirtAddr| -> HyperCallResult<GuestPhysAddr> {
+            let mut ptr: GuestPtr<[u8; PAGE_SIZE]> =
+                gvaddr.as_guest_ptr_s(enclave, cpu_state, privilege_level);
+            Ok(GuestPtr::ref_to_gpaddr(ptr.as_mut()?))
+        };
+
+        let xsave_size = enclave.ssa_xsave_size();
+        let mut xsave_pages = [0; SSA_XSAVE_MAX_PAGES];
+        for (i, page) in xsave_pages
+            .iter_mut()
+            .take(align_up(xsave_size) / PAGE_SIZE)
+            .enumerate()
+        {
+            *page = page_gpaddr(start_addr + i * PAGE_SIZE)?;
+        }
+        let last_page = page_gpaddr(start_addr + frame_size - PAGE_SIZE)?;
+        let gpr_paddr = last_page + PAGE_SIZE - size_of::<GprSgx>();
+        let cet_paddr = if enclave.cet_user_state(0).is_some() {
+            let cet_gvaddr = enclave.secs().base_addr as usize
+                + tcs.ocet_ssa as usize
+                + cssa as usize * size_of::<CetSsaFrame>();
+            let mut ptr: GuestPtr<CetSsaFrame> =
+                cet_gvaddr.as_guest_ptr_s(enclave, cpu_state, privilege_level);
+            Some(GuestPtr::ref_to_gpaddr(ptr.as_mut()?))
+        } else {
+            None
+        };
+        Ok(Self {
+            xsave_pages,
+            xsave_size,
+            misc_paddr: gpr_paddr - size_of::<MiscSgx>(),
+            gpr_paddr,
+            cet_paddr,
+        })
+    }
+
+    pub fn xsave_size(&self) -> usize {
+        self.xsave_size
+    }
+
+    /// Guest physical addresses of the pages accessed by hypervisor.
+    pub fn pages(&self) -> impl Iterator<Item = GuestPhysAddr> + '_ {
+        self.xsave_pages
+            .iter()
+            .take(align_up(self.xsave_size) / PAGE_SIZE)
+            .copied()
+            .chain(core::iter::once(align_down(self.gpr_paddr)))
+            .chain(self.cet_paddr.map(align_down))
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    pub fn gpr(&self) -> HvResult<&mut GprSgx> {
+        GuestPtr::gpaddr_to_ref_mut(&self.gpr_paddr, true)
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    pub fn misc(&self) -> HvResult<&mut MiscSgx> {
+        GuestPtr::gpaddr_to_ref_mut(&self.misc_paddr, true)
+    }
+
+    /// The CET state save area frame, `None` if CET is not enabled for the enclave.
+    #[allow(clippy::mut_from_ref)]
+    pub fn cet(&self) -> HvResult<Option<&mut CetSsaFrame>> {
+        match self.cet_paddr {
+            Some(paddr) => Ok(Some(GuestPtr::gpaddr_to_ref_mut(&paddr, true)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Copy the XSAVE region from `xsave_region` to the frame.
+    pub fn store_xsave(&self, xsave_region: &XsaveRegion) -> HvResult {
+        let src = &xsave_region.as_slice()[..self.xsave_size];
+        for (chunk, page) in src.chunks(PAGE_SIZE).zip(self.xsave_pages.iter()) {
+            let dst: &mut [u8; PAGE_SIZE] = GuestPtr::gpaddr_to_ref_mut(page, true)?;
+            dst[..chunk.len()].copy_from_slice(chunk);
+        }
+        Ok(())
+    }
+
+    /// Copy the XSAVE region from the frame to `xsave_region`.
+    pub fn load_xsave(&self, xsave_region: &mut XsaveRegion) -> HvResult {
+        let dst = &mut xsave_region.as_mut_slice()[..self.xsave_size];
+        for (chunk, page) in dst.chunks_mut(PAGE_SIZE).zip(self.xsave_pages.iter()) {
+            let src: &[u8; PAGE_SIZE] = GuestPtr::gpaddr_to_ref(page, true)?;
+            chunk.copy_from_slice(&src[..chunk.len()]);
         }
-        Ok(start_addr.as_guest_ptr_s(enclave, cpu_state, privilege_level))
+        Ok(())
     }
 }
 
+/// A frame of the CET state save area, which holds the CET state of the enclave
+/// thread on AEX. Frame `cssa` is at `ocet_ssa + cssa * size_of::<CetSsaFrame>()`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct CetSsaFrame {
+    /// Shadow stack pointer.
+    pub ssp: u64,
+    /// Indirect branch tracking state, IA32_U_CET.SUPPRESS (bit 0) and
+    /// IA32_U_CET.TRACKER (bit 1).
+    pub ib_track_state: u64,
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct SgxExInfo {
@@ -521,7 +736,9 @@ pub struct GprSgx {
     pub urbp: u64,
     /// Contains information about exceptions that cause AEXs, which might be needed by enclave software.
     pub exit_info: SgxExitInfo,
-    _reserved: u32,
+    _reserved: [u8; 3],
+    /// Bit 0 is set if the AEX that saved this frame should be notified to the enclave.
+    pub aex_notify: u8,
     /// FS BASE.
     pub fs_base: u64,
     /// GS BASE.
@@ -605,6 +822,7 @@ pub struct SigStruct {
 #[derive(PartialEq, Copy, Clone)]
 #[allow(clippy::upper_case_acronyms)]
 pub enum EnclaveErrorCode {
+    EINVALIDATTRIBUTE = 0x4000_0002,
     EBLKSTATE = 0x4000_0003,
     EPAGENOTBLOCKED = 0x4000_000a,
     ENOTTRACKED = 0x4000_000b,
@@ -614,6 +832,10 @@ pub enum EnclaveErrorCode {
     EPAGEATTRIBUTESMISMATCH = 0x4000_0013,
     PAGENOTMODIFIABLE = 0x4000_0014,
     ECANCELRECLAIM = 0x4000_001d,
+    /// HyperEnclave: the report is not bound to the RA-TLS public key.
+    ERATLSREPORTDATA = 0x4000_0100,
+    /// HyperEnclave: the RA-TLS public key is not a DER `SubjectPublicKeyInfo`.
+    ERATLSPUBKEY = 0x4000_0101,
 }
 
 impl EnclaveErrorCode {
@@ -621,6 +843,7 @@ impl EnclaveErrorCode {
         use EnclaveErrorCode::*;
 
         let msg = match self {
+            EINVALIDATTRIBUTE => "The enclave requests attributes that are not enabled",
             EBLKSTATE => "Page is already in blocked state",
             EPAGENOTBLOCKED => "Page is not marked as blocked",
             ENOTTRACKED => "Tracking cycle isn't done",
@@ -632,6 +855,8 @@ impl EnclaveErrorCode {
                 "Page cannot be modified because it is in the PENDING or MODIFIED state"
             }
             ECANCELRECLAIM => "Cancel reclaim EPC page",
+            ERATLSREPORTDATA => "Report data is not bound to the RA-TLS public key",
+            ERATLSPUBKEY => "Malformed RA-TLS public key",
         };
         String::from(msg)
     }
@@ -710,6 +935,8 @@ impl Debug for SgxSecs {
             .field("base_addr", &self.base_addr)
             .field("ssa_frame_size", &self.ssa_frame_size)
             .field("misc_select", &self.misc_select)
+            .field("cet_leg_bitmap_offset", &self.cet_leg_bitmap_offset)
+            .field("cet_attributes", &self.cet_attributes)
             .field("ms_buf_size", &self.ms_buf_size)
             .field("attributes", &self.attributes)
             .field("mr_enclave", &self.mr_enclave)
@@ -718,6 +945,7 @@ impl Debug for SgxSecs {
             .field("isv_prod_id", &self.isv_prod_id)
             .field("isv_svn", &self.isv_svn)
             .field("config_svn", &self.config_svn)
+            .field("he_features", &self.he_features)
             .finish()
     }
 }
@@ -736,6 +964,8 @@ impl Debug for SgxTcs {
             .field("ogs_base", &self.ogs_base)
             .field("fs_limit", &self.fs_limit)
             .field("gs_limit", &self.gs_limit)
+            .field("ocet_ssa", &self.ocet_ssa)
+            .field("prev_ssp", &self.prev_ssp)
             .finish()
     }
 }
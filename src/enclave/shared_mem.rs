@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::arch::GuestPageTableImmut;
+use crate::arch::{GuestPageTableImmut, PageFaultErrorCode};
 use crate::error::{HvError, HvResult};
 use crate::intervaltree::overlap;
 use crate::memory::addr::{align_down, align_up, GuestVirtAddr};
@@ -25,6 +25,7 @@ use core::ops::Range;
 use core::sync::atomic::Ordering;
 
 use super::epcm::EpcmManager;
+use super::structs::HvSharedMemoryFlags;
 use super::{Enclave, EnclaveStatsId};
 
 /// Shared Memory Range
@@ -37,10 +38,39 @@ pub enum SharedMemSyncType {
     InvalidEnd,
 }
 
+impl HvSharedMemoryFlags {
+    pub fn from_desc(flags: u64) -> HvResult<Self> {
+        let flags = match Self::from_bits(flags) {
+            Some(flags) => flags,
+            None => {
+                return hv_result_err!(EINVAL, format!("invalid shared memory flags {:#x}", flags))
+            }
+        };
+        // x86 paging has no way to map a page writable but not readable.
+        if flags.contains(Self::WRITE_ONLY) {
+            return hv_result_err!(EINVAL, "write-only shared memory is not supported");
+        }
+        Ok(flags)
+    }
+
+    /// Permissions removed from the mappings of the range.
+    fn denied(&self) -> MemFlags {
+        let mut denied = MemFlags::empty();
+        if self.contains(Self::READ_ONLY) {
+            denied |= MemFlags::WRITE;
+        }
+        if self.contains(Self::NO_EXEC) {
+            denied |= MemFlags::EXECUTE;
+        }
+        denied
+    }
+}
+
 impl Enclave {
     pub fn map_shared_memory_range(
         &self,
         mem_range: &SharedMemRange,
+        flags: HvSharedMemoryFlags,
         gpt: &GuestPageTableImmut,
     ) -> HvResult {
         let start_addr = align_down(mem_range.start);
@@ -64,6 +94,7 @@ impl Enclave {
                 );
             }
             {
+                let gpt_flags = gpt_flags - flags.denied();
                 if let Err(e) = self.gpt.write().map(&MemoryRegion::new_with_offset_mapper(
                     gvaddr, gpaddr, PAGE_SIZE, gpt_flags,
                 )) {
@@ -156,7 +187,11 @@ impl Enclave {
                     if overlap(&self.elrange, mem_range).is_some() {
                         return hv_result_err!(EINVAL, "valid range is append to ELRANGE");
                     }
-                    self.map_shared_memory_range(mem_range, gpt)?;
+                    // The range may be removed since the page fault.
+                    let range = self.shmem.read().get(&mem_range.start);
+                    if let Some((_, flags)) = range {
+                        self.map_shared_memory_range(mem_range, flags, gpt)?;
+                    }
                     self.atomic_add_stats(EnclaveStatsId::ResumeMapSharedMemory, now.elapsed());
                 }
             }
@@ -167,6 +202,7 @@ impl Enclave {
     pub fn add_shared_memory(
         &self,
         mem_range: &SharedMemRange,
+        flags: HvSharedMemoryFlags,
         gpt: &GuestPageTableImmut,
     ) -> HvResult {
         if !self.is_init() {
@@ -182,12 +218,62 @@ impl Enclave {
             );
         }
         // Add new range to shared memory
-        self.shmem.write().insert(mem_range.start..mem_range.end)?;
+        self.shmem
+            .write()
+            .insert_with(mem_range.start..mem_range.end, flags)?;
         // Map new range to GPT and NPT
-        self.map_shared_memory_range(mem_range, gpt)?;
+        self.map_shared_memory_range(mem_range, flags, gpt)?;
         Ok(())
     }
 
+    /// Add `flags` to the attributes of a shared memory range, on behalf of the
+    /// enclave. Attributes can only be added, never cleared, by the enclave.
+    ///
+    /// The existing mappings of the range are removed, and are created again
+    /// with the new attributes on the next access. The caller must then flush
+    /// the TLBs of the CPUs running the enclave, see
+    /// `PerCpu::flush_enclave_tlb`.
+    pub fn restrict_shared_memory(
+        &self,
+        mem_range: &SharedMemRange,
+        flags: HvSharedMemoryFlags,
+    ) -> HvResult {
+        let _lock = self.shmem_lock.write();
+        {
+            let mut shmem = self.shmem.write();
+            let old_flags = match shmem.get(&mem_range.start) {
+                Some((range, old_flags)) if &range == mem_range => old_flags,
+                _ => {
+                    return hv_result_err!(
+                        EINVAL,
+                        format!(
+                            "restrict_shared_memory(): {:#x?} is not a shared memory range",
+                            mem_range
+                        )
+                    )
+                }
+            };
+            shmem.update(mem_range, old_flags | flags)?;
+        }
+        self.unmap_shared_memory_range(mem_range)
+    }
+
+    /// Whether a page fault in shared memory is caused by an access the
+    /// attributes of the range deny, rather than by a missing mapping.
+    pub fn shared_memory_access_denied(&self, gvaddr: GuestVirtAddr, error_code: u32) -> bool {
+        let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+        match self.shmem.read().get(&gvaddr) {
+            Some((_, flags)) => {
+                let denied = flags.denied();
+                (error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+                    && denied.contains(MemFlags::WRITE))
+                    || (error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+                        && denied.contains(MemFlags::EXECUTE))
+            }
+            None => false,
+        }
+    }
+
     pub fn remove_shared_memory(&self, mem_range: &SharedMemRange) -> HvResult {
         if overlap(&self.elrange, mem_range).is_some() {
             return hv_result_err!(
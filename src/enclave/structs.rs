@@ -35,11 +35,48 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// Policy flags passed along with `EnclaveCreate`.
+    pub struct HvEnclCreateFlags: u64 {
+        /// Interrupts arriving during enclave running cause an AEX.
+        const INTR_EXITING      = 1 << 0;
+        /// Interrupts are held pending until the enclave exits.
+        const NO_INTR_EXITING   = 1 << 1;
+        /// Read-only pages may share EPC frames with identical pages of other enclaves.
+        const EPC_DEDUP         = 1 << 3;
+    }
+}
+
+impl HvEnclCreateFlags {
+    /// Whether interrupts cause an AEX, falls back to the build default
+    /// (the `enclave_interrupt` feature) if neither policy is requested.
+    pub fn intr_exiting(&self) -> Option<bool> {
+        match (
+            self.contains(Self::INTR_EXITING),
+            self.contains(Self::NO_INTR_EXITING),
+        ) {
+            (true, true) => None,
+            (true, false) => Some(true),
+            (false, true) => Some(false),
+            (false, false) => Some(cfg!(feature = "enclave_interrupt")),
+        }
+    }
+}
+
+bitflags! {
+    /// HyperEnclave features an enclave opts in to, in `HvEnclDesc::he_features`.
+    pub struct HvEnclFeatures: u64 {
+        /// CPUID inside the enclave is served from a table built by the hypervisor.
+        const CPUID_EMULATION   = 1 << 0;
+    }
+}
+
 /// Each enclave descriptor occupies exactly one page, as does the SGX SECS.
 /// Just leverage SGX secs_t directly except that this page is not hidden from
 /// either N or S world, since no secret is stored in it yet. However, if we do
 /// decide to store sensitive information (such as the enclave's measurement) in
-/// it later, we can simply unmap this page from EPT-N and EPT-S.
+/// it later, we can simply unmap this page from EPT-N and EPT-S. HyperEnclave
+/// extensions (`ms_buf_size`, `he_features`) live in fields reserved in SGX.
 pub type HvEnclDesc = SgxSecs;
 
 #[derive(Debug)]
@@ -95,7 +132,20 @@ pub struct HvEnclRemovePagesAtDestroyResArray {
     pub val: [isize; PAGE_SIZE / size_of::<isize>()],
 }
 
-#[derive(Debug)]
+bitflags! {
+    /// Attributes of a shared memory range, masked out of its mappings in the enclave.
+    #[derive(Default)]
+    pub struct HvSharedMemoryFlags: u64 {
+        /// The enclave cannot write to the range.
+        const READ_ONLY = 1 << 0;
+        /// The enclave cannot read from the range.
+        const WRITE_ONLY = 1 << 1;
+        /// The enclave cannot execute code in the range.
+        const NO_EXEC = 1 << 2;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
 pub struct HvSharedMemoryDesc {
     pub config_addr: u64,
@@ -103,6 +153,15 @@ pub struct HvSharedMemoryDesc {
     pub end_addr: u64,
 }
 
+/// `HvSharedMemoryDesc` followed by the `HvSharedMemoryFlags` of the range,
+/// used by `SharedMemoryAddWithFlags` and `EnclaveSharedMemoryRestrict`.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct HvSharedMemoryFlagsDesc {
+    pub desc: HvSharedMemoryDesc,
+    pub flags: u64,
+}
+
 #[derive(Debug)]
 #[repr(C, packed)]
 pub struct HvEnclAugPageDesc {
@@ -147,6 +206,175 @@ pub struct HvEnclRemovePageAtRuntimeDesc {
     pub enclave_lin_addr: u64,
 }
 
+#[derive(Debug)]
+#[repr(C, packed)]
+pub struct HvEnclTimeSliceDesc {
+    /// Guest linear address of SECS the enclave belongs to
+    pub config_address: u64,
+    /// Maximum TSC cycles an enclave thread may run before a forced AEX, 0 for no limit
+    pub time_slice: u64,
+}
+
+bitflags! {
+    /// Flags of `HvEnclAttackPolicy`.
+    pub struct HvEnclAttackPolicyFlags: u64 {
+        /// ERESUME fails while there are attack events not read by the enclave.
+        const REFUSE_RESUME = 1 << 0;
+    }
+}
+
+bitflags! {
+    /// Thresholds crossed since the enclave read the attack status last time.
+    #[derive(Default)]
+    pub struct HvEnclAttackEvents: u64 {
+        /// Too many AEXs in the window, e.g. single-stepping.
+        const AEX_RATE          = 1 << 0;
+        /// Too many page faults in the window, e.g. a page-fault side channel.
+        const PAGE_FAULT_RATE   = 1 << 1;
+    }
+}
+
+/// Controlled-channel attack detection policy, set by the enclave itself.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C, packed)]
+pub struct HvEnclAttackPolicy {
+    /// Length of the sliding window in TSC cycles, 0 to disable detection
+    pub window: u64,
+    /// Maximum AEXs in the window, 0 for no limit
+    pub max_aex: u64,
+    /// Maximum in-enclave page faults in the window, 0 for no limit
+    pub max_page_faults: u64,
+    /// `HvEnclAttackPolicyFlags`
+    pub flags: u64,
+}
+
+/// Controlled-channel attack status returned to the enclave.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C, packed)]
+pub struct HvEnclAttackStatus {
+    /// AEXs in the current window
+    pub aex: u64,
+    /// In-enclave page faults in the current window
+    pub page_faults: u64,
+    /// `HvEnclAttackEvents` since the last read
+    pub events: u64,
+    /// Number of thresholds crossed over the lifetime of the enclave
+    pub total_events: u64,
+}
+
+/// HyperEnclave identity of the enclave that SGX reports cannot carry,
+/// returned to the enclave.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C, packed)]
+pub struct HvEnclIdentity {
+    /// `HvEnclFeatures` of the enclave
+    pub features: u64,
+    /// SHA-256 digest of the CPUID table, all 0 if CPUID emulation is disabled
+    pub cpuid_table_digest: Sha256Value,
+    /// Identity of the template a clone derives from, all 0 if not a clone
+    pub template_digest: Sha256Value,
+}
+
+/// Enclave identity checked by the other end of an EPC channel.
+pub const HV_ENCL_CHANNEL_POLICY_MRENCLAVE: u64 = 0;
+pub const HV_ENCL_CHANNEL_POLICY_MRSIGNER: u64 = 1;
+
+/// Identity an enclave requires from the other end of an EPC channel.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct HvEnclChannelPolicy {
+    /// `HV_ENCL_CHANNEL_POLICY_MRENCLAVE` or `HV_ENCL_CHANNEL_POLICY_MRSIGNER`
+    pub policy_type: u64,
+    /// Expected MRENCLAVE or MRSIGNER
+    pub value: Sha256Value,
+}
+
+/// EPC channel offered by the owner enclave.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct HvEnclChannelCreateDesc {
+    /// Start of the owner's pages in its ELRANGE, page aligned
+    pub start_addr: u64,
+    /// Size in bytes, page aligned
+    pub size: u64,
+    /// Identity required from the peer
+    pub peer_policy: HvEnclChannelPolicy,
+    /// Channel ID, returned by hypervisor
+    pub id: u64,
+}
+
+/// Identity owning an enclave monotonic counter.
+pub const HV_ENCL_COUNTER_OWNER_MRENCLAVE: u64 = 0;
+pub const HV_ENCL_COUNTER_OWNER_MRSIGNER: u64 = 1;
+
+/// Monotonic counter of an enclave, kept by the TPM across reboots.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct HvEnclCounterDesc {
+    /// `HV_ENCL_COUNTER_OWNER_MRENCLAVE` or `HV_ENCL_COUNTER_OWNER_MRSIGNER`
+    pub owner_type: u64,
+    /// Counter ID, returned by hypervisor on creation
+    pub id: u64,
+    /// Counter value, returned by hypervisor
+    pub value: u64,
+}
+
+/// EPC channel accepted by the peer enclave.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct HvEnclChannelAcceptDesc {
+    /// Channel ID returned to the owner
+    pub id: u64,
+    /// Where the channel is mapped in the peer's ELRANGE, page aligned
+    pub addr: u64,
+    /// Identity required from the owner
+    pub owner_policy: HvEnclChannelPolicy,
+    /// Size in bytes, returned by hypervisor
+    pub size: u64,
+}
+
+#[derive(Debug)]
+#[repr(C, packed)]
+pub struct HvEnclCloneDesc {
+    /// Guest linear address of SECS of the clone
+    pub config_address: u64,
+    /// Guest linear address of SECS of the initialized template enclave
+    pub template_address: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct HvEnclClonePage {
+    /// Guest linear address of the template page
+    pub enclave_lin_addr: u64,
+    /// Guest physical address of the EPC page backing it in the clone
+    pub epc_page_pa: u64,
+}
+
+#[repr(C)]
+pub struct HvEnclClonePageArray {
+    pub pages: [HvEnclClonePage; PAGE_SIZE / size_of::<HvEnclClonePage>()],
+}
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct HvEnclCloneAddPagesDesc {
+    pub config_address: u64,
+    pub page_array_addr: u64,
+    pub batch_size: u64,
+}
+
+#[derive(Debug)]
+#[repr(C, packed)]
+pub struct HvEnclDedupBreakDesc {
+    /// Guest linear address of SECS the page belongs to
+    pub config_address: u64,
+    /// Guest linear address of the deduplicated page
+    pub enclave_lin_addr: u64,
+    /// Guest physical address of the EPC page for the private copy
+    pub epc_page_pa: u64,
+}
+
 pub const SHA256_HASH_SIZE: usize = 32;
 
 #[repr(transparent)]
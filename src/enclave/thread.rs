@@ -16,12 +16,13 @@ use alloc::sync::Arc;
 use core::fmt::{Debug, Formatter, Result};
 
 use super::epcm::EpcmManager;
-use super::sgx::{SgxTcs, StateSaveArea};
+use super::sgx::{SgxTcs, SsaFrame};
 use super::shared_mem::SharedMemSyncType;
 use super::{AexException, Enclave, EnclaveStatsId, EnclaveThreadState};
 use crate::arch::vmm::VcpuAccessGuestState;
 use crate::arch::{
-    EnclaveExceptionInfo, EnclavePFErrorCode, GuestPageTableImmut, PageFaultErrorCode,
+    CetUserState, EnclaveExceptionInfo, EnclavePFErrorCode, GuestPageTableImmut, Mitigations,
+    NormalWorldMsrs, NormalWorldXsaveRegion, PageFaultErrorCode, XsaveRegionFrame,
 };
 use crate::error::HvResult;
 use crate::hypercall::error::HyperCallResult;
@@ -40,6 +41,9 @@ pub trait VcpuAccessEnclaveState: VcpuAccessGuestState {
         state: &EnclaveThreadState,
         is_enter: bool,
     ) -> HvResult;
+    /// Drops the TLB entries of the guest on this CPU, derived from both the
+    /// guest and the nested page tables.
+    fn flush_tlb(&mut self) -> HvResult;
 }
 
 /// Describes an execution thread of an enclave. Each enclave thread binds a CPU
@@ -51,21 +55,37 @@ pub struct EnclaveThread {
     tcs_vaddr: GuestVirtAddr,
     /// Guest phyical address of TCS.
     tcs_paddr: GuestPhysAddr,
-    /// Guest physical address of SSA.
-    ssa_paddr: GuestPhysAddr,
+    /// Current SSA frame.
+    ssa: SsaFrame,
+    /// Bounce buffer for XSAVE/XRSTOR, since the SSA frame may span several
+    /// non-contiguous guest physical pages.
+    xsave_region: XsaveRegionFrame,
     /// N world states, loaded onto the CPU on enclave exit.
     normal_world_state: EnclaveThreadState,
+    /// N world extended states, loaded onto the CPU on enclave exit.
+    normal_xsave_region: NormalWorldXsaveRegion,
+    /// N world CET states, `None` if CET is not used by the running enclave thread.
+    normal_cet_state: Option<CetUserState>,
+    /// N world values of the MSRs cleared while the enclave thread runs.
+    normal_msrs: NormalWorldMsrs,
+    /// TSC value at which the current time slice expires, 0 for no limit.
+    time_slice_deadline: u64,
 }
 
 impl EnclaveThread {
-    pub fn new() -> Self {
-        Self {
+    pub fn new() -> HvResult<Self> {
+        Ok(Self {
             is_active: false,
             tcs_vaddr: 0,
             tcs_paddr: 0,
-            ssa_paddr: 0,
+            ssa: SsaFrame::default(),
+            xsave_region: XsaveRegionFrame::new()?,
             normal_world_state: Default::default(),
-        }
+            normal_xsave_region: NormalWorldXsaveRegion::new()?,
+            normal_cet_state: None,
+            normal_msrs: NormalWorldMsrs::default(),
+            time_slice_deadline: 0,
+        })
     }
 
     pub fn enter(
@@ -93,6 +113,13 @@ impl EnclaveThread {
                 "EnclaveThread::enter(): enclave is not initialized"
             );
         }
+        if enclave.is_template() {
+            return hypercall_hv_err_result!(
+                EPERM,
+                "EnclaveThread::enter(): the template of clones cannot run"
+            );
+        }
+        Self::check_cet(vcpu, &enclave)?;
         let time_get_tcs = now.elapsed();
 
         let base = enclave.elrange().start as u64;
@@ -100,18 +127,15 @@ impl EnclaveThread {
         let fs_base = base + tcs.ofs_base;
         let gs_base = base + tcs.ogs_base;
 
-        let mut ssa_ptr =
-            StateSaveArea::ssa_ptr(&enclave, tcs, tcs.cssa, cpu_state, PrivilegeLevel::User)?;
-        let ssa = ssa_ptr.as_mut()?;
-        let ssa_paddr = GuestPtr::ref_to_gpaddr(ssa);
-
-        let gpr = &mut ssa.gpr;
+        let ssa = SsaFrame::new(&enclave, tcs, tcs.cssa, cpu_state, PrivilegeLevel::User)?;
+        let gpr = ssa.gpr()?;
 
         let time_get_ssa = now.elapsed();
 
         tcs.aep = aep;
         gpr.urbp = vcpu.frame_pointer();
         gpr.ursp = vcpu.stack_pointer();
+        let time_slice = enclave.time_slice();
         self.normal_world_state = vcpu.load_enclave_thread_state()?;
         EnclaveThreadState::enclave_enter(
             vcpu,
@@ -122,12 +146,19 @@ impl EnclaveThread {
             tcs.cssa,
             enclave.nested_page_table_root(),
             enclave.page_table_root(),
+            enclave.intr_exiting(),
+            time_slice,
+            &mut self.normal_xsave_region,
         )?;
+        self.enter_cet(&enclave, tcs.prev_ssp, 0);
+        self.normal_msrs.save_and_clear(vcpu)?;
+        Self::mitigate(&enclave);
 
         self.is_active = true;
+        self.start_time_slice(time_slice);
         self.tcs_vaddr = tcs_vaddr;
         self.tcs_paddr = tcs_paddr;
-        self.ssa_paddr = ssa_paddr;
+        self.ssa = ssa;
 
         let time_total = now.elapsed();
 
@@ -162,18 +193,28 @@ impl EnclaveThread {
                 "EnclaveThread::enter(): enclave is not in initialized state"
             );
         }
+        if enclave.is_template() {
+            return hypercall_hv_err_result!(
+                EPERM,
+                "EnclaveThread::resume(): the template of clones cannot run"
+            );
+        }
+        Self::check_cet(vcpu, &enclave)?;
         if tcs.cssa == 0 {
             return hypercall_hv_err_result!(EIO, "EnclaveThread::resume(): tcs.cssa == 0");
         }
+        if enclave.resume_refused() {
+            return hypercall_hv_err_result!(
+                EPERM,
+                "EnclaveThread::resume(): refused on a suspected controlled-channel attack"
+            );
+        }
         let time_get_tcs = now.elapsed();
 
-        let mut ssa_ptr =
-            StateSaveArea::ssa_ptr(&enclave, tcs, tcs.cssa - 1, cpu_state, PrivilegeLevel::User)?;
-        let ssa = ssa_ptr.as_mut()?;
-        let ssa_paddr = GuestPtr::ref_to_gpaddr(ssa);
+        let ssa = SsaFrame::new(&enclave, tcs, tcs.cssa - 1, cpu_state, PrivilegeLevel::User)?;
         let time_get_ssa = now.elapsed();
 
-        let ssa_misc = &mut ssa.misc;
+        let ssa_misc = ssa.misc()?;
         if (ssa_misc.exinfo.errcd & EnclavePFErrorCode::SHARED_MEM_FETCH.bits()) != 0 {
             let fault_gvaddr = ssa_misc.exinfo.maddr as usize;
             let start_addr = align_down(fault_gvaddr);
@@ -183,21 +224,41 @@ impl EnclaveThread {
             ssa_misc.exinfo.errcd = 0;
         }
 
+        // With AEX-Notify, ERESUME enters the enclave at OENTRY as EENTER does,
+        // and leaves the interrupted context in the SSA frame. The enclave handler
+        // pops the frame with EDECCSSA when it is done.
+        if enclave.aex_notify_enabled(tcs) && ssa.gpr()?.aex_notify & 1 != 0 {
+            return self.enter(tcs_vaddr, aep, vcpu, gpt, cpu_state);
+        }
+
+        let (ssp, ib_track_state) = match ssa.cet()? {
+            Some(cet) => (cet.ssp, cet.ib_track_state),
+            None => (0, 0),
+        };
+        let time_slice = enclave.time_slice();
         self.normal_world_state = vcpu.load_enclave_thread_state()?;
         EnclaveThreadState::enclave_resume(
             vcpu,
             enclave.secs().attributes.xfrm,
             enclave.nested_page_table_root(),
             enclave.page_table_root(),
-            ssa,
+            &ssa,
+            &mut self.xsave_region,
+            enclave.intr_exiting(),
+            time_slice,
+            &mut self.normal_xsave_region,
         )?;
+        self.enter_cet(&enclave, ssp, ib_track_state);
+        self.normal_msrs.save_and_clear(vcpu)?;
+        Self::mitigate(&enclave);
         tcs.aep = aep;
         tcs.cssa -= 1;
 
         self.is_active = true;
+        self.start_time_slice(time_slice);
         self.tcs_vaddr = tcs_vaddr;
         self.tcs_paddr = tcs_paddr;
-        self.ssa_paddr = ssa_paddr;
+        self.ssa = ssa;
 
         enclave.atomic_add_stats(EnclaveStatsId::ResumeGetTcs, time_get_tcs);
         enclave.atomic_add_stats(EnclaveStatsId::ResumeGetSsa, time_get_ssa - time_get_tcs);
@@ -214,12 +275,23 @@ impl EnclaveThread {
         }
         let enclave = EpcmManager::get_enclave_in_encl(self.tcs_paddr)?;
         let tcs: &mut SgxTcs = GuestPtr::gpaddr_to_ref_mut(&self.tcs_paddr, true)?;
-        EnclaveThreadState::enclave_exit(vcpu, exit_ip, tcs.aep, &self.normal_world_state)?;
+        if let Some(cet) = self.leave_cet() {
+            tcs.prev_ssp = cet.ssp;
+        }
+        self.normal_msrs.restore(vcpu)?;
+        EnclaveThreadState::enclave_exit(
+            vcpu,
+            exit_ip,
+            tcs.aep,
+            &self.normal_world_state,
+            &mut self.normal_xsave_region,
+        )?;
+        Self::mitigate(&enclave);
 
         self.is_active = false;
         self.tcs_vaddr = 0;
         self.tcs_paddr = 0;
-        self.ssa_paddr = 0;
+        self.ssa = SsaFrame::default();
 
         Ok(enclave)
     }
@@ -235,22 +307,108 @@ impl EnclaveThread {
         }
         let enclave = EpcmManager::get_enclave_in_encl(self.tcs_paddr)?;
         let tcs: &mut SgxTcs = GuestPtr::gpaddr_to_ref_mut(&self.tcs_paddr, true)?;
-        let mut ssa = GuestPtr::gpaddr_to_ref_mut(&self.ssa_paddr, true)?;
+        if let Some(cet) = self.leave_cet() {
+            if let Some(frame) = self.ssa.cet()? {
+                frame.ssp = cet.ssp;
+                frame.ib_track_state = cet.ib_track_state();
+            }
+        }
+        self.normal_msrs.restore(vcpu)?;
         EnclaveThreadState::enclave_aex(
             vcpu,
             aex_excep,
             tcs.aep,
             enclave.secs().attributes.xfrm,
             self.tcs_vaddr,
-            &mut ssa,
+            &self.ssa,
+            &mut self.xsave_region,
             &self.normal_world_state,
+            &mut self.normal_xsave_region,
         )?;
+        Self::mitigate(&enclave);
+        enclave.record_aex();
+        self.ssa.gpr()?.aex_notify = enclave.aex_notify_enabled(tcs) as u8;
         tcs.cssa += 1;
 
         self.is_active = false;
         self.tcs_vaddr = 0;
         self.tcs_paddr = 0;
-        self.ssa_paddr = 0;
+        self.ssa = SsaFrame::default();
+
+        Ok(enclave)
+    }
+
+    /// Run the transient-execution mitigations after a world switch, and record
+    /// their cost.
+    fn mitigate(enclave: &Enclave) {
+        let now = Instant::now();
+        Mitigations::apply();
+        enclave.atomic_add_stats(EnclaveStatsId::Mitigations, now.elapsed());
+    }
+
+    /// As in SGX, an enclave with CET cannot run unless the guest OS enables it.
+    fn check_cet(vcpu: &impl VcpuAccessGuestState, enclave: &Enclave) -> HyperCallResult {
+        if enclave.cet_enabled() && !CetUserState::is_enabled(vcpu.cr(4)) {
+            return Err(hypercall_enclave_err!(
+                EINVALIDATTRIBUTE,
+                "EnclaveThread: the enclave requests CET, which CR4.CET disables"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Save the N world CET state and load the one of the enclave thread, if CET
+    /// is enabled for the enclave, which `check_cet` requires of the guest OS.
+    fn enter_cet(&mut self, enclave: &Enclave, ssp: u64, ib_track_state: u64) {
+        self.normal_cet_state = enclave.cet_user_state(ssp).map(|mut state| {
+            state.set_ib_track_state(ib_track_state);
+            let normal_state = CetUserState::load();
+            state.store();
+            normal_state
+        });
+    }
+
+    /// Restore the N world CET state, and return the one of the enclave thread.
+    fn leave_cet(&mut self) -> Option<CetUserState> {
+        self.normal_cet_state.take().map(|normal_state| {
+            let state = CetUserState::load();
+            normal_state.store();
+            state
+        })
+    }
+
+    fn start_time_slice(&mut self, time_slice: u64) {
+        self.time_slice_deadline = if time_slice != 0 {
+            crate::arch::cpu::time_now().saturating_add(time_slice)
+        } else {
+            0
+        };
+    }
+
+    /// Whether the running enclave thread has used up its time slice.
+    pub fn time_slice_expired(&self) -> bool {
+        self.is_active
+            && self.time_slice_deadline != 0
+            && crate::arch::cpu::time_now() >= self.time_slice_deadline
+    }
+
+    /// Pop the current SSA frame of the running thread (EDECCSSA). The next AEX
+    /// will save the enclave context into the popped frame.
+    pub fn dec_cssa(&mut self, cpu_state: &CpuState) -> HyperCallResult<Arc<Enclave>> {
+        if !self.is_active {
+            return hypercall_hv_err_result!(EIO);
+        }
+        let enclave = EpcmManager::get_enclave_in_encl(self.tcs_paddr)?;
+        let tcs: &mut SgxTcs = GuestPtr::gpaddr_to_ref_mut(&self.tcs_paddr, true)?;
+        if tcs.cssa == 0 {
+            return Err(hypercall_excep_err!(
+                EnclaveExceptionInfo::general_protection(0, cpu_state),
+                "EnclaveThread::dec_cssa(): tcs.cssa == 0"
+            ));
+        }
+
+        self.ssa = SsaFrame::new(&enclave, tcs, tcs.cssa - 1, cpu_state, PrivilegeLevel::User)?;
+        tcs.cssa -= 1;
 
         Ok(enclave)
     }
@@ -265,8 +423,9 @@ impl EnclaveThread {
         EpcmManager::get_enclave_in_encl(self.tcs_paddr)
     }
 
-    pub fn get_ssa_paddr(&self) -> usize {
-        self.ssa_paddr
+    /// Guest physical addresses of the pages of the current SSA frame.
+    pub fn ssa_pages(&self) -> impl Iterator<Item = GuestPhysAddr> + '_ {
+        self.ssa.pages()
     }
 
     fn get_tcs_paddr(
@@ -325,7 +484,7 @@ impl Debug for EnclaveThread {
             f.debug_struct("EnclaveThread")
                 .field("tcs_vaddr", &self.tcs_vaddr)
                 .field("tcs_paddr", &self.tcs_paddr)
-                .field("ssa_paddr", &self.ssa_paddr)
+                .field("ssa", &self.ssa)
                 .field("normal_world_state", &self.normal_world_state)
                 .finish()
         } else {
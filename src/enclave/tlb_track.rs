@@ -28,6 +28,8 @@ pub struct TLBFlushTrackingState {
     tracked_threads: u16,
     /// Keep track of the logic processors that have exited the current enclave after the ETRACK instruction was issued.
     lp_mask: CpuMask,
+    /// The logic processors that are currently executing the code of the enclave.
+    running_cpus: CpuMask,
 }
 
 impl TLBFlushTrackingState {
@@ -35,6 +37,10 @@ impl TLBFlushTrackingState {
         self.active_threads
     }
 
+    pub fn running_cpus(&self) -> &CpuMask {
+        &self.running_cpus
+    }
+
     pub fn is_in_tracking(&self) -> bool {
         self.tracking
     }
@@ -76,12 +82,14 @@ impl TLBFlushTrackingState {
     pub fn update(&mut self, is_enter: bool, cpuid: usize) {
         if is_enter {
             self.active_threads += 1;
+            self.running_cpus.set_cpu(cpuid);
 
             if self.is_in_tracking() {
                 self.lp_mask.set_cpu(cpuid);
             }
         } else {
             self.active_threads -= 1;
+            self.running_cpus.clear_cpu(cpuid);
 
             if self.is_in_tracking() {
                 let already_counted = self.lp_mask.test_cpu(cpuid);
@@ -110,6 +118,7 @@ impl Default for TLBFlushTrackingState {
             active_threads: 0,
             tracked_threads: 0,
             lp_mask: CpuMask::default(),
+            running_cpus: CpuMask::default(),
         }
     }
 }
@@ -31,6 +31,7 @@ pub enum HvErrorNum {
     EEXIST = 17,
     ENODEV = 19,
     EINVAL = 22,
+    ENOSPC = 28,
     ERANGE = 34,
     ENOSYS = 38,
 }
@@ -60,6 +61,7 @@ impl HvErrorNum {
             EEXIST => "File exists",
             ENODEV => "No such device",
             EINVAL => "Invalid argument",
+            ENOSPC => "No space left on device",
             ERANGE => "Math result not representable",
             ENOSYS => "Function not implemented",
         }
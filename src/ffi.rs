@@ -18,7 +18,15 @@ use crate::percpu::PerCpu;
 extern "C" {
     fn __header_start();
     fn __core_end();
+    fn __text_start();
+    fn __rodata_end();
 }
 
 pub const PER_CPU_ARRAY_PTR: *mut PerCpu = __core_end as _;
 pub const HEADER_PTR: *const HvHeader = __header_start as *const HvHeader; 
+
+/// The code and read-only data of the hypervisor, which do not change once loaded.
+pub fn readonly_image() -> &'static [u8] {
+    let start = __text_start as usize;
+    unsafe { core::slice::from_raw_parts(start as *const u8, __rodata_end as usize - start) }
+}
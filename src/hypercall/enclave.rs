@@ -15,10 +15,12 @@
 use addr::GuestPhysAddr;
 
 use super::error::HyperCallResult;
+use super::eventlog::{self, HV_IMAGE_DATA_SIZE};
 use super::tc;
 use super::tc::TPM_LOCK;
 use super::HyperCall;
 use crate::arch::vmm::VcpuAccessGuestState;
+use crate::enclave::ratls::RATLS_PUB_KEY_MAX_LEN;
 use crate::enclave::reclaim;
 use crate::enclave::report::{
     CSRRequest, Cert, EncBlob, EncSecret, KeyPubArea, PCRList, SM2Sig, SM4Key, SgxKey128Bit,
@@ -27,29 +29,53 @@ use crate::enclave::report::{
 use crate::enclave::sgx::SigStruct;
 use crate::enclave::shared_mem::SharedMemSyncType;
 use crate::enclave::structs::{
-    HvEnclAugPageDesc, HvEnclDesc, HvEnclInitDesc, HvEnclModtPageDesc, HvEnclNewPageDesc,
-    HvEnclRemovePageAtRuntimeDesc, HvEnclRemovePagesAtDestroyDesc,
-    HvEnclRemovePagesAtDestroyPageArray, HvEnclRemovePagesAtDestroyResArray,
-    HvEnclRestrictPageDesc, HvReclaimerPageDesc, HvReclaimerPagesDesc, HvSharedMemoryDesc,
-    NR_RECLAIM_EPC_PAGES,
+    HvEnclAttackPolicy, HvEnclAttackStatus, HvEnclAugPageDesc, HvEnclChannelAcceptDesc,
+    HvEnclChannelCreateDesc, HvEnclCloneAddPagesDesc, HvEnclCloneDesc, HvEnclClonePageArray,
+    HvEnclCounterDesc, HvEnclCreateFlags, HvEnclDedupBreakDesc, HvEnclDesc, HvEnclIdentity,
+    HvEnclInitDesc, HvEnclModtPageDesc, HvEnclNewPageDesc, HvEnclRemovePageAtRuntimeDesc,
+    HvEnclRemovePagesAtDestroyDesc, HvEnclRemovePagesAtDestroyPageArray,
+    HvEnclRemovePagesAtDestroyResArray, HvEnclRestrictPageDesc, HvEnclTimeSliceDesc,
+    HvReclaimerPageDesc, HvReclaimerPagesDesc, HvSharedMemoryDesc, HvSharedMemoryFlags,
+    HvSharedMemoryFlagsDesc, NR_RECLAIM_EPC_PAGES,
 };
 use crate::enclave::{Enclave, EnclaveStatsId, ENCLAVE_MANAGER};
+use crate::header::HvHeader;
+use crate::logging::HEFeature;
 use crate::memory::cmr::ConvMemManager;
 use crate::memory::gaccess::{AsGuestPtr, GuestPtr};
 use crate::memory::{addr, GenericPageTableImmut, GuestVirtAddr};
 use crate::stats::Instant;
+use core::convert::TryFrom;
 use core::mem::size_of;
 
 impl HyperCall<'_> {
     pub(super) fn enclave_create(
         &self,
         config_ptr: GuestPtr<HvEnclDesc>,
+        flags: u64,
     ) -> HyperCallResult<usize> {
         let now = Instant::now();
         let secs_gpaddr = config_ptr.as_guest_paddr()?;
         let secs = *GuestPtr::gpaddr_to_ref(&secs_gpaddr, false)?;
-        info!("enclave_create({:#x?}): {:#x?}", config_ptr, secs);
-        let enclave = Enclave::new(secs_gpaddr, config_ptr.guest_vaddr(), secs)?;
+        // Older drivers leave the second argument undefined.
+        let flags = if !HvHeader::get()
+            .feature_mask
+            .contains(HEFeature::ENCLAVE_CREATE_FLAGS)
+        {
+            HvEnclCreateFlags::empty()
+        } else if let Some(flags) = HvEnclCreateFlags::from_bits(flags) {
+            flags
+        } else {
+            return hypercall_hv_err_result!(
+                EINVAL,
+                format!("enclave_create(): invalid flags {:#x}", flags)
+            );
+        };
+        info!(
+            "enclave_create({:#x?}, {:?}): {:#x?}",
+            config_ptr, flags, secs
+        );
+        let enclave = Enclave::new(secs_gpaddr, config_ptr.guest_vaddr(), secs, flags)?;
         ENCLAVE_MANAGER.add_enclave(enclave.clone())?;
         enclave.atomic_add_stats(EnclaveStatsId::Create, now.elapsed());
         Ok(0)
@@ -66,10 +92,10 @@ impl HyperCall<'_> {
             .config_address
             .as_guest_ptr_ns::<HvEnclDesc>(&self.gpt, self.privilege_level());
         let enclave = ENCLAVE_MANAGER.find_enclave(config_ptr.as_guest_paddr()?)?;
-        enclave.add_page(&page_desc, &self.gpt)?;
+        let ret = enclave.add_page(&page_desc, &self.gpt)?;
         enclave.atomic_add_stats(EnclaveStatsId::AddPage, now.elapsed());
 
-        Ok(0)
+        Ok(ret)
     }
 
     pub(super) fn enclave_init(
@@ -91,8 +117,88 @@ impl HyperCall<'_> {
         Ok(0)
     }
 
-    pub(super) fn enclave_prepare_destroy(
+    pub(super) fn enclave_clone(
+        &self,
+        clone_desc_ptr: GuestPtr<HvEnclCloneDesc>,
+    ) -> HyperCallResult<usize> {
+        let now = Instant::now();
+        let clone_desc = clone_desc_ptr.read()?;
+        info!("enclave_clone({:#x?}): {:#x?}", clone_desc_ptr, clone_desc);
+        let template_ptr = clone_desc
+            .template_address
+            .as_guest_ptr_ns::<HvEnclDesc>(&self.gpt, self.privilege_level());
+        let template = ENCLAVE_MANAGER.find_enclave(template_ptr.as_guest_paddr()?)?;
+        let config_ptr = clone_desc
+            .config_address
+            .as_guest_ptr_ns::<HvEnclDesc>(&self.gpt, self.privilege_level());
+        let enclave =
+            template.clone_enclave(config_ptr.as_guest_paddr()?, config_ptr.guest_vaddr())?;
+        ENCLAVE_MANAGER.add_enclave(enclave.clone())?;
+        enclave.atomic_add_stats(EnclaveStatsId::Create, now.elapsed());
+        Ok(0)
+    }
+
+    pub(super) fn enclave_clone_add_pages(
+        &self,
+        add_desc_ptr: GuestPtr<HvEnclCloneAddPagesDesc>,
+    ) -> HyperCallResult<usize> {
+        let now = Instant::now();
+        let add_desc = add_desc_ptr.read()?;
+        debug!(
+            "enclave_clone_add_pages({:#x?}): {:#x?}",
+            add_desc_ptr, add_desc
+        );
+        let config_ptr = add_desc
+            .config_address
+            .as_guest_ptr_ns::<HvEnclDesc>(&self.gpt, self.privilege_level());
+        let enclave = ENCLAVE_MANAGER.find_enclave(config_ptr.as_guest_paddr()?)?;
+
+        let page_array_ptr = add_desc
+            .page_array_addr
+            .as_guest_ptr_ns::<HvEnclClonePageArray>(&self.gpt, self.privilege_level());
+        let page_array = page_array_ptr.as_ref()?;
+        let batch_size = add_desc.batch_size as usize;
+        if batch_size > page_array.pages.len() {
+            return hypercall_hv_err_result!(
+                EINVAL,
+                format!(
+                    "enclave_clone_add_pages(): invalid batch size {}",
+                    batch_size
+                )
+            );
+        }
+        enclave.clone_add_pages(&page_array.pages[..batch_size])?;
+        enclave.atomic_add_stats(EnclaveStatsId::AddPage, now.elapsed());
+        Ok(0)
+    }
+
+    pub(super) fn enclave_clone_init(
+        &self,
+        config_ptr: GuestPtr<HvEnclDesc>,
+    ) -> HyperCallResult<usize> {
+        let now = Instant::now();
+        info!("enclave_clone_init({:#x?})", config_ptr);
+        let enclave = ENCLAVE_MANAGER.find_enclave(config_ptr.as_guest_paddr()?)?;
+        enclave.clone_init()?;
+        enclave.atomic_add_stats(EnclaveStatsId::Init, now.elapsed());
+        Ok(0)
+    }
+
+    pub(super) fn enclave_dedup_break(
         &self,
+        desc_ptr: GuestPtr<HvEnclDedupBreakDesc>,
+    ) -> HyperCallResult<usize> {
+        let desc = desc_ptr.read()?;
+        debug!("enclave_dedup_break({:#x?}): {:#x?}", desc_ptr, desc);
+        let config_ptr = desc
+            .config_address
+            .as_guest_ptr_ns::<HvEnclDesc>(&self.gpt, self.privilege_level());
+        let enclave = ENCLAVE_MANAGER.find_enclave(config_ptr.as_guest_paddr()?)?;
+        enclave.break_dedup_page(desc.enclave_lin_addr as _, desc.epc_page_pa as _)
+    }
+
+    pub(super) fn enclave_prepare_destroy(
+        &mut self,
         config_ptr: GuestPtr<HvEnclDesc>,
     ) -> HyperCallResult<usize> {
         let now = Instant::now();
@@ -101,7 +207,7 @@ impl HyperCall<'_> {
             "enclave_prepare_destroy, config_ptr: {:#x?}), encalve: {:?}",
             config_ptr, enclave
         );
-        enclave.prepare_destroy()?;
+        enclave.prepare_destroy(self.cpu_data)?;
         enclave.atomic_add_stats(EnclaveStatsId::PrepareDestroy, now.elapsed());
         Ok(0)
     }
@@ -185,6 +291,101 @@ impl HyperCall<'_> {
         Ok(0)
     }
 
+    pub(super) fn enclave_dec_cssa(&mut self) -> HyperCallResult<usize> {
+        let now = Instant::now();
+        debug!("enclave_dec_cssa()");
+        let enclave = self.cpu_data.enclave_dec_cssa()?;
+        enclave.atomic_add_stats(EnclaveStatsId::DecCssa, now.elapsed());
+        Ok(0)
+    }
+
+    pub(super) fn enclave_set_attack_policy(&mut self) -> HyperCallResult<usize> {
+        let enclave = self.cpu_data.get_current_enclave()?;
+        let guest_regs = self.cpu_data.vcpu.regs();
+        let policy_ptr: GuestPtr<HvEnclAttackPolicy> =
+            guest_regs
+                .rbx
+                .as_guest_ptr_s(&enclave, &self.cpu_data.state, self.privilege_level());
+        let policy = policy_ptr.read()?;
+        debug!("enclave_set_attack_policy({:#x?})", policy);
+        enclave.set_attack_policy(policy)?;
+        self.cpu_data.vcpu.set_return_val(0);
+        Ok(0)
+    }
+
+    pub(super) fn enclave_get_attack_status(&mut self) -> HyperCallResult<usize> {
+        let enclave = self.cpu_data.get_current_enclave()?;
+        let guest_regs = self.cpu_data.vcpu.regs();
+        let mut status_ptr: GuestPtr<HvEnclAttackStatus> =
+            guest_regs
+                .rbx
+                .as_guest_ptr_s(&enclave, &self.cpu_data.state, self.privilege_level());
+        status_ptr.write(enclave.attack_status())?;
+        self.cpu_data.vcpu.set_return_val(0);
+        Ok(0)
+    }
+
+    pub(super) fn enclave_get_identity(&mut self) -> HyperCallResult<usize> {
+        let enclave = self.cpu_data.get_current_enclave()?;
+        let guest_regs = self.cpu_data.vcpu.regs();
+        let mut identity_ptr: GuestPtr<HvEnclIdentity> =
+            guest_regs
+                .rbx
+                .as_guest_ptr_s(&enclave, &self.cpu_data.state, self.privilege_level());
+        identity_ptr.write(enclave.he_identity())?;
+        self.cpu_data.vcpu.set_return_val(0);
+        Ok(0)
+    }
+
+    pub(super) fn enclave_channel_create(&mut self) -> HyperCallResult<usize> {
+        let enclave = self.cpu_data.get_current_enclave()?;
+        let guest_regs = self.cpu_data.vcpu.regs();
+        let mut desc_ptr: GuestPtr<HvEnclChannelCreateDesc> =
+            guest_regs
+                .rbx
+                .as_guest_ptr_s(&enclave, &self.cpu_data.state, self.privilege_level());
+        let mut desc = desc_ptr.read()?;
+        debug!("enclave_channel_create({:#x?})", desc);
+        desc.id = enclave.create_channel(&desc)?;
+        desc_ptr.write(desc)?;
+        self.cpu_data.vcpu.set_return_val(0);
+        Ok(0)
+    }
+
+    pub(super) fn enclave_channel_accept(&mut self) -> HyperCallResult<usize> {
+        let enclave = self.cpu_data.get_current_enclave()?;
+        let guest_regs = self.cpu_data.vcpu.regs();
+        let mut desc_ptr: GuestPtr<HvEnclChannelAcceptDesc> =
+            guest_regs
+                .rbx
+                .as_guest_ptr_s(&enclave, &self.cpu_data.state, self.privilege_level());
+        let mut desc = desc_ptr.read()?;
+        debug!("enclave_channel_accept({:#x?})", desc);
+        desc.size = enclave.accept_channel(&desc)? as u64;
+        desc_ptr.write(desc)?;
+        self.cpu_data.vcpu.set_return_val(0);
+        Ok(0)
+    }
+
+    pub(super) fn enclave_shared_memory_restrict(&mut self) -> HyperCallResult<usize> {
+        let enclave = self.cpu_data.get_current_enclave()?;
+        let guest_regs = self.cpu_data.vcpu.regs();
+        let desc_ptr: GuestPtr<HvSharedMemoryFlagsDesc> =
+            guest_regs
+                .rbx
+                .as_guest_ptr_s(&enclave, &self.cpu_data.state, self.privilege_level());
+        let flags_desc = desc_ptr.read()?;
+        debug!("enclave_shared_memory_restrict({:#x?})", flags_desc);
+        let flags = HvSharedMemoryFlags::from_desc(flags_desc.flags)?;
+        let mem_desc = flags_desc.desc;
+        let start_addr = mem_desc.start_addr as GuestVirtAddr;
+        let end_addr = mem_desc.end_addr as GuestVirtAddr;
+        enclave.restrict_shared_memory(&(start_addr..end_addr), flags)?;
+        self.cpu_data.flush_enclave_tlb(&enclave)?;
+        self.cpu_data.vcpu.set_return_val(0);
+        Ok(0)
+    }
+
     pub(super) fn enclave_exit(&mut self) -> HyperCallResult<usize> {
         let now = Instant::now();
         let exit_ip = self.cpu_data.vcpu.regs().rbx;
@@ -248,6 +449,73 @@ impl HyperCall<'_> {
         Ok(0 as usize)
     }
 
+    /// Writes a quote of the report at `rdi` in the DCAP layout of version
+    /// `r8` to the buffer of `rdx` bytes at `rsi`, if it fits, and its length
+    /// to `rcx`; the length is 0 if the quote could not be made.
+    pub(super) fn enclave_dcap_quote(&mut self) -> HyperCallResult<usize> {
+        let guest_regs = self.cpu_data.vcpu.regs();
+        let report_ptr: GuestPtr<SgxReport> = guest_regs
+            .rdi
+            .as_guest_ptr_ns(&self.gpt, self.privilege_level());
+        let mut quote_ptr: GuestPtr<u8> = guest_regs
+            .rsi
+            .as_guest_ptr_ns(&self.gpt, self.privilege_level());
+        let quote_buffer_size = guest_regs.rdx as usize;
+        let mut ret_len_ptr: GuestPtr<u64> = guest_regs
+            .rcx
+            .as_guest_ptr_ns(&self.gpt, self.privilege_level());
+        // unsupported, like any version that does not fit
+        let version = u16::try_from(guest_regs.r8).unwrap_or(0);
+        let report = report_ptr.read()?;
+        let quote = tc::create_dcap_quote(&report, version).unwrap_or_default();
+        if quote.len() <= quote_buffer_size {
+            quote_ptr.write_bytes(&quote)?;
+        }
+        ret_len_ptr.write(quote.len() as u64)?;
+
+        self.cpu_data.vcpu.set_return_val(0);
+        Ok(0)
+    }
+
+    /// Writes an RA-TLS certificate of the DER `SubjectPublicKeyInfo` of `rdx`
+    /// bytes at `rsi`, bound to by the report at `rdi`, to the buffer of `r8`
+    /// bytes at `rcx`, if it fits, and its length to `r9`; the length is 0 if
+    /// the quote could not be signed. A report not bound to the key fails with
+    /// `ERATLSREPORTDATA`, a malformed key with `ERATLSPUBKEY`.
+    pub(super) fn enclave_ratls_cert(&mut self) -> HyperCallResult<usize> {
+        let guest_regs = self.cpu_data.vcpu.regs();
+        let report_ptr: GuestPtr<SgxReport> = guest_regs
+            .rdi
+            .as_guest_ptr_ns(&self.gpt, self.privilege_level());
+        let public_key_ptr: GuestPtr<u8> = guest_regs
+            .rsi
+            .as_guest_ptr_ns(&self.gpt, self.privilege_level());
+        let public_key_len = guest_regs.rdx as usize;
+        let mut cert_ptr: GuestPtr<u8> = guest_regs
+            .rcx
+            .as_guest_ptr_ns(&self.gpt, self.privilege_level());
+        let cert_buffer_size = guest_regs.r8 as usize;
+        let mut ret_len_ptr: GuestPtr<u64> = guest_regs
+            .r9
+            .as_guest_ptr_ns(&self.gpt, self.privilege_level());
+        if public_key_len > RATLS_PUB_KEY_MAX_LEN {
+            return Err(hypercall_enclave_err!(
+                ERATLSPUBKEY,
+                "RA-TLS public key too long"
+            ));
+        }
+        let report = report_ptr.read()?;
+        let public_key = public_key_ptr.read_bytes(public_key_len)?;
+        let cert = tc::create_ratls_cert(&report, &public_key)?;
+        if cert.len() <= cert_buffer_size {
+            cert_ptr.write_bytes(&cert)?;
+        }
+        ret_len_ptr.write(cert.len() as u64)?;
+
+        self.cpu_data.vcpu.set_return_val(0);
+        Ok(0)
+    }
+
     pub(super) fn enclave_getkey(&mut self) -> HyperCallResult<usize> {
         let enclave = self.cpu_data.get_current_enclave()?;
         if !enclave.is_init() {
@@ -380,6 +648,59 @@ impl HyperCall<'_> {
         Ok(result as usize)
     }
 
+    /// Runs the counter operation `op` on the descriptor at `rbx`, with the
+    /// owner it names, then writes the descriptor back.
+    fn enclave_counter(
+        &mut self,
+        op: impl FnOnce(&[u8; 32], &mut HvEnclCounterDesc) -> HyperCallResult,
+    ) -> HyperCallResult<usize> {
+        let enclave = self.cpu_data.get_current_enclave()?;
+        if !enclave.is_init() {
+            return hypercall_hv_err_result!(EINVAL, "enclave is not initialized");
+        }
+        let guest_regs = self.cpu_data.vcpu.regs();
+        let mut desc_ptr: GuestPtr<HvEnclCounterDesc> =
+            guest_regs
+                .rbx
+                .as_guest_ptr_s(&enclave, &self.cpu_data.state, self.privilege_level());
+        let mut desc = desc_ptr.read()?;
+        debug!("enclave_counter({:#x?})", desc);
+        let owner = tc::counter_owner(&enclave, desc.owner_type)?;
+        op(&owner, &mut desc)?;
+        desc_ptr.write(desc)?;
+        self.cpu_data.vcpu.set_return_val(0);
+        Ok(0)
+    }
+
+    /// Creates a counter of the owner in the descriptor at `rbx`, returning
+    /// its ID and initial value.
+    pub(super) fn enclave_counter_create(&mut self) -> HyperCallResult<usize> {
+        self.enclave_counter(|owner, desc| {
+            let (id, value) = tc::create_counter(owner)?;
+            desc.id = id;
+            desc.value = value;
+            Ok(())
+        })
+    }
+
+    pub(super) fn enclave_counter_increment(&mut self) -> HyperCallResult<usize> {
+        self.enclave_counter(|owner, desc| {
+            desc.value = tc::increment_counter(owner, desc.id)?;
+            Ok(())
+        })
+    }
+
+    pub(super) fn enclave_counter_read(&mut self) -> HyperCallResult<usize> {
+        self.enclave_counter(|owner, desc| {
+            desc.value = tc::read_counter(owner, desc.id)?;
+            Ok(())
+        })
+    }
+
+    pub(super) fn enclave_counter_destroy(&mut self) -> HyperCallResult<usize> {
+        self.enclave_counter(|owner, desc| tc::destroy_counter(owner, desc.id))
+    }
+
     pub(super) fn tpm_command_sync(&self, locked: u64) -> HyperCallResult<usize> {
         info!("+tpm_lock {} -> {}", TPM_LOCK.is_locked(), locked > 0);
         let result = tc::tpm_command_sync(locked);
@@ -387,6 +708,30 @@ impl HyperCall<'_> {
         result
     }
 
+    /// Copies the measurement event log to `buf` if it holds `buf_size`
+    /// bytes, returning the size of the log either way.
+    pub(super) fn get_event_log(
+        &self,
+        mut buf: GuestPtr<u8>,
+        buf_size: u64,
+    ) -> HyperCallResult<usize> {
+        eventlog::with_event_log(|log| {
+            if log.len() <= buf_size as usize {
+                buf.write_bytes(log)?;
+            }
+            Ok(log.len())
+        })
+    }
+
+    /// Seals the root secrets for the hypervisor the host is about to update
+    /// to, given the data of the `TAG_HV_IMAGE` event it will measure.
+    pub(super) fn seal_for_update(
+        &self,
+        image_data: GuestPtr<[u8; HV_IMAGE_DATA_SIZE]>,
+    ) -> HyperCallResult<usize> {
+        tc::seal_root_secrets_for_update(&image_data.read()?)
+    }
+
     pub(super) fn activate_credential(&self) -> HyperCallResult<usize> {
         let guest_regs = self.cpu_data.vcpu.regs();
         let blob_ptr: GuestPtr<EncBlob> = guest_regs
@@ -593,6 +938,20 @@ impl HyperCall<'_> {
         Ok(0)
     }
 
+    pub(super) fn enclave_set_time_slice(
+        &self,
+        desc_ptr: GuestPtr<HvEnclTimeSliceDesc>,
+    ) -> HyperCallResult<usize> {
+        let desc = desc_ptr.read()?;
+        info!("enclave_set_time_slice({:#x?}): {:#x?}", desc_ptr, desc);
+        let config_ptr = desc
+            .config_address
+            .as_guest_ptr_ns::<HvEnclDesc>(&self.gpt, self.privilege_level());
+        let enclave = ENCLAVE_MANAGER.find_enclave(config_ptr.as_guest_paddr()?)?;
+        enclave.set_time_slice(desc.time_slice)?;
+        Ok(0)
+    }
+
     pub(super) fn enclave_augment_page(
         &self,
         page_desc_ptr: GuestPtr<HvEnclAugPageDesc>,
@@ -672,15 +1031,32 @@ impl HyperCall<'_> {
         &self,
         mem_add_desc_ptr: GuestPtr<HvSharedMemoryDesc>,
     ) -> HyperCallResult<usize> {
-        let now = Instant::now();
         let mem_desc = mem_add_desc_ptr.read()?;
+        self.add_shared_memory(mem_desc, HvSharedMemoryFlags::empty())
+    }
+
+    pub(super) fn enclave_shared_memory_add_with_flags(
+        &self,
+        mem_add_desc_ptr: GuestPtr<HvSharedMemoryFlagsDesc>,
+    ) -> HyperCallResult<usize> {
+        let flags_desc = mem_add_desc_ptr.read()?;
+        let flags = HvSharedMemoryFlags::from_desc(flags_desc.flags)?;
+        self.add_shared_memory(flags_desc.desc, flags)
+    }
+
+    fn add_shared_memory(
+        &self,
+        mem_desc: HvSharedMemoryDesc,
+        flags: HvSharedMemoryFlags,
+    ) -> HyperCallResult<usize> {
+        let now = Instant::now();
         let config_ptr = mem_desc
             .config_addr
             .as_guest_ptr_ns::<HvEnclDesc>(&self.gpt, self.privilege_level());
         let start_addr = mem_desc.start_addr as GuestVirtAddr;
         let end_addr = mem_desc.end_addr as GuestVirtAddr;
         let enclave = ENCLAVE_MANAGER.find_enclave(config_ptr.as_guest_paddr()?)?;
-        enclave.add_shared_memory(&(start_addr..end_addr), &self.gpt)?;
+        enclave.add_shared_memory(&(start_addr..end_addr), flags, &self.gpt)?;
         enclave.atomic_add_stats(EnclaveStatsId::AddSharedMemory, now.elapsed());
         Ok(0)
     }
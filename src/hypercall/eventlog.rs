@@ -0,0 +1,191 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The log of the measurements the hypervisor extends into the TPM, in the
+//! TCG crypto agile format so that verifiers can replay it against the PCRs
+//! of a platform quote.
+//!
+//! Every event is an `EV_EVENT_TAG` whose SM3 digest is extended: the
+//! hypervisor measures itself into `tc::HV_PCR` at boot and, if configured,
+//! every enclave launch. The exception is its attestation key, extended into
+//! PCR 13 as the SM3 digest of the bare key like older releases did, which
+//! verifiers check it against: that event is an `EV_IPL` holding the key.
+
+use alloc::vec::Vec;
+
+use spin::mutex::SpinMutex;
+use tpm2::consts::TPM_ALG_SM3_256;
+use tpm2::eventlog::{tagged_event, Bank, EventLog, EV_EVENT_TAG, EV_IPL};
+
+use super::tc;
+use crate::arch::Mitigations;
+use crate::config::HvSystemConfig;
+use crate::enclave::sgx::SgxSecs;
+use crate::error::HvResult;
+use crate::{ffi, iommu};
+
+/// Measurements that would make the log larger are refused rather than left
+/// out of it.
+const EVENT_LOG_MAX_SIZE: usize = 0x10000;
+
+/// Tagged event holding the MRENCLAVE, MRSIGNER and ATTRIBUTES of a launched
+/// enclave.
+pub const TAG_ENCLAVE_LAUNCH: u32 = 0x4845_0001;
+/// Tagged event holding the size and the SM3 digest of the code and read-only
+/// data of the hypervisor.
+pub const TAG_HV_IMAGE: u32 = 0x4845_0002;
+/// Size of the data of a `TAG_HV_IMAGE` event.
+pub const HV_IMAGE_DATA_SIZE: usize = 40;
+/// Tagged event holding the whole `HvSystemConfig`, CPUID policies included.
+pub const TAG_HV_CONFIG: u32 = 0x4845_0003;
+/// Tagged event holding the settings the hypervisor derived at boot: the
+/// enabled enclave transition mitigations, then the number and the base of
+/// the IOMMU units enabled.
+pub const TAG_HV_SETTINGS: u32 = 0x4845_0004;
+
+/// PCRs enclave launches may be measured into besides `tc::HV_PCR`, when the
+/// hypervisor is launched through TXT: the dynamic PCRs it extends from a
+/// locality Linux has no access to.
+const DRTM_LAUNCH_PCRS: [u32; 2] = [17, 18];
+
+const SM3_BANK: Bank = Bank::new(TPM_ALG_SM3_256, 32);
+
+/// The event log, with the size of the events being extended.
+struct MeasurementLog {
+    log: EventLog,
+    reserved: usize,
+}
+
+static EVENT_LOG: SpinMutex<Option<MeasurementLog>> = SpinMutex::new(None);
+
+fn with_measurement_log<R>(f: impl FnOnce(&mut MeasurementLog) -> R) -> R {
+    let mut log = EVENT_LOG.lock();
+    f(log.get_or_insert_with(|| MeasurementLog {
+        log: EventLog::new(&[SM3_BANK]),
+        reserved: 0,
+    }))
+}
+
+/// Extends the SM3 digest of the tagged event `(tag, data)` into `pcr` and
+/// appends the event to the log.
+pub fn measure(pcr: u32, tag: u32, data: &[u8]) -> HvResult {
+    measure_event(pcr, EV_EVENT_TAG, &tagged_event(tag, data))
+}
+
+/// Extends the SM3 digest of the hypervisor attestation public key `pk` into
+/// `pcr` and appends it to the log as is.
+pub fn measure_attestation_key(pcr: u32, pk: &[u8]) -> HvResult {
+    measure_event(pcr, EV_IPL, pk)
+}
+
+fn measure_event(pcr: u32, event_type: u32, event: &[u8]) -> HvResult {
+    // Reserve room for the event, so that the log is not locked during the
+    // extend. The extends are ordered by `tc::TPM_LOCK`, and each event is
+    // appended before the lock is released, in the same order.
+    let size = with_measurement_log(|m| {
+        let size = m.log.event_size(event);
+        if m.log.len() + m.reserved + size > EVENT_LOG_MAX_SIZE {
+            return hv_result_err!(ENOMEM, "measure(): the event log is full");
+        }
+        m.reserved += size;
+        Ok(size)
+    })?;
+    let digest = tc::sm3_digest(event);
+    let res = tc::extend_pcr(pcr, &digest, || {
+        with_measurement_log(|m| {
+            m.reserved -= size;
+            m.log.push(pcr, event_type, &[&digest], event).unwrap();
+        })
+    });
+    if let Err(e) = res {
+        with_measurement_log(|m| m.reserved -= size);
+        return hv_result_err!(
+            EIO,
+            format!("measure(): failed to extend PCR {}: {}", pcr, e)
+        );
+    }
+    Ok(())
+}
+
+/// Checks the PCR enclave launches are measured into, which Linux chooses.
+/// Any other PCR would let Linux spoof the launch measurements by extending it
+/// too.
+fn check_measured_launch_pcr() -> HvResult {
+    let pcr = HvSystemConfig::get().measured_launch_pcr();
+    if pcr == 0 || pcr == tc::HV_PCR || (tc::is_txt_launched() && DRTM_LAUNCH_PCRS.contains(&pcr)) {
+        return Ok(());
+    }
+    hv_result_err!(
+        EINVAL,
+        format!("enclave launches cannot be measured into PCR {}", pcr)
+    )
+}
+
+/// The SM3 digest of the `TAG_HV_IMAGE` event with `data`, which is extended
+/// into `tc::HV_PCR`.
+pub fn hv_image_event_digest(data: &[u8]) -> [u8; 32] {
+    tc::sm3_digest(&tagged_event(TAG_HV_IMAGE, data))
+}
+
+/// Measures the code and read-only data of the hypervisor into `tc::HV_PCR`,
+/// which the root secrets are bound to. Called by `tc::tc_init` once the TPM
+/// is started up.
+pub fn measure_hypervisor_image() -> HvResult {
+    let image = ffi::readonly_image();
+    let mut data = Vec::with_capacity(HV_IMAGE_DATA_SIZE);
+    data.extend_from_slice(&(image.len() as u64).to_le_bytes());
+    data.extend_from_slice(&tc::sm3_digest(image));
+    measure(tc::HV_PCR, TAG_HV_IMAGE, &data)
+}
+
+/// Measures the configuration and the security settings of the hypervisor
+/// into `tc::HV_PCR`, after its image, once the IOMMU and the TPM are set up.
+/// Fails if enclave launches are to be measured into an unsuitable PCR.
+pub fn measure_hypervisor() -> HvResult {
+    check_measured_launch_pcr()?;
+    let config = HvSystemConfig::get();
+    measure(tc::HV_PCR, TAG_HV_CONFIG, config.as_bytes())?;
+
+    let iommu_units = &config.iommu_units()[..iommu::enabled_units()];
+    let mut data = Vec::with_capacity(8 + iommu_units.len() * 8);
+    data.extend_from_slice(&Mitigations::get().bits().to_le_bytes());
+    data.extend_from_slice(&(iommu_units.len() as u32).to_le_bytes());
+    for unit in iommu_units {
+        data.extend_from_slice(&{ unit.base }.to_le_bytes());
+    }
+    measure(tc::HV_PCR, TAG_HV_SETTINGS, &data)?;
+    info!("Hypervisor measured into PCR {}", tc::HV_PCR);
+    Ok(())
+}
+
+/// Measures the launch of the enclave of `secs` into the PCR configured in
+/// `HvSystemConfig`, if any.
+pub fn measure_enclave_launch(secs: &SgxSecs) -> HvResult {
+    let pcr = HvSystemConfig::get().measured_launch_pcr();
+    if pcr == 0 {
+        return Ok(());
+    }
+    let mut data = [0; 80];
+    data[..32].copy_from_slice(secs.mr_enclave.as_slice());
+    data[32..64].copy_from_slice(secs.mr_signer.as_slice());
+    data[64..72].copy_from_slice(&secs.attributes.flags.bits().to_le_bytes());
+    data[72..].copy_from_slice(&secs.attributes.xfrm.to_le_bytes());
+    measure(pcr, TAG_ENCLAVE_LAUNCH, &data)
+}
+
+/// Runs `f` on the log, which holds only its header until the first
+/// measurement.
+pub fn with_event_log<R>(f: impl FnOnce(&[u8]) -> R) -> R {
+    with_measurement_log(|m| f(m.log.as_bytes()))
+}
@@ -16,6 +16,7 @@
 pub mod error;
 
 mod enclave;
+pub mod eventlog;
 pub mod tc;
 
 use core::convert::TryFrom;
@@ -53,11 +54,19 @@ numeric_enum! {
         EnlcaveRestrictPagePerm = 0x26,
         EnclaveRemovePageAtRuntime = 0x27,
         EnclaveRemovePagesAtDestroy = 0x28,
+        EnclaveSetTimeSlice = 0x29,
+        EnclaveClone = 0x2a,
+        EnclaveCloneAddPages = 0x2b,
+        EnclaveCloneInit = 0x2c,
+        EnclaveDedupBreak = 0x2d,
+        HypervisorGetEventLog = 0x30,
+        HypervisorSealForUpdate = 0x31,
         EnclaveResetStats = 0x100,
         SharedMemoryAdd = 0x101,
         SharedMemoryRemove = 0x102,
         SharedMemoryInvalidStart = 0x103,
         SharedMemoryInvalidEnd = 0x104,
+        SharedMemoryAddWithFlags = 0x105,
 
         InitCmrm = 0x200,
         SetInitCmrmDone = 0x201,
@@ -68,8 +77,21 @@ numeric_enum! {
         EnclaveAcceptCopy       = 0x8000_0003,
         EnclaveExtendPagePerm   = 0x8000_0004,
         EnclaveResume           = 0x8000_0005,
+        EnclaveDecCssa          = 0x8000_0006,
+        EnclaveSetAttackPolicy  = 0x8000_0007,
+        EnclaveGetAttackStatus  = 0x8000_0008,
+        EnclaveChannelCreate    = 0x8000_0009,
+        EnclaveChannelAccept    = 0x8000_000e,
+        EnclaveSharedMemoryRestrict = 0x8000_000f,
         EnclaveReport           = 0x8000_000c,
         EnclaveQuote            = 0x8000_000d,
+        EnclaveDcapQuote        = 0x8000_0010,
+        EnclaveRaTlsCert        = 0x8000_0011,
+        EnclaveCounterCreate    = 0x8000_0012,
+        EnclaveCounterIncrement = 0x8000_0013,
+        EnclaveCounterRead      = 0x8000_0014,
+        EnclaveCounterDestroy   = 0x8000_0015,
+        EnclaveGetIdentity      = 0x8000_0016,
         EnclaveGetKey           = 0x8000_000b,
         EnclaveVerifyReport     = 0x8000_000a,
 
@@ -109,16 +131,26 @@ impl HyperCallCode {
             | HyperCallCode::EnlcaveRestrictPagePerm
             | HyperCallCode::EnclaveRemovePageAtRuntime
             | HyperCallCode::EnclaveRemovePagesAtDestroy
+            | HyperCallCode::EnclaveSetTimeSlice
+            | HyperCallCode::EnclaveClone
+            | HyperCallCode::EnclaveCloneAddPages
+            | HyperCallCode::EnclaveCloneInit
+            | HyperCallCode::EnclaveDedupBreak
+            | HyperCallCode::HypervisorGetEventLog
+            | HyperCallCode::HypervisorSealForUpdate
             | HyperCallCode::EnclaveResetStats
             | HyperCallCode::SharedMemoryAdd
             | HyperCallCode::SharedMemoryRemove
             | HyperCallCode::SharedMemoryInvalidStart
             | HyperCallCode::SharedMemoryInvalidEnd
+            | HyperCallCode::SharedMemoryAddWithFlags
             | HyperCallCode::InitCmrm
             | HyperCallCode::SetInitCmrmDone
             | HyperCallCode::EnclaveEnter
             | HyperCallCode::EnclaveResume
             | HyperCallCode::EnclaveQuote
+            | HyperCallCode::EnclaveDcapQuote
+            | HyperCallCode::EnclaveRaTlsCert
             | HyperCallCode::HypervisorGetPubKeys
             | HyperCallCode::HypervisorSignCSR
             | HyperCallCode::HypervisorWriteCert
@@ -128,9 +160,20 @@ impl HyperCallCode {
             | HyperCallCode::EnclaveAccept
             | HyperCallCode::EnclaveAcceptCopy
             | HyperCallCode::EnclaveExtendPagePerm
+            | HyperCallCode::EnclaveDecCssa
+            | HyperCallCode::EnclaveSetAttackPolicy
+            | HyperCallCode::EnclaveGetAttackStatus
+            | HyperCallCode::EnclaveChannelCreate
+            | HyperCallCode::EnclaveChannelAccept
+            | HyperCallCode::EnclaveSharedMemoryRestrict
             | HyperCallCode::EnclaveReport
             | HyperCallCode::EnclaveGetKey
-            | HyperCallCode::EnclaveVerifyReport => *cpu_state == CpuState::EnclaveRunning,
+            | HyperCallCode::EnclaveVerifyReport
+            | HyperCallCode::EnclaveCounterCreate
+            | HyperCallCode::EnclaveCounterIncrement
+            | HyperCallCode::EnclaveCounterRead
+            | HyperCallCode::EnclaveCounterDestroy
+            | HyperCallCode::EnclaveGetIdentity => *cpu_state == CpuState::EnclaveRunning,
         }
     }
 }
@@ -210,7 +253,7 @@ impl<'a> HyperCall<'a> {
         let ret = match code {
             HyperCallCode::HypervisorDisable => self.hypervisor_disable(arg0),
             HyperCallCode::EnclaveCreate => {
-                self.enclave_create(arg0.as_guest_ptr_ns(&self.gpt, guest_privilege_level))
+                self.enclave_create(arg0.as_guest_ptr_ns(&self.gpt, guest_privilege_level), arg1)
             }
             HyperCallCode::EnclaveAddPage => {
                 self.enclave_add_page(arg0.as_guest_ptr_ns(&self.gpt, guest_privilege_level))
@@ -257,6 +300,27 @@ impl<'a> HyperCall<'a> {
             HyperCallCode::EnclaveRemovePagesAtDestroy => self.enclave_remove_pages_at_destroy(
                 arg0.as_guest_ptr_ns(&self.gpt, guest_privilege_level),
             ),
+            HyperCallCode::EnclaveSetTimeSlice => {
+                self.enclave_set_time_slice(arg0.as_guest_ptr_ns(&self.gpt, guest_privilege_level))
+            }
+            HyperCallCode::EnclaveClone => {
+                self.enclave_clone(arg0.as_guest_ptr_ns(&self.gpt, guest_privilege_level))
+            }
+            HyperCallCode::EnclaveCloneAddPages => {
+                self.enclave_clone_add_pages(arg0.as_guest_ptr_ns(&self.gpt, guest_privilege_level))
+            }
+            HyperCallCode::EnclaveCloneInit => {
+                self.enclave_clone_init(arg0.as_guest_ptr_ns(&self.gpt, guest_privilege_level))
+            }
+            HyperCallCode::EnclaveDedupBreak => {
+                self.enclave_dedup_break(arg0.as_guest_ptr_ns(&self.gpt, guest_privilege_level))
+            }
+            HyperCallCode::HypervisorGetEventLog => {
+                self.get_event_log(arg0.as_guest_ptr_ns(&self.gpt, guest_privilege_level), arg1)
+            }
+            HyperCallCode::HypervisorSealForUpdate => {
+                self.seal_for_update(arg0.as_guest_ptr_ns(&self.gpt, guest_privilege_level))
+            }
             HyperCallCode::EnclaveResetStats => {
                 self.enclave_reset_stats(arg0.as_guest_ptr_ns(&self.gpt, guest_privilege_level))
             }
@@ -271,6 +335,9 @@ impl<'a> HyperCall<'a> {
             HyperCallCode::SharedMemoryInvalidEnd => self.enclave_shared_memory_invalid_end(
                 arg0.as_guest_ptr_ns(&self.gpt, guest_privilege_level),
             ),
+            HyperCallCode::SharedMemoryAddWithFlags => self.enclave_shared_memory_add_with_flags(
+                arg0.as_guest_ptr_ns(&self.gpt, guest_privilege_level),
+            ),
             HyperCallCode::InitCmrm => self.init_cmrm(arg0),
             HyperCallCode::SetInitCmrmDone => self.set_init_cmrm_done(),
             HyperCallCode::EnclaveEnter => self.enclave_enter(),
@@ -279,13 +346,26 @@ impl<'a> HyperCall<'a> {
             HyperCallCode::EnclaveAcceptCopy => self.enclave_accept_copy(),
             HyperCallCode::EnclaveExtendPagePerm => self.enclave_extend_page_perm(),
             HyperCallCode::EnclaveResume => self.enclave_resume(),
+            HyperCallCode::EnclaveDecCssa => self.enclave_dec_cssa(),
+            HyperCallCode::EnclaveSetAttackPolicy => self.enclave_set_attack_policy(),
+            HyperCallCode::EnclaveGetAttackStatus => self.enclave_get_attack_status(),
+            HyperCallCode::EnclaveChannelCreate => self.enclave_channel_create(),
+            HyperCallCode::EnclaveChannelAccept => self.enclave_channel_accept(),
+            HyperCallCode::EnclaveSharedMemoryRestrict => self.enclave_shared_memory_restrict(),
             HyperCallCode::EnclaveReport => self.enclave_report(),
             HyperCallCode::EnclaveQuote => self.enclave_quote(),
+            HyperCallCode::EnclaveDcapQuote => self.enclave_dcap_quote(),
+            HyperCallCode::EnclaveRaTlsCert => self.enclave_ratls_cert(),
             HyperCallCode::EnclaveGetKey => self.enclave_getkey(),
             HyperCallCode::HypervisorGetPubKeys => self.get_pub_keys(),
             HyperCallCode::HypervisorSignCSR => self.sign_csr(),
             HyperCallCode::HypervisorWriteCert => self.mng_tpm_cert(),
             HyperCallCode::EnclaveVerifyReport => self.verify_report(),
+            HyperCallCode::EnclaveCounterCreate => self.enclave_counter_create(),
+            HyperCallCode::EnclaveCounterIncrement => self.enclave_counter_increment(),
+            HyperCallCode::EnclaveCounterRead => self.enclave_counter_read(),
+            HyperCallCode::EnclaveCounterDestroy => self.enclave_counter_destroy(),
+            HyperCallCode::EnclaveGetIdentity => self.enclave_get_identity(),
             HyperCallCode::HypervisorActivateCredential => self.activate_credential(),
         };
 
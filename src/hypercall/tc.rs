@@ -12,78 +12,778 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-extern crate cstr_core;
-extern crate cty;
 extern crate yogcrypt;
 use alloc::vec::Vec;
 
+use crate::enclave::dcap::{self, DcapQuote, DCAP_ATT_KEY_LEN, DCAP_SIG_LEN};
+use crate::enclave::ratls::{self, RaTlsCert};
 use crate::enclave::report::{
-    bytes_to_u64x4, convert_sm3_hash_bytes_order, reverse_byte_array_copy, u64x4_to_bytes,
-    DerivationData, SgxKey128Bit, SgxKeyRequest, SgxQuote, SgxReport, SgxReportData, SgxTargetInfo,
-    HE_HV_ATT_KEY_LEN, SGX_ENCLAVE_KEY_SIZE, SGX_HASH_SIZE, SGX_QUOTE_SIZE,
+    bytes_to_u64x4, reverse_byte_array_copy, u64x4_to_bytes, DerivationData, SgxKey128Bit,
+    SgxKeyId, SgxKeyRequest, SgxOwnerEpoch, SgxQuote, SgxReport, SgxReportData, SgxTargetInfo,
+    HE_CERT_BUF_LEN, HE_ENCL_QUOTE_SIZE, HE_HV_ATT_KEY_LEN, HE_TPM_ATT_DATA_LEN, HE_TPM_SIG_LEN,
+    SGX_ENCLAVE_KEY_SIZE, SGX_KEYSELECT_REPORT, SGX_QUOTE_SIZE,
 };
 
-use super::error::HyperCallResult;
+use super::error::{HyperCallError, HyperCallResult};
+use super::eventlog::{self, HV_IMAGE_DATA_SIZE};
+use crate::config::HvSystemConfig;
+use crate::enclave::structs::{HV_ENCL_COUNTER_OWNER_MRENCLAVE, HV_ENCL_COUNTER_OWNER_MRSIGNER};
 use crate::enclave::Enclave;
 use crate::header::HvHeader;
 use crate::memory::addr::*;
-use core::convert::TryInto;
-use core::{mem::size_of, slice};
-use cstr_core::CStr;
-use cty::{c_char, uint32_t, uint64_t, uint8_t, uintptr_t};
-use spin::mutex::SpinMutex;
+use crate::memory::Mmio;
+use core::{mem::size_of, ptr, slice};
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature as EcdsaSignature, SigningKey};
+use spin::mutex::{SpinMutex, SpinMutexGuard};
+use spin::Once;
+use tpm2::consts::*;
+use tpm2::marshal::Writer;
+use tpm2::mmio::{Clock, Crb, Interface, Psp, Registers, Tis, PSP_BUFFER_SIZE};
+use tpm2::sim::SimTpm;
+use tpm2::{
+    Auth, NvPublic, PcrSelection, Public, Quote, Scheme, Signature, SymDef, Tpm, Transport,
+};
 use yogcrypt::sm2::*;
 use yogcrypt::sm3::sm3_enc;
 
-extern "C" {
-    fn tpm_detect(tpm_type: uint32_t, mmio_va: uint64_t) -> bool;
-    fn he_get_secret() -> bool;
-    //fn he_get_enclave_secret(ptr: *mut uintptr_t) -> uint32_t;
-    fn he_get_ak_seed(ptr: *mut uintptr_t) -> uint32_t;
-    fn he_extend_ak_pub(buf: *const uint8_t, len: uint32_t) -> uint32_t;
-    //fn he_extend_image() -> c_int;
-    //fn he_get_tpm_signing_pub() -> uintptr_t;
-    fn he_get_quote(user_data: *const uint8_t, data_len: uint32_t, ptr: *mut uint8_t) -> uint32_t;
-    fn he_activate_credential(
-        enc_blob: *const uint8_t,
-        enc_len: uint32_t,
-        enc_secret: *const uint8_t,
-        enc_sec_size: uint32_t,
-        secret: *mut uint8_t,
-    ) -> uint32_t;
-    fn he_sign_csr(p10: *const uint8_t, p10_len: uint32_t, csr: *mut uint8_t) -> uint32_t;
-    fn he_get_tpm_ak_pub(ak_buf: *mut uint8_t) -> uint32_t;
-    fn he_get_pcr_list(pcr_buf: *mut uint8_t) -> uint32_t;
-    fn he_write_tpm_ak_cert(cert_buf: *const uint8_t, cert_len: uint32_t) -> uint32_t;
-    fn he_read_cert(cert_buf: *mut uint8_t, buf_len: uint32_t, read: uint32_t) -> uint32_t;
-    fn he_get_ak_pub_area(pub_area: *mut uint8_t) -> uint32_t;
+/// `HvHeader::tpm_type` of the Hygon firmware TPM, reached through the PSP
+/// mailbox at `HvHeader::tpm_mmio_pa`; any other TPM is probed through its
+/// TIS or CRB registers.
+const TPM_TYPE_HYGON_FTPM: u32 = 4;
+/// `HvHeader::tpm_type` of platforms without a TPM, for development. A
+/// `SimTpm` stands in for it, which protects neither the secrets nor the
+/// quotes and forgets everything on reboot.
+const TPM_TYPE_FAKE: u32 = 8;
+/// The seed of the fake TPM, so that its keys and the root secrets drawn from
+/// it are the same on every boot, and data sealed by enclaves survives a
+/// reboot.
+const FAKE_TPM_SEED: [u8; 32] = *b"HyperEnclave fake TPM, insecure!";
+
+/// The locality of a hypervisor launched through TXT. Linux stays at
+/// locality 0, so only the hypervisor passes `TPM2_PolicyLocality` with it.
+const TXT_HV_LOCALITY: u8 = 2;
+
+/// Assumed when the CPU does not enumerate its time stamp frequency. Too high
+/// a value only makes the TPM timeouts longer.
+const FALLBACK_TIME_FREQ_MHZ: u64 = 5000;
+
+/// PCR extended with the measurements of the hypervisor itself.
+pub const HV_PCR: u32 = 12;
+/// PCR extended with the SM3 digest of the hypervisor attestation public key.
+const HV_AK_PCR: u32 = 13;
+/// PCRs of the SM3 bank covered by platform quotes; `PCR_LIST_BUF_SIZE`
+/// holds exactly their values.
+const QUOTE_PCRS: [u32; 3] = [HV_PCR, HV_AK_PCR, 14];
+
+/// Persistent handle of the TPM attestation key.
+const TPM_AK_HANDLE: u32 = 0x8100_0101;
+/// Persistent handle of the TPM attestation key of older releases, which
+/// their AK certificates were issued for.
+const LEGACY_TPM_AK_HANDLE: u32 = 0x8100_0001;
+/// NV index of the `RootSecrets`.
+const NV_INDEX_ROOT_SECRETS: u32 = 0x0150_0101;
+/// NV index of the `RootSecrets` staged by `seal_root_secrets_for_update` for
+/// the next hypervisor.
+const NV_INDEX_STAGED_ROOT_SECRETS: u32 = 0x0150_0102;
+/// NV index of the root secret sealed by older releases, which cannot be
+/// recovered and is undefined on first boot.
+const LEGACY_NV_INDEX_ROOT_SECRET: u32 = 0x0150_0016;
+const NV_INDEX_AK_CERT: u32 = 0x0150_001a;
+/// NV index of the SM2 endorsement key certificate, provisioned by the TPM vendor.
+const NV_INDEX_EK_CERT: u32 = 0x01c0_001a;
+
+/// The root secrets can only be written and read through the policy of
+/// `root_secrets_policy`, are written once and are read-locked by the
+/// hypervisor until the next TPM reset.
+const ROOT_SECRETS_NV_ATTRIBUTES: u32 = TPMA_NV_POLICYWRITE
+    | TPMA_NV_POLICYREAD
+    | TPMA_NV_WRITEDEFINE
+    | TPMA_NV_READ_STCLEAR
+    | TPMA_NV_NO_DA;
+/// The staged root secrets are written by the running hypervisor with the
+/// owner authorization, as it cannot satisfy the policy of the next one, and
+/// write-locked at once.
+const STAGED_ROOT_SECRETS_NV_ATTRIBUTES: u32 =
+    TPMA_NV_OWNERWRITE | TPMA_NV_POLICYREAD | TPMA_NV_WRITEDEFINE | TPMA_NV_NO_DA;
+/// NV indices of the enclave monotonic counters, one per slot.
+///
+/// Each counter is reached with an authValue derived from the key derivation
+/// secret, its index and its owner, so only the hypervisor can increment or
+/// read it, and only for its owner. Linux can still undefine any NV index with
+/// the owner authorization, or take free slots with indices of its own: a
+/// counter never goes back, which keeps enclaves from being rolled back, but
+/// its availability is not protected, and an enclave must treat a counter that
+/// is gone as an attack.
+const NV_INDEX_COUNTER_BASE: u32 = 0x0150_0200;
+/// Counter slots when `HvSystemConfig` does not set their number.
+const DEFAULT_COUNTER_SLOTS: u32 = 32;
+/// Counter slots at most, up to the end of the NV index range they start in.
+const MAX_COUNTER_SLOTS: u32 = 0x100;
+/// Counters an MRENCLAVE or MRSIGNER may own at once.
+const COUNTER_QUOTA: usize = 8;
+/// Counters are orderly: the TPM keeps them in RAM and writes them to NV only
+/// every so many increments, so that enclaves incrementing often neither wear
+/// the NV out nor make the TPM return `TPM_RC_NV_RATE` to its other users. On
+/// a shutdown without `TPM2_Shutdown` they jump ahead instead of going back.
+///
+/// Wrong authValues are expected when looking for the counters of an owner,
+/// and must not count against the dictionary attack protection of the TPM.
+const COUNTER_NV_ATTRIBUTES: u32 =
+    TPMA_NV_COUNTER | TPMA_NV_AUTHWRITE | TPMA_NV_AUTHREAD | TPMA_NV_NO_DA | TPMA_NV_ORDERLY;
+/// Attributes the TPM sets by itself as an index is used.
+const NV_STATE_ATTRIBUTES: u32 = TPMA_NV_WRITTEN | TPMA_NV_READLOCKED | TPMA_NV_WRITELOCKED;
+
+const AK_SCHEME: Scheme = Scheme::new(TPM_ALG_SM2, TPM_ALG_SM3_256);
+
+/// The distinguishing identifier of certificate signatures, the default of
+/// GM/T 0009.
+const CERT_SIGNER_ID: &[u8] = b"1234567812345678";
+
+/// The TPM registers, identity mapped by `Cell::new`.
+struct MmioRegisters {
+    base: VirtAddr,
+    size: usize,
+}
+
+impl MmioRegisters {
+    fn tpm() -> Self {
+        let header = HvHeader::get();
+        Self {
+            base: header.tpm_mmio_pa,
+            size: header.tpm_mmio_size as usize,
+        }
+    }
+
+    fn addr(&self, offset: usize, width: usize) -> VirtAddr {
+        assert!(offset + width <= self.size);
+        self.base + offset
+    }
+}
+
+impl Registers for MmioRegisters {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn read8(&mut self, offset: usize) -> u8 {
+        unsafe { Mmio::<u8>::from_base(self.addr(offset, 1)) }.read()
+    }
+
+    fn read32(&mut self, offset: usize) -> u32 {
+        unsafe { Mmio::<u32>::from_base(self.addr(offset, 4)) }.read()
+    }
+
+    fn write8(&mut self, offset: usize, value: u8) {
+        unsafe { Mmio::<u8>::from_base(self.addr(offset, 1)) }.write(value)
+    }
+
+    fn write32(&mut self, offset: usize, value: u32) {
+        unsafe { Mmio::<u32>::from_base(self.addr(offset, 4)) }.write(value)
+    }
+}
+
+/// The command buffer of the Hygon firmware TPM, which the PSP reads and
+/// writes by its physical address.
+#[repr(C, align(4096))]
+struct PspBuffer([u8; PSP_BUFFER_SIZE]);
+
+static PSP_BUFFER: SpinMutex<PspBuffer> = SpinMutex::new(PspBuffer([0; PSP_BUFFER_SIZE]));
+
+/// `PSP_BUFFER`, accessed with volatile reads and writes since the PSP
+/// changes it behind our back.
+struct PspBufferRegisters(SpinMutexGuard<'static, PspBuffer>);
+
+impl PspBufferRegisters {
+    fn lock() -> Self {
+        Self(PSP_BUFFER.lock())
+    }
+
+    /// The address the PSP reaches the buffer at, with the C-bit set since
+    /// the hypervisor memory is encrypted.
+    fn phys_addr(&self) -> u64 {
+        phys_encrypted(virt_to_phys(self.0 .0.as_ptr() as VirtAddr)) as u64
+    }
+}
+
+impl Registers for PspBufferRegisters {
+    fn size(&self) -> usize {
+        PSP_BUFFER_SIZE
+    }
+
+    fn read8(&mut self, offset: usize) -> u8 {
+        unsafe { ptr::read_volatile(&self.0 .0[offset]) }
+    }
+
+    fn read32(&mut self, offset: usize) -> u32 {
+        let mut bytes = [0; 4];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.read8(offset + i);
+        }
+        u32::from_ne_bytes(bytes)
+    }
+
+    fn write8(&mut self, offset: usize, value: u8) {
+        unsafe { ptr::write_volatile(&mut self.0 .0[offset], value) }
+    }
+
+    fn write32(&mut self, offset: usize, value: u32) {
+        for (i, &byte) in value.to_ne_bytes().iter().enumerate() {
+            self.write8(offset + i, byte);
+        }
+    }
+}
+
+struct HvClock {
+    freq_mhz: u64,
+}
+
+impl HvClock {
+    fn new() -> Self {
+        Self {
+            freq_mhz: crate::arch::cpu::time_freq_mhz().unwrap_or(FALLBACK_TIME_FREQ_MHZ),
+        }
+    }
+}
+
+impl Clock for HvClock {
+    fn now_us(&self) -> u64 {
+        crate::arch::cpu::time_now() / self.freq_mhz
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TpmInterface {
+    Mmio(Interface),
+    HygonFtpm,
+    Fake,
+}
+
+static TPM_INTERFACE: Once<TpmInterface> = Once::new();
+
+/// The TPM of platforms without one, created by `tpm_detect`.
+static FAKE_TPM: Once<SpinMutex<SimTpm>> = Once::new();
+
+/// Finds out how to reach the TPM the loader told us about.
+fn tpm_detect() -> Option<TpmInterface> {
+    let header = HvHeader::get();
+    if header.tpm_type == TPM_TYPE_FAKE {
+        FAKE_TPM.call_once(|| {
+            let mut tpm = SimTpm::with_seed(FAKE_TPM_SEED);
+            tpm.locality = hv_locality();
+            SpinMutex::new(tpm)
+        });
+        return Some(TpmInterface::Fake);
+    }
+    if header.tpm_type == TPM_TYPE_HYGON_FTPM {
+        return Some(TpmInterface::HygonFtpm);
+    }
+    tpm2::mmio::probe(&mut MmioRegisters::tpm()).map(TpmInterface::Mmio)
+}
+
+/// Submits marshalled commands through the interface found by `tpm_detect`.
+enum HvTpmTransport {
+    Tis(Tis<MmioRegisters, HvClock>),
+    Crb(Crb<MmioRegisters, HvClock>),
+    Psp(Psp<MmioRegisters, HvClock, PspBufferRegisters>),
+    Fake(SpinMutexGuard<'static, SimTpm>),
+}
+
+/// Whether the hypervisor was launched through TXT.
+pub fn is_txt_launched() -> bool {
+    crate::arch::txt::is_launched()
+}
+
+/// The locality the hypervisor talks to the TPM at: `TXT_HV_LOCALITY` when
+/// launched through TXT, or else 0 like Linux.
+fn hv_locality() -> u8 {
+    if is_txt_launched() {
+        TXT_HV_LOCALITY
+    } else {
+        0
+    }
+}
+
+impl HvTpmTransport {
+    fn new() -> tpm2::Result<Self> {
+        let (regs, clock) = (MmioRegisters::tpm(), HvClock::new());
+        match TPM_INTERFACE.get() {
+            Some(TpmInterface::Mmio(Interface::Fifo)) => {
+                Ok(Self::Tis(Tis::new(regs, clock, hv_locality())?))
+            }
+            Some(TpmInterface::Mmio(Interface::Crb)) => {
+                let phys_base = HvHeader::get().tpm_mmio_pa as u64;
+                Ok(Self::Crb(Crb::new(regs, clock, hv_locality(), phys_base)?))
+            }
+            Some(TpmInterface::HygonFtpm) => {
+                let buffer = PspBufferRegisters::lock();
+                let buffer_addr = buffer.phys_addr();
+                Ok(Self::Psp(Psp::new(regs, clock, buffer, buffer_addr)?))
+            }
+            Some(TpmInterface::Fake) => match FAKE_TPM.get() {
+                Some(tpm) => Ok(Self::Fake(tpm.lock())),
+                None => Err(tpm2::Error::Transport("no fake TPM")),
+            },
+            None => Err(tpm2::Error::Transport("no TPM interface detected")),
+        }
+    }
+}
+
+impl Transport for HvTpmTransport {
+    fn transmit(&mut self, cmd: &[u8], rsp: &mut [u8]) -> tpm2::Result<usize> {
+        match self {
+            Self::Tis(tis) => tis.transmit(cmd, rsp),
+            Self::Crb(crb) => crb.transmit(cmd, rsp),
+            Self::Psp(psp) => psp.transmit(cmd, rsp),
+            Self::Fake(tpm) => tpm.transmit(cmd, rsp),
+        }
+    }
+}
+
+type HvTpm = Tpm<HvTpmTransport>;
+
+/// Runs `f` on the TPM, serialized against the host through `TPM_LOCK`.
+fn with_tpm<R>(f: impl FnOnce(&mut HvTpm) -> tpm2::Result<R>) -> tpm2::Result<R> {
+    let _lock = TPM_LOCK.lock();
+    f(&mut Tpm::new(HvTpmTransport::new()?))
+}
+
+/// The secrets kept in `NV_INDEX_ROOT_SECRETS`, one after the other.
+struct RootSecrets {
+    hv_ak_seed: [u8; 32],
+    key_derivation_secret: SgxOwnerEpoch,
+    dcap_ak_seed: [u8; 32],
+}
+
+impl RootSecrets {
+    const SIZE: usize = 96;
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let mut secrets = Self {
+            hv_ak_seed: [0; 32],
+            key_derivation_secret: Default::default(),
+            dcap_ak_seed: [0; 32],
+        };
+        secrets.hv_ak_seed.copy_from_slice(&bytes[..32]);
+        secrets
+            .key_derivation_secret
+            .copy_from_slice(&bytes[32..64]);
+        secrets.dcap_ak_seed.copy_from_slice(&bytes[64..Self::SIZE]);
+        secrets
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::SIZE);
+        bytes.extend_from_slice(&self.hv_ak_seed);
+        bytes.extend_from_slice(&self.key_derivation_secret);
+        bytes.extend_from_slice(&self.dcap_ak_seed);
+        bytes
+    }
+}
+
+struct TpmAk {
+    handle: u32,
+    public: Public,
+}
+
+/// Secrets and keys recovered from the TPM by `tc_init`.
+struct RootOfTrust {
+    secrets: RootSecrets,
+    report_key_id: SgxKeyId,
+    tpm_ak: TpmAk,
+}
+
+static ROOT_OF_TRUST: Once<RootOfTrust> = Once::new();
+
+/// The value of `HV_PCR` before the hypervisor measured itself, which the
+/// next hypervisor will measure itself on top of.
+static HV_PCR_BASE: Once<[u8; 32]> = Once::new();
+
+fn tpm_ak_handle() -> u32 {
+    ROOT_OF_TRUST
+        .get()
+        .map_or(TPM_AK_HANDLE, |root| root.tpm_ak.handle)
+}
+
+pub fn sm3_digest(data: &[u8]) -> [u8; 32] {
+    let mut digest = [0; 32];
+    for (i, word) in sm3_enc(data).iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn tpm_ak_template() -> Public {
+    Public::ecc_signing_key(TPM_ALG_SM3_256, TPM_ECC_SM2_P256, AK_SCHEME)
+}
+
+/// The default SM2 endorsement key template of the TCG EK Credential Profile,
+/// matching the vendor certificate at `NV_INDEX_EK_CERT`.
+fn tpm_ek_template() -> Public {
+    let sm4 = SymDef {
+        alg: TPM_ALG_SM4,
+        key_bits: 128,
+        mode: TPM_ALG_CFB,
+    };
+    Public::ecc_endorsement_key(TPM_ALG_SM3_256, TPM_ECC_SM2_P256, sm4, &EK_POLICY_A_SM3_256)
+}
+
+/// Asserts in `session` the policy of the root secrets, which only the
+/// hypervisor satisfies:
+///
+/// - under TXT, the locality of the hypervisor, which Linux has no access to.
+///   It holds for any hypervisor launched through TXT, so updates keep the
+///   secrets.
+/// - otherwise, the value of `HV_PCR` once the hypervisor has measured its
+///   image, with `pcr_digest` its SM3 digest, or empty for the current value.
+///   The measurements of the platform are left out, so that firmware updates
+///   keep the secrets, and hypervisor updates go through
+///   `seal_root_secrets_for_update`. Extends of `HV_PCR` made before the
+///   hypervisor is loaded must be the same on every boot.
+///
+/// Without TXT, root in Linux can replay the measurement of the hypervisor
+/// image before loading it and read the secrets: they are only as safe as the
+/// host is until the hypervisor runs. The hypervisor then measures itself on
+/// top of the replay and fails to unseal the secrets, so that such a boot does
+/// not go unnoticed.
+fn root_secrets_policy(tpm: &mut HvTpm, session: u32, pcr_digest: &[u8]) -> tpm2::Result<()> {
+    if is_txt_launched() {
+        tpm.policy_locality(session, tpma_locality(TXT_HV_LOCALITY))
+    } else {
+        let selection = PcrSelection::new(TPM_ALG_SM3_256, &[HV_PCR]);
+        tpm.policy_pcr(session, pcr_digest, &[selection])
+    }
+}
+
+/// Runs `f` with a policy session satisfying `root_secrets_policy` for the
+/// current boot, which the command run by `f` consumes.
+fn with_root_secrets_policy<R>(
+    tpm: &mut HvTpm,
+    f: impl FnOnce(&mut HvTpm, u32) -> tpm2::Result<R>,
+) -> tpm2::Result<R> {
+    let session = tpm.start_policy_session(TPM_ALG_SM3_256)?;
+    let result = root_secrets_policy(tpm, session, &[]).and_then(|_| f(tpm, session));
+    // the session is only flushed by the TPM when the command succeeds
+    if result.is_err() {
+        tpm.flush_context(session).ok();
+    }
+    result
+}
+
+/// The public area of the root secrets at `index`, with the digest of
+/// `root_secrets_policy` for `pcr_digest` computed by a trial session.
+fn root_secrets_nv_public(
+    tpm: &mut HvTpm,
+    index: u32,
+    attributes: u32,
+    pcr_digest: &[u8],
+) -> tpm2::Result<NvPublic> {
+    let trial = tpm.start_trial_session(TPM_ALG_SM3_256)?;
+    let digest =
+        root_secrets_policy(tpm, trial, pcr_digest).and_then(|_| tpm.policy_get_digest(trial));
+    tpm.flush_context(trial)?;
+    Ok(NvPublic {
+        index,
+        name_alg: TPM_ALG_SM3_256,
+        attributes,
+        auth_policy: digest?,
+        data_size: RootSecrets::SIZE as u16,
+    })
+}
+
+/// Reads the value of `HV_PCR`.
+fn read_hv_pcr(tpm: &mut HvTpm) -> tpm2::Result<[u8; 32]> {
+    let values = tpm.pcr_read(&[PcrSelection::new(TPM_ALG_SM3_256, &[HV_PCR])])?;
+    match values.digests.as_slice() {
+        [digest] if digest.len() == 32 => {
+            let mut value = [0; 32];
+            value.copy_from_slice(digest);
+            Ok(value)
+        }
+        _ => Err(tpm2::Error::Malformed),
+    }
+}
+
+/// Undefines `index` if it is defined.
+fn nv_undefine_if_defined(tpm: &mut HvTpm, index: u32) -> tpm2::Result<()> {
+    match tpm.nv_read_public(index) {
+        Ok(_) => tpm.nv_undefine_space(TPM_RH_OWNER, &[], index),
+        Err(e) if e.is_rc(TPM_RC_HANDLE) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads the root secrets staged for this hypervisor, whose policy is then
+/// `auth_policy`, if there are any.
+fn read_staged_root_secrets(tpm: &mut HvTpm, auth_policy: &[u8]) -> tpm2::Result<Option<Vec<u8>>> {
+    let public = match tpm.nv_read_public(NV_INDEX_STAGED_ROOT_SECRETS) {
+        Ok((public, _)) => public,
+        Err(e) if e.is_rc(TPM_RC_HANDLE) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let staged = TPMA_NV_WRITTEN | TPMA_NV_WRITELOCKED;
+    if public.attributes & !NV_STATE_ATTRIBUTES != STAGED_ROOT_SECRETS_NV_ATTRIBUTES
+        || public.attributes & staged != staged
+        || public.data_size != RootSecrets::SIZE as u16
+        || public.auth_policy != auth_policy
+    {
+        return Ok(None);
+    }
+    with_root_secrets_policy(tpm, |tpm, session| {
+        tpm.nv_read_policy(
+            NV_INDEX_STAGED_ROOT_SECRETS,
+            session,
+            RootSecrets::SIZE as u16,
+            0,
+        )
+    })
+    .map(Some)
+}
+
+/// Reads the root secrets, creating them on first boot, and read-locks them
+/// until the next TPM reset so that the host cannot read them once we are
+/// running.
+///
+/// If the secrets were sealed for another hypervisor, the ones staged for
+/// this one by `seal_root_secrets_for_update` are sealed again in their
+/// place. The root secret of older releases cannot be recovered: its NV index
+/// is undefined, so that it is not left exposed, and data sealed with it is
+/// lost.
+fn recover_root_secrets(tpm: &mut HvTpm) -> tpm2::Result<RootSecrets> {
+    let expected =
+        root_secrets_nv_public(tpm, NV_INDEX_ROOT_SECRETS, ROOT_SECRETS_NV_ATTRIBUTES, &[])?;
+    let mut staged = None;
+    let attributes = match tpm.nv_read_public(NV_INDEX_ROOT_SECRETS) {
+        Ok((public, _))
+            if public.attributes & !NV_STATE_ATTRIBUTES != expected.attributes
+                || public.data_size != expected.data_size =>
+        {
+            // Not ours, or left by a release that did not protect the
+            // secrets: what it holds cannot be trusted.
+            println!(
+                "HyperEnclave: replacing the unexpected root secrets NV index {:#x?}",
+                public
+            );
+            tpm.nv_undefine_space(TPM_RH_OWNER, &[], NV_INDEX_ROOT_SECRETS)?;
+            tpm.nv_define_space(TPM_RH_OWNER, &[], &[], &expected)?;
+            0
+        }
+        Ok((public, _)) if public.auth_policy != expected.auth_policy => {
+            staged = read_staged_root_secrets(tpm, &expected.auth_policy)?;
+            if staged.is_none() {
+                println!(
+                    "HyperEnclave: the root secrets were sealed for another hypervisor. Seal \
+                    them for this one from the previous one, or undefine NV index {:#x} to \
+                    start over with new secrets, losing the data sealed with the old ones",
+                    NV_INDEX_ROOT_SECRETS
+                );
+                return Err(tpm2::Error::Tpm(TPM_RC_POLICY_FAIL));
+            }
+            println!("HyperEnclave: sealing the root secrets staged for this hypervisor");
+            tpm.nv_undefine_space(TPM_RH_OWNER, &[], NV_INDEX_ROOT_SECRETS)?;
+            tpm.nv_define_space(TPM_RH_OWNER, &[], &[], &expected)?;
+            0
+        }
+        Ok((public, _)) => {
+            if public.attributes & TPMA_NV_READLOCKED != 0 {
+                println!("HyperEnclave: root secrets are locked, reboot to reload the hypervisor");
+                return Err(tpm2::Error::Tpm(TPM_RC_NV_LOCKED));
+            }
+            public.attributes
+        }
+        Err(e) if e.is_rc(TPM_RC_HANDLE) => {
+            tpm.nv_define_space(TPM_RH_OWNER, &[], &[], &expected)?;
+            0
+        }
+        Err(e) => return Err(e),
+    };
+    if attributes & TPMA_NV_WRITTEN == 0 {
+        let secrets = match staged {
+            Some(secrets) => secrets,
+            None => {
+                println!("HyperEnclave: generating new root secrets");
+                tpm.get_random(RootSecrets::SIZE)?
+            }
+        };
+        with_root_secrets_policy(tpm, |tpm, session| {
+            tpm.nv_write_policy(NV_INDEX_ROOT_SECRETS, session, &secrets, 0)
+        })?;
+    }
+    if attributes & TPMA_NV_WRITELOCKED == 0 {
+        with_root_secrets_policy(tpm, |tpm, session| {
+            tpm.nv_write_lock_policy(NV_INDEX_ROOT_SECRETS, session)
+        })?;
+    }
+    let secrets = with_root_secrets_policy(tpm, |tpm, session| {
+        tpm.nv_read_policy(NV_INDEX_ROOT_SECRETS, session, RootSecrets::SIZE as u16, 0)
+    })?;
+    with_root_secrets_policy(tpm, |tpm, session| {
+        tpm.nv_read_lock_policy(NV_INDEX_ROOT_SECRETS, session)
+    })?;
+    if attributes & TPMA_NV_WRITTEN == 0 {
+        nv_undefine_if_defined(tpm, NV_INDEX_STAGED_ROOT_SECRETS)?;
+        if let Err(e) = nv_undefine_if_defined(tpm, LEGACY_NV_INDEX_ROOT_SECRET) {
+            println!(
+                "HyperEnclave: failed to undefine the root secret of older releases: {}",
+                e
+            );
+        }
+    }
+    Ok(RootSecrets::from_bytes(&secrets))
+}
+
+/// Whether the key older releases persisted can sign quotes and CSRs as ours does.
+fn is_legacy_tpm_ak(public: &Public) -> bool {
+    let signing = TPMA_OBJECT_SIGN | TPMA_OBJECT_FIXED_TPM;
+    public.curve == TPM_ECC_SM2_P256
+        && public.attributes & signing == signing
+        && (public.scheme == AK_SCHEME
+            || public.scheme == Scheme::NULL && public.attributes & TPMA_OBJECT_RESTRICTED == 0)
+}
+
+/// Loads the persistent TPM attestation key, creating it if it is missing or
+/// was not created from our template.
+///
+/// The key of older releases is kept instead of creating one, so that its AK
+/// certificate remains valid.
+fn load_tpm_ak(tpm: &mut HvTpm) -> tpm2::Result<TpmAk> {
+    let template = tpm_ak_template();
+    match tpm.read_public(TPM_AK_HANDLE) {
+        Ok((public, _)) if template.matches_template(&public) => {
+            return Ok(TpmAk {
+                handle: TPM_AK_HANDLE,
+                public,
+            })
+        }
+        Ok(_) => tpm.evict_control(TPM_RH_OWNER, &[], TPM_AK_HANDLE, TPM_AK_HANDLE)?,
+        Err(e) if e.is_rc(TPM_RC_HANDLE) => {}
+        Err(e) => return Err(e),
+    }
+    match tpm.read_public(LEGACY_TPM_AK_HANDLE) {
+        Ok((public, _)) if is_legacy_tpm_ak(&public) => {
+            return Ok(TpmAk {
+                handle: LEGACY_TPM_AK_HANDLE,
+                public,
+            })
+        }
+        // not usable, or not even an ECC key
+        Ok(_) | Err(tpm2::Error::Unsupported(_)) => {}
+        Err(e) if e.is_rc(TPM_RC_HANDLE) => {}
+        Err(e) => return Err(e),
+    }
+    println!("HyperEnclave: creating the TPM attestation key");
+    let ak = tpm.create_primary(TPM_RH_ENDORSEMENT, &[], &template, &[])?;
+    let persisted = tpm.evict_control(TPM_RH_OWNER, &[], ak.handle, TPM_AK_HANDLE);
+    tpm.flush_context(ak.handle)?;
+    persisted?;
+    Ok(TpmAk {
+        handle: TPM_AK_HANDLE,
+        public: ak.public,
+    })
+}
+
+/// Starts the TPM up and reads the value `HV_PCR` has before the hypervisor
+/// measures itself.
+fn start_tpm(tpm: &mut HvTpm) -> tpm2::Result<[u8; 32]> {
+    tpm.startup(TPM_SU_CLEAR)?;
+    read_hv_pcr(tpm)
+}
+
+fn recover_root_of_trust(tpm: &mut HvTpm) -> tpm2::Result<RootOfTrust> {
+    let secrets = recover_root_secrets(tpm)?;
+    let tpm_ak = load_tpm_ak(tpm)?;
+    let mut report_key_id: SgxKeyId = Default::default();
+    report_key_id.copy_from_slice(&tpm.get_random(report_key_id.len())?);
+    Ok(RootOfTrust {
+        secrets,
+        report_key_id,
+        tpm_ak,
+    })
 }
 
 pub fn tc_init() -> bool {
-    unsafe {
-        //he_gen_ak_ex();
-        if !tpm_detect(
-            HvHeader::get().tpm_type,
-            HvHeader::get().tpm_mmio_pa as uint64_t,
-        ) {
+    match tpm_detect() {
+        Some(interface) => {
+            println!("HyperEnclave: using the {:?} TPM interface", interface);
+            TPM_INTERFACE.call_once(|| interface);
+        }
+        None => {
             println!("HyperEnclave: failed to detect the tpm chip");
             return false;
         }
-        //ask the hyperenclave and TPM to generate or recover the root secret
-        if !he_get_secret() {
-            println!("HyperEnclave: failed to recover secret");
+    }
+    match with_tpm(start_tpm) {
+        Ok(base) => {
+            HV_PCR_BASE.call_once(|| base);
+        }
+        Err(e) => {
+            println!("HyperEnclave: failed to start the TPM up: {}", e);
             return false;
         }
-        if !he_extend_ak() {
-            println!("HyperEnclave: failed to extend hv ak pub");
+    }
+    // the root secrets are bound to the measurement of the image
+    if let Err(e) = eventlog::measure_hypervisor_image() {
+        println!("HyperEnclave: failed to measure the hypervisor: {:?}", e);
+        return false;
+    }
+    //ask the TPM to generate or recover the root secret
+    match with_tpm(recover_root_of_trust) {
+        Ok(root) => {
+            ROOT_OF_TRUST.call_once(|| root);
+        }
+        Err(e) => {
+            println!("HyperEnclave: failed to recover secret: {}", e);
             return false;
         }
-        //println!("sizeof(sgxquote)={}",size_of::<SgxQuote>());
-        println!("HyperEnclave: root of trust initialized!");
-    };
+    }
+    if !he_extend_ak() {
+        println!("HyperEnclave: failed to extend hv ak pub");
+        return false;
+    }
+    println!("HyperEnclave: root of trust initialized!");
     true
 }
 
+/// Seals the root secrets for the next hypervisor, whose image measurement
+/// is `image_data` (the data of its `TAG_HV_IMAGE` event), so that it
+/// recovers them after an update. They are staged in
+/// `NV_INDEX_STAGED_ROOT_SECRETS`, which the next hypervisor seals again as
+/// its own and undefines.
+///
+/// Nothing needs to be done under TXT, where the policy holds for any
+/// hypervisor.
+pub fn seal_root_secrets_for_update(image_data: &[u8]) -> HyperCallResult<usize> {
+    if image_data.len() != HV_IMAGE_DATA_SIZE {
+        return hypercall_hv_err_result!(EINVAL, "invalid image measurement size");
+    }
+    if is_txt_launched() {
+        return Ok(0);
+    }
+    let (root, base) = match (ROOT_OF_TRUST.get(), HV_PCR_BASE.get()) {
+        (Some(root), Some(base)) => (root, base),
+        _ => return hypercall_hv_err_result!(ENODEV, "no root of trust"),
+    };
+    let mut next = base.to_vec();
+    next.extend_from_slice(&eventlog::hv_image_event_digest(image_data));
+    let pcr_digest = sm3_digest(&sm3_digest(&next));
+    let secrets = root.secrets.to_bytes();
+    with_tpm(|tpm| {
+        let public = root_secrets_nv_public(
+            tpm,
+            NV_INDEX_STAGED_ROOT_SECRETS,
+            STAGED_ROOT_SECRETS_NV_ATTRIBUTES,
+            &pcr_digest,
+        )?;
+        nv_undefine_if_defined(tpm, NV_INDEX_STAGED_ROOT_SECRETS)?;
+        tpm.nv_define_space(TPM_RH_OWNER, &[], &[], &public)?;
+        tpm.nv_write(TPM_RH_OWNER, &[], NV_INDEX_STAGED_ROOT_SECRETS, &secrets, 0)?;
+        tpm.nv_write_lock(TPM_RH_OWNER, &[], NV_INDEX_STAGED_ROOT_SECRETS)
+    })
+    .map_err(tpm_error)?;
+    println!("HyperEnclave: root secrets sealed for the next hypervisor");
+    Ok(0)
+}
+
 pub static TPM_LOCK: SpinMutex<()> = SpinMutex::new(());
 
 pub fn tpm_command_sync(locked: u64) -> HyperCallResult<usize> {
@@ -99,16 +799,34 @@ pub fn tpm_command_sync(locked: u64) -> HyperCallResult<usize> {
     Ok(0)
 }
 
-fn gen_att_key(sk: &mut SecKey, pk: &mut PubKey) -> bool {
-    let seed;
-    unsafe {
-        let mut secret_ptr: uintptr_t = 0;
-        let secret_len = he_get_ak_seed(&mut secret_ptr) as usize;
-        if secret_len == 0 {
-            return false;
+/// Fills the secret used as `OWNEREPOCH` in key derivation.
+pub fn key_derivation_secret(epoch: &mut SgxOwnerEpoch) -> bool {
+    match ROOT_OF_TRUST.get() {
+        Some(root) => {
+            epoch.copy_from_slice(&root.secrets.key_derivation_secret);
+            true
         }
-        seed = slice::from_raw_parts(secret_ptr as *const u64, secret_len / 8);
+        None => false,
+    }
+}
+
+/// Fills the key ID of reports, which changes on every boot.
+pub fn report_key_id(key_id: &mut SgxKeyId) -> bool {
+    match ROOT_OF_TRUST.get() {
+        Some(root) => {
+            key_id.copy_from_slice(&root.report_key_id);
+            true
+        }
+        None => false,
+    }
+}
+
+fn gen_att_key(sk: &mut SecKey, pk: &mut PubKey) -> bool {
+    let seed = match ROOT_OF_TRUST.get() {
+        Some(root) => root.secrets.hv_ak_seed,
+        None => return false,
     };
+    let seed = bytes_to_u64x4(&seed);
     let hv_sec_key: SecKey = U64x4::new(seed[0], seed[1], seed[2], seed[3]);
     let hv_pub_key: PubKey = get_pub_key(hv_sec_key);
     *sk = hv_sec_key;
@@ -116,23 +834,37 @@ fn gen_att_key(sk: &mut SecKey, pk: &mut PubKey) -> bool {
     true
 }
 
-pub fn create_report(
-    target_info: &SgxTargetInfo,
-    report_data: &SgxReportData,
-    report: &mut SgxReport,
-    enclave: &Enclave,
-) -> u32 {
+/// Fills the report body with the identity of `enclave`.
+fn set_report_identity(report: &mut SgxReport, enclave: &Enclave) {
     let mr_enclave = enclave.measurement();
     let mr_signer = enclave.mr_signer();
     let (flags, xfrm) = enclave.attributes();
     let (isv_prod_id, isv_svn) = enclave.isv();
+    let (misc_select, config_svn) = enclave.misc_config();
     report.set_basics(isv_prod_id, isv_svn, flags, xfrm);
+    report.set_misc_config(misc_select, config_svn);
     report.set_mr_enclave_signer(mr_enclave.as_slice(), mr_signer);
+}
+
+/// The target info of `enclave` itself, from which its report key derives.
+fn self_target_info(enclave: &Enclave) -> SgxTargetInfo {
+    let mut report: SgxReport = Default::default();
+    set_report_identity(&mut report, enclave);
+    SgxTargetInfo::from_report(&report)
+}
+
+pub fn create_report(
+    target_info: &SgxTargetInfo,
+    report_data: &SgxReportData,
+    report: &mut SgxReport,
+    enclave: &Enclave,
+) -> u32 {
+    set_report_identity(report, enclave);
     report.set_report_data(report_data);
     report.set_key_id();
     let mut report_key: SgxKey128Bit = [0; SGX_ENCLAVE_KEY_SIZE as usize];
     let mut dd: DerivationData = Default::default();
-    if !dd.init_with_target_info(target_info, report) {
+    if !dd.init_with_target_info(target_info, report.get_key_id()) {
         println!("HyperEnclave:DerivationData.init_with_target_info failed");
         return 0;
     }
@@ -148,11 +880,16 @@ pub fn create_report(
 }
 
 pub fn create_quote(report: &SgxReport, quote: &mut SgxQuote, quote_buffer_size: u32) -> u32 {
-    let mut quote_len: u32;
     if (quote_buffer_size as usize) < size_of::<SgxQuote>() {
         println!("quote buffer is not big enough!");
         return 0;
     }
+    fill_quote(report, quote)
+}
+
+/// Fills `quote` with a quote of `report`, returning its length.
+fn fill_quote(report: &SgxReport, quote: &mut SgxQuote) -> u32 {
+    let mut quote_len: u32;
     if !qe_verify_report(report) {
         println!("qe_verify_report failed");
         return 0;
@@ -168,40 +905,109 @@ pub fn create_quote(report: &SgxReport, quote: &mut SgxQuote, quote_buffer_size:
         return 0;
     }
     quote_len += pub_len;
-    unsafe {
-        let report_data_hash = sm3_enc(report.get_report_data());
-        let mut converted_hash: [u8; SGX_HASH_SIZE as usize] = [0; SGX_HASH_SIZE as usize];
-        let report_hash_slice = slice::from_raw_parts(
-            report_data_hash.as_ptr() as *const u8,
-            report_data_hash.len() * 4,
-        );
-        convert_sm3_hash_bytes_order(&mut converted_hash[..], report_hash_slice);
-        let _lock = TPM_LOCK.lock();
-        let tpm_quote_len = he_get_quote(
-            converted_hash.as_ptr(),
-            converted_hash.len() as u32,
-            quote.get_hv_quote_mut_ptr(),
-        );
-        if tpm_quote_len == 0 {
+    let tpm_quote_len = match copy_platform_quote(&mut quote.hv_quote, report.get_report_data()) {
+        Some(tpm_quote_len) => tpm_quote_len as u32,
+        None => {
             println!("HyperEnclave: failed to get platform quote");
             return 0;
         }
-        let cert_len = he_read_cert(
-            quote.certificate.as_mut_ptr(),
-            quote.certificate.len() as u32,
-            1,
-        );
-        if cert_len == 0 {
-            println!("HyperEnclave: no platform cert");
-        }
-        quote_len = SGX_QUOTE_SIZE + tpm_quote_len + quote_len + cert_len;
+    };
+    let cert_len = read_cert(&mut quote.certificate, 1);
+    if cert_len == 0 {
+        println!("HyperEnclave: no platform cert");
     }
+    quote_len = SGX_QUOTE_SIZE + tpm_quote_len + quote_len + cert_len;
     quote.sig_len = quote_len - SGX_QUOTE_SIZE;
     //quote.print_mem_layout();
-    println!("quote len ={} sig_len = {}", quote_len, quote.sig_len);
     quote_len
 }
 
+/// Builds a quote of `report` in the DCAP layout of `version`, whose
+/// attestation key is certified by a QE report signed by the hypervisor.
+pub fn create_dcap_quote(report: &SgxReport, version: u16) -> Option<Vec<u8>> {
+    if !qe_verify_report(report) {
+        println!("qe_verify_report failed");
+        return None;
+    }
+    let quote = match DcapQuote::new(version, &report.body) {
+        Some(quote) => quote,
+        None => {
+            println!("HyperEnclave: unsupported DCAP quote version {}", version);
+            return None;
+        }
+    };
+    let att_key = dcap_att_key()?;
+    let isv_sig: EcdsaSignature = att_key.sign(quote.signed_data());
+    let mut isv_sig_buf = [0; DCAP_SIG_LEN];
+    isv_sig_buf.copy_from_slice(&isv_sig.to_bytes());
+    let mut att_pub = [0; DCAP_ATT_KEY_LEN];
+    // without the leading 0x04 of uncompressed points
+    att_pub.copy_from_slice(&att_key.verifying_key().to_encoded_point(false).as_bytes()[1..]);
+
+    let qe_report = dcap::qe_report_body(&att_pub);
+    let mut hv_sec_key = get_sec_key();
+    let mut hv_pub_key = get_pub_key(hv_sec_key);
+    if !gen_att_key(&mut hv_sec_key, &mut hv_pub_key) {
+        return None;
+    }
+    let sig = sm2_gen_sign(qe_report.as_bytes(), hv_sec_key, hv_pub_key, true);
+    let mut qe_report_sig = [0; DCAP_SIG_LEN];
+    qe_report_sig[..32].copy_from_slice(&u64x4_to_bytes(&sig.r.value));
+    qe_report_sig[32..].copy_from_slice(&u64x4_to_bytes(&sig.s.value));
+
+    let evidence = tpm_evidence(&qe_report.report_data)?;
+    Some(quote.finish(
+        &isv_sig_buf,
+        &att_pub,
+        &qe_report,
+        &qe_report_sig,
+        &evidence,
+    ))
+}
+
+/// Builds an RA-TLS certificate of the DER `SubjectPublicKeyInfo`
+/// `public_key`, which `report` must be bound to, signed by the hypervisor
+/// attestation key. The certificate is empty if the quote cannot be signed.
+pub fn create_ratls_cert(report: &SgxReport, public_key: &[u8]) -> HyperCallResult<Vec<u8>> {
+    if report.get_report_data() != &ratls::public_key_report_data(public_key)[..] {
+        return Err(hypercall_enclave_err!(
+            ERATLSREPORTDATA,
+            "report not bound to the RA-TLS public key"
+        ));
+    }
+    let mut quote: SgxQuote = Default::default();
+    let quote_len = fill_quote(report, &mut quote) as usize;
+    if quote_len == 0 {
+        return Ok(Vec::new());
+    }
+    let quote = &quote.as_bytes()[..quote_len];
+    let serial = sm3_digest(quote);
+    let cert = match RaTlsCert::new(public_key, quote, &serial[..16]) {
+        Some(cert) => cert,
+        None => {
+            return Err(hypercall_enclave_err!(
+                ERATLSPUBKEY,
+                "malformed RA-TLS public key"
+            ))
+        }
+    };
+
+    let mut hv_sec_key = get_sec_key();
+    let mut hv_pub_key = get_pub_key(hv_sec_key);
+    if !gen_att_key(&mut hv_sec_key, &mut hv_pub_key) {
+        return Ok(Vec::new());
+    }
+    let z = sm2_z(CERT_SIGNER_ID, hv_pub_key);
+    let mut signed = Vec::with_capacity(z.len() + cert.tbs_certificate().len());
+    signed.extend_from_slice(&z);
+    signed.extend_from_slice(cert.tbs_certificate());
+    let sig = sm2_gen_sign_digest(&sm3_digest(&signed), hv_sec_key);
+    let mut sig_buf = [0; HE_ENCL_QUOTE_SIZE as usize];
+    sig_buf[..32].copy_from_slice(&u64x4_to_bytes(&sig.r.value));
+    sig_buf[32..].copy_from_slice(&u64x4_to_bytes(&sig.s.value));
+    Ok(cert.finish(&sig_buf))
+}
+
 pub fn he_create_enclave_quote(report: &SgxReport, quote: &mut SgxQuote) -> u32 {
     quote.report_body = report.body;
     // quote.report_body.print();
@@ -217,6 +1023,13 @@ pub fn he_create_enclave_quote(report: &SgxReport, quote: &mut SgxQuote) -> u32
 
 pub fn create_key(key_request: &SgxKeyRequest, enclave: &Enclave, key: &mut SgxKey128Bit) -> u32 {
     let mut dd: DerivationData = Default::default();
+    if key_request.key_name == SGX_KEYSELECT_REPORT {
+        if !dd.init_with_target_info(&self_target_info(enclave), &key_request.key_id) {
+            println!("HyperEnclave:DerivationData.init_with_target_info failed");
+            return 0;
+        }
+        return derive_enclave_key(&dd, key);
+    }
     let mr_enclave = enclave.measurement();
     let mr_signer = enclave.mr_signer();
     let (flags, xfrm) = enclave.attributes();
@@ -237,11 +1050,7 @@ pub fn create_key(key_request: &SgxKeyRequest, enclave: &Enclave, key: &mut SgxK
 
 pub fn derive_enclave_key(dd: &DerivationData, key: &mut SgxKey128Bit) -> u32 {
     unsafe {
-        let dd_bytes = slice::from_raw_parts(
-            dd as *const DerivationData as *const u8,
-            size_of::<DerivationData>(),
-        );
-        let hash = sm3_enc(dd_bytes);
+        let hash = sm3_enc(dd.as_bytes());
         let hash_bytes =
             slice::from_raw_parts(hash.as_ptr() as *const u8, size_of::<SgxKey128Bit>());
         key.copy_from_slice(hash_bytes);
@@ -251,20 +1060,8 @@ pub fn derive_enclave_key(dd: &DerivationData, key: &mut SgxKey128Bit) -> u32 {
 
 pub fn enclave_verify_report(report: &SgxReport, enclave: &Enclave) -> bool {
     let mut dd: DerivationData = Default::default();
-    let (flags, xfrm) = enclave.attributes();
-    let mr_enclave = enclave.measurement();
-    let (isv_prod_id, _isv_svn) = enclave.isv();
-    let mut kr: SgxKeyRequest = Default::default();
-    kr.key_id.copy_from_slice(report.get_key_id());
-    if !dd.init_with_key_request(
-        &kr,
-        mr_enclave.as_slice(),
-        enclave.mr_signer(),
-        isv_prod_id,
-        flags,
-        xfrm,
-    ) {
-        println!("HyperEnclave: DerivationData.init_with_key_request failed");
+    if !dd.init_with_target_info(&self_target_info(enclave), report.get_key_id()) {
+        println!("HyperEnclave: DerivationData.init_with_target_info failed");
         return false;
     }
     verify_report(report, &dd)
@@ -278,24 +1075,25 @@ fn verify_report(report: &SgxReport, dd: &DerivationData) -> bool {
     report_replica.mac_equal(&report.mac)
 }
 
+/// Verifies a report targeted to the quoting enclave, as the reports to be
+/// quoted are.
 pub fn qe_verify_report(report: &SgxReport) -> bool {
-    let qe: SgxTargetInfo = Default::default();
     let mut dd: DerivationData = Default::default();
-    let mut kr: SgxKeyRequest = Default::default();
-    debug!("{:?}", qe);
-    debug!("{:?}", dd);
-    debug!("{:?}", kr);
-    kr.key_id.copy_from_slice(report.get_key_id());
-    let mr_signer: [u8; SGX_HASH_SIZE as usize] = [0; SGX_HASH_SIZE as usize];
-    let (flags, xfrm) = qe.get_attributes();
-    if !dd.init_with_key_request(&kr, qe.get_mr_enclave(), &mr_signer, 0, flags, xfrm) {
-        //shoud be changed to flags, xfrm
-        println!("HyperEnclave: DerivationData.init_with_key_request failed");
+    if !dd.init_with_target_info(&SgxTargetInfo::quoting_enclave(), report.get_key_id()) {
+        println!("HyperEnclave: DerivationData.init_with_target_info failed");
         return false;
     }
     verify_report(report, &dd)
 }
 
+/// The ECDSA P-256 attestation key of DCAP quotes.
+fn dcap_att_key() -> Option<SigningKey> {
+    let seed = ROOT_OF_TRUST.get()?.secrets.dcap_ak_seed;
+    // fails only if the seed is not below the order of the curve, with a
+    // negligible probability
+    SigningKey::from_bytes(&seed.into()).ok()
+}
+
 fn he_fill_quote_ak_pub(quote: &mut SgxQuote) -> u32 {
     let mut hv_sec_key = get_sec_key();
     let mut hv_pub_key = get_pub_key(hv_sec_key);
@@ -333,29 +1131,125 @@ fn copy_hv_pub_ak_buf(buf: &mut [u8]) -> usize {
     start_index
 }
 
-// extend hash of hypervisor ak pub into pcr 13
+// measure hypervisor ak pub into pcr 13
 fn he_extend_ak() -> bool {
     let mut pk_buf: [u8; HE_HV_ATT_KEY_LEN as usize] = [0; HE_HV_ATT_KEY_LEN as usize];
     if copy_hv_pub_ak_buf(&mut pk_buf) != HE_HV_ATT_KEY_LEN as usize {
         println!("HyperEnclave: failed to get attestation key");
         return false;
     }
-    unsafe {
-        if he_extend_ak_pub(pk_buf.as_ptr() as *const u8, HE_HV_ATT_KEY_LEN as u32) == 0 {
-            println!("HyperEnclave: failed to extend attestation key");
-            return false;
-        }
+    if let Err(e) = eventlog::measure_attestation_key(HV_AK_PCR, &pk_buf) {
+        println!("HyperEnclave: failed to extend attestation key: {:?}", e);
+        return false;
     }
     true
 }
 
+/// Extends `digest` into `pcr` of the SM3 bank, then runs `f` before any other
+/// TPM command, so that `f` sees the extends in the order they are made.
+pub fn extend_pcr<R>(pcr: u32, digest: &[u8; 32], f: impl FnOnce() -> R) -> tpm2::Result<R> {
+    with_tpm(|tpm| {
+        tpm.pcr_extend(pcr, &[(TPM_ALG_SM3_256, digest)])?;
+        Ok(f())
+    })
+}
+
+fn quote_pcr_selection() -> PcrSelection {
+    PcrSelection::new(TPM_ALG_SM3_256, &QUOTE_PCRS)
+}
+
+/// Quotes the PCRs of `QUOTE_PCRS` with the SM3 digest of `report_data` as
+/// qualifying data.
+fn tpm_quote(report_data: &[u8]) -> tpm2::Result<Quote> {
+    let digest = sm3_digest(report_data);
+    with_tpm(|tpm| {
+        tpm.quote(
+            tpm_ak_handle(),
+            &[],
+            &digest,
+            AK_SCHEME,
+            &[quote_pcr_selection()],
+        )
+    })
+}
+
+/// The evidence for the hypervisor attestation key that follows `encl_quote`
+/// in an `SgxQuote`: `hv_att_pub`, a TPM quote of `report_data` with its
+/// signature, then the AK certificate if there is one.
+fn tpm_evidence(report_data: &[u8]) -> Option<Vec<u8>> {
+    let size = HE_HV_ATT_KEY_LEN + HE_TPM_ATT_DATA_LEN + HE_TPM_SIG_LEN + HE_CERT_BUF_LEN;
+    let mut evidence = vec![0; size as usize];
+    let mut len = copy_hv_pub_ak_buf(&mut evidence);
+    if len != HE_HV_ATT_KEY_LEN as usize {
+        println!("HyperEnclave: failed to get attestation key");
+        return None;
+    }
+    len += match copy_platform_quote(&mut evidence[len..], report_data) {
+        Some(tpm_quote_len) => tpm_quote_len,
+        None => {
+            println!("HyperEnclave: failed to get platform quote");
+            return None;
+        }
+    };
+    len += read_cert(&mut evidence[len..], 1) as usize;
+    evidence.truncate(len);
+    Some(evidence)
+}
+
+/// Copies `data` to the start of `buf`, returning its length.
+fn copy_to_buf(buf: &mut [u8], data: &[u8]) -> Option<usize> {
+    buf.get_mut(..data.len())?.copy_from_slice(data);
+    Some(data.len())
+}
+
+/// Copies an SM2 signature of the TPM as `r || s`, each left-padded to 32
+/// bytes, returning its length.
+fn copy_signature(buf: &mut [u8], sig: &Signature) -> Option<usize> {
+    let (r, s) = buf.get_mut(..HE_TPM_SIG_LEN as usize)?.split_at_mut(32);
+    for (dst, src) in [(r, &sig.r), (s, &sig.s)].iter_mut() {
+        let pad = dst.len().checked_sub(src.len())?;
+        dst[..pad].fill(0);
+        dst[pad..].copy_from_slice(src);
+    }
+    Some(HE_TPM_SIG_LEN as usize)
+}
+
+/// Copies a TPM quote as a `TPM2B_ATTEST` followed by the signature.
+fn copy_tpm_quote(buf: &mut [u8], tpm_quote: &Quote) -> Option<usize> {
+    let mut attest = Writer::new();
+    attest.tpm2b(&tpm_quote.attest);
+    if attest.len() != HE_TPM_ATT_DATA_LEN as usize {
+        return None;
+    }
+    let attest_len = copy_to_buf(buf, attest.as_slice())?;
+    let sig_len = copy_signature(&mut buf[attest_len..], &tpm_quote.signature)?;
+    Some(attest_len + sig_len)
+}
+
+/// Copies a platform quote of `report_data`, laid out as by `copy_tpm_quote`.
+fn copy_platform_quote(buf: &mut [u8], report_data: &[u8]) -> Option<usize> {
+    tpm_quote(report_data)
+        .ok()
+        .and_then(|tpm_quote| copy_tpm_quote(buf, &tpm_quote))
+}
+
 pub fn sign_csr(csr: &[u8], csr_len: u32, sig: &mut [u8]) -> u32 {
-    let _lock = TPM_LOCK.lock();
-    let sign_len;
-    unsafe {
-        sign_len = he_sign_csr(csr.as_ptr(), csr_len as uint32_t, sig.as_mut_ptr()) as u32;
+    let csr = match csr.get(..csr_len as usize) {
+        Some(csr) => csr,
+        None => return 0,
+    };
+    let result = with_tpm(|tpm| {
+        // the ticket proves that the csr is not a forged TPM attestation
+        let (digest, ticket) = tpm.hash(csr, TPM_ALG_SM3_256, TPM_RH_ENDORSEMENT)?;
+        tpm.sign(tpm_ak_handle(), &[], &digest, AK_SCHEME, &ticket)
+    });
+    match result {
+        Ok(signature) => copy_signature(sig, &signature).unwrap_or(0) as u32,
+        Err(e) => {
+            println!("HyperEnclave: failed to sign csr: {}", e);
+            0
+        }
     }
-    sign_len
 }
 
 pub fn get_pub_keys(
@@ -364,227 +1258,307 @@ pub fn get_pub_keys(
     pcr_buf: &mut [u8],
     pub_area: &mut [u8],
 ) -> u32 {
-    let _lock = TPM_LOCK.lock();
     if copy_hv_pub_ak_buf(hv_ak_pub) != HE_HV_ATT_KEY_LEN as usize {
         println!("HyperEnclave: failed to get hvattestation key");
         return 0;
     }
-    unsafe {
-        if he_get_tpm_ak_pub(tpm_ak_pub.as_mut_ptr() as *mut u8) != HE_HV_ATT_KEY_LEN as u32 {
-            println!("HyperEnclave: failed to get tpm attestation key");
+    let tpm_ak = match ROOT_OF_TRUST.get() {
+        Some(root) => &root.tpm_ak.public,
+        None => return 0,
+    };
+    let tpm_ak_len = copy_to_buf(tpm_ak_pub, &tpm_ak.unique.x).and_then(|x_len| {
+        copy_to_buf(&mut tpm_ak_pub[x_len..], &tpm_ak.unique.y).map(|y_len| x_len + y_len)
+    });
+    if tpm_ak_len != Some(HE_HV_ATT_KEY_LEN as usize) {
+        println!("HyperEnclave: failed to get tpm attestation key");
+        return 0;
+    }
+    let pcrs = match with_tpm(|tpm| tpm.pcr_read(&[quote_pcr_selection()])) {
+        Ok(pcrs) if pcrs.digests.len() == QUOTE_PCRS.len() => pcrs.digests.concat(),
+        Ok(_) => {
+            println!("HyperEnclave: missing SM3 PCRs");
             return 0;
         }
-    }
-    let pcr_list_size;
-    let area_size;
-    unsafe {
-        pcr_list_size = he_get_pcr_list(pcr_buf.as_mut_ptr() as *mut u8);
-        area_size = he_get_ak_pub_area(pub_area.as_mut_ptr() as *mut u8);
-    }
-    println!(
-        "pcr_list_size={} pub_area_size={}",
-        pcr_list_size, area_size
-    );
-
-    (HE_HV_ATT_KEY_LEN * 2 + pcr_list_size + area_size) as u32
+        Err(e) => {
+            println!("HyperEnclave: failed to read PCRs: {}", e);
+            return 0;
+        }
+    };
+    let (pcr_list_size, area_size) = match (
+        copy_to_buf(pcr_buf, &pcrs),
+        copy_to_buf(pub_area, &tpm_ak.to_bytes()),
+    ) {
+        (Some(pcr_list_size), Some(area_size)) => (pcr_list_size, area_size),
+        _ => return 0,
+    };
+    (HE_HV_ATT_KEY_LEN as usize * 2 + pcr_list_size + area_size) as u32
 }
 
 pub fn write_cert(cert_buf: &[u8], cert_len: u32) -> u32 {
-    let _lock = TPM_LOCK.lock();
-    unsafe {
-        if he_write_tpm_ak_cert(cert_buf.as_ptr(), cert_len) != cert_len {
-            error!("HyperEnclave: failed to write certificate to TPM NV");
-            return 0;
+    let cert = match cert_buf.get(..cert_len as usize) {
+        Some(cert) if cert.len() <= HE_CERT_BUF_LEN as usize => cert,
+        _ => return 0,
+    };
+    let result = with_tpm(|tpm| {
+        let defined = match tpm.nv_read_public(NV_INDEX_AK_CERT) {
+            Ok((public, _)) if public.data_size as usize >= cert.len() => true,
+            Ok(_) => {
+                tpm.nv_undefine_space(TPM_RH_OWNER, &[], NV_INDEX_AK_CERT)?;
+                false
+            }
+            Err(e) if e.is_rc(TPM_RC_HANDLE) => false,
+            Err(e) => return Err(e),
+        };
+        if !defined {
+            let public = NvPublic {
+                index: NV_INDEX_AK_CERT,
+                name_alg: TPM_ALG_SM3_256,
+                attributes: TPMA_NV_OWNERWRITE
+                    | TPMA_NV_OWNERREAD
+                    | TPMA_NV_AUTHREAD
+                    | TPMA_NV_NO_DA,
+                auth_policy: Vec::new(),
+                data_size: HE_CERT_BUF_LEN as u16,
+            };
+            tpm.nv_define_space(TPM_RH_OWNER, &[], &[], &public)?;
         }
+        tpm.nv_write(TPM_RH_OWNER, &[], NV_INDEX_AK_CERT, cert, 0)
+    });
+    if let Err(e) = result {
+        error!("HyperEnclave: failed to write certificate to TPM NV: {}", e);
+        return 0;
     }
     cert_len
 }
 
-pub fn read_cert(cert_buf: &mut [u8], read: u32) -> u32 {
-    let _lock = TPM_LOCK.lock();
-    unsafe { he_read_cert(cert_buf.as_mut_ptr(), cert_buf.len() as u32, read) as u32 }
+/// Returns the length of the DER encoded certificate starting with `header`.
+fn der_cert_len(header: &[u8]) -> Option<usize> {
+    match *header {
+        [0x30, len, ..] if len < 0x80 => Some(2 + len as usize),
+        [0x30, 0x81, len, ..] => Some(3 + len as usize),
+        [0x30, 0x82, hi, lo, ..] => Some(4 + u16::from_be_bytes([hi, lo]) as usize),
+        _ => None,
+    }
 }
 
-pub fn activate_credential(blob: &[u8], enc_secret: &[u8], key: &mut [u8]) -> u32 {
-    let _lock = TPM_LOCK.lock();
-    unsafe {
-        he_activate_credential(
-            blob.as_ptr(),
-            blob.len() as u32,
-            enc_secret.as_ptr(),
-            enc_secret.len() as u32,
-            key.as_mut_ptr(),
-        )
+/// Reads the TPM AK certificate if `read` is 1, or else the EK certificate.
+pub fn read_cert(cert_buf: &mut [u8], read: u32) -> u32 {
+    let index = if read == 1 {
+        NV_INDEX_AK_CERT
+    } else {
+        NV_INDEX_EK_CERT
+    };
+    let result = with_tpm(|tpm| {
+        let (public, _) = tpm.nv_read_public(index)?;
+        let auth_handle = if public.attributes & TPMA_NV_AUTHREAD != 0 {
+            index
+        } else {
+            TPM_RH_OWNER
+        };
+        let header = tpm.nv_read(auth_handle, &[], index, public.data_size.min(4), 0)?;
+        let cert_len = der_cert_len(&header).ok_or(tpm2::Error::Malformed)?;
+        if cert_len > public.data_size as usize || cert_len > cert_buf.len() {
+            return Err(tpm2::Error::Malformed);
+        }
+        let cert = tpm.nv_read(auth_handle, &[], index, cert_len as u16, 0)?;
+        cert_buf[..cert_len].copy_from_slice(&cert);
+        Ok(cert_len)
+    });
+    match result {
+        Ok(cert_len) => cert_len as u32,
+        Err(e) => {
+            println!("HyperEnclave: failed to read cert {:#x}: {}", index, e);
+            0
+        }
     }
 }
-#[no_mangle]
-pub extern "C" fn hv_va_to_pa(va: uint64_t) -> uint64_t {
-    virt_to_phys(va as usize) as uint64_t
-}
 
-#[no_mangle]
-pub extern "C" fn println_str(s: *const c_char) {
-    let c_str = unsafe {
-        assert!(!s.is_null());
-        CStr::from_ptr(s)
-    };
-    let r_str = c_str.to_str().unwrap();
-    println!("{}", r_str);
+/// Activates `blob` with the TPM AK, using the endorsement key `ek` to
+/// decrypt the seed in `secret`.
+fn activate_with_ek(tpm: &mut HvTpm, ek: u32, blob: &[u8], secret: &[u8]) -> tpm2::Result<Vec<u8>> {
+    let ak = tpm_ak_handle();
+    let session = tpm.start_policy_session(TPM_ALG_SM3_256)?;
+    let result = tpm
+        .policy_secret(TPM_RH_ENDORSEMENT, &[], session)
+        .and_then(|_| tpm.activate_credential(ak, &[], ek, Auth::Policy(session), blob, secret));
+    // the session is only flushed by the TPM when the command succeeds
+    if result.is_err() {
+        tpm.flush_context(session).ok();
+    }
+    result
 }
 
-#[no_mangle]
-pub extern "C" fn println_u32(s: *const c_char, num1: uint32_t, num2: uint32_t) {
-    let c_str = unsafe {
-        assert!(!s.is_null());
-        CStr::from_ptr(s)
-    };
-    let r_str = c_str.to_str().unwrap();
-    println!("{} :{} :{}", r_str, num1, num2);
+pub fn activate_credential(blob: &[u8], enc_secret: &[u8], key: &mut [u8]) -> u32 {
+    // the secret is the ephemeral ECC point of the certificate authority
+    let (x, y) = enc_secret.split_at(enc_secret.len() / 2);
+    let mut secret = Writer::new();
+    secret.tpm2b(x).tpm2b(y);
+    let result = with_tpm(|tpm| {
+        let ek = tpm.create_primary(TPM_RH_ENDORSEMENT, &[], &tpm_ek_template(), &[])?;
+        let result = activate_with_ek(tpm, ek.handle, blob, secret.as_slice());
+        tpm.flush_context(ek.handle)?;
+        result
+    });
+    match result {
+        Ok(credential) => copy_to_buf(key, &credential).unwrap_or(0) as u32,
+        Err(e) => {
+            println!("HyperEnclave: failed to activate credential: {}", e);
+            0
+        }
+    }
 }
 
-#[no_mangle]
-pub extern "C" fn println_u64(s: *const c_char, addr: uint64_t) {
-    let c_str = unsafe {
-        assert!(!s.is_null());
-        CStr::from_ptr(s)
-    };
-    let r_str = c_str.to_str().unwrap();
-    println!("{} :{:#x}", r_str, addr);
+fn tpm_error(e: tpm2::Error) -> HyperCallError {
+    hypercall_hv_err!(EIO, format!("TPM error: {:?}", e))
 }
 
-#[no_mangle]
-pub extern "C" fn println_hex(s: *const c_char, hex_ptr: *const uint8_t, len: uint32_t) {
-    let c_str = unsafe {
-        assert!(!s.is_null());
-        CStr::from_ptr(s)
-    };
-    let r_str = c_str.to_str().unwrap();
-    println!("{}:{}", r_str, len);
-    unsafe {
-        let mut pos: u32 = 0;
-        let mut pointer: uintptr_t = hex_ptr as uintptr_t;
-        while pos < len {
-            print!("{:#04x}", *(pointer as *const uint8_t));
-            pointer += 1;
-            pos += 1;
+/// The owner of the counters of `enclave` for `owner_type`, the SM3 digest of
+/// the owner type and the MRENCLAVE or MRSIGNER.
+pub fn counter_owner(enclave: &Enclave, owner_type: u64) -> HyperCallResult<[u8; 32]> {
+    let mut data = Vec::with_capacity(40);
+    data.extend_from_slice(&owner_type.to_le_bytes());
+    match owner_type {
+        HV_ENCL_COUNTER_OWNER_MRENCLAVE => data.extend_from_slice(enclave.measurement().as_slice()),
+        HV_ENCL_COUNTER_OWNER_MRSIGNER => data.extend_from_slice(enclave.mr_signer()),
+        _ => {
+            return hypercall_hv_err_result!(
+                EINVAL,
+                format!("invalid counter owner type {:#x}", owner_type)
+            )
         }
     }
-    println!(":hex_end");
+    Ok(sm3_digest(&data))
 }
 
-#[no_mangle]
-pub extern "C" fn hv_gen_sm3_hash(
-    data_ptr: *const uint8_t,
-    data_size: usize,
-    hash_ptr: *mut uint8_t,
-) -> bool {
-    if data_ptr.is_null() || hash_ptr.is_null() || data_size == 0 {
-        return false;
+/// The number of counter slots configured in `HvSystemConfig`.
+fn num_counter_slots() -> u32 {
+    match HvSystemConfig::get().num_counter_slots() {
+        0 => DEFAULT_COUNTER_SLOTS,
+        n => n.min(MAX_COUNTER_SLOTS),
     }
-    let data = unsafe { slice::from_raw_parts(data_ptr, data_size) };
-    let hash = sm3_enc(data);
-    let hash_bytes: Vec<u8> = hash.iter().flat_map(|var| var.to_be_bytes()).collect();
-    unsafe {
-        core::ptr::copy_nonoverlapping(
-            hash_bytes.as_ptr() as *const u8,
-            hash_ptr,
-            hash_bytes.len(),
-        );
-    }
-    true
 }
 
-#[no_mangle]
-pub extern "C" fn hv_gen_key(
-    seed_ptr: *const uint8_t,
-    seed_size: usize,
-    sk_ptr: *mut uint8_t,
-    pk_x_ptr: *mut uint8_t,
-    pk_y_ptr: *mut uint8_t,
-) -> bool {
-    if seed_ptr.is_null()
-        || sk_ptr.is_null()
-        || pk_x_ptr.is_null()
-        || pk_y_ptr.is_null()
-        || seed_size != size_of::<SecKey>()
-    {
-        return false;
+/// The authValue of the counter at `index` owned by `owner`.
+fn counter_auth(index: u32, owner: &[u8; 32]) -> HyperCallResult<[u8; 32]> {
+    let root = match ROOT_OF_TRUST.get() {
+        Some(root) => root,
+        None => return hypercall_hv_err_result!(ENODEV, "no root of trust"),
+    };
+    let mut data = b"HyperEnclave counter".to_vec();
+    data.extend_from_slice(&root.secrets.key_derivation_secret);
+    data.extend_from_slice(&index.to_le_bytes());
+    data.extend_from_slice(owner);
+    Ok(sm3_digest(&data))
+}
+
+/// Whether `public` is a counter index owned by `owner`, proven by reading it
+/// with the authValue of `owner`.
+fn is_counter_of(tpm: &mut HvTpm, public: &NvPublic, owner: &[u8; 32]) -> HyperCallResult<bool> {
+    if public.attributes & !NV_STATE_ATTRIBUTES != COUNTER_NV_ATTRIBUTES || public.data_size != 8 {
+        return Ok(false);
     }
-    let seed_bytes = unsafe { slice::from_raw_parts(seed_ptr, seed_size) };
-    let seed = bytes_to_u64x4(seed_bytes.try_into().unwrap());
-    let sec_key: SecKey = U64x4 { value: seed };
-    let pub_key: PubKey = get_pub_key(sec_key);
-    let sec_key_bytes = u64x4_to_bytes(&sec_key.value);
-    let pub_key_x_bytes = u64x4_to_bytes(&pub_key.x.num.value);
-    let pub_key_y_bytes = u64x4_to_bytes(&pub_key.y.num.value);
-    unsafe {
-        core::ptr::copy_nonoverlapping(
-            sec_key_bytes.as_ptr() as *const u8,
-            sk_ptr,
-            sec_key_bytes.len(),
-        );
-        core::ptr::copy_nonoverlapping(
-            pub_key_x_bytes.as_ptr() as *const u8,
-            pk_x_ptr,
-            pub_key_x_bytes.len(),
-        );
-        core::ptr::copy_nonoverlapping(
-            pub_key_y_bytes.as_ptr() as *const u8,
-            pk_y_ptr,
-            pub_key_y_bytes.len(),
-        );
+    let auth = counter_auth(public.index, owner)?;
+    match tpm.nv_read_counter(public.index, &auth, public.index) {
+        // only read once authorized
+        Ok(_) => Ok(true),
+        Err(e) if e.is_rc(TPM_RC_NV_UNINITIALIZED) => Ok(true),
+        Err(e) if e.is_rc(TPM_RC_AUTH_FAIL) => Ok(false),
+        Err(e) => Err(tpm_error(e)),
     }
-    true
 }
 
-#[no_mangle]
-pub extern "C" fn hv_gen_sm2_sign(
-    data_ptr: *const uint8_t,
-    data_size: usize,
-    sk_ptr: *const uint8_t,
-    pk_x_ptr: *const uint8_t,
-    pk_y_ptr: *const uint8_t,
-    use_z: bool,
-    sign_ptr: *mut uint8_t,
-) -> bool {
-    if data_ptr.is_null()
-        || sk_ptr.is_null()
-        || pk_x_ptr.is_null()
-        || pk_y_ptr.is_null()
-        || sign_ptr.is_null()
-        || data_size == 0
-    {
-        return false;
+/// The NV index and the authValue of the counter `id` of `owner`.
+fn counter_index(tpm: &mut HvTpm, owner: &[u8; 32], id: u64) -> HyperCallResult<(u32, [u8; 32])> {
+    if id >= num_counter_slots() as u64 {
+        return hypercall_hv_err_result!(ENOENT, format!("invalid counter ID {}", id));
+    }
+    let index = NV_INDEX_COUNTER_BASE + id as u32;
+    match tpm.nv_read_public(index) {
+        Ok((public, _)) if is_counter_of(tpm, &public, owner)? => {
+            Ok((index, counter_auth(index, owner)?))
+        }
+        Ok(_) => hypercall_hv_err_result!(EPERM, format!("counter {} has another owner", id)),
+        Err(e) if e.is_rc(TPM_RC_HANDLE) => {
+            hypercall_hv_err_result!(ENOENT, format!("counter {} does not exist", id))
+        }
+        Err(e) => Err(tpm_error(e)),
     }
+}
 
-    let data = unsafe { slice::from_raw_parts(data_ptr, data_size) };
-    let sk_bytes = unsafe { slice::from_raw_parts(sk_ptr, size_of::<SecKey>()) };
-    let pk_x_bytes = unsafe { slice::from_raw_parts(pk_x_ptr, size_of::<PubKey>() / 2) };
-    let pk_y_bytes = unsafe { slice::from_raw_parts(pk_y_ptr, size_of::<PubKey>() / 2) };
+/// Runs `f` on the TPM, failing with `EIO` where the TPM does.
+fn with_tpm_counters<R>(f: impl FnOnce(&mut HvTpm) -> HyperCallResult<R>) -> HyperCallResult<R> {
+    with_tpm(|tpm| Ok(f(tpm))).map_err(tpm_error)?
+}
 
-    let sk = bytes_to_u64x4(sk_bytes.try_into().unwrap());
-    let pk_x = bytes_to_u64x4(pk_x_bytes.try_into().unwrap());
-    let pk_y = bytes_to_u64x4(pk_y_bytes.try_into().unwrap());
+/// Creates a monotonic counter of `owner` in a free slot, returning its ID
+/// and initial value.
+///
+/// A counter starts above the highest value any counter of the TPM has ever
+/// had, so a counter destroyed and created again never goes back.
+pub fn create_counter(owner: &[u8; 32]) -> HyperCallResult<(u64, u64)> {
+    with_tpm_counters(|tpm| {
+        let mut owned = 0;
+        let mut free = None;
+        for slot in 0..num_counter_slots() {
+            let index = NV_INDEX_COUNTER_BASE + slot;
+            match tpm.nv_read_public(index) {
+                Ok((public, _)) if is_counter_of(tpm, &public, owner)? => owned += 1,
+                Ok(_) => {}
+                Err(e) if e.is_rc(TPM_RC_HANDLE) => free = free.or(Some(slot)),
+                Err(e) => return Err(tpm_error(e)),
+            }
+        }
+        if owned >= COUNTER_QUOTA {
+            return hypercall_hv_err_result!(
+                ENOSPC,
+                format!("counter quota of {} reached", COUNTER_QUOTA)
+            );
+        }
+        let slot = match free {
+            Some(slot) => slot,
+            None => return hypercall_hv_err_result!(ENOSPC, "no free counter slot"),
+        };
+        let index = NV_INDEX_COUNTER_BASE + slot;
+        let auth = counter_auth(index, owner)?;
+        let public = NvPublic {
+            index,
+            name_alg: TPM_ALG_SM3_256,
+            attributes: COUNTER_NV_ATTRIBUTES,
+            auth_policy: Vec::new(),
+            data_size: 8,
+        };
+        tpm.nv_define_space(TPM_RH_OWNER, &[], &auth, &public)
+            .map_err(tpm_error)?;
+        tpm.nv_increment(index, &auth, index).map_err(tpm_error)?;
+        let value = tpm
+            .nv_read_counter(index, &auth, index)
+            .map_err(tpm_error)?;
+        Ok((slot as u64, value))
+    })
+}
 
-    let sec_key: SecKey = U64x4 { value: sk };
-    let pub_key = PubKey::new(
-        Coordinate::new(U64x4 { value: pk_x }),
-        Coordinate::new(U64x4 { value: pk_y }),
-    );
+/// Increments the counter `id` of `owner`, returning its new value.
+pub fn increment_counter(owner: &[u8; 32], id: u64) -> HyperCallResult<u64> {
+    with_tpm_counters(|tpm| {
+        let (index, auth) = counter_index(tpm, owner, id)?;
+        tpm.nv_increment(index, &auth, index).map_err(tpm_error)?;
+        tpm.nv_read_counter(index, &auth, index).map_err(tpm_error)
+    })
+}
 
-    let sig = sm2_gen_sign(data, sec_key, pub_key, use_z);
+/// Reads the value of the counter `id` of `owner`.
+pub fn read_counter(owner: &[u8; 32], id: u64) -> HyperCallResult<u64> {
+    with_tpm_counters(|tpm| {
+        let (index, auth) = counter_index(tpm, owner, id)?;
+        tpm.nv_read_counter(index, &auth, index).map_err(tpm_error)
+    })
+}
 
-    unsafe {
-        core::ptr::copy_nonoverlapping(
-            u64x4_to_bytes(&sig.r.value).as_ptr() as *const u8,
-            sign_ptr,
-            size_of::<PubKey>() / 2,
-        );
-        core::ptr::copy_nonoverlapping(
-            u64x4_to_bytes(&sig.s.value).as_ptr() as *const u8,
-            sign_ptr.add(size_of::<PubKey>() / 2),
-            size_of::<PubKey>() / 2,
-        );
-    }
-    true
+/// Destroys the counter `id` of `owner`, freeing its slot.
+pub fn destroy_counter(owner: &[u8; 32], id: u64) -> HyperCallResult {
+    with_tpm_counters(|tpm| {
+        let (index, _) = counter_index(tpm, owner, id)?;
+        tpm.nv_undefine_space(TPM_RH_OWNER, &[], index)
+            .map_err(tpm_error)
+    })
 }
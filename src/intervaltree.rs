@@ -17,9 +17,10 @@ use alloc::collections::btree_map::BTreeMap;
 use alloc::vec::Vec;
 use core::ops::Range;
 
+/// Non-overlapping ranges, each with a value of type `T`.
 #[derive(Debug)]
-pub struct IntervalTree {
-    tree: BTreeMap<usize, Range<usize>>,
+pub struct IntervalTree<T = ()> {
+    tree: BTreeMap<usize, (Range<usize>, T)>,
 }
 
 pub fn overlap(left: &Range<usize>, right: &Range<usize>) -> Option<Range<usize>> {
@@ -32,31 +33,48 @@ pub fn overlap(left: &Range<usize>, right: &Range<usize>) -> Option<Range<usize>
     }
 }
 
-impl IntervalTree {
+impl<T: Copy + Default> IntervalTree<T> {
     pub fn new() -> Self {
         Self {
             tree: BTreeMap::new(),
         }
     }
 
-    /// Insert a value into the tree for a given range.
+    /// Insert a given range into the tree, with the default value.
     pub fn insert(&mut self, range: Range<usize>) -> HvResult {
+        self.insert_with(range, T::default())
+    }
+
+    /// Insert a value into the tree for a given range.
+    pub fn insert_with(&mut self, range: Range<usize>, value: T) -> HvResult {
         // checking to see if any overlapping occurs
         let nodes = self.tree.range(..range.end);
         if let Some(node) = nodes.last() {
-            if overlap(node.1, &range).is_some() {
+            if overlap(&(node.1).0, &range).is_some() {
                 return hv_result_err!(EINVAL, "Insert overlap");
             }
         }
-        self.tree.insert(range.start, range);
+        self.tree.insert(range.start, (range, value));
         Ok(())
     }
 
+    /// Replace the value of a range, which must completely match the input range.
+    pub fn update(&mut self, range: &Range<usize>, value: T) -> HvResult {
+        match self.tree.get_mut(&range.start) {
+            Some((var, old)) if var == range => {
+                *old = value;
+                Ok(())
+            }
+            Some(_) => hv_result_err!(EINVAL, "Range does not match"),
+            None => hv_result_err!(EINVAL, "Range does not exist"),
+        }
+    }
+
     /// Remove a value from the tree for a given range.
     /// It only allows to remove the entire range which completely matches the input range.
     pub fn remove(&mut self, range: &Range<usize>) -> HvResult {
         if let Some(var) = self.tree.remove(&(range.start)) {
-            if &var == range {
+            if &var.0 == range {
                 Ok(())
             } else {
                 self.tree.insert(range.start, var);
@@ -69,19 +87,24 @@ impl IntervalTree {
 
     /// Returns true if there is a range that contains the point argument.
     pub fn contains(&self, point: &usize) -> bool {
+        self.get(point).is_some()
+    }
+
+    /// Returns the range that contains the point argument, and its value.
+    pub fn get(&self, point: &usize) -> Option<(Range<usize>, T)> {
         let nodes = self.tree.range(..=point);
         if let Some(node) = nodes.last() {
-            if node.1.contains(point) {
-                return true;
+            if (node.1).0.contains(point) {
+                return Some(node.1.clone());
             }
         }
-        false
+        None
     }
 
     pub fn contains_range(&self, range: Range<usize>) -> bool {
         let nodes = self.tree.range(..=range.start);
         if let Some(node) = nodes.last() {
-            if node.1.start <= range.start && node.1.end >= range.end {
+            if (node.1).0.start <= range.start && (node.1).0.end >= range.end {
                 return true;
             }
         }
@@ -98,11 +121,13 @@ impl IntervalTree {
             self.tree
                 .range(..=start)
                 .last()
-                .map_or(start, |node| node.1.start)..range.end
+                .map_or(start, |node| (node.1).0.start)..range.end
         };
         let nodes = self.tree.range(the_range);
 
-        let result = nodes.filter_map(|(_, var)| overlap(range, var)).collect();
+        let result = nodes
+            .filter_map(|(_, (var, _))| overlap(range, var))
+            .collect();
 
         Ok(result)
     }
@@ -114,4 +139,18 @@ mod tests {
         use core::ops::Range;
         assert_eq!(core::mem::size_of::<(usize, Range<usize>)>(), 24);
     }
+
+    #[test]
+    fn test_range_value() {
+        use super::IntervalTree;
+        let mut tree: IntervalTree<u64> = IntervalTree::new();
+        tree.insert(0x1000..0x3000).unwrap();
+        tree.insert_with(0x5000..0x6000, 1).unwrap();
+        assert_eq!(tree.get(&0x2000), Some((0x1000..0x3000, 0)));
+        assert_eq!(tree.get(&0x3000), None);
+        assert!(tree.update(&(0x1000..0x2000), 2).is_err());
+        tree.update(&(0x1000..0x3000), 2).unwrap();
+        assert_eq!(tree.get(&0x1000), Some((0x1000..0x3000, 2)));
+        assert_eq!(tree.get(&0x5fff), Some((0x5000..0x6000, 1)));
+    }
 }
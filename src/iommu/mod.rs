@@ -48,6 +48,11 @@ pub fn init() -> HvResult {
     Ok(())
 }
 
+/// Number of IOMMU units `init` has enabled.
+pub fn enabled_units() -> usize {
+    IOMMU_LIST.get().map_or(0, |list| list.len())
+}
+
 pub fn disable() -> HvResult {
     info!("Disable IOMMU...");
     for iommu in IOMMU_LIST.get().ok_or(hv_err!(EINVAL))? {
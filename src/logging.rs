@@ -127,6 +127,11 @@ bitflags! {
     pub struct HEFeature: u64 {
         const HHBOX_LOG        = 1 << 0;
         const HHBOX_CRASH      = 1 << 1;
+        // Bits 2-3 select the reclaim crypto algorithm, see `enclave::reclaim`.
+        /// `HvSystemConfig` is followed by a `HvSystemConfigExt`.
+        const SYSTEM_CONFIG_EXT = 1 << 4;
+        /// The second argument of `EnclaveCreate` holds `HvEnclCreateFlags`.
+        const ENCLAVE_CREATE_FLAGS = 1 << 5;
     }
 }
 
@@ -68,7 +68,7 @@ use config::HvSystemConfig;
 use enclave::reclaim;
 use error::HvResult;
 use header::HvHeader;
-use hypercall::tc;
+use hypercall::{eventlog, tc};
 use percpu::PerCpu;
 
 static ENTERED_CPUS: AtomicUsize = AtomicUsize::new(0);
@@ -147,6 +147,7 @@ fn primary_init_late() -> HvResult {
         println!("HyperEnclave: tpm or cyrpto module initialization failed");
         return hv_result_err!(EIO);
     }
+    eventlog::measure_hypervisor()?;
 
     INIT_LATE_OK.store(1, Ordering::Release);
     Ok(())
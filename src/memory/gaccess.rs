@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use alloc::sync::Arc;
+use alloc::{sync::Arc, vec::Vec};
 use core::fmt::{Debug, Formatter, Result};
 use core::marker::PhantomData;
 use core::mem::size_of;
@@ -301,11 +301,13 @@ impl<T> GuestPtr<'_, T> {
 
     pub fn read(&self) -> HyperCallResult<T> {
         self.check_addr_alignment()?;
-        let mut ret = core::mem::MaybeUninit::uninit();
-        let mut dst = ret.as_mut_ptr() as *mut u8;
+        let mut ret = core::mem::MaybeUninit::<T>::uninit();
+        self.read_raw(ret.as_mut_ptr() as *mut u8, size_of::<T>())?;
+        unsafe { Ok(ret.assume_init()) }
+    }
 
+    fn read_raw(&self, mut dst: *mut u8, mut size: usize) -> HyperCallResult {
         let mut gvaddr = self.gvaddr;
-        let mut size = size_of::<T>();
         while size > 0 {
             let (gpaddr, pg_size) = Self::translate_to_gpa(
                 gvaddr,
@@ -323,15 +325,16 @@ impl<T> GuestPtr<'_, T> {
                 dst = dst.add(read_size);
             }
         }
-        unsafe { Ok(ret.assume_init()) }
+        Ok(())
     }
 
     pub fn write(&mut self, data: T) -> HyperCallResult {
         self.check_addr_alignment()?;
-        let mut src = &data as *const _ as *const u8;
+        self.write_raw(&data as *const _ as *const u8, size_of::<T>())
+    }
 
+    fn write_raw(&mut self, mut src: *const u8, mut size: usize) -> HyperCallResult {
         let mut gvaddr = self.gvaddr;
-        let mut size = size_of::<T>();
         while size > 0 {
             let (gpaddr, pg_size) = Self::translate_to_gpa(
                 gvaddr,
@@ -370,3 +373,17 @@ impl<T> GuestPtr<'_, T> {
         virt_to_phys(ptr as _)
     }
 }
+
+impl GuestPtr<'_, u8> {
+    /// Write `data` to the guest memory starting at the pointer, which may cross pages.
+    pub fn write_bytes(&mut self, data: &[u8]) -> HyperCallResult {
+        self.write_raw(data.as_ptr(), data.len())
+    }
+
+    /// Read `len` bytes from the guest memory starting at the pointer, which may cross pages.
+    pub fn read_bytes(&self, len: usize) -> HyperCallResult<Vec<u8>> {
+        let mut data = vec![0; len];
+        self.read_raw(data.as_mut_ptr(), len)?;
+        Ok(data)
+    }
+}
@@ -54,6 +54,7 @@ bitflags! {
         const USER          = 1 << 9;
         const ENCRYPTED     = 1 << 10;
         const NO_PRESENT    = 1 << 11;
+        const SHADOW_STACK  = 1 << 12;
     }
 }
 
@@ -13,16 +13,21 @@
 // limitations under the License.
 
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::fmt::{Debug, Formatter, Result};
 use core::mem::size_of;
-use core::sync::atomic::{AtomicIsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicIsize, AtomicU32, Ordering};
 
+use crate::arch::apic;
 use crate::arch::vmm::{Vcpu, VcpuAccessGuestState};
 use crate::arch::{ExceptionType, HostPageTable, LinuxContext};
 use crate::cell::Cell;
 use crate::consts::{HV_STACK_SIZE, LOCAL_PER_CPU_BASE};
+use crate::cpumask::NR_CPUS;
 use crate::enclave::epcm::EpcmManager;
-use crate::enclave::{sgx::MiscSgx, AexException, Enclave, EnclaveStatsId, EnclaveThread};
+use crate::enclave::{
+    sgx::MiscSgx, AexException, Enclave, EnclaveStatsId, EnclaveThread, VcpuAccessEnclaveState,
+};
 use crate::error::HvResult;
 use crate::ffi::PER_CPU_ARRAY_PTR;
 use crate::header::HvHeader;
@@ -38,6 +43,21 @@ pub const PER_CPU_SIZE: usize = size_of::<PerCpu>();
 
 static ACTIVATED_CPUS: AtomicIsize = AtomicIsize::new(0);
 
+// The per-CPU areas of other CPUs are not mapped, per-CPU state read by other
+// CPUs lives here, indexed by `cpu_id`.
+#[allow(clippy::declare_interior_mutable_const)]
+const APIC_ID_INIT: AtomicU32 = AtomicU32::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const ATOMIC_BOOL_INIT: AtomicBool = AtomicBool::new(false);
+/// Local APIC IDs, to send NMIs.
+static APIC_IDS: [AtomicU32; NR_CPUS] = [APIC_ID_INIT; NR_CPUS];
+/// Whether the CPU must flush the TLB before running the enclave again.
+static TLB_FLUSH_PENDING: [AtomicBool; NR_CPUS] = [ATOMIC_BOOL_INIT; NR_CPUS];
+/// Whether an NMI sent by `flush_enclave_tlb` has not reached the CPU yet.
+static FLUSH_NMI_SENT: [AtomicBool; NR_CPUS] = [ATOMIC_BOOL_INIT; NR_CPUS];
+/// Whether the CPU received an NMI that must be delivered to Linux.
+static NMI_PENDING: [AtomicBool; NR_CPUS] = [ATOMIC_BOOL_INIT; NR_CPUS];
+
 #[derive(Debug, Eq, PartialEq)]
 pub enum CpuState {
     HvDisabled,
@@ -131,10 +151,11 @@ impl PerCpu {
         unsafe {
             // avoid dropping, same below
             core::ptr::write(&mut self.hvm, hvm);
-            core::ptr::write(&mut self.enclave_thread, EnclaveThread::new());
+            core::ptr::write(&mut self.enclave_thread, EnclaveThread::new()?);
             self.hvm.activate();
             core::ptr::write(&mut self.vcpu, Vcpu::new(&self.linux, cell)?);
         }
+        APIC_IDS[cpu_id].store(apic::local_apic_id(), Ordering::Release);
 
         self.state = CpuState::HvEnabled;
         Ok(())
@@ -234,10 +255,14 @@ impl PerCpu {
                 .enter(tcs_vaddr, aep, &mut self.vcpu, &gpt, &self.state)?;
         let now = Instant::now();
         enclave.update_tracking_state(true, self.cpu_id);
+        // Entering the enclave has flushed the TLB.
+        TLB_FLUSH_PENDING[self.cpu_id].store(false, Ordering::Release);
         let time_update = now.elapsed();
         // Currently, the latency of EENTER is much less than EWB, clear ssa pages's
         // BLOCKED state when switch to enclave mode in case ssa pages are reclaimed.
-        EpcmManager::clear_blocked(self.enclave_thread.get_ssa_paddr());
+        self.enclave_thread
+            .ssa_pages()
+            .for_each(EpcmManager::clear_blocked);
         let time_clear_blocked = now.elapsed();
         self.state = CpuState::EnclaveRunning;
         enclave.atomic_add_stats(EnclaveStatsId::EnterUpdateTrackingState, time_update);
@@ -273,10 +298,14 @@ impl PerCpu {
                 .resume(tcs_vaddr, aep, &mut self.vcpu, &gpt, &self.state)?;
         let now = Instant::now();
         enclave.update_tracking_state(true, self.cpu_id);
+        // Entering the enclave has flushed the TLB.
+        TLB_FLUSH_PENDING[self.cpu_id].store(false, Ordering::Release);
         let time_update = now.elapsed();
         // Currently, the latency of ERESUME is much less than EWB, clear ssa pages's
         // BLOCKED state when switch to enclave mode in case ssa pages are reclaimed.
-        EpcmManager::clear_blocked(self.enclave_thread.get_ssa_paddr());
+        self.enclave_thread
+            .ssa_pages()
+            .for_each(EpcmManager::clear_blocked);
         let time_clear_blocked = now.elapsed();
         self.state = CpuState::EnclaveRunning;
         enclave.atomic_add_stats(EnclaveStatsId::ResumeUpdateTrackingState, time_update);
@@ -320,6 +349,87 @@ impl PerCpu {
         Ok(enclave)
     }
 
+    pub fn enclave_dec_cssa(&mut self) -> HyperCallResult<Arc<Enclave>> {
+        if self.state != CpuState::EnclaveRunning {
+            return hypercall_hv_err_result!(
+                EINVAL,
+                format!(
+                    "PerCpu::enclave_dec_cssa(): CPU {} is not running in enclave mode",
+                    self.cpu_id
+                )
+            );
+        }
+        let enclave = self.enclave_thread.dec_cssa(&self.state)?;
+        // The popped SSA frame is used by the next AEX, make sure it is not reclaimed.
+        self.enclave_thread
+            .ssa_pages()
+            .for_each(EpcmManager::clear_blocked);
+        Ok(enclave)
+    }
+
+    /// Makes the CPUs running `enclave` drop the TLB entries of mappings that
+    /// were removed or restricted. The other CPUs are forced to exit with an
+    /// NMI, and flush in `handle_tlb_flush_request` before running the enclave
+    /// again. This waits for them, so no lock of the enclave may be held.
+    pub fn flush_enclave_tlb(&mut self, enclave: &Enclave) -> HvResult {
+        let cpus: Vec<usize> = enclave
+            .running_cpus()
+            .into_iter()
+            .filter(|&cpu| cpu != self.cpu_id)
+            .collect();
+        for &cpu in &cpus {
+            TLB_FLUSH_PENDING[cpu].store(true, Ordering::Release);
+            // An NMI still on its way makes the CPU exit as well.
+            if !FLUSH_NMI_SENT[cpu].swap(true, Ordering::AcqRel) {
+                apic::send_nmi(APIC_IDS[cpu].load(Ordering::Acquire));
+            }
+        }
+        self.vcpu.flush_tlb()?;
+        for cpu in cpus {
+            while TLB_FLUSH_PENDING[cpu].load(Ordering::Acquire) && enclave.is_running_on(cpu) {
+                // The other CPU may be waiting for this one as well.
+                self.handle_tlb_flush_request()?;
+                core::hint::spin_loop();
+            }
+        }
+        Ok(())
+    }
+
+    /// Flushes the TLB if another CPU asked for it in `flush_enclave_tlb`,
+    /// before the guest runs again.
+    pub fn handle_tlb_flush_request(&mut self) -> HvResult {
+        if TLB_FLUSH_PENDING[self.cpu_id].swap(false, Ordering::AcqRel) {
+            self.vcpu.flush_tlb()?;
+        }
+        Ok(())
+    }
+
+    /// Called on NMIs taken by the hypervisor. The one sent by
+    /// `flush_enclave_tlb` only forces a VM exit, any other NMI is kept
+    /// pending until it can be injected into Linux.
+    pub fn nmi_received() {
+        let cpu_id = Self::from_local_base().cpu_id;
+        if !FLUSH_NMI_SENT[cpu_id].swap(false, Ordering::AcqRel) {
+            NMI_PENDING[cpu_id].store(true, Ordering::Release);
+        }
+    }
+
+    /// Whether an NMI received by `nmi_received` is waiting for injection.
+    pub fn nmi_pending(&self) -> bool {
+        NMI_PENDING[self.cpu_id].load(Ordering::Acquire)
+    }
+
+    /// Marks the pending NMI as injected into Linux.
+    pub fn clear_nmi_pending(&self) {
+        NMI_PENDING[self.cpu_id].store(false, Ordering::Release);
+    }
+
+    /// Whether the enclave running on this CPU has exceeded its time slice.
+    #[cfg_attr(feature = "intel", allow(dead_code))]
+    pub fn enclave_time_slice_expired(&self) -> bool {
+        self.state == CpuState::EnclaveRunning && self.enclave_thread.time_slice_expired()
+    }
+
     pub fn get_current_enclave(&self) -> HvResult<Arc<Enclave>> {
         if self.state != CpuState::EnclaveRunning {
             return hv_result_err!(